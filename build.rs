@@ -0,0 +1,25 @@
+//! Build the C++ shim for the parts of the LLJIT API which are missing from the LLVM C API.
+
+use std::process::Command;
+
+fn main() {
+    println!("cargo:rerun-if-changed=src/llvm_wrapper/lljit.cpp");
+
+    // llvm-sys exports the llvm-config it found, unless it is built without linking LLVM.
+    let Some(llvm_config) = std::env::var_os("DEP_LLVM_16_CONFIG_PATH") else {
+        return;
+    };
+
+    let output = Command::new(llvm_config)
+        .arg("--cxxflags")
+        .output()
+        .expect("Failed to run llvm-config");
+    let cxxflags = String::from_utf8(output.stdout).expect("Expected UTF8 output from llvm-config");
+
+    let mut build = cc::Build::new();
+    build.cpp(true).file("src/llvm_wrapper/lljit.cpp");
+    for flag in cxxflags.split_whitespace() {
+        build.flag(flag);
+    }
+    build.compile("lljitshim");
+}
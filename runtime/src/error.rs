@@ -0,0 +1,264 @@
+//! Runtime errors raised by generated code.
+//!
+//! Errors unwind the stack from the runtime function raising them, through the frames of
+//! compiled Cobra code, to a `try` block (see [`crate::exception`]) or to the host which called
+//! into Cobra code with [`catch`].
+//!
+//! Every Cobra function links a [`Frame`] on its native stack into the list headed by
+//! [`FRAME`], and stores the line of each call it makes in it. Raising an error walks the list
+//! to build the Cobra stack trace.
+
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use std::sync::atomic::{AtomicPtr, Ordering};
+
+use crate::exception;
+use crate::limits::CALL_DEPTH;
+use crate::CobraStr;
+
+/// Kind of a runtime error, passed by generated code to [`cobra_panic`].
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    /// `panic("message")` in Cobra code.
+    Panic = 1,
+    DivisionByZero = 2,
+    IndexOutOfBounds = 3,
+    AssertionFailed = 4,
+    Timeout = 5,
+    RecursionLimit = 6,
+    /// `raise "message"` in Cobra code.
+    Raised = 7,
+}
+
+impl ErrorCode {
+    fn from_u32(code: u32) -> ErrorCode {
+        match code {
+            2 => ErrorCode::DivisionByZero,
+            3 => ErrorCode::IndexOutOfBounds,
+            4 => ErrorCode::AssertionFailed,
+            5 => ErrorCode::Timeout,
+            6 => ErrorCode::RecursionLimit,
+            7 => ErrorCode::Raised,
+            _ => ErrorCode::Panic,
+        }
+    }
+}
+
+/// Stack frame of a Cobra function, matching the `frame` LLVM struct type of the generated code.
+#[repr(C)]
+pub struct Frame {
+    /// Frame of the calling Cobra function, null for the outermost one.
+    pub prev: *const Frame,
+    /// Name of the function.
+    pub name: CobraStr,
+    /// Line of the call the function is executing, 0 before its first call.
+    pub line: u64,
+}
+
+/// Frame of the innermost running Cobra function, null if no Cobra code is running.
+#[export_name = "cobra_frame"]
+pub static FRAME: AtomicPtr<Frame> = AtomicPtr::new(ptr::null_mut());
+
+/// Prefix of the names of the functions holding top-level expressions.
+pub const TOP_LEVEL_PREFIX: &str = "__anon_expr";
+
+/// An entry of a Cobra stack trace.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceEntry {
+    pub function: String,
+    /// Line executed in the function, 0 if unknown.
+    pub line: u32,
+}
+
+/// A runtime error of Cobra code.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CobraError {
+    pub code: ErrorCode,
+    pub message: String,
+    /// Source location (line, column) the error was raised at, if known.
+    pub loc: Option<(u32, u32)>,
+    /// The Cobra functions running when the error was raised, innermost first.
+    pub trace: Vec<TraceEntry>,
+}
+
+impl CobraError {
+    /// Create an error raised at `line:col` (0 if unknown), capturing the current stack trace.
+    pub fn new<S: Into<String>>(code: ErrorCode, message: S, line: u32, col: u32) -> CobraError {
+        let loc = (line != 0).then_some((line, col));
+        CobraError {
+            code,
+            message: message.into(),
+            loc,
+            trace: capture_trace(loc.map(|(line, _)| line)),
+        }
+    }
+}
+
+impl fmt::Display for CobraError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.loc {
+            Some((line, col)) => write!(f, "{} at {}:{}", self.message, line, col)?,
+            None => f.write_str(&self.message)?,
+        }
+        if !self.trace.is_empty() {
+            write!(f, "\nStack trace (most recent call first):")?;
+        }
+        for entry in &self.trace {
+            match entry.line {
+                0 => write!(f, "\n  in {}", entry.function)?,
+                line => write!(f, "\n  in {} at line {}", entry.function, line)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Walk the frames of the running Cobra functions. `line` overrides the line of the innermost
+/// one, which raised the error.
+fn capture_trace(mut line: Option<u32>) -> Vec<TraceEntry> {
+    let mut trace = Vec::new();
+    let mut frame = FRAME.load(Ordering::Relaxed) as *const Frame;
+    while let Some(current) = unsafe { frame.as_ref() } {
+        let name = unsafe { String::from_utf8_lossy(CobraStr::bytes(current.name.ptr, current.name.len)) };
+        let function = if name.starts_with(TOP_LEVEL_PREFIX) {
+            "<top-level>".to_string()
+        } else {
+            name.into_owned()
+        };
+        trace.push(TraceEntry {
+            function,
+            line: line.take().unwrap_or(current.line as u32),
+        });
+        frame = current.prev;
+    }
+    trace
+}
+
+/// Raise `error`, unwinding to the innermost `try` block or [`catch`].
+pub fn raise(error: CobraError) -> ! {
+    exception::throw(error)
+}
+
+/// Raise the runtime error `code` at the source location `line:col`. Called by generated code.
+///
+/// `ptr[..len]` is the message of a `panic` or a failed assertion, and may be empty.
+///
+/// # Safety
+///
+/// `ptr` and `len` must describe a valid Cobra string.
+#[no_mangle]
+pub unsafe extern "C-unwind" fn cobra_panic(code: u32, line: u32, col: u32, ptr: *const u8, len: u64) -> ! {
+    let code = ErrorCode::from_u32(code);
+    let detail = String::from_utf8_lossy(CobraStr::bytes(ptr, len));
+    let message = match code {
+        ErrorCode::DivisionByZero => "Division by zero".to_string(),
+        ErrorCode::AssertionFailed if detail.is_empty() => "Assertion failed".to_string(),
+        ErrorCode::AssertionFailed => format!("Assertion failed: {}", detail),
+        _ => detail.into_owned(),
+    };
+    raise(CobraError::new(code, message, line, col))
+}
+
+/// Call the Cobra code `f`, returning the error it raised if any.
+///
+/// `f` must call a top-level expression, whose handler turns errors into panics. Panics which are
+/// not Cobra errors are propagated.
+pub fn catch<R, F: FnOnce() -> R>(f: F) -> Result<R, CobraError> {
+    let depth = CALL_DEPTH.load(Ordering::Relaxed);
+    let frame = FRAME.load(Ordering::Relaxed);
+    panic::catch_unwind(AssertUnwindSafe(f)).map_err(|payload| {
+        // Functions restore the call depth and frame when they return, which the unwound ones
+        // didn't.
+        CALL_DEPTH.store(depth, Ordering::Relaxed);
+        FRAME.store(frame, Ordering::Relaxed);
+        match payload.downcast::<CobraError>() {
+            Ok(error) => *error,
+            Err(payload) => panic::resume_unwind(payload),
+        }
+    })
+}
+
+/// Call the top-level expression function `f` of an ahead-of-time compiled executable. Exit the
+/// process with status 1 if it raises an error.
+#[no_mangle]
+pub extern "C" fn cobra_run(f: unsafe extern "C-unwind" fn() -> f64) -> f64 {
+    match catch(|| unsafe { f() }) {
+        Ok(value) => value,
+        Err(error) => {
+            eprintln!("Runtime error: {}", error);
+            std::process::exit(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn name(name: &'static str) -> CobraStr {
+        CobraStr { ptr: name.as_ptr(), len: name.len() as u64 }
+    }
+
+    #[test]
+    fn errors_are_displayed_with_their_trace() {
+        let error = CobraError {
+            code: ErrorCode::IndexOutOfBounds,
+            message: "Index 3 out of bounds for length 2".to_string(),
+            loc: Some((4, 9)),
+            trace: vec![
+                TraceEntry { function: "get".to_string(), line: 4 },
+                TraceEntry { function: "<top-level>".to_string(), line: 0 },
+            ],
+        };
+        assert_eq!(
+            error.to_string(),
+            "Index 3 out of bounds for length 2 at 4:9\n\
+             Stack trace (most recent call first):\n  in get at line 4\n  in <top-level>"
+        );
+
+        let error = CobraError { loc: None, trace: Vec::new(), ..error };
+        assert_eq!(error.to_string(), "Index 3 out of bounds for length 2");
+        assert_eq!(ErrorCode::from_u32(2), ErrorCode::DivisionByZero);
+        assert_eq!(ErrorCode::from_u32(99), ErrorCode::Panic);
+    }
+
+    // The only test touching `FRAME` and `CALL_DEPTH`.
+    #[test]
+    fn catch_captures_the_trace_and_restores_the_stack() {
+        let outer = Frame { prev: ptr::null(), name: name("__anon_expr3"), line: 5 };
+        let inner = Frame { prev: &outer, name: name("fib"), line: 2 };
+        let frame = &inner as *const Frame as *mut Frame;
+        FRAME.store(frame, Ordering::Relaxed);
+        CALL_DEPTH.store(2, Ordering::Relaxed);
+
+        let res = catch(|| {
+            let error = CobraError::new(ErrorCode::Raised, "boom", 9, 4);
+            // The unwound functions don't unlink their frames.
+            FRAME.store(ptr::null_mut(), Ordering::Relaxed);
+            CALL_DEPTH.store(7, Ordering::Relaxed);
+            panic::resume_unwind(Box::new(error))
+        });
+        let error = res.unwrap_err();
+        assert_eq!(error.loc, Some((9, 4)));
+        assert_eq!(
+            error.trace,
+            [
+                TraceEntry { function: "fib".to_string(), line: 9 },
+                TraceEntry { function: "<top-level>".to_string(), line: 5 },
+            ]
+        );
+        assert_eq!(FRAME.load(Ordering::Relaxed), frame);
+        assert_eq!(CALL_DEPTH.load(Ordering::Relaxed), 2);
+
+        // Other panics are propagated.
+        let res = panic::catch_unwind(|| catch(|| panic::resume_unwind(Box::new("not a Cobra error"))));
+        assert_eq!(res.unwrap_err().downcast_ref::<&str>(), Some(&"not a Cobra error"));
+        assert_eq!(catch(|| 1.5), Ok(1.5));
+
+        FRAME.store(ptr::null_mut(), Ordering::Relaxed);
+        CALL_DEPTH.store(0, Ordering::Relaxed);
+        assert!(CobraError::new(ErrorCode::Panic, "", 0, 0).trace.is_empty());
+    }
+}
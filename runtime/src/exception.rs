@@ -0,0 +1,339 @@
+//! Exceptions carrying [`CobraError`]s through compiled Cobra code.
+//!
+//! Errors are thrown with the Itanium C++ ABI unwinder, as exceptions of the class
+//! [`EXCEPTION_CLASS`]. Generated code catches them in the landing pads of `try` blocks, found
+//! by [`cobra_personality`] in the exception tables emitted by LLVM.
+//!
+//! Rust can't catch foreign exceptions, so every top-level expression catches the exceptions
+//! its code didn't handle and passes them to [`cobra_uncaught`], which turns them into a panic
+//! caught by [`catch`][crate::error::catch].
+
+use std::os::raw::{c_int, c_void};
+use std::panic;
+
+use crate::error::{CobraError, ErrorCode};
+use crate::CobraStr;
+
+/// Exception class of Cobra exceptions, "COBRA\0\0\0" read as a big endian number.
+pub const EXCEPTION_CLASS: u64 = u64::from_be_bytes(*b"COBRA\0\0\0");
+
+/// `_Unwind_Exception` header of exceptions, which the unwinder uses for its own state.
+#[repr(C)]
+struct UnwindException {
+    exception_class: u64,
+    exception_cleanup: Option<extern "C" fn(c_int, *mut UnwindException)>,
+    /// Private fields of the unwinder, sized for the largest unwinder (LLVM libunwind).
+    private: [usize; 6],
+}
+
+/// A Cobra exception. The header must come first, the unwinder passes pointers to it.
+#[repr(C)]
+struct Exception {
+    header: UnwindException,
+    error: CobraError,
+}
+
+/// Opaque `_Unwind_Context` of the frame being unwound.
+#[repr(C)]
+pub struct UnwindContext {
+    _private: [u8; 0],
+}
+
+// `_Unwind_Reason_Code` values.
+const URC_FATAL_PHASE1_ERROR: c_int = 3;
+const URC_HANDLER_FOUND: c_int = 6;
+const URC_INSTALL_CONTEXT: c_int = 7;
+const URC_CONTINUE_UNWIND: c_int = 8;
+
+// `_Unwind_Action` flags.
+const UA_SEARCH_PHASE: c_int = 1;
+
+/// Registers receiving the exception pointer and the selector in landing pads
+/// (`__builtin_eh_return_data_regno(0)` and `(1)`).
+#[cfg(target_arch = "x86_64")]
+const DATA_REGS: (c_int, c_int) = (0, 1);
+#[cfg(target_arch = "x86")]
+const DATA_REGS: (c_int, c_int) = (0, 2);
+#[cfg(any(target_arch = "aarch64", target_arch = "arm"))]
+const DATA_REGS: (c_int, c_int) = (0, 1);
+#[cfg(any(target_arch = "riscv64", target_arch = "riscv32"))]
+const DATA_REGS: (c_int, c_int) = (10, 11);
+
+extern "C-unwind" {
+    fn _Unwind_RaiseException(exception: *mut UnwindException) -> c_int;
+}
+
+extern "C" {
+    fn _Unwind_GetLanguageSpecificData(context: *mut UnwindContext) -> *const u8;
+    fn _Unwind_GetRegionStart(context: *mut UnwindContext) -> usize;
+    fn _Unwind_GetIPInfo(context: *mut UnwindContext, ip_before_insn: *mut c_int) -> usize;
+    fn _Unwind_SetGR(context: *mut UnwindContext, index: c_int, value: usize);
+    fn _Unwind_SetIP(context: *mut UnwindContext, value: usize);
+}
+
+/// Throw `error` as a Cobra exception, unwinding to the innermost landing pad of Cobra code.
+pub fn throw(error: CobraError) -> ! {
+    extern "C" fn cleanup(_reason: c_int, exception: *mut UnwindException) {
+        drop(unsafe { Box::from_raw(exception as *mut Exception) });
+    }
+
+    let exception = Box::into_raw(Box::new(Exception {
+        header: UnwindException {
+            exception_class: EXCEPTION_CLASS,
+            exception_cleanup: Some(cleanup),
+            private: [0; 6],
+        },
+        error,
+    }));
+
+    // Only returns if no Cobra frame handles the exception, ie Cobra code was called without
+    // the handler of a top-level expression.
+    let reason = unsafe { _Unwind_RaiseException(exception as *mut UnwindException) };
+    let error = unsafe { Box::from_raw(exception) }.error;
+    eprintln!("Uncaught runtime error (unwinder returned {}): {}", reason, error);
+    std::process::abort()
+}
+
+/// Take the error out of the exception caught by a Cobra landing pad.
+///
+/// # Safety
+///
+/// `exception` must be a Cobra exception caught by a landing pad, which is not used afterwards.
+unsafe fn take_error(exception: *mut c_void) -> CobraError {
+    let exception = Box::from_raw(exception as *mut Exception);
+    debug_assert_eq!(exception.header.exception_class, EXCEPTION_CLASS);
+    exception.error
+}
+
+/// Begin handling the exception caught by the `except` clause of a `try` block, returning the
+/// message of its error. Called by generated code.
+///
+/// Timeouts can't be handled by Cobra code, they are thrown again.
+///
+/// # Safety
+///
+/// `exception` must be a Cobra exception caught by a landing pad, which is not used afterwards.
+#[no_mangle]
+pub unsafe extern "C-unwind" fn cobra_begin_catch(exception: *mut c_void) -> CobraStr {
+    let error = take_error(exception);
+    if error.code == ErrorCode::Timeout {
+        throw(error);
+    }
+    CobraStr::leak(error.message.into_bytes())
+}
+
+/// Raise the exception no `try` block of a top-level expression handled as a panic, unwinding
+/// to the host. Called by generated code.
+///
+/// # Safety
+///
+/// `exception` must be a Cobra exception caught by a landing pad, which is not used afterwards.
+#[no_mangle]
+pub unsafe extern "C-unwind" fn cobra_uncaught(exception: *mut c_void) -> ! {
+    panic::resume_unwind(Box::new(take_error(exception)))
+}
+
+/// Personality function of Cobra code, called by the unwinder for every Cobra frame on the
+/// stack.
+///
+/// Landing pads with a `catch` clause (`except` clauses and the handlers of top-level
+/// expressions) handle every Cobra exception, cleanup landing pads (`finally` clauses) are
+/// entered in the second phase only. Other exceptions pass through Cobra code.
+///
+/// # Safety
+///
+/// Must only be called by the unwinder.
+#[no_mangle]
+pub unsafe extern "C" fn cobra_personality(
+    _version: c_int,
+    actions: c_int,
+    exception_class: u64,
+    exception: *mut c_void,
+    context: *mut UnwindContext,
+) -> c_int {
+    if exception_class != EXCEPTION_CLASS {
+        return URC_CONTINUE_UNWIND;
+    }
+    let (landing_pad, catches) = match find_landing_pad(context) {
+        Ok(Some(found)) => found,
+        Ok(None) => return URC_CONTINUE_UNWIND,
+        Err(()) => return URC_FATAL_PHASE1_ERROR,
+    };
+
+    if actions & UA_SEARCH_PHASE != 0 {
+        return if catches { URC_HANDLER_FOUND } else { URC_CONTINUE_UNWIND };
+    }
+    _Unwind_SetGR(context, DATA_REGS.0, exception as usize);
+    _Unwind_SetGR(context, DATA_REGS.1, catches as usize);
+    _Unwind_SetIP(context, landing_pad);
+    URC_INSTALL_CONTEXT
+}
+
+// Pointer encodings of the exception tables (`DW_EH_PE_*`).
+const DW_EH_PE_OMIT: u8 = 0xff;
+const DW_EH_PE_ABSPTR: u8 = 0x00;
+const DW_EH_PE_ULEB128: u8 = 0x01;
+const DW_EH_PE_UDATA2: u8 = 0x02;
+const DW_EH_PE_UDATA4: u8 = 0x03;
+const DW_EH_PE_UDATA8: u8 = 0x04;
+const DW_EH_PE_SLEB128: u8 = 0x09;
+const DW_EH_PE_SDATA2: u8 = 0x0a;
+const DW_EH_PE_SDATA4: u8 = 0x0b;
+const DW_EH_PE_SDATA8: u8 = 0x0c;
+
+/// Find the landing pad of the call being unwound in the frame of `context`, and whether it has a
+/// `catch` clause.
+///
+/// Reads the call site table of the language specific data area, see
+/// <https://itanium-cxx-abi.github.io/cxx-abi/exceptions.pdf>.
+unsafe fn find_landing_pad(context: *mut UnwindContext) -> Result<Option<(usize, bool)>, ()> {
+    let lsda = _Unwind_GetLanguageSpecificData(context);
+    if lsda.is_null() {
+        return Ok(None);
+    }
+    let func_start = _Unwind_GetRegionStart(context);
+    let mut ip_before_insn = 0;
+    let mut ip = _Unwind_GetIPInfo(context, &mut ip_before_insn);
+    // The IP is the return address, which may belong to the next call site.
+    if ip_before_insn == 0 {
+        ip -= 1;
+    }
+
+    let mut reader = Reader(lsda);
+    let lpstart_encoding = reader.u8();
+    let lpstart = if lpstart_encoding == DW_EH_PE_OMIT {
+        func_start
+    } else {
+        reader.encoded(lpstart_encoding)?
+    };
+    if reader.u8() != DW_EH_PE_OMIT {
+        // Offset of the type table, which isn't needed: every `catch` clause catches everything.
+        reader.uleb128();
+    }
+    let call_site_encoding = reader.u8();
+    let table_len = reader.uleb128();
+    let table_end = reader.0.add(table_len);
+
+    while reader.0 < table_end {
+        let start = reader.encoded(call_site_encoding)?;
+        let len = reader.encoded(call_site_encoding)?;
+        let landing_pad = reader.encoded(call_site_encoding)?;
+        let action = reader.uleb128();
+        // Call sites are sorted by address.
+        if ip < func_start + start {
+            break;
+        }
+        if ip < func_start + start + len {
+            return Ok((landing_pad != 0).then_some((lpstart + landing_pad, action != 0)));
+        }
+    }
+    Ok(None)
+}
+
+/// Reads the values of a language specific data area.
+struct Reader(*const u8);
+
+impl Reader {
+    unsafe fn u8(&mut self) -> u8 {
+        self.read::<u8>()
+    }
+
+    unsafe fn read<T: Copy>(&mut self) -> T {
+        let value = (self.0 as *const T).read_unaligned();
+        self.0 = self.0.add(std::mem::size_of::<T>());
+        value
+    }
+
+    unsafe fn uleb128(&mut self) -> usize {
+        let (mut value, mut shift) = (0, 0);
+        loop {
+            let byte = self.u8();
+            value |= ((byte & 0x7f) as usize) << shift;
+            shift += 7;
+            if byte & 0x80 == 0 {
+                return value;
+            }
+        }
+    }
+
+    unsafe fn sleb128(&mut self) -> isize {
+        let (mut value, mut shift) = (0, 0);
+        loop {
+            let byte = self.u8();
+            value |= ((byte & 0x7f) as isize) << shift;
+            shift += 7;
+            if byte & 0x80 == 0 {
+                if shift < isize::BITS && byte & 0x40 != 0 {
+                    value |= -1 << shift;
+                }
+                return value;
+            }
+        }
+    }
+
+    /// Read a value with the pointer encoding `encoding`. Only the plain formats used by LLVM for
+    /// offsets in the call site table are supported.
+    unsafe fn encoded(&mut self, encoding: u8) -> Result<usize, ()> {
+        Ok(match encoding {
+            DW_EH_PE_ABSPTR => self.read::<usize>(),
+            DW_EH_PE_ULEB128 => self.uleb128(),
+            DW_EH_PE_UDATA2 => self.read::<u16>() as usize,
+            DW_EH_PE_UDATA4 => self.read::<u32>() as usize,
+            DW_EH_PE_UDATA8 => self.read::<u64>() as usize,
+            DW_EH_PE_SLEB128 => self.sleb128() as usize,
+            DW_EH_PE_SDATA2 => self.read::<i16>() as usize,
+            DW_EH_PE_SDATA4 => self.read::<i32>() as usize,
+            DW_EH_PE_SDATA8 => self.read::<i64>() as usize,
+            _ => return Err(()),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn leb128() {
+        let bytes = [0xe5, 0x8e, 0x26, 0x7f, 0x80, 0x7f, 0x02];
+        let mut reader = Reader(bytes.as_ptr());
+        unsafe {
+            assert_eq!(reader.uleb128(), 624_485);
+            assert_eq!(reader.sleb128(), -1);
+            assert_eq!(reader.sleb128(), -128);
+            assert_eq!(reader.sleb128(), 2);
+        }
+        assert_eq!(reader.0, bytes.as_ptr_range().end);
+    }
+
+    #[test]
+    fn encoded_values() {
+        // The fixed size formats are in native byte order, little endian on all tested targets.
+        let mut bytes = vec![0x34, 0x12, 0xfe, 0xff, 0xff, 0xff, 0x81, 0x01];
+        bytes.extend_from_slice(&7usize.to_ne_bytes());
+        let mut reader = Reader(bytes.as_ptr());
+        unsafe {
+            assert_eq!(reader.encoded(DW_EH_PE_UDATA2), Ok(0x1234));
+            assert_eq!(reader.encoded(DW_EH_PE_SDATA4), Ok(-2isize as usize));
+            assert_eq!(reader.encoded(DW_EH_PE_ULEB128), Ok(129));
+            assert_eq!(reader.encoded(DW_EH_PE_ABSPTR), Ok(7));
+            assert_eq!(reader.encoded(0x10), Err(()));
+        }
+    }
+
+    fn catch(code: ErrorCode, message: &str) -> Vec<u8> {
+        let error = CobraError { code, message: message.to_string(), loc: None, trace: Vec::new() };
+        let exception = Box::into_raw(Box::new(Exception {
+            header: UnwindException { exception_class: EXCEPTION_CLASS, exception_cleanup: None, private: [0; 6] },
+            error,
+        }));
+        let message = unsafe { cobra_begin_catch(exception as *mut c_void) };
+        unsafe { CobraStr::bytes(message.ptr, message.len) }.to_vec()
+    }
+
+    #[test]
+    fn caught_exceptions_hold_their_message() {
+        assert_eq!(catch(ErrorCode::Raised, "boom"), b"boom");
+        assert_eq!(catch(ErrorCode::RecursionLimit, "too deep"), b"too deep");
+    }
+}
@@ -0,0 +1,285 @@
+//! Runtime support library of the Cobra prelude.
+//!
+//! All functions have C linkage. The compiler registers them with the JIT, and ahead-of-time
+//! compiled objects are linked against the `cobra_runtime` static library.
+
+pub mod error;
+pub mod exception;
+pub mod limits;
+
+use error::{CobraError, ErrorCode};
+
+use std::io::{BufRead, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::OnceLock;
+use std::time::Instant;
+
+/// Print `x` without a trailing newline.
+#[no_mangle]
+pub extern "C" fn cobra_print(x: f64) -> f64 {
+    print!("{}", x);
+    std::io::stdout().flush().unwrap();
+    0f64
+}
+
+/// Print `x` followed by a newline.
+#[no_mangle]
+pub extern "C" fn cobra_println(x: f64) -> f64 {
+    println!("{}", x);
+    0f64
+}
+
+/// Print `x` with six decimal places followed by a newline.
+#[no_mangle]
+pub extern "C" fn cobra_printd(x: f64) -> f64 {
+    println!("{:.6}", x);
+    0f64
+}
+
+/// Print the character with the character code `c`.
+#[no_mangle]
+#[inline(never)]
+pub extern "C" fn putchard(c: libc::c_double) -> f64 {
+    std::io::stdout().write_all(&[c as u8]).unwrap();
+    0f64
+}
+
+/// Get the number of seconds elapsed since the first call to `clock`.
+#[no_mangle]
+pub extern "C" fn cobra_clock() -> f64 {
+    static START: OnceLock<Instant> = OnceLock::new();
+    START.get_or_init(Instant::now).elapsed().as_secs_f64()
+}
+
+/// Get a pseudo random number in `[0, 1)`.
+#[no_mangle]
+pub extern "C" fn cobra_random() -> f64 {
+    static STATE: AtomicU64 = AtomicU64::new(0x2545_f491_4f6c_dd1d);
+
+    // xorshift64*, not suitable for cryptographic use.
+    let mut x = STATE.load(Ordering::Relaxed);
+    x ^= x >> 12;
+    x ^= x << 25;
+    x ^= x >> 27;
+    STATE.store(x, Ordering::Relaxed);
+
+    // Use the upper 53 bits as the mantissa.
+    (x.wrapping_mul(0x2545_f491_4f6c_dd1d) >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// Read a number from a line on stdin. Return `NaN` if the line is not a number or at EOF.
+#[no_mangle]
+pub extern "C" fn cobra_read_number() -> f64 {
+    let mut line = String::new();
+    match std::io::stdin().lock().read_line(&mut line) {
+        Ok(n) if n > 0 => line.trim().parse().unwrap_or(f64::NAN),
+        _ => f64::NAN,
+    }
+}
+
+/// In-memory representation of a Cobra `str`, matching the `{ ptr, i64 }` LLVM struct type.
+///
+/// Strings are immutable and not necessarily valid UTF8. String literals point into constant
+/// globals, strings created at runtime are never freed.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct CobraStr {
+    pub ptr: *const u8,
+    pub len: u64,
+}
+
+impl CobraStr {
+    /// Leak `bytes` and return a `CobraStr` referencing them.
+    fn leak(bytes: Vec<u8>) -> CobraStr {
+        let bytes = Box::leak(bytes.into_boxed_slice());
+        CobraStr {
+            ptr: bytes.as_ptr(),
+            len: bytes.len() as u64,
+        }
+    }
+
+    /// # Safety
+    ///
+    /// `ptr` must point to `len` readable bytes (or be dangling/null with `len == 0`).
+    unsafe fn bytes<'a>(ptr: *const u8, len: u64) -> &'a [u8] {
+        if len == 0 {
+            &[]
+        } else {
+            std::slice::from_raw_parts(ptr, len as usize)
+        }
+    }
+}
+
+/// Print the string `ptr[..len]` without a trailing newline.
+///
+/// # Safety
+///
+/// `ptr` and `len` must describe a valid Cobra string.
+#[no_mangle]
+pub unsafe extern "C" fn cobra_print_str(ptr: *const u8, len: u64) -> f64 {
+    let mut stdout = std::io::stdout();
+    stdout.write_all(CobraStr::bytes(ptr, len)).unwrap();
+    stdout.flush().unwrap();
+    0f64
+}
+
+/// Print the string `ptr[..len]` followed by a newline.
+///
+/// # Safety
+///
+/// `ptr` and `len` must describe a valid Cobra string.
+#[no_mangle]
+pub unsafe extern "C" fn cobra_println_str(ptr: *const u8, len: u64) -> f64 {
+    let mut stdout = std::io::stdout();
+    stdout.write_all(CobraStr::bytes(ptr, len)).unwrap();
+    stdout.write_all(b"\n").unwrap();
+    0f64
+}
+
+/// Concatenate the strings `a` and `b` into a new string.
+///
+/// # Safety
+///
+/// Both pointer/length pairs must describe valid Cobra strings.
+#[no_mangle]
+pub unsafe extern "C" fn cobra_str_concat(
+    a_ptr: *const u8,
+    a_len: u64,
+    b_ptr: *const u8,
+    b_len: u64,
+) -> CobraStr {
+    let mut bytes = Vec::with_capacity((a_len + b_len) as usize);
+    bytes.extend_from_slice(CobraStr::bytes(a_ptr, a_len));
+    bytes.extend_from_slice(CobraStr::bytes(b_ptr, b_len));
+    CobraStr::leak(bytes)
+}
+
+/// Copy the string `ptr[..len]` to the heap.
+///
+/// Strings stored in global variables are copied, as they may point into the constants of a
+/// JIT module which is removed before the variable is read again.
+///
+/// # Safety
+///
+/// `ptr` and `len` must describe a valid Cobra string.
+#[no_mangle]
+pub unsafe extern "C" fn cobra_str_copy(ptr: *const u8, len: u64) -> CobraStr {
+    CobraStr::leak(CobraStr::bytes(ptr, len).to_vec())
+}
+
+/// Get the string holding the single byte at index `idx` of the string `ptr[..len]`.
+///
+/// Raises an error at the source location `line:col` if `idx` is not an integer in `[0, len)`.
+///
+/// # Safety
+///
+/// `ptr` and `len` must describe a valid Cobra string.
+#[no_mangle]
+pub unsafe extern "C-unwind" fn cobra_str_index(ptr: *const u8, len: u64, idx: f64, line: u32, col: u32) -> CobraStr {
+    if idx < 0.0 || idx.fract() != 0.0 || idx >= len as f64 {
+        let message = format!("String index {} out of range for length {}", idx, len);
+        error::raise(CobraError::new(ErrorCode::IndexOutOfBounds, message, line, col));
+    }
+
+    CobraStr {
+        ptr: ptr.add(idx as usize),
+        len: 1,
+    }
+}
+
+/// Allocate zero initialized memory for `count` elements of `size` bytes each (8 byte aligned).
+///
+/// Memory allocated by the Cobra runtime is never freed.
+#[no_mangle]
+pub extern "C" fn cobra_alloc(count: u64, size: u64) -> *mut u8 {
+    let bytes = count.checked_mul(size).expect("Runtime error: allocation size overflow");
+    if bytes == 0 {
+        return std::ptr::NonNull::<u64>::dangling().as_ptr().cast();
+    }
+
+    let layout = std::alloc::Layout::from_size_align(bytes as usize, 8)
+        .expect("Runtime error: invalid allocation size");
+    let ptr = unsafe { std::alloc::alloc_zeroed(layout) };
+    if ptr.is_null() {
+        std::alloc::handle_alloc_error(layout);
+    }
+    ptr
+}
+
+/// Raise the error of an out of bounds access at index `idx` into an array of length `len` at
+/// the source location `line:col`.
+#[no_mangle]
+pub extern "C-unwind" fn cobra_index_out_of_bounds(idx: i64, len: u64, line: u32, col: u32) -> ! {
+    let message = format!("Index {} out of bounds for length {}", idx, len);
+    error::raise(CobraError::new(ErrorCode::IndexOutOfBounds, message, line, col))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn random_numbers_are_in_unit_interval() {
+        let numbers: Vec<f64> = (0..1000).map(|_| cobra_random()).collect();
+        assert!(numbers.iter().all(|x| (0.0..1.0).contains(x)));
+        assert!(numbers.windows(2).any(|pair| pair[0] != pair[1]));
+    }
+
+    #[test]
+    fn clock_is_monotonic() {
+        let start = cobra_clock();
+        let end = cobra_clock();
+        assert!(start >= 0.0);
+        assert!(end >= start);
+    }
+
+    #[test]
+    fn concat_strings() {
+        let a = b"foo";
+        let b = b"bar";
+        unsafe {
+            let s = cobra_str_concat(a.as_ptr(), a.len() as u64, b.as_ptr(), b.len() as u64);
+            assert_eq!(CobraStr::bytes(s.ptr, s.len), b"foobar");
+
+            let s = cobra_str_concat(std::ptr::null(), 0, b.as_ptr(), b.len() as u64);
+            assert_eq!(CobraStr::bytes(s.ptr, s.len), b"bar");
+            let s = cobra_str_concat(std::ptr::null(), 0, std::ptr::null(), 0);
+            assert_eq!(s.len, 0);
+        }
+    }
+
+    #[test]
+    fn copy_strings() {
+        let s = b"abc".to_vec();
+        unsafe {
+            let copy = cobra_str_copy(s.as_ptr(), s.len() as u64);
+            drop(s);
+            assert_eq!(CobraStr::bytes(copy.ptr, copy.len), b"abc");
+            assert_eq!(cobra_str_copy(std::ptr::null(), 0).len, 0);
+        }
+    }
+
+    #[test]
+    fn index_strings() {
+        let s = b"abc";
+        unsafe {
+            let c = cobra_str_index(s.as_ptr(), s.len() as u64, 0.0, 1, 1);
+            assert_eq!(CobraStr::bytes(c.ptr, c.len), b"a");
+            let c = cobra_str_index(s.as_ptr(), s.len() as u64, 2.0, 1, 1);
+            assert_eq!(CobraStr::bytes(c.ptr, c.len), b"c");
+        }
+    }
+
+    #[test]
+    fn allocations_are_zeroed_and_aligned() {
+        let ptr = cobra_alloc(3, 8);
+        assert_eq!(ptr as usize % 8, 0);
+        let bytes = unsafe { std::slice::from_raw_parts(ptr, 24) };
+        assert!(bytes.iter().all(|&b| b == 0));
+
+        assert_eq!(cobra_alloc(1, 1) as usize % 8, 0);
+        let empty = cobra_alloc(0, 8);
+        assert!(!empty.is_null());
+        assert_eq!(empty as usize % 8, 0);
+    }
+}
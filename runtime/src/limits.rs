@@ -0,0 +1,123 @@
+//! Execution time and recursion depth limits of Cobra code.
+//!
+//! Generated code checks [`INTERRUPT`] at every function entry and loop iteration, and counts
+//! the depth of nested calls in [`CALL_DEPTH`]. A [`Watchdog`] sets the interrupt flag when an
+//! evaluation runs for too long. Both limits raise a [`CobraError`] instead of hanging or
+//! overflowing the stack.
+
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crate::error::{raise, CobraError, ErrorCode};
+use crate::CobraStr;
+
+/// Default of [`MAX_CALL_DEPTH`].
+pub const DEFAULT_MAX_CALL_DEPTH: u64 = 10_000;
+
+/// Set to interrupt the running Cobra code.
+#[export_name = "cobra_interrupt"]
+pub static INTERRUPT: AtomicBool = AtomicBool::new(false);
+
+/// Number of Cobra function calls currently on the stack.
+#[export_name = "cobra_call_depth"]
+pub static CALL_DEPTH: AtomicU64 = AtomicU64::new(0);
+
+/// Maximum number of nested Cobra function calls.
+#[export_name = "cobra_max_call_depth"]
+pub static MAX_CALL_DEPTH: AtomicU64 = AtomicU64::new(DEFAULT_MAX_CALL_DEPTH);
+
+/// Raise the error of an interrupted evaluation. Called by generated code which found
+/// [`INTERRUPT`] set.
+#[no_mangle]
+pub extern "C-unwind" fn cobra_interrupted() -> ! {
+    INTERRUPT.store(false, Ordering::Relaxed);
+    raise(CobraError::new(ErrorCode::Timeout, "Execution timed out", 0, 0))
+}
+
+/// Raise the error of a call exceeding [`MAX_CALL_DEPTH`]. Called by generated code on entry to
+/// the function named `ptr[..len]`.
+///
+/// # Safety
+///
+/// `ptr` and `len` must describe a valid Cobra string.
+#[no_mangle]
+pub unsafe extern "C-unwind" fn cobra_recursion_limit(ptr: *const u8, len: u64) -> ! {
+    let name = String::from_utf8_lossy(CobraStr::bytes(ptr, len));
+    let message = format!(
+        "Maximum recursion depth {} exceeded in {}",
+        MAX_CALL_DEPTH.load(Ordering::Relaxed),
+        name
+    );
+    raise(CobraError::new(ErrorCode::RecursionLimit, message, 0, 0))
+}
+
+/// Interrupts Cobra code running for longer than a timeout, until it is dropped.
+pub struct Watchdog {
+    done: Arc<(Mutex<bool>, Condvar)>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Watchdog {
+    /// Start a watchdog which interrupts the Cobra code running after `timeout`.
+    pub fn start(timeout: Duration) -> Watchdog {
+        INTERRUPT.store(false, Ordering::Relaxed);
+
+        let done = Arc::new((Mutex::new(false), Condvar::new()));
+        let thread = {
+            let done = Arc::clone(&done);
+            thread::spawn(move || {
+                let (lock, cvar) = &*done;
+                let finished = lock.lock().unwrap();
+                let (finished, _) = cvar
+                    .wait_timeout_while(finished, timeout, |finished| !*finished)
+                    .unwrap();
+                if !*finished {
+                    INTERRUPT.store(true, Ordering::Relaxed);
+                }
+            })
+        };
+
+        Watchdog { done, thread: Some(thread) }
+    }
+}
+
+impl Drop for Watchdog {
+    fn drop(&mut self) {
+        let (lock, cvar) = &*self.done;
+        *lock.lock().unwrap() = true;
+        cvar.notify_one();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+        // The code may have finished just before the watchdog fired.
+        INTERRUPT.store(false, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    // The only test touching `INTERRUPT`, so the two cases run in sequence.
+    #[test]
+    fn watchdog_interrupts_after_the_timeout() {
+        let start = Instant::now();
+        let watchdog = Watchdog::start(Duration::from_secs(60));
+        assert!(!INTERRUPT.load(Ordering::Relaxed));
+        // Dropping the watchdog wakes its thread instead of waiting for the timeout.
+        drop(watchdog);
+        assert!(start.elapsed() < Duration::from_secs(30));
+        assert!(!INTERRUPT.load(Ordering::Relaxed));
+
+        let watchdog = Watchdog::start(Duration::from_millis(10));
+        while !INTERRUPT.load(Ordering::Relaxed) {
+            assert!(start.elapsed() < Duration::from_secs(30), "Watchdog did not interrupt");
+            thread::sleep(Duration::from_millis(1));
+        }
+        drop(watchdog);
+        assert!(!INTERRUPT.load(Ordering::Relaxed));
+    }
+}
@@ -0,0 +1,205 @@
+//! Ahead-of-time compilation of a Cobra script into a native executable.
+//!
+//! All definitions of the script (and the modules it imports) are compiled into a single LLVM
+//! module. Top-level expressions become `__anon_expr.<n>` functions which are called in order
+//! from a generated `main` function. The resulting object file is linked against the
+//! `cobra_runtime` static library with the system C compiler.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use crate::consteval::{self, ConstValue};
+use crate::import::{ModuleLoader, Namespace};
+use crate::ir_gen::{IRGen, TypeDefs};
+use crate::lexer::{Lexer, Token};
+use crate::llvm::{IRBuilder, Module, TargetMachine};
+use crate::parser::{FunctionAST, Parser, PrototypeAST, TypeAST};
+use crate::{prelude, Either};
+
+/// Name of the static runtime library AOT compiled executables are linked against.
+pub const RUNTIME_LIB: &str = "cobra_runtime";
+
+/// Native libraries required by the Rust standard library in the static runtime library.
+const RUNTIME_NATIVE_LIBS: &[&str] = &["gcc_s", "util", "rt", "pthread", "m", "dl", "c"];
+
+/// State of an ahead-of-time build.
+struct Build<'llvm> {
+    module: &'llvm Module,
+    loader: ModuleLoader,
+    fn_protos: HashMap<String, PrototypeAST>,
+    type_defs: TypeDefs,
+    generics: HashMap<String, FunctionAST>,
+    /// Definitions of all compiled functions, for evaluating `const` declarations.
+    functions: HashMap<String, FunctionAST>,
+    consts: HashMap<String, ConstValue>,
+    globals: HashMap<String, TypeAST>,
+    /// Names of the functions holding the top-level expressions, in source order.
+    entries: Vec<String>,
+}
+
+/// Compile the script at `source` into the executable `output`.
+///
+/// The native target must have been initialized with
+/// [`initialize_native_taget`][crate::llvm::initialize_native_taget].
+pub fn build(source: &Path, output: &Path) -> Result<(), String> {
+    let contents = std::fs::read_to_string(source)
+        .map_err(|err| format!("Failed to read {}: {}", source.display(), err))?;
+    let root = source.parent().unwrap_or(Path::new("."));
+
+    let module = Module::with_name("main");
+    let mut build = Build {
+        module: &module,
+        loader: ModuleLoader::with_root(root),
+        fn_protos: HashMap::new(),
+        type_defs: TypeDefs::default(),
+        generics: HashMap::new(),
+        functions: HashMap::new(),
+        consts: HashMap::new(),
+        globals: HashMap::new(),
+        entries: Vec::new(),
+    };
+    prelude::register_prelude(&mut build.fn_protos);
+
+    compile_source(&mut build, &contents, &mut Namespace::root())?;
+    emit_main(&module, &build.entries);
+
+    let object = output.with_extension("o");
+    TargetMachine::native().emit_object(&module, &object)?;
+    let res = link(&object, output);
+    let _ = std::fs::remove_file(&object);
+    res
+}
+
+fn compile_source(build: &mut Build<'_>, contents: &str, ns: &mut Namespace) -> Result<(), String> {
+    let mut parser = Parser::new(Lexer::new(contents.chars()));
+    parser.get_next_token();
+
+    loop {
+        match parser.current_token() {
+            Token::Eof => break,
+            Token::Char(';') => {
+                parser.get_next_token();
+            }
+            Token::Import | Token::From => {
+                let import = parser.parse_import()?;
+                if build.loader.mark_loaded(&import.module) {
+                    let path = build.loader.resolve(&import.module)?;
+                    let contents = std::fs::read_to_string(&path)
+                        .map_err(|err| format!("Failed to read {}: {}", path.display(), err))?;
+                    let mut module_ns = Namespace::new(&import.module);
+                    module_ns.declare(&contents);
+                    compile_source(build, &contents, &mut module_ns)?;
+                }
+                ns.import(&import, &build.fn_protos, &build.consts, &build.globals)?;
+            }
+            Token::Def => {
+                let mut function = parser.parse_definition()?;
+                ns.mangle_function(&mut function);
+                if !function.0.type_params.is_empty() {
+                    // Generic functions are compiled when they are instantiated by a call.
+                    build.fn_protos.insert(function.0.name.clone(), function.0.clone());
+                    build.generics.insert(function.0.name.clone(), function);
+                    continue;
+                }
+                IRGen::compile(build.module, &mut build.fn_protos, &build.type_defs, &build.generics, &build.consts, &build.globals, Either::Right(&function))?;
+                build.functions.insert(function.0.name.clone(), function);
+            }
+            Token::Const => {
+                let mut def = parser.parse_const()?;
+                ns.mangle_const(&mut def);
+                if build.consts.contains_key(&def.name) || build.globals.contains_key(&def.name) {
+                    return Err(format!("{} is already defined", def.name));
+                }
+                let value = consteval::eval_const(&def.value, &build.functions, &build.consts)?;
+                build.consts.insert(def.name, value);
+            }
+            Token::Global => {
+                let mut def = parser.parse_global()?;
+                ns.mangle_global(&mut def);
+                if build.globals.contains_key(&def.name) || build.consts.contains_key(&def.name) {
+                    return Err(format!("{} is already defined", def.name));
+                }
+                let init = consteval::eval_const(&def.value, &build.functions, &build.consts)?;
+                IRGen::define_global(build.module, &def.name, &init);
+                build.globals.insert(def.name, init.type_ast());
+            }
+            Token::Struct => {
+                let def = parser.parse_struct()?;
+                build.type_defs.define_struct(def)?;
+            }
+            Token::Enum => {
+                let def = parser.parse_enum()?;
+                build.type_defs.define_enum(def)?;
+            }
+            Token::Test => {
+                // Tests are only run by `cobra test`.
+                parser.parse_test()?;
+            }
+            Token::Extern => {
+                let proto = parser.parse_external()?;
+                build.fn_protos.insert(proto.name.clone(), proto);
+            }
+            _ => {
+                let mut function = parser.parse_top_level_expr()?;
+                ns.mangle_top_level(&mut function);
+                // Every top-level expression needs its own symbol in the single module.
+                function.0.name = format!("__anon_expr.{}", build.entries.len());
+                IRGen::compile(build.module, &mut build.fn_protos, &build.type_defs, &build.generics, &build.consts, &build.globals, Either::Right(&function))?;
+                build.entries.push(function.0.name);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Emit the `main` function calling all top-level expression functions in order.
+///
+/// The functions are called through `cobra_run`, which exits with an error message if one
+/// raises a runtime error.
+fn emit_main(module: &Module, entries: &[String]) {
+    let builder = IRBuilder::with_ctx(module);
+    let type_i32 = module.type_i32();
+    let main = module.add_fn("main", module.type_fn(&mut [], type_i32));
+    builder.pos_at_end(module.append_basic_block(main));
+
+    let run = module.add_fn("cobra_run", module.type_fn(&mut [module.type_ptr()], module.type_f64()));
+    for entry in entries {
+        let entry = module
+            .get_fn(entry)
+            .expect("Top-level expression function must be defined in the module!");
+        builder.call(run, &mut [*entry]);
+    }
+
+    builder.ret(type_i32.const_int(0));
+}
+
+/// Link `object` against the runtime library into the executable `output`.
+fn link(object: &Path, output: &Path) -> Result<(), String> {
+    let mut cmd = Command::new(std::env::var_os("CC").unwrap_or_else(|| "cc".into()));
+    cmd.arg(object).arg("-o").arg(output);
+
+    if let Some(dir) = runtime_lib_dir() {
+        cmd.arg("-L").arg(dir);
+    }
+    cmd.arg(format!("-l{}", RUNTIME_LIB));
+    for lib in RUNTIME_NATIVE_LIBS {
+        cmd.arg(format!("-l{}", lib));
+    }
+
+    let status = cmd
+        .status()
+        .map_err(|err| format!("Failed to run linker: {}", err))?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(format!("Linker failed with {}", status))
+    }
+}
+
+/// The runtime library is built next to the compiler executable (eg `target/debug`).
+fn runtime_lib_dir() -> Option<PathBuf> {
+    let exe = std::env::current_exe().ok()?;
+    exe.parent().map(Path::to_path_buf)
+}
@@ -0,0 +1,252 @@
+//! On-disk cache of the native code of compiled functions.
+//!
+//! Functions are stored as object files named after a hash of everything their code depends on:
+//! the function AST, the signatures, constants and types it references, the compiler version and
+//! the optimization options. The full key is stored next to the object and compared on load, so
+//! hash collisions never load the wrong code. When a script is run again, unchanged functions are
+//! loaded from the cache instead of being lowered, optimized and compiled again.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use crate::consteval::ConstValue;
+use crate::ir_gen::{collect_variables, TypeDefs};
+use crate::llvm::{Module, TargetMachine};
+use crate::parser::{FunctionAST, PrototypeAST, TypeAST};
+
+/// Environment variable overriding the cache directory. An empty value disables the cache.
+pub const CACHE_DIR_ENV: &str = "COBRA_CACHE_DIR";
+
+/// Version of the code generation. Must be bumped when the code generated for the same AST
+/// changes without a new release of the crate, so stale objects are not reused.
+pub const CODEGEN_VERSION: u32 = 2;
+
+/// Optimization applied to cached code. Must be updated when the passes run by
+/// [`FunctionPassManager`][crate::llvm::FunctionPassManager] or the code generation options of
+/// [`TargetMachine`] change, so stale objects are not reused.
+const OPT_OPTIONS: &str = "instcombine,reassociate,newgvn,simplifycfg;codegen=default,pic";
+
+/// Names of the iterator protocol functions, which `for x in value` finds by the argument type
+/// instead of by a name in the AST.
+const PROTOCOL_FNS: [&str; 2] = ["has_next", "next"];
+
+/// Everything the code of a function depends on, see [`ObjectCache::key`].
+#[derive(Debug, PartialEq, Clone)]
+pub struct CacheKey {
+    input: String,
+}
+
+impl CacheKey {
+    /// Get the name the object is stored under, a hash of the key.
+    pub fn hash(&self) -> String {
+        format!("{:016x}", fnv1a(self.input.as_bytes()))
+    }
+}
+
+/// Object files of compiled functions, stored in a directory.
+pub struct ObjectCache {
+    dir: PathBuf,
+    target: TargetMachine,
+}
+
+impl ObjectCache {
+    /// Create a cache storing objects in `dir`. The native target must have been initialized.
+    pub fn new(dir: PathBuf) -> ObjectCache {
+        ObjectCache {
+            dir,
+            target: TargetMachine::native(),
+        }
+    }
+
+    /// Create a cache in the directory named by [`CACHE_DIR_ENV`], `$XDG_CACHE_HOME/cobra` or
+    /// `~/.cache/cobra`. Return `None` if the cache is disabled or no directory is known.
+    pub fn from_env() -> Option<ObjectCache> {
+        let dir = match std::env::var_os(CACHE_DIR_ENV) {
+            Some(dir) if dir.is_empty() => return None,
+            Some(dir) => PathBuf::from(dir),
+            None => match std::env::var_os("XDG_CACHE_HOME") {
+                Some(dir) => PathBuf::from(dir).join("cobra"),
+                None => PathBuf::from(std::env::var_os("HOME")?).join(".cache").join("cobra"),
+            },
+        };
+        Some(ObjectCache::new(dir))
+    }
+
+    /// Compute the cache key of `function`, compiled with the given definitions of the session.
+    pub fn key(
+        &self,
+        function: &FunctionAST,
+        fn_protos: &HashMap<String, PrototypeAST>,
+        type_defs: &TypeDefs,
+        generics: &HashMap<String, FunctionAST>,
+        consts: &HashMap<String, ConstValue>,
+        globals: &HashMap<String, TypeAST>,
+    ) -> CacheKey {
+        let mut input = format!(
+            "cobra {} codegen {}\n{}\n{}\n{:?}\n",
+            env!("CARGO_PKG_VERSION"),
+            CODEGEN_VERSION,
+            OPT_OPTIONS,
+            self.target.triple(),
+            function
+        );
+
+        // Generic functions are instantiated into the module of the caller, so the ASTs of the
+        // generic functions called (directly or by other generic functions) are part of the code.
+        let mut names = Vec::new();
+        collect_variables(&function.1, &mut names);
+        let mut i = 0;
+        while i < names.len() {
+            if let Some(generic) = generics.get(&names[i]) {
+                collect_variables(&generic.1, &mut names);
+            }
+            i += 1;
+        }
+        names.extend(PROTOCOL_FNS.iter().map(|name| name.to_string()));
+
+        let mut deps = Vec::new();
+        for (name, proto) in fn_protos {
+            let protocol = PROTOCOL_FNS.iter().any(|f| name == f || name.ends_with(&format!(".{}", f)));
+            if protocol || names.contains(name) {
+                deps.push(format!("fn {:?}", proto));
+            }
+        }
+        for name in &names {
+            if let Some(generic) = generics.get(name) {
+                deps.push(format!("generic {:?}", generic));
+            } else if let Some(value) = consts.get(name) {
+                deps.push(format!("const {} = {:?}", name, value));
+            } else if let Some(ty) = globals.get(name) {
+                deps.push(format!("global {}: {:?}", name, ty));
+            }
+        }
+        // Types may be referenced by argument types, fields and variants, so all are included.
+        deps.extend(type_defs.structs.values().map(|def| format!("struct {:?}", def)));
+        deps.extend(type_defs.enums.values().map(|def| format!("enum {:?}", def)));
+
+        // Hash maps are iterated in random order, so the entries are sorted.
+        deps.sort();
+        for dep in deps {
+            input.push_str(&dep);
+            input.push('\n');
+        }
+
+        CacheKey { input }
+    }
+
+    /// Load the object file stored under `key`, unless another key with the same hash was stored
+    /// last.
+    pub fn load(&self, key: &CacheKey) -> Option<Vec<u8>> {
+        load_object(&self.dir, key)
+    }
+
+    /// Compile `module` to an object file and store it under `key`.
+    ///
+    /// The object is returned even if it can't be written to the cache.
+    pub fn store(&self, key: &CacheKey, module: &Module) -> Result<Vec<u8>, String> {
+        let object = self.target.emit_object_to_memory(module)?;
+        self.store_object(key, &object);
+        Ok(object)
+    }
+
+    /// Store an object file compiled elsewhere (eg by a worker thread) under `key`.
+    ///
+    /// The cache only saves work, so failing to write it is not an error.
+    pub fn store_object(&self, key: &CacheKey, object: &[u8]) {
+        let _ = store_object(&self.dir, key, object);
+    }
+}
+
+fn load_object(dir: &Path, key: &CacheKey) -> Option<Vec<u8>> {
+    let path = dir.join(key.hash());
+    let stored = std::fs::read_to_string(path.with_extension("key")).ok()?;
+    if stored != key.input {
+        return None;
+    }
+    std::fs::read(path.with_extension("o")).ok()
+}
+
+fn store_object(dir: &Path, key: &CacheKey, object: &[u8]) -> std::io::Result<()> {
+    // The object is written before its key, so a key is never paired with the object of an
+    // earlier colliding key.
+    let path = dir.join(key.hash());
+    let _ = std::fs::remove_file(path.with_extension("key"));
+    std::fs::create_dir_all(dir)?;
+    write_file(&path.with_extension("o"), object)?;
+    write_file(&path.with_extension("key"), key.input.as_bytes())
+}
+
+/// Write `contents` to a temporary file first and move it to `path`, so other processes never
+/// read a partial file.
+fn write_file(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    let tmp = path.with_extension(format!("tmp{}", std::process::id()));
+    let res = std::fs::write(&tmp, contents).and_then(|_| std::fs::rename(&tmp, path));
+    if res.is_err() {
+        let _ = std::fs::remove_file(&tmp);
+    }
+    res
+}
+
+/// 64 bit FNV-1a hash, which unlike the hasher of the standard library is stable across Rust
+/// versions.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &b| {
+        (hash ^ b as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(input: &str) -> CacheKey {
+        CacheKey { input: input.to_string() }
+    }
+
+    /// A fresh cache directory for a test.
+    fn cache_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("cobra-cache-test-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn keys_are_hashed_stably() {
+        assert_eq!(fnv1a(b""), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(fnv1a(b"a"), 0xaf63_dc4c_8601_ec8c);
+        assert_eq!(key("a").hash(), "af63dc4c8601ec8c");
+    }
+
+    #[test]
+    fn objects_are_loaded_by_their_full_key() {
+        let dir = cache_dir("load");
+        assert_eq!(load_object(&dir, &key("def f(x) x")), None);
+
+        store_object(&dir, &key("def f(x) x"), b"object").unwrap();
+        assert_eq!(load_object(&dir, &key("def f(x) x")), Some(b"object".to_vec()));
+        assert_eq!(load_object(&dir, &key("def f(x) x + 1")), None);
+
+        store_object(&dir, &key("def f(x) x"), b"updated").unwrap();
+        assert_eq!(load_object(&dir, &key("def f(x) x")), Some(b"updated".to_vec()));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn colliding_keys_are_not_loaded() {
+        let dir = cache_dir("collision");
+        let (stored, other) = (key("stored"), key("other"));
+        store_object(&dir, &stored, b"object").unwrap();
+
+        // Pretend `other` has the same hash as `stored`.
+        let path = dir.join(stored.hash());
+        std::fs::rename(path.with_extension("o"), dir.join(other.hash()).with_extension("o")).unwrap();
+        std::fs::rename(path.with_extension("key"), dir.join(other.hash()).with_extension("key")).unwrap();
+        assert_eq!(load_object(&dir, &other), None);
+
+        // A missing key file is a miss too.
+        store_object(&dir, &other, b"object").unwrap();
+        std::fs::remove_file(dir.join(other.hash()).with_extension("key")).unwrap();
+        assert_eq!(load_object(&dir, &other), None);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
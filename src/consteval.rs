@@ -0,0 +1,555 @@
+//! Compile-time evaluation of `const` declarations.
+//!
+//! `const N = fib(10)` is evaluated by interpreting the AST of the (pure) functions it calls,
+//! and uses of `N` are compiled to the resulting constant. Only the side-effect free part of the
+//! language is supported: numbers, bools and strings, operators, `if`, loops, blocks and calls
+//! of Cobra functions and math builtins. Evaluation is bounded by [`STEP_LIMIT`].
+
+use std::collections::HashMap;
+use std::fmt;
+use std::{panic, thread};
+
+use crate::ir_gen::{fold_binary_op, fold_unary_op};
+use crate::parser::{ExprAST, FunctionAST, TypeAST};
+
+/// Maximum number of expressions evaluated for a single `const` declaration.
+pub const STEP_LIMIT: u64 = 10_000_000;
+
+/// Maximum nesting of function calls during constant evaluation.
+pub const CALL_DEPTH_LIMIT: usize = 512;
+
+/// Stack size of the thread evaluating a constant, enough for [`CALL_DEPTH_LIMIT`] nested calls
+/// of complex functions in unoptimized builds.
+const EVAL_STACK_SIZE: usize = 256 << 20;
+
+/// Value of a constant.
+#[derive(Debug, PartialEq, Clone)]
+pub enum ConstValue {
+    Number(f64),
+    Bool(bool),
+    Str(String),
+}
+
+impl ConstValue {
+    /// Get the type of the value.
+    pub fn type_ast(&self) -> TypeAST {
+        match self {
+            ConstValue::Number(_) => TypeAST::F64,
+            ConstValue::Bool(_) => TypeAST::Bool,
+            ConstValue::Str(_) => TypeAST::Str,
+        }
+    }
+}
+
+impl fmt::Display for ConstValue {
+    /// Format the value as a Cobra literal, which evaluates to the same value.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            // There are no literals for NaN and infinities, but `/` computes them.
+            ConstValue::Number(value) if value.is_nan() => f.write_str("(0 / 0)"),
+            ConstValue::Number(value) if value.is_infinite() => {
+                write!(f, "({}1 / 0)", if *value < 0.0 { "-" } else { "" })
+            }
+            ConstValue::Number(value) => write!(f, "{}", value),
+            ConstValue::Bool(value) => write!(f, "{}", value),
+            ConstValue::Str(value) => {
+                // Only the escapes understood by the lexer, other characters are written as is.
+                f.write_str("\"")?;
+                for c in value.chars() {
+                    match c {
+                        '\n' => f.write_str("\\n")?,
+                        '\t' => f.write_str("\\t")?,
+                        '\r' => f.write_str("\\r")?,
+                        '\0' => f.write_str("\\0")?,
+                        '\\' => f.write_str("\\\\")?,
+                        '"' => f.write_str("\\\"")?,
+                        c => write!(f, "{}", c)?,
+                    }
+                }
+                f.write_str("\"")
+            }
+        }
+    }
+}
+
+/// Ways the evaluation of an expression can end other than with a value.
+enum Flow {
+    Break,
+    Continue,
+    Return(ConstValue),
+    Error(String),
+}
+
+type EvalResult = Result<ConstValue, Flow>;
+
+/// Evaluate the value of a `const` declaration.
+///
+/// `functions` holds the definitions of all (mangled) functions which may be called, `consts`
+/// the previously declared constants.
+pub fn eval_const(
+    expr: &ExprAST,
+    functions: &HashMap<String, FunctionAST>,
+    consts: &HashMap<String, ConstValue>,
+) -> Result<ConstValue, String> {
+    // The evaluator recurses on the native stack, so it runs on a thread of its own instead of
+    // the calling one, whose stack may be much smaller (eg a compile worker).
+    thread::scope(|scope| {
+        thread::Builder::new()
+            .name("consteval".to_string())
+            .stack_size(EVAL_STACK_SIZE)
+            .spawn_scoped(scope, || eval_on_current_thread(expr, functions, consts))
+            .expect("Failed to spawn the constant evaluation thread")
+            .join()
+            .unwrap_or_else(|payload| panic::resume_unwind(payload))
+    })
+}
+
+fn eval_on_current_thread(
+    expr: &ExprAST,
+    functions: &HashMap<String, FunctionAST>,
+    consts: &HashMap<String, ConstValue>,
+) -> Result<ConstValue, String> {
+    let mut eval = ConstEval { functions, consts, steps: 0, depth: 0 };
+    match eval.eval(expr, &mut HashMap::new()) {
+        Ok(value) => Ok(value),
+        Err(Flow::Error(err)) => Err(err),
+        Err(Flow::Return(_)) => Err("'return' outside of a function".to_string()),
+        Err(_) => Err("'break' or 'continue' outside of a loop".to_string()),
+    }
+}
+
+struct ConstEval<'a> {
+    functions: &'a HashMap<String, FunctionAST>,
+    consts: &'a HashMap<String, ConstValue>,
+    steps: u64,
+    depth: usize,
+}
+
+impl<'a> ConstEval<'a> {
+    fn eval(&mut self, expr: &ExprAST, locals: &mut HashMap<String, ConstValue>) -> EvalResult {
+        self.steps += 1;
+        if self.steps > STEP_LIMIT {
+            return Err(Flow::Error(format!("Constant evaluation exceeded the limit of {} steps", STEP_LIMIT)));
+        }
+
+        match expr {
+            ExprAST::Number(value) => Ok(ConstValue::Number(*value)),
+            ExprAST::Bool(value) => Ok(ConstValue::Bool(*value)),
+            ExprAST::Str(value) => Ok(ConstValue::Str(value.clone())),
+            ExprAST::Variable(name) => locals
+                .get(name)
+                .or_else(|| self.consts.get(name))
+                .cloned()
+                .ok_or_else(|| Flow::Error(format!("Unknown variable name: {}", name))),
+            ExprAST::BinaryOp(op, lhs, rhs, _) if op == "and" || op == "or" => {
+                let lhs = self.eval_condition(lhs, locals)?;
+                // Short-circuit like the generated code.
+                if lhs == (op == "or") {
+                    return Ok(ConstValue::Bool(lhs));
+                }
+                Ok(ConstValue::Bool(self.eval_condition(rhs, locals)?))
+            }
+            ExprAST::BinaryOp(op, lhs, rhs, _) => {
+                let lhs = self.eval(lhs, locals)?;
+                let rhs = self.eval(rhs, locals)?;
+                binary_op(op, lhs, rhs).map_err(Flow::Error)
+            }
+            ExprAST::UnaryOp(op, operand) if op == "not" => {
+                Ok(ConstValue::Bool(!self.eval_condition(operand, locals)?))
+            }
+            ExprAST::UnaryOp(op, operand) => match self.eval(operand, locals)? {
+                ConstValue::Number(value) => fold_unary_op(op, value)
+                    .map(ConstValue::Number)
+                    .ok_or_else(|| Flow::Error(format!("Unknown unary operator: {}", op))),
+                _ => Err(Flow::Error(format!("Unsupported operand type for unary operator: {}", op))),
+            },
+            ExprAST::Call(callee, args, _) => {
+                let mut values = Vec::new();
+                for arg in args {
+                    values.push(self.eval(arg, locals)?);
+                }
+                self.call(callee, values)
+            }
+            ExprAST::If { condition, then, else_ } => {
+                if self.eval_condition(condition, locals)? {
+                    self.eval_scoped(then, locals)
+                } else {
+                    self.eval_scoped(else_, locals)
+                }
+            }
+            ExprAST::For { variable_name, start, end, step, body } => {
+                let mut scope = locals.clone();
+                let mut variable = self.eval_number(start, &mut scope)?;
+                loop {
+                    scope.insert(variable_name.clone(), ConstValue::Number(variable));
+                    let step = match step {
+                        Some(step) => self.eval_number(step, &mut scope)?,
+                        None => 1.0,
+                    };
+                    let in_range = match self.eval(end, &mut scope)? {
+                        ConstValue::Bool(value) => value,
+                        ConstValue::Number(end) => in_range(variable, end, step),
+                        ConstValue::Str(_) => return Err(Flow::Error("End of a for loop must be a bool or a number".to_string())),
+                    };
+                    if !in_range || !self.eval_loop_body(body, &mut scope)? {
+                        break;
+                    }
+                    variable += step;
+                }
+                Ok(ConstValue::Number(0.0))
+            }
+            ExprAST::ForIn { variable_name, iterable, body } => {
+                let mut scope = locals.clone();
+                if let Some((mut variable, end, step)) = self.eval_range(iterable, &mut scope)? {
+                    while in_range(variable, end, step) {
+                        scope.insert(variable_name.clone(), ConstValue::Number(variable));
+                        if !self.eval_loop_body(body, &mut scope)? {
+                            break;
+                        }
+                        variable += step;
+                    }
+                    return Ok(ConstValue::Number(0.0));
+                }
+
+                // Like indexing, iteration visits the bytes of a string.
+                let value = match self.eval(iterable, &mut scope)? {
+                    ConstValue::Str(value) => value,
+                    _ => return Err(Flow::Error("Only ranges and strings can be iterated at compile time".to_string())),
+                };
+                for byte in value.bytes() {
+                    let element = String::from_utf8_lossy(&[byte]).into_owned();
+                    scope.insert(variable_name.clone(), ConstValue::Str(element));
+                    if !self.eval_loop_body(body, &mut scope)? {
+                        break;
+                    }
+                }
+                Ok(ConstValue::Number(0.0))
+            }
+            ExprAST::While { condition, body } => {
+                while self.eval_condition(condition, locals)? {
+                    if !self.eval_loop_body(body, locals)? {
+                        break;
+                    }
+                }
+                Ok(ConstValue::Number(0.0))
+            }
+            ExprAST::Break => Err(Flow::Break),
+            ExprAST::Continue => Err(Flow::Continue),
+            ExprAST::Block(statements) => {
+                let mut scope = locals.clone();
+                let mut value = ConstValue::Number(0.0);
+                for statement in statements {
+                    value = self.eval(statement, &mut scope)?;
+                }
+                Ok(value)
+            }
+            ExprAST::Let(name, value) => {
+                let value = self.eval(value, locals)?;
+                locals.insert(name.clone(), value.clone());
+                Ok(value)
+            }
+            ExprAST::Return(value) => Err(Flow::Return(self.eval(value, locals)?)),
+            _ => Err(Flow::Error(
+                "Only numbers, bools, strings and calls of pure functions can be evaluated at compile time".to_string(),
+            )),
+        }
+    }
+
+    /// Evaluate `expr` in its own scope, like a branch of an `if`.
+    fn eval_scoped(&mut self, expr: &ExprAST, locals: &HashMap<String, ConstValue>) -> EvalResult {
+        self.eval(expr, &mut locals.clone())
+    }
+
+    /// Evaluate a loop body. Returns `false` if the loop must be left.
+    fn eval_loop_body(&mut self, body: &ExprAST, locals: &mut HashMap<String, ConstValue>) -> Result<bool, Flow> {
+        match self.eval_scoped(body, locals) {
+            Ok(_) | Err(Flow::Continue) => Ok(true),
+            Err(Flow::Break) => Ok(false),
+            Err(flow) => Err(flow),
+        }
+    }
+
+    /// Evaluate a condition, numbers are true unless they are zero.
+    fn eval_condition(&mut self, expr: &ExprAST, locals: &mut HashMap<String, ConstValue>) -> Result<bool, Flow> {
+        match self.eval(expr, locals)? {
+            ConstValue::Bool(value) => Ok(value),
+            ConstValue::Number(value) => Ok(value != 0.0),
+            ConstValue::Str(_) => Err(Flow::Error("Condition must be a bool or a number".to_string())),
+        }
+    }
+
+    fn eval_number(&mut self, expr: &ExprAST, locals: &mut HashMap<String, ConstValue>) -> Result<f64, Flow> {
+        match self.eval(expr, locals)? {
+            ConstValue::Number(value) => Ok(value),
+            _ => Err(Flow::Error("Expected a number".to_string())),
+        }
+    }
+
+    /// Evaluate the bounds of `range(...)` if `iterable` is a call of the builtin `range`.
+    fn eval_range(
+        &mut self,
+        iterable: &ExprAST,
+        locals: &mut HashMap<String, ConstValue>,
+    ) -> Result<Option<(f64, f64, f64)>, Flow> {
+        let args = match iterable {
+            ExprAST::Call(callee, args, _) if callee == "range" && !self.functions.contains_key(callee) => args,
+            _ => return Ok(None),
+        };
+
+        let mut bounds = Vec::new();
+        for arg in args {
+            bounds.push(self.eval_number(arg, locals)?);
+        }
+        match bounds[..] {
+            [end] => Ok(Some((0.0, end, 1.0))),
+            [start, end] => Ok(Some((start, end, 1.0))),
+            [start, end, step] => Ok(Some((start, end, step))),
+            _ => Err(Flow::Error(format!("range() expects 1 to 3 arguments, found {}", args.len()))),
+        }
+    }
+
+    /// Call the function `callee`, which must be a Cobra function or a pure builtin.
+    fn call(&mut self, callee: &str, args: Vec<ConstValue>) -> EvalResult {
+        let FunctionAST(proto, body) = match self.functions.get(callee) {
+            Some(function) => function,
+            None => return builtin(callee, &args).map_err(Flow::Error),
+        };
+
+        if proto.args.len() != args.len() {
+            return Err(Flow::Error(format!("Incorrect # of arguments passed to {}: expected {}, found {}", proto.name, proto.args.len(), args.len())));
+        }
+        if self.depth >= CALL_DEPTH_LIMIT {
+            return Err(Flow::Error(format!("Constant evaluation exceeded the call depth limit of {}", CALL_DEPTH_LIMIT)));
+        }
+
+        let mut scope: HashMap<_, _> = proto.args.iter().cloned().zip(args).collect();
+        self.depth += 1;
+        let res = self.eval(body, &mut scope);
+        self.depth -= 1;
+
+        match res {
+            Ok(value) | Err(Flow::Return(value)) => Ok(value),
+            Err(Flow::Error(err)) => Err(Flow::Error(err)),
+            Err(_) => Err(Flow::Error(format!("'break' or 'continue' outside of a loop in {}", proto.name))),
+        }
+    }
+}
+
+/// Check whether a counter is still before the exclusive bound `end`, like the generated code.
+fn in_range(variable: f64, end: f64, step: f64) -> bool {
+    if step >= 0.0 {
+        variable < end
+    } else {
+        variable > end
+    }
+}
+
+fn binary_op(op: &str, lhs: ConstValue, rhs: ConstValue) -> Result<ConstValue, String> {
+    use ConstValue::*;
+
+    Ok(match (lhs, rhs) {
+        (Str(lhs), Str(rhs)) if op == "+" => Str(lhs + &rhs),
+        (Bool(lhs), Bool(rhs)) => match op {
+            "==" => Bool(lhs == rhs),
+            "!=" => Bool(lhs != rhs),
+            "&" => Bool(lhs & rhs),
+            "|" => Bool(lhs | rhs),
+            "^" => Bool(lhs ^ rhs),
+            _ => return Err(format!("Unsupported operand types for binary operator: {}", op)),
+        },
+        // Like in the generated code, floor division and modulo by zero are errors.
+        (Number(_), Number(rhs)) if rhs == 0.0 && matches!(op, "//" | "%") => {
+            return Err("Division by zero".to_string());
+        }
+        (Number(lhs), Number(rhs)) => match op {
+            "+" => Number(lhs + rhs),
+            "-" => Number(lhs - rhs),
+            "*" => Number(lhs * rhs),
+            "/" => Number(lhs / rhs),
+            "<" => Bool(lhs < rhs),
+            ">" => Bool(lhs > rhs),
+            "<=" => Bool(lhs <= rhs),
+            ">=" => Bool(lhs >= rhs),
+            "==" => Bool(lhs == rhs),
+            "!=" => Bool(lhs != rhs),
+            _ => Number(fold_binary_op(op, lhs, rhs).ok_or_else(|| format!("Unknown binary operator: {}", op))?),
+        },
+        _ => return Err(format!("Unsupported operand types for binary operator: {}", op)),
+    })
+}
+
+/// Evaluate a call of a builtin function without side effects.
+fn builtin(name: &str, args: &[ConstValue]) -> Result<ConstValue, String> {
+    use ConstValue::*;
+
+    Ok(match (name, args) {
+        ("len", [Str(value)]) => Number(value.len() as f64),
+        ("sqrt", [Number(x)]) => Number(x.sqrt()),
+        ("sin", [Number(x)]) => Number(x.sin()),
+        ("pow", [Number(x), Number(y)]) => Number(x.powf(*y)),
+        ("floor", [Number(x)]) => Number(x.floor()),
+        _ => return Err(format!("{} cannot be called at compile time", name)),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+
+    /// Evaluate the expression `source` with the functions defined by `definitions`.
+    fn eval(definitions: &str, source: &str) -> Result<ConstValue, String> {
+        let mut parser = Parser::new(Lexer::new(definitions.chars()));
+        parser.get_next_token();
+        let mut functions = HashMap::new();
+        while *parser.current_token() != crate::lexer::Token::Eof {
+            let function = parser.parse_definition()?;
+            functions.insert(function.0.name.clone(), function);
+        }
+
+        let mut parser = Parser::new(Lexer::new(source.chars()));
+        parser.get_next_token();
+        let FunctionAST(_, expr) = parser.parse_top_level_expr()?;
+        eval_const(&expr, &functions, &HashMap::new())
+    }
+
+    fn number(source: &str) -> f64 {
+        match eval("", source) {
+            Ok(ConstValue::Number(value)) => value,
+            res => panic!("Expected a number for {}, found {:?}", source, res),
+        }
+    }
+
+    #[test]
+    fn division_follows_ieee() {
+        assert_eq!(number("1 / 0"), f64::INFINITY);
+        assert_eq!(number("-1 / 0"), f64::NEG_INFINITY);
+        assert!(number("0 / 0").is_nan());
+        assert_eq!(number("7 / 2"), 3.5);
+    }
+
+    #[test]
+    fn floor_division_and_modulo_by_zero() {
+        assert_eq!(eval("", "1 // 0"), Err("Division by zero".to_string()));
+        assert_eq!(eval("", "1 % 0"), Err("Division by zero".to_string()));
+        assert_eq!(number("-7 // 2"), -4.0);
+        assert_eq!(number("-7 % 3"), 2.0);
+        assert_eq!(number("7 % -3"), -2.0);
+    }
+
+    #[test]
+    fn operators() {
+        assert_eq!(number("2 ** 3 ** 2"), 512.0);
+        assert_eq!(number("6 & 3 | 8"), 10.0);
+        assert_eq!(number("1 << 4 >> 2"), 4.0);
+        assert_eq!(number("-~5"), 6.0);
+        assert_eq!(eval("", "\"a\" + \"b\""), Ok(ConstValue::Str("ab".to_string())));
+        assert_eq!(eval("", "1 < 2 and not false"), Ok(ConstValue::Bool(true)));
+        assert!(eval("", "\"a\" + 1").is_err());
+    }
+
+    #[test]
+    fn values_are_displayed_as_literals() {
+        let values = [
+            ConstValue::Number(-2.5),
+            ConstValue::Number(f64::INFINITY),
+            ConstValue::Number(f64::NEG_INFINITY),
+            ConstValue::Bool(false),
+            ConstValue::Str("tab\t quote\" backslash\\ newline\n nul\0 é".to_string()),
+        ];
+        for value in values {
+            assert_eq!(eval("", &value.to_string()), Ok(value.clone()), "{}", value);
+        }
+        assert!(matches!(eval("", &ConstValue::Number(f64::NAN).to_string()), Ok(ConstValue::Number(n)) if n.is_nan()));
+    }
+
+    #[test]
+    fn function_calls() {
+        let fib = "def fib(n) if n < 2 then n else fib(n - 1) + fib(n - 2)";
+        assert_eq!(eval(fib, "fib(10)"), Ok(ConstValue::Number(55.0)));
+        assert!(eval("", "unknown(1)").is_err());
+    }
+
+    #[test]
+    fn deep_recursion_is_an_error() {
+        // Runs on a test thread, whose 2 MiB stack is smaller than the stacks of the compiler's
+        // threads.
+        let depth_error = Err(format!("Constant evaluation exceeded the call depth limit of {}", CALL_DEPTH_LIMIT));
+        assert_eq!(eval("def forever(n) forever(n + 1)", "forever(0)"), depth_error);
+
+        let nested = "def nested(n) {
+            let x = n + 1;
+            for i in range(1): {
+                while true: { if x > 0 then return nested(x) * 2 else break }
+            };
+            0
+        }";
+        assert_eq!(eval(nested, "nested(0)"), depth_error);
+    }
+
+    #[test]
+    fn boolean_operators_short_circuit() {
+        assert_eq!(eval("", "false and unknown(1)"), Ok(ConstValue::Bool(false)));
+        assert_eq!(eval("", "true or unknown(1)"), Ok(ConstValue::Bool(true)));
+        assert!(eval("", "true and unknown(1)").is_err());
+        assert_eq!(eval("", "not 1 == 2"), Ok(ConstValue::Bool(true)));
+    }
+
+    #[test]
+    fn while_loops_with_break_and_continue() {
+        let first = "def first(n) { while true: { if n > 2 then return n else break }; 0 }";
+        assert_eq!(eval(first, "first(3)"), Ok(ConstValue::Number(3.0)));
+        assert_eq!(eval(first, "first(1)"), Ok(ConstValue::Number(0.0)));
+
+        let skip = "def skip(n) { for i = 0, i < n in { if i < 3 then continue else return i }; -1 }";
+        assert_eq!(eval(skip, "skip(10)"), Ok(ConstValue::Number(3.0)));
+        assert_eq!(eval(skip, "skip(2)"), Ok(ConstValue::Number(-1.0)));
+        assert_eq!(number("while false: 1"), 0.0);
+    }
+
+    #[test]
+    fn for_in_ranges_and_strings() {
+        let below = "def below(n) { for i in range(10, 0, -2): if i < n then return i else 0; -1 }";
+        assert_eq!(eval(below, "below(5)"), Ok(ConstValue::Number(4.0)));
+        assert_eq!(eval(below, "below(2)"), Ok(ConstValue::Number(-1.0)));
+
+        let last = "def last(n) { let x = -1; for i in range(n): { if i == n - 1 then return i else 0 }; x }";
+        assert_eq!(eval(last, "last(3)"), Ok(ConstValue::Number(2.0)));
+        assert_eq!(eval(last, "last(0)"), Ok(ConstValue::Number(-1.0)));
+
+        let first = "def first(s) { for c in s: return c + c; \"\" }";
+        assert_eq!(eval(first, "first(\"abc\")"), Ok(ConstValue::Str("aa".to_string())));
+        assert_eq!(eval(first, "first(\"\")"), Ok(ConstValue::Str(String::new())));
+        assert!(eval("", "for i in range(1, 2, 3, 4): i").is_err());
+        assert!(eval("", "for i in 3: i").is_err());
+    }
+
+    #[test]
+    fn blocks_let_and_return() {
+        assert_eq!(number("{ let x = 2; let y = x * 3; x + y }"), 8.0);
+        // `let` bindings are scoped to their block.
+        assert!(eval("", "{ { let x = 1 }; x }").is_err());
+        assert_eq!(number("{ let x = 1; { let x = 2; x } + x }"), 3.0);
+
+        let abs = "def abs(x) { if x < 0 then return -x else 0; x }";
+        assert_eq!(eval(abs, "abs(-4)"), Ok(ConstValue::Number(4.0)));
+        assert_eq!(eval(abs, "abs(4)"), Ok(ConstValue::Number(4.0)));
+    }
+
+    #[test]
+    fn constants_refer_to_earlier_constants() {
+        let consts = HashMap::from([
+            ("N".to_string(), ConstValue::Number(10.0)),
+            ("util.NAME".to_string(), ConstValue::Str("cobra".to_string())),
+        ]);
+        let mut parser = Parser::new(Lexer::new("import util\nN * 2\nutil.NAME + \"!\"\nM".chars()));
+        parser.get_next_token();
+        parser.parse_import().unwrap();
+        let mut next = || parser.parse_top_level_expr().unwrap().1;
+
+        assert_eq!(eval_const(&next(), &HashMap::new(), &consts), Ok(ConstValue::Number(20.0)));
+        assert_eq!(eval_const(&next(), &HashMap::new(), &consts), Ok(ConstValue::Str("cobra!".to_string())));
+        assert_eq!(eval_const(&next(), &HashMap::new(), &consts), Err("Unknown variable name: M".to_string()));
+    }
+}
@@ -0,0 +1,484 @@
+//! Resolution of `import` statements and per-file namespaces.
+//!
+//! Every imported file is compiled with its own [`Namespace`]. Functions defined in a file named
+//! `util.ks` are mangled to `util.<name>`, so the same name can be defined in several files
+//! without clashing in the shared `fn_protos` table or the JIT.
+
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+use crate::consteval::ConstValue;
+use crate::lexer::{Lexer, Token};
+use crate::parser::{
+    ConstAST, ExprAST, FunctionAST, GlobalAST, ImportAST, PatternAST, PrototypeAST, TestAST, TypeAST,
+};
+
+/// File extension of Cobra source files.
+pub const SOURCE_EXT: &str = "ks";
+
+/// Environment variable holding additional (`:` separated) module search directories.
+pub const SEARCH_PATH_ENV: &str = "COBRA_PATH";
+
+/// Locates module source files on a search path and remembers which modules were loaded.
+pub struct ModuleLoader {
+    search_path: Vec<PathBuf>,
+    loaded: HashSet<String>,
+}
+
+impl ModuleLoader {
+    /// Create a new loader searching the given directories in order.
+    pub fn new(search_path: Vec<PathBuf>) -> ModuleLoader {
+        ModuleLoader {
+            search_path,
+            loaded: HashSet::new(),
+        }
+    }
+
+    /// Create a loader searching `root` (usually the directory of the main file) followed by the
+    /// directories listed in [`SEARCH_PATH_ENV`].
+    pub fn with_root(root: &Path) -> ModuleLoader {
+        let mut search_path = vec![root.to_path_buf()];
+        if let Some(paths) = std::env::var_os(SEARCH_PATH_ENV) {
+            search_path.extend(std::env::split_paths(&paths));
+        }
+        ModuleLoader::new(search_path)
+    }
+
+    /// Find the source file for `module`. A dotted module name `a.b` maps to `a/b.ks`.
+    pub fn resolve(&self, module: &str) -> Result<PathBuf, String> {
+        let mut rel = PathBuf::new();
+        for part in module.split('.') {
+            if part.is_empty() {
+                return Err(format!("Invalid module name: {}", module));
+            }
+            rel.push(part);
+        }
+        rel.set_extension(SOURCE_EXT);
+
+        self.search_path
+            .iter()
+            .map(|dir| dir.join(&rel))
+            .find(|path| path.is_file())
+            .ok_or_else(|| format!("Module not found: {} (searched for {})", module, rel.display()))
+    }
+
+    /// Mark `module` as loaded. Return `false` if it was loaded before, in which case it must not
+    /// be compiled again (this also breaks import cycles).
+    pub fn mark_loaded(&mut self, module: &str) -> bool {
+        self.loaded.insert(module.to_string())
+    }
+}
+
+/// Name scope of a single source file.
+///
+/// Maps the unqualified names used in the file to the mangled names stored in `fn_protos`.
+pub struct Namespace {
+    prefix: Option<String>,
+    aliases: HashMap<String, String>,
+    locals: HashSet<String>,
+}
+
+impl Namespace {
+    /// The namespace of the main file. Its functions are not mangled.
+    pub fn root() -> Namespace {
+        Namespace {
+            prefix: None,
+            aliases: HashMap::new(),
+            locals: HashSet::new(),
+        }
+    }
+
+    /// The namespace of the imported module `module`.
+    pub fn new(module: &str) -> Namespace {
+        Namespace {
+            prefix: Some(module.to_string()),
+            ..Namespace::root()
+        }
+    }
+
+    /// Check if this is the namespace of the main file.
+    pub fn is_root(&self) -> bool {
+        self.prefix.is_none()
+    }
+
+    /// Get the name of the module this namespace belongs to (`main` for the main file).
+    pub fn name(&self) -> &str {
+        self.prefix.as_deref().unwrap_or("main")
+    }
+
+    /// Get the mangled name for a function `name` defined in this namespace.
+    pub fn qualify(&self, name: &str) -> String {
+        match self.prefix {
+            Some(ref prefix) => mangle(prefix, name),
+            None => name.to_string(),
+        }
+    }
+
+    /// Resolve a function or constant name referenced in this namespace to its mangled name.
+    ///
+    /// Functions defined in this file take precedence over `from ... import` aliases. Anything
+    /// else (qualified names like `math.sqrt` or externs) is global and used as is.
+    pub fn resolve(&self, name: &str) -> String {
+        if self.locals.contains(name) {
+            self.qualify(name)
+        } else if let Some(alias) = self.aliases.get(name) {
+            alias.clone()
+        } else {
+            name.to_string()
+        }
+    }
+
+    /// Declare the functions, constants and global variables defined anywhere in `source`, the
+    /// contents of the file, so definitions may refer to the ones following them.
+    pub fn declare(&mut self, source: &str) {
+        // `def`, `const` and `global` only start top-level definitions.
+        let mut lexer = Lexer::new(source.chars());
+        let mut prev = Token::Eof;
+        loop {
+            let token = lexer.gettok();
+            match (&prev, &token) {
+                (_, Token::Eof) => break,
+                (Token::Def | Token::Const | Token::Global, Token::Identifier(name)) => {
+                    self.locals.insert(name.clone());
+                }
+                _ => {}
+            }
+            prev = token;
+        }
+    }
+
+    /// Bring the names of an already loaded module into scope.
+    ///
+    /// Returns an error if a `from module import name` refers to a function, constant or global
+    /// not defined in the module.
+    pub fn import(
+        &mut self,
+        import: &ImportAST,
+        fn_protos: &HashMap<String, PrototypeAST>,
+        consts: &HashMap<String, ConstValue>,
+        globals: &HashMap<String, TypeAST>,
+    ) -> Result<(), String> {
+        for name in &import.names {
+            let mangled = mangle(&import.module, name);
+            let defined = fn_protos.contains_key(&mangled)
+                || consts.contains_key(&mangled)
+                || globals.contains_key(&mangled);
+            if !defined {
+                return Err(format!("Module {} has no definition {}", import.module, name));
+            }
+            self.aliases.insert(name.clone(), mangled);
+        }
+        Ok(())
+    }
+
+    /// Mangle the name of a function defined in this namespace.
+    pub fn mangle_proto(&mut self, proto: &mut PrototypeAST) {
+        self.locals.insert(proto.name.clone());
+        proto.name = self.qualify(&proto.name);
+    }
+
+    /// Mangle the name of a function defined in this namespace and all calls in its body.
+    pub fn mangle_function(&mut self, FunctionAST(proto, body): &mut FunctionAST) {
+        // Mangle the prototype first so recursive calls resolve to the local definition.
+        self.mangle_proto(proto);
+        self.mangle_expr(body, &mut proto.args.clone());
+    }
+
+    /// Mangle the name of a constant defined in this namespace and all calls in its value.
+    pub fn mangle_const(&mut self, ConstAST { name, value }: &mut ConstAST) {
+        self.mangle_binding(name, value);
+    }
+
+    /// Mangle the name of a global variable defined in this namespace and all calls in its
+    /// initial value.
+    pub fn mangle_global(&mut self, GlobalAST { name, value }: &mut GlobalAST) {
+        self.mangle_binding(name, value);
+    }
+
+    fn mangle_binding(&mut self, name: &mut String, value: &mut ExprAST) {
+        // The value can't refer to the name being defined, so it is mangled first.
+        self.mangle_expr(value, &mut Vec::new());
+        self.locals.insert(name.clone());
+        *name = self.qualify(name);
+    }
+
+    /// Mangle all calls in the body of a `test` block of this namespace.
+    pub fn mangle_test(&self, test: &mut TestAST) {
+        self.mangle_expr(&mut test.body, &mut Vec::new());
+    }
+
+    /// Mangle all calls in a top-level expression evaluated in this namespace.
+    pub fn mangle_top_level(&self, FunctionAST(_, body): &mut FunctionAST) {
+        self.mangle_expr(body, &mut Vec::new());
+    }
+
+    /// Resolve `name` unless it is one of the local variables `bound`, which shadow definitions.
+    fn resolve_unbound(&self, name: &mut String, bound: &[String]) {
+        if !bound.contains(name) {
+            *name = self.resolve(name);
+        }
+    }
+
+    /// Mangle the names referenced by `expr`, except the local variables in `bound`.
+    ///
+    /// Names bound inside `expr` are pushed to `bound` while they are in scope.
+    fn mangle_expr(&self, expr: &mut ExprAST, bound: &mut Vec<String>) {
+        let scope = bound.len();
+        match expr {
+            ExprAST::Number(_) | ExprAST::Str(_) | ExprAST::Bool(_) => {}
+            // Variables may name functions used as values.
+            ExprAST::Variable(name) => self.resolve_unbound(name, bound),
+            ExprAST::UnaryOp(_, operand) => self.mangle_expr(operand, bound),
+            ExprAST::BinaryOp(_, lhs, rhs, _) => {
+                self.mangle_expr(lhs, bound);
+                self.mangle_expr(rhs, bound);
+            }
+            ExprAST::Call(callee, args, _) => {
+                self.resolve_unbound(callee, bound);
+                for arg in args {
+                    self.mangle_expr(arg, bound);
+                }
+            }
+            ExprAST::Index(lhs, rhs, _) | ExprAST::Assign(lhs, rhs) => {
+                self.mangle_expr(lhs, bound);
+                self.mangle_expr(rhs, bound);
+            }
+            ExprAST::Array(elements) | ExprAST::New(_, elements) => {
+                for element in elements {
+                    self.mangle_expr(element, bound);
+                }
+            }
+            ExprAST::Field(value, _) => self.mangle_expr(value, bound),
+            ExprAST::If { condition, then, else_ } => {
+                self.mangle_expr(condition, bound);
+                self.mangle_expr(then, bound);
+                self.mangle_expr(else_, bound);
+            }
+            ExprAST::For { variable_name, start, end, step, body } => {
+                self.mangle_expr(start, bound);
+                // The end condition and the step are evaluated with the variable in scope.
+                bound.push(variable_name.clone());
+                self.mangle_expr(end, bound);
+                if let Some(step) = step {
+                    self.mangle_expr(step, bound);
+                }
+                self.mangle_expr(body, bound);
+            }
+            ExprAST::While { condition, body } => {
+                self.mangle_expr(condition, bound);
+                self.mangle_expr(body, bound);
+            }
+            ExprAST::Break | ExprAST::Continue => {}
+            ExprAST::ForIn { variable_name, iterable, body } => {
+                self.mangle_expr(iterable, bound);
+                bound.push(variable_name.clone());
+                self.mangle_expr(body, bound);
+            }
+            ExprAST::Lambda { params, body } => {
+                bound.extend(params.iter().cloned());
+                self.mangle_expr(body, bound);
+            }
+            ExprAST::Match { value, arms } => {
+                self.mangle_expr(value, bound);
+                for arm in arms {
+                    pattern_bindings(&arm.pattern, bound);
+                    if let Some(guard) = &mut arm.guard {
+                        self.mangle_expr(guard, bound);
+                    }
+                    self.mangle_expr(&mut arm.body, bound);
+                    bound.truncate(scope);
+                }
+            }
+            ExprAST::Block(statements) => {
+                // `let` bindings are in scope until the end of the block.
+                for statement in statements {
+                    self.mangle_expr(statement, bound);
+                }
+            }
+            ExprAST::Let(name, value) => {
+                self.mangle_expr(value, bound);
+                bound.push(name.clone());
+                // Keep the binding for the following statements of the block.
+                return;
+            }
+            ExprAST::Return(value) | ExprAST::Raise(value, _) => self.mangle_expr(value, bound),
+            ExprAST::Try { body, handler, finally } => {
+                self.mangle_expr(body, bound);
+                if let Some((name, handler)) = handler {
+                    bound.extend(name.iter().cloned());
+                    self.mangle_expr(handler, bound);
+                    bound.truncate(scope);
+                }
+                if let Some(finally) = finally {
+                    self.mangle_expr(finally, bound);
+                }
+            }
+            ExprAST::Assert { condition, message, .. } => {
+                self.mangle_expr(condition, bound);
+                if let Some(message) = message {
+                    self.mangle_expr(message, bound);
+                }
+            }
+        }
+        bound.truncate(scope);
+    }
+}
+
+/// Add the names bound by `pattern` to `bound`.
+fn pattern_bindings(pattern: &PatternAST, bound: &mut Vec<String>) {
+    match pattern {
+        PatternAST::Binding(name) => bound.push(name.clone()),
+        PatternAST::Variant(_, fields) => {
+            for field in fields {
+                pattern_bindings(field, bound);
+            }
+        }
+        PatternAST::Wildcard | PatternAST::Literal(_) => {}
+    }
+}
+
+/// Get the symbol name of function `name` defined in module `module`.
+pub fn mangle(module: &str, name: &str) -> String {
+    format!("{}.{}", module, name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+
+    fn parser(source: &str) -> Parser<std::str::Chars<'_>> {
+        let mut parser = Parser::new(Lexer::new(source.chars()));
+        parser.get_next_token();
+        parser
+    }
+
+    #[test]
+    fn modules_are_found_on_the_search_path() {
+        let root = std::env::temp_dir().join(format!("cobra-import-test-{}", std::process::id()));
+        let lib = root.join("lib");
+        std::fs::create_dir_all(lib.join("util")).unwrap();
+        std::fs::write(lib.join("util").join("math.ks"), "def sq(x) x * x").unwrap();
+        std::fs::write(root.join("util.ks"), "").unwrap();
+
+        let mut loader = ModuleLoader::new(vec![root.clone(), lib.clone()]);
+        assert_eq!(loader.resolve("util"), Ok(root.join("util.ks")));
+        assert_eq!(loader.resolve("util.math"), Ok(lib.join("util").join("math.ks")));
+        assert!(loader.resolve("missing").is_err());
+        assert_eq!(loader.resolve("util..math"), Err("Invalid module name: util..math".to_string()));
+
+        assert!(loader.mark_loaded("util"));
+        assert!(!loader.mark_loaded("util"));
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn module_functions_are_mangled() {
+        let mut namespace = Namespace::new("util");
+        let mut fact = parser("def fact(n) fact(n - 1) + sqrt(n)").parse_definition().unwrap();
+        namespace.mangle_function(&mut fact);
+        assert_eq!(fact.0.name, "util.fact");
+
+        let ExprAST::BinaryOp(_, recursive, sqrt, _) = fact.1 else {
+            panic!("Expected a binary operation");
+        };
+        // Recursive calls resolve to the module, prelude functions stay global.
+        assert!(matches!(*recursive, ExprAST::Call(ref name, ..) if name == "util.fact"));
+        assert!(matches!(*sqrt, ExprAST::Call(ref name, ..) if name == "sqrt"));
+        assert_eq!(Namespace::root().qualify("fact"), "fact");
+    }
+
+    /// Mangle the function `source` of the module `util`, which also defines `count` and `total`.
+    fn mangled_body(source: &str) -> String {
+        let mut namespace = Namespace::new("util");
+        namespace.declare("global count = 0\ndef total(x) x");
+        let mut function = parser(source).parse_definition().unwrap();
+        namespace.mangle_function(&mut function);
+        format!("{:?}", function.1)
+    }
+
+    #[test]
+    fn local_bindings_shadow_module_definitions() {
+        let sources = [
+            "def f(count) count + count(1)",
+            "def f(x) { let count = x; count }",
+            "def f(x) for count = 0, count < x in count",
+            "def f(x) for count in range(x): count",
+            "def f(x) lambda count: count + x",
+            "def f(x) match x: case Some(count): count case count if count > 0: count",
+            "def f(x) try: x except count: count",
+        ];
+        for source in sources {
+            let body = mangled_body(source);
+            assert!(!body.contains("util.count"), "{}: {}", source, body);
+        }
+
+        // Bindings end with their scope.
+        let body = mangled_body("def f(x) { { let count = x }; try: count except count: count; count + total(1) }");
+        assert_eq!(body.matches("util.count").count(), 2, "{}", body);
+        assert!(body.contains("\"util.total\""), "{}", body);
+        assert_eq!(mangled_body("def f(total) lambda x: total(x)").matches("util.total").count(), 0);
+    }
+
+    #[test]
+    fn later_definitions_are_mangled() {
+        let source = "def f(x) g(x) + LIMIT\ndef g(x) x\nconst LIMIT = 1";
+        let mut namespace = Namespace::new("util");
+        namespace.declare(source);
+
+        let mut f = parser(source).parse_definition().unwrap();
+        namespace.mangle_function(&mut f);
+        let ExprAST::BinaryOp(_, call, limit, _) = f.1 else {
+            panic!("Expected a binary operation");
+        };
+        assert!(matches!(*call, ExprAST::Call(ref name, ..) if name == "util.g"));
+        assert_eq!(*limit, ExprAST::Variable("util.LIMIT".to_string()));
+        // Only definitions are declared, not their arguments or externs.
+        namespace.declare("extern sin(x)\ndef h(y) y");
+        assert_eq!(namespace.resolve("sin"), "sin");
+        assert_eq!(namespace.resolve("y"), "y");
+    }
+
+    #[test]
+    fn module_bindings_are_mangled() {
+        let mut namespace = Namespace::new("util");
+        let mut scale = parser("const SCALE = 2").parse_const().unwrap();
+        namespace.mangle_const(&mut scale);
+        assert_eq!(scale.name, "util.SCALE");
+
+        let mut count = parser("global count = SCALE * limit").parse_global().unwrap();
+        namespace.mangle_global(&mut count);
+        assert_eq!(count.name, "util.count");
+        let ExprAST::BinaryOp(_, lhs, rhs, _) = count.value else {
+            panic!("Expected a binary operation");
+        };
+        assert_eq!(*lhs, ExprAST::Variable("util.SCALE".to_string()));
+        // Names not defined in the module are global.
+        assert_eq!(*rhs, ExprAST::Variable("limit".to_string()));
+
+        let mut root = Namespace::root();
+        let mut scale = parser("const SCALE = 2").parse_const().unwrap();
+        root.mangle_const(&mut scale);
+        assert_eq!(scale.name, "SCALE");
+    }
+
+    #[test]
+    fn imported_bindings() {
+        let consts = HashMap::from([("util.SCALE".to_string(), ConstValue::Number(2.0))]);
+        let globals = HashMap::from([("util.count".to_string(), TypeAST::F64)]);
+        let import = ImportAST { module: "util".to_string(), names: vec!["SCALE".to_string(), "count".to_string()] };
+
+        let mut namespace = Namespace::root();
+        namespace.import(&import, &HashMap::new(), &consts, &globals).unwrap();
+        assert_eq!(namespace.resolve("SCALE"), "util.SCALE");
+        assert_eq!(namespace.resolve("count"), "util.count");
+        assert_eq!(namespace.resolve("other"), "other");
+
+        let missing = ImportAST { module: "util".to_string(), names: vec!["limit".to_string()] };
+        assert_eq!(
+            namespace.import(&missing, &HashMap::new(), &consts, &globals),
+            Err("Module util has no definition limit".to_string())
+        );
+    }
+}
@@ -1,15 +1,188 @@
+use std::cell::RefCell;
 use std::collections::HashMap;
-use crate::llvm::{IRBuilder, FnValue, FunctionPassManager, Module, Value};
-use crate::parser::{ExprAST, FunctionAST, PrototypeAST};
-use crate::Either;
+use crate::lexer::SourceLoc;
+use crate::llvm::{BasicBlock, IRBuilder, FnValue, FunctionPassManager, Module, Type, Value};
+use crate::parser::{EnumAST, ExprAST, FunctionAST, MatchArm, PatternAST, PrototypeAST, StructAST, TypeAST};
+use crate::consteval::ConstValue;
+use crate::{prelude, typeck, Either};
+use cobra_runtime::error::{ErrorCode, TOP_LEVEL_PREFIX};
 
-type ParseResult<T> = Result<T, String>;
+type IRGenResult<T> = Result<T, String>;
+
+/// Prefix of the LLVM named struct type of a struct passed by value.
+const STRUCT_PREFIX: &str = "struct.";
+/// Prefix of the LLVM named struct type wrapping the pointer of a `&Name` reference.
+///
+/// Pointers are opaque, so the wrapper keeps the referenced struct known to the type checks.
+const REF_PREFIX: &str = "ref.";
+/// Prefix of the LLVM named struct type `{ i64 tag, ptr fields }` of an enum.
+const ENUM_PREFIX: &str = "enum.";
+
+/// User defined types, shared by all functions compiled in a session.
+#[derive(Default)]
+pub struct TypeDefs {
+    pub structs: HashMap<String, StructAST>,
+    pub enums: HashMap<String, EnumAST>,
+    /// Maps variant names to their enum and their index (the tag) in it.
+    pub variants: HashMap<String, (String, usize)>,
+}
+
+impl TypeDefs {
+    /// Register the struct `def` after checking its name and fields.
+    pub fn define_struct(&mut self, def: StructAST) -> Result<(), String> {
+        self.check_name(&def.name)?;
+        // A struct may reference itself, eg `next: &Node`, but not contain itself.
+        self.check_fields(&def, &def.name, false)?;
+
+        self.structs.insert(def.name.clone(), def);
+        Ok(())
+    }
+
+    /// Register the enum `def` after checking its name and variants.
+    pub fn define_enum(&mut self, def: EnumAST) -> Result<(), String> {
+        self.check_name(&def.name)?;
+        for (i, variant) in def.variants.iter().enumerate() {
+            self.check_name(&variant.name)?;
+            if variant.name == def.name || def.variants[..i].iter().any(|other| other.name == variant.name) {
+                return Err(format!("Duplicate variant {} in enum {}", variant.name, def.name));
+            }
+            // Variant fields are stored on the heap, so enums may be recursive, eg `tail: List`.
+            self.check_fields(variant, &def.name, true)?;
+        }
+
+        for (i, variant) in def.variants.iter().enumerate() {
+            self.variants.insert(variant.name.clone(), (def.name.clone(), i));
+        }
+        self.enums.insert(def.name.clone(), def);
+        Ok(())
+    }
+
+    /// Get the enum the variant `name` belongs to and its tag.
+    pub fn variant(&self, name: &str) -> Option<(&EnumAST, usize)> {
+        let (enum_name, tag) = self.variants.get(name)?;
+        Some((&self.enums[enum_name], *tag))
+    }
+
+    fn is_defined(&self, name: &str) -> bool {
+        self.structs.contains_key(name) || self.enums.contains_key(name)
+    }
+
+    fn check_name(&self, name: &str) -> Result<(), String> {
+        if matches!(name, "f64" | "str" | "array") {
+            Err(format!("Cannot redefine builtin type {}", name))
+        } else if self.is_defined(name) || self.variants.contains_key(name) {
+            Err(format!("Redefinition of {}", name))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Check the fields of `def`, a struct or variant of the type `owner` which is being defined.
+    fn check_fields(&self, def: &StructAST, owner: &str, recursive: bool) -> Result<(), String> {
+        for (i, (field, ty)) in def.fields.iter().enumerate() {
+            if def.fields[..i].iter().any(|(other, _)| other == field) {
+                return Err(format!("Duplicate field {} in {}", field, def.name));
+            }
+            match ty {
+                TypeAST::Struct(name) if name == owner && !recursive => {
+                    return Err(format!("Struct {} cannot contain itself by value, use &{}", name, name));
+                }
+                TypeAST::Struct(name) | TypeAST::Ref(name) if name == owner => {}
+                TypeAST::Struct(name) | TypeAST::Ref(name) if !self.is_defined(name) => {
+                    return Err(format!("Unknown type {} of field {}", name, field));
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Blocks `break` and `continue` jump to in the innermost loop.
+struct LoopBlocks<'llvm> {
+    exit: BasicBlock<'llvm>,
+    latch: BasicBlock<'llvm>,
+}
+
+/// Shared return block of the function being generated, which `return` jumps to.
+struct ReturnState<'llvm> {
+    block: BasicBlock<'llvm>,
+    /// Returned values and the blocks they are returned from.
+    incoming: Vec<(Value<'llvm>, BasicBlock<'llvm>)>,
+}
+
+/// Values set up by the entry of a function, see [`IRGen::irgen_entry`].
+#[derive(Clone, Copy)]
+struct FunctionEntry<'llvm> {
+    /// Call depth of the caller.
+    depth: Value<'llvm>,
+    /// Stack frame of the function.
+    frame: Value<'llvm>,
+    /// Stack frame of the caller.
+    prev_frame: Value<'llvm>,
+}
+
+/// Landing pad of an enclosing `try` block, which calls raising an error unwind to.
+struct Handler<'llvm> {
+    landing_pad: BasicBlock<'llvm>,
+}
+
+/// Ways of leaving a `try` block with a `finally` clause. The selector of the `finally` block
+/// holds the way it was entered, to continue there after running the clause.
+#[derive(Clone, Copy, PartialEq)]
+enum Exit {
+    Normal,
+    Unwind,
+    Return,
+    Break,
+    Continue,
+}
+
+/// The `finally` clause of an enclosing `try` block, which all exits of the block run.
+struct FinallyState<'llvm> {
+    block: BasicBlock<'llvm>,
+    /// Number of enclosing loops of the `try` block.
+    loops: usize,
+    /// Exits taken so far with their value (the value of the block, the unwinding exception or
+    /// the returned value) and the blocks they are taken from.
+    exits: Vec<(Exit, Option<Value<'llvm>>, BasicBlock<'llvm>)>,
+}
+
+/// State shared by the chains of arm tests of a `match` expression.
+struct MatchState<'llvm> {
+    value: Value<'llvm>,
+    /// Blocks of the arm bodies generated so far.
+    bodies: Vec<Option<BasicBlock<'llvm>>>,
+    incoming: Vec<(Value<'llvm>, BasicBlock<'llvm>)>,
+    merge_block: BasicBlock<'llvm>,
+}
 
 pub struct IRGen<'llvm, 'a> {
     builder: &'a IRBuilder<'llvm>,
     module: &'llvm Module,
     fn_proto_map: &'a mut HashMap<String, PrototypeAST>,
+    type_defs: &'a TypeDefs,
+    /// Generic functions by name, instantiated on use.
+    generics: &'a HashMap<String, FunctionAST>,
+    /// Values of the `const` declarations by (mangled) name.
+    consts: &'a HashMap<String, ConstValue>,
+    /// Types of the global variables by (mangled) name.
+    globals: &'a HashMap<String, TypeAST>,
     fpm: &'a FunctionPassManager<'llvm>,
+    /// Enclosing loops of the code being generated, innermost last.
+    loops: RefCell<Vec<LoopBlocks<'llvm>>>,
+    /// Return blocks of the function being generated and the functions it is nested in.
+    returns: RefCell<Vec<ReturnState<'llvm>>>,
+    /// Entries of the function being generated and the functions it is nested in.
+    frames: RefCell<Vec<FunctionEntry<'llvm>>>,
+    /// Landing pads of the enclosing `try` blocks in the function being generated, innermost
+    /// last.
+    handlers: RefCell<Vec<Handler<'llvm>>>,
+    /// `finally` clauses of the enclosing `try` blocks in the function being generated,
+    /// innermost last.
+    finallies: RefCell<Vec<FinallyState<'llvm>>>,
+    /// Argument and return types of the closure types created so far, by type name.
+    fn_signatures: RefCell<HashMap<String, (Vec<Type<'llvm>>, Type<'llvm>)>>,
 }
 
 impl<'llvm, 'a> IRGen<'llvm, 'a> {
@@ -17,21 +190,86 @@ impl<'llvm, 'a> IRGen<'llvm, 'a> {
     pub fn compile(
         module: &'llvm Module,
         fn_proto_map: &mut HashMap<String, PrototypeAST>,
+        type_defs: &TypeDefs,
+        generics: &HashMap<String, FunctionAST>,
+        consts: &HashMap<String, ConstValue>,
+        globals: &HashMap<String, TypeAST>,
         compilee: Either<&PrototypeAST, &FunctionAST>,
     ) -> IRGenResult<FnValue<'llvm>> {
-        let builder = IRBuilder::new(module);
-        let fpm = FunctionPassManager::new(module);
+        IRGen::with(module, fn_proto_map, type_defs, generics, consts, globals, |ir_gen| match compilee {
+            Either::Left(proto) => ir_gen.irgen_proto(proto),
+            Either::Right(func) => ir_gen.irgen_function(func),
+        })
+    }
+
+    /// Infer the type of the top-level expression `expr` by generating it into a function of
+    /// `module`, which is deleted again. Nothing else is added to the session.
+    pub fn infer_type(
+        module: &'llvm Module,
+        fn_proto_map: &mut HashMap<String, PrototypeAST>,
+        type_defs: &TypeDefs,
+        generics: &HashMap<String, FunctionAST>,
+        consts: &HashMap<String, ConstValue>,
+        globals: &HashMap<String, TypeAST>,
+        expr: &ExprAST,
+    ) -> IRGenResult<TypeAST> {
+        IRGen::with(module, fn_proto_map, type_defs, generics, consts, globals, |ir_gen| {
+            let proto = PrototypeAST {
+                name: TOP_LEVEL_PREFIX.to_string(),
+                type_params: Vec::new(),
+                args: Vec::new(),
+                arg_types: Vec::new(),
+                ret_type: TypeAST::F64,
+            };
+            let function = ir_gen.irgen_proto(&proto)?;
+            ir_gen.builder.pos_at_end(module.append_basic_block(function));
+            let res = ir_gen.irgen_body(function, expr, &mut HashMap::new());
+            function.delete();
+
+            let ty = res?.type_of();
+            ir_gen.type_ast_of(ty).ok_or_else(|| format!("Unsupported type {}", ty))
+        })
+    }
+
+    /// Run `f` with a code generator for `module`.
+    fn with<T>(
+        module: &'llvm Module,
+        fn_proto_map: &mut HashMap<String, PrototypeAST>,
+        type_defs: &TypeDefs,
+        generics: &HashMap<String, FunctionAST>,
+        consts: &HashMap<String, ConstValue>,
+        globals: &HashMap<String, TypeAST>,
+        f: impl FnOnce(&mut IRGen<'llvm, '_>) -> T,
+    ) -> T {
+        let builder = IRBuilder::with_ctx(module);
+        let fpm = FunctionPassManager::with_ctx(module);
         let mut ir_gen = IRGen {
             builder: &builder,
-            module: module,
-            fn_proto_map: fn_proto_map,
+            module,
+            fn_proto_map,
+            type_defs,
+            generics,
+            consts,
+            globals,
             fpm: &fpm,
+            loops: RefCell::new(Vec::new()),
+            returns: RefCell::new(Vec::new()),
+            frames: RefCell::new(Vec::new()),
+            handlers: RefCell::new(Vec::new()),
+            finallies: RefCell::new(Vec::new()),
+            fn_signatures: RefCell::new(HashMap::new()),
         };
+        f(&mut ir_gen)
+    }
 
-        match compilee {
-            Either::Left(proto) => ir_gen.compile(proto),
-            Either::Right(func) => ir_gen.compile(func),
-        }
+    /// Define the global variable `name` with the initial value `init` in `module`.
+    ///
+    /// Other modules refer to the global by name, so in the JIT it lives as long as the module
+    /// defining it.
+    pub fn define_global(module: &'llvm Module, name: &str, init: &ConstValue) {
+        let init = Self::const_value(module, init);
+        let global = module.add_global(init.type_of(), name);
+        module.set_initializer(global, init);
     }
 
     fn irgen_expr(
@@ -40,32 +278,156 @@ impl<'llvm, 'a> IRGen<'llvm, 'a> {
         named_values: &mut HashMap<String, Value<'llvm>>,
     ) -> IRGenResult<Value<'llvm>> {
         match expr {
-            ExprAST::Number(value) => Ok(self.builder.build_const_f64(*value)),
+            ExprAST::Number(value) => Ok(self.module.type_f64().const_f64(*value)),
+            ExprAST::Str(value) => Ok(self.module.add_global_str(value)),
+            ExprAST::Bool(value) => Ok(self.module.type_i1().const_int(*value as u64)),
             ExprAST::Variable(name) => {
                 if let Some(value) = named_values.get(name) {
                     Ok(*value)
+                } else if let Some(value) = self.consts.get(name) {
+                    Ok(Self::const_value(self.module, value))
+                } else if let Some(ty) = self.globals.get(name) {
+                    let ty = self.irgen_type(ty)?;
+                    Ok(self.builder.load(ty, self.irgen_global(name, ty)))
+                } else if self.type_defs.variants.contains_key(name) {
+                    self.irgen_variant(name, &[], named_values)
+                } else if let Some(proto) = self.fn_proto_map.get(name) {
+                    if !proto.type_params.is_empty() {
+                        return Err(format!("Generic function {} cannot be used as a value", name));
+                    }
+                    self.irgen_fn_value(proto)
                 } else {
                     Err(format!("Unknown variable name: {}", name))
                 }
             },
-            ExprAST::BinaryOp(op, lhs, rhs) => {
+            ExprAST::BinaryOp(op, lhs, rhs, _) if op == "and" || op == "or" => {
+                self.irgen_logical(op == "and", lhs, rhs, named_values)
+            },
+            ExprAST::BinaryOp(op, lhs, rhs, loc) => {
                 let lhs = self.irgen_expr(lhs, named_values)?;
                 let rhs = self.irgen_expr(rhs, named_values)?;
+                if self.is_str(lhs) || self.is_str(rhs) {
+                    return match op.as_str() {
+                        "+" if self.is_str(lhs) && self.is_str(rhs) => Ok(self.irgen_str_concat(lhs, rhs)),
+                        _ => Err(format!("Unsupported operand types for binary operator: {}", op)),
+                    };
+                }
+                if lhs.is_bool() && rhs.is_bool() {
+                    return match op.as_str() {
+                        "==" => Ok(self.builder.icmpeq(lhs, rhs)),
+                        "!=" => Ok(self.builder.icmpne(lhs, rhs)),
+                        // Like in Python, bitwise operators on bools don't short-circuit.
+                        "&" => Ok(self.builder.and(lhs, rhs)),
+                        "|" => Ok(self.builder.or(lhs, rhs)),
+                        "^" => Ok(self.builder.xor(lhs, rhs)),
+                        _ => Err(format!("Unsupported operand types for binary operator: {}", op)),
+                    };
+                }
+                if !lhs.is_f64() || !rhs.is_f64() {
+                    return Err(format!("Unsupported operand types for binary operator: {}", op));
+                }
+
+                // Floor division and modulo by a constant other than zero need no check, `/`
+                // follows IEEE 754.
+                if matches!(op.as_str(), "//" | "%") && rhs.const_f64_value().is_none_or(|rhs| rhs == 0.0) {
+                    let nonzero = self.builder.fcmpune(rhs, self.module.type_f64().const_f64(0.0));
+                    self.irgen_check(nonzero, ErrorCode::DivisionByZero, *loc);
+                }
+
+                // The builder folds instructions on constants, but not the intrinsics and
+                // integer conversions used by the operators below.
+                if let (Some(lhs), Some(rhs)) = (lhs.const_f64_value(), rhs.const_f64_value()) {
+                    if let Some(value) = fold_binary_op(op, lhs, rhs) {
+                        return Ok(self.module.type_f64().const_f64(value));
+                    }
+                }
+
                 match op.as_str() {
-                    "+" => Ok(self.builder.build_add(lhs, rhs)),
-                    "-" => Ok(self.builder.build_sub(lhs, rhs)),
-                    "*" => Ok(self.builder.build_mul(lhs, rhs)),
-                    "/" => Ok(self.builder.build_div(lhs, rhs)),
-                    "<" => Ok(self.builder.build_lt(lhs, rhs)),
-                    ">" => Ok(self.builder.build_gt(lhs, rhs)),
-                    "<=" => Ok(self.builder.build_le(lhs, rhs)),
-                    ">=" => Ok(self.builder.build_ge(lhs, rhs)),
-                    "==" => Ok(self.builder.build_eq(lhs, rhs)),
-                    "!=" => Ok(self.builder.build_ne(lhs, rhs)),
+                    "+" => Ok(self.builder.fadd(lhs, rhs)),
+                    "-" => Ok(self.builder.fsub(lhs, rhs)),
+                    "*" => Ok(self.builder.fmul(lhs, rhs)),
+                    "/" => Ok(self.builder.fdiv(lhs, rhs)),
+                    "//" => Ok(self.irgen_intrinsic("llvm.floor.f64", &mut [self.builder.fdiv(lhs, rhs)])),
+                    "%" => Ok(self.irgen_modulo(lhs, rhs)),
+                    "**" => Ok(self.irgen_intrinsic("llvm.pow.f64", &mut [lhs, rhs])),
+                    "&" | "|" | "^" | "<<" | ">>" => Ok(self.irgen_bitwise(op, lhs, rhs)),
+                    "<" => Ok(self.builder.fcmpolt(lhs, rhs)),
+                    ">" => Ok(self.builder.fcmpogt(lhs, rhs)),
+                    "<=" => Ok(self.builder.fcmpole(lhs, rhs)),
+                    ">=" => Ok(self.builder.fcmpoge(lhs, rhs)),
+                    "==" => Ok(self.builder.fcmpoeq(lhs, rhs)),
+                    // Unordered, so `nan != nan` holds.
+                    "!=" => Ok(self.builder.fcmpune(lhs, rhs)),
                     _ => Err(format!("Unknown binary operator: {}", op)),
                 }
             },
-            ExprAST::Call(callee, args) => {
+            ExprAST::UnaryOp(op, operand) => {
+                let operand = self.irgen_expr(operand, named_values)?;
+                if op == "not" {
+                    return Ok(self.builder.not(self.irgen_condition(operand)?));
+                }
+                if !operand.is_f64() {
+                    return Err(format!("Unsupported operand type for unary operator: {}", op));
+                }
+
+                if let Some(value) = operand.const_f64_value().and_then(|value| fold_unary_op(op, value)) {
+                    return Ok(self.module.type_f64().const_f64(value));
+                }
+                match op.as_str() {
+                    "-" => Ok(self.builder.fneg(operand)),
+                    "~" => {
+                        let type_i64 = self.module.type_i64();
+                        let operand = self.builder.fptosi(operand, type_i64);
+                        let inverted = self.builder.xor(operand, type_i64.const_int(u64::MAX));
+                        Ok(self.builder.sitofp(inverted, self.module.type_f64()))
+                    }
+                    _ => Err(format!("Unknown unary operator: {}", op)),
+                }
+            },
+            ExprAST::Call(callee, args, loc) => {
+                if callee == "len" && args.len() == 1 {
+                    return self.irgen_len(&args[0], named_values);
+                }
+                if callee == "array" && args.len() == 1 {
+                    let len = self.irgen_expr(&args[0], named_values)?;
+                    if !len.is_f64() {
+                        return Err("array() expects a number".to_string());
+                    }
+                    let len = self.builder.fptosi(len, self.module.type_i64());
+                    return Ok(self.irgen_array_alloc(len));
+                }
+                if callee == "panic" && args.len() == 1 && !self.fn_proto_map.contains_key(callee) {
+                    let message = self.irgen_expr(&args[0], named_values)?;
+                    if !self.is_str(message) {
+                        return Err("panic() expects a string".to_string());
+                    }
+                    self.irgen_panic(ErrorCode::Panic, *loc, Some(message));
+
+                    // Code following the call is unreachable, but still needs a block.
+                    let function = self.builder.get_insert_block().get_parent();
+                    self.builder.pos_at_end(self.module.append_basic_block(function));
+                    return Ok(self.module.type_f64().const_f64(0.0));
+                }
+
+                // Local function values shadow global functions.
+                if let Some(closure) = named_values.get(callee).copied() {
+                    let mut args_values = Vec::new();
+                    for arg in args {
+                        args_values.push(self.irgen_expr(arg, named_values)?);
+                    }
+                    self.irgen_set_line(*loc);
+                    return self.irgen_closure_call(callee, closure, args_values);
+                }
+
+                if !self.fn_proto_map.contains_key(callee) {
+                    if self.type_defs.structs.contains_key(callee) {
+                        return self.irgen_struct(callee, args, named_values);
+                    }
+                    if self.type_defs.variants.contains_key(callee) {
+                        return self.irgen_variant(callee, args, named_values);
+                    }
+                }
+
                 let callee = match self.fn_proto_map.get(callee) {
                     Some(proto) => proto,
                     None => return Err(format!("Unknown function referenced: {}", callee)),
@@ -79,130 +441,2077 @@ impl<'llvm, 'a> IRGen<'llvm, 'a> {
                 for arg in args {
                     args_values.push(self.irgen_expr(arg, named_values)?);
                 }
+                self.irgen_set_line(*loc);
+
+                if !callee.type_params.is_empty() {
+                    let function = self.irgen_instance(&callee.name, &args_values)?;
+                    for (i, value) in args_values.iter().enumerate() {
+                        if value.type_of() != function.arg(i).type_of() {
+                            return Err(format!("Argument {} of {} must be of type {}", i + 1, function.get_name(), function.arg(i).type_of()));
+                        }
+                    }
+                    return Ok(self.irgen_call(function, &mut args_values));
+                }
+
+                // Prelude print functions are overloaded for strings.
+                if let [arg] = args_values[..] {
+                    if let Some(symbol) = prelude::str_overload(&callee.name).filter(|_| self.is_str(arg)) {
+                        let (ptr, len) = self.irgen_str_parts(arg);
+                        let function = self.runtime_fn(symbol, &mut [ptr.type_of(), len.type_of()], self.module.type_f64());
+                        return Ok(self.builder.call(function, &mut [ptr, len]));
+                    }
+                }
+
+                for (i, (value, ty)) in args_values.iter_mut().zip(&callee.arg_types).enumerate() {
+                    *value = self.irgen_coerce(*value, ty)
+                        .ok_or_else(|| format!("Argument {} of {} must be of type {:?}", i + 1, callee.name, ty))?;
+                }
+
+                let function = match self.module.get_fn(&callee.name) {
+                    Some(function) => function,
+                    None => self.irgen_proto(callee)?,
+                };
+                Ok(self.irgen_call(function, &mut args_values))
+            },
+            ExprAST::Index(value, index, loc) => {
+                let value = self.irgen_expr(value, named_values)?;
+                let index = self.irgen_expr(index, named_values)?;
+                if !index.is_f64() {
+                    return Err("Index must be a number".to_string());
+                }
+                if self.is_array(value) {
+                    let type_f64 = self.module.type_f64();
+                    let ptr = self.irgen_element_ptr(value, index, *loc);
+                    return Ok(self.builder.load(type_f64, ptr));
+                }
+                if !self.is_str(value) {
+                    return Err("Only strings and arrays can be indexed".to_string());
+                }
+
+                let (ptr, len) = self.irgen_str_parts(value);
+                let type_i32 = self.module.type_i32();
+                let function = self.runtime_fn(
+                    "cobra_str_index",
+                    &mut [ptr.type_of(), len.type_of(), index.type_of(), type_i32, type_i32],
+                    self.module.type_str(),
+                );
+                let (line, col) = (type_i32.const_int(loc.line.into()), type_i32.const_int(loc.col.into()));
+                Ok(self.irgen_call(function, &mut [ptr, len, index, line, col]))
+            },
+            ExprAST::Array(elements) => {
+                let len = self.module.type_i64().const_int(elements.len() as u64);
+                let array = self.irgen_array_alloc(len);
+                let ptr = self.builder.extract_value(array, 0);
+
+                for (i, element) in elements.iter().enumerate() {
+                    let element = self.irgen_expr(element, named_values)?;
+                    if !element.is_f64() {
+                        return Err("Array elements must be numbers".to_string());
+                    }
+                    let idx = self.module.type_i64().const_int(i as u64);
+                    let element_ptr = self.builder.gep(element.type_of(), ptr, idx);
+                    self.builder.store(element, element_ptr);
+                }
 
-                Ok(self.builder.build_call(callee.name, args_values))
+                Ok(array)
             },
+            ExprAST::Field(value, field) => {
+                let value = self.irgen_expr(value, named_values)?;
+                let (def, is_ref) = self.struct_def_of(value)
+                    .ok_or_else(|| format!("Cannot access field {} of a non-struct value", field))?;
+                let idx = Self::field_index(def, field)?;
+
+                if is_ref {
+                    let ty = self.irgen_type(&def.fields[idx].1)?;
+                    let ptr = self.irgen_field_ptr(value, def, idx)?;
+                    Ok(self.builder.load(ty, ptr))
+                } else {
+                    Ok(self.builder.extract_value(value, idx as u32))
+                }
+            },
+            ExprAST::New(name, args) => {
+                let value = self.irgen_struct(name, args, named_values)?;
+                let type_i64 = self.module.type_i64();
+                let alloc = self.runtime_fn("cobra_alloc", &mut [type_i64, type_i64], self.module.type_ptr());
+                let ptr = self.builder.call(alloc, &mut [type_i64.const_int(1), value.type_of().size_of()]);
+                self.builder.store(value, ptr);
+
+                let reference = self.irgen_type(&TypeAST::Ref(name.clone()))?.undef();
+                Ok(self.builder.insert_value(reference, ptr, 0))
+            },
+            ExprAST::Assign(target, value) => match **target {
+                ExprAST::Index(ref array, ref index, loc) => {
+                    let array = self.irgen_expr(array, named_values)?;
+                    let index = self.irgen_expr(index, named_values)?;
+                    let value = self.irgen_expr(value, named_values)?;
+                    if !self.is_array(array) {
+                        return Err("Only array elements can be assigned".to_string());
+                    }
+                    if !index.is_f64() || !value.is_f64() {
+                        return Err("Array index and element must be numbers".to_string());
+                    }
+
+                    let ptr = self.irgen_element_ptr(array, index, loc);
+                    self.builder.store(value, ptr);
+                    Ok(value)
+                }
+                ExprAST::Field(ref base, ref field) => {
+                    let value = self.irgen_expr(value, named_values)?;
+                    let base_value = self.irgen_expr(base, named_values)?;
+                    let (def, is_ref) = self.struct_def_of(base_value)
+                        .ok_or_else(|| format!("Cannot assign field {} of a non-struct value", field))?;
+                    let idx = Self::field_index(def, field)?;
+                    let ty = &def.fields[idx].1;
+                    let value = self.irgen_coerce(value, ty)
+                        .ok_or_else(|| format!("Field {} of {} must be of type {:?}", field, def.name, ty))?;
+
+                    if is_ref {
+                        // Writes through a reference are visible to every holder of the reference.
+                        let ptr = self.irgen_field_ptr(base_value, def, idx)?;
+                        self.builder.store(value, ptr);
+                        return Ok(value);
+                    }
+
+                    // Structs passed by value are copies, so updating one rebinds the variable.
+                    match **base {
+                        ExprAST::Variable(ref name) => {
+                            let updated = self.builder.insert_value(base_value, value, idx as u32);
+                            named_values.insert(name.clone(), updated);
+                            Ok(value)
+                        }
+                        _ => Err(format!("Cannot assign field {} of a temporary struct value", field)),
+                    }
+                }
+                ExprAST::Variable(ref name) => {
+                    let ty = match self.globals.get(name) {
+                        Some(ty) if !named_values.contains_key(name) => ty,
+                        _ => return Err(format!("Cannot assign to {}, only global variables can be reassigned", name)),
+                    };
+                    let value = self.irgen_expr(value, named_values)?;
+                    let llvm_ty = self.irgen_type(ty)?;
+                    if value.type_of() != llvm_ty {
+                        return Err(format!("Global {} must be of type {}", name, ty));
+                    }
+
+                    // The string may be a constant of this module, which can be removed from the
+                    // JIT while the global still holds it.
+                    let value = if self.is_str(value) { self.irgen_str_copy(value) } else { value };
+                    self.builder.store(value, self.irgen_global(name, llvm_ty));
+                    Ok(value)
+                }
+                _ => Err(format!("Invalid assignment target {:?}", target)),
+            },
+            ExprAST::ForIn { variable_name, iterable, body } => {
+                self.irgen_for_in(variable_name, iterable, body, named_values)
+            },
+            ExprAST::Match { value, arms } => self.irgen_match(value, arms, named_values),
+            ExprAST::Lambda { params, body } => self.irgen_lambda(params, body, named_values),
             ExprAST::If { condition, then, else_ } => {
                 let condition = self.irgen_expr(condition, named_values)?;
-                let zero = self.builder.build_const_f64(0.0);
-                let condition = self.builder.build_fcmp_gt(condition, zero);
+                let condition = self.irgen_condition(condition)?;
+
                 let function = self.builder.get_insert_block().get_parent();
-                let then_block = self.builder.build_append_block("then", function);
-                let else_block = self.builder.build_append_block("else", function);
-                let merge_block = self.builder.build_append_block("ifcont", function);
-                self.builder.build_cond_br(condition, then_block, else_block);
-
-                self.builder.set_insert_point(then_block);
-                let then_value = self.irgen_expr(then, named_values)?;
-                self.builder.build_br(merge_block);
+                let then_block = self.module.append_basic_block(function);
+                let else_block = self.module.append_basic_block(function);
+                let merge_block = self.module.append_basic_block(function);
+                self.builder.cond_br(condition, then_block, else_block);
+
+                self.builder.pos_at_end(then_block);
+                let then_value = self.irgen_scoped(then, named_values)?;
+                self.builder.br(merge_block);
                 let then_block = self.builder.get_insert_block();
 
-                self.builder.set_insert_point(else_block);
-                let else_value = self.irgen_expr(else_, named_values)?;
-                self.builder.build_br(merge_block);
+                self.builder.pos_at_end(else_block);
+                let else_value = self.irgen_scoped(else_, named_values)?;
+                self.builder.br(merge_block);
                 let else_block = self.builder.get_insert_block();
 
-                self.builder.set_insert_point(merge_block);
-                let phi = self.builder.build_phi(then_value.get_type());
-                phi.add_incoming(then_value, then_block);
-                phi.add_incoming(else_value, else_block);
-                Ok(phi)
-            },
-            ExprAST::For {
-                var,
-                start,
-                end,
-                step,
-                body,
-            } => {
+                if then_value.type_of() != else_value.type_of() {
+                    return Err("Both branches of an if expression must have the same type".to_string());
+                }
 
+                self.builder.pos_at_end(merge_block);
+                let phi = self.builder.phi(then_value.type_of(), &[(then_value, then_block), (else_value, else_block)]);
+                Ok(*phi)
+            },
+            ExprAST::For { variable_name, start, end, step, body } => {
                 let start = self.irgen_expr(start, named_values)?;
-                let function = self.builder.get_insert_block().get_parent();
-                let entry_block = self.builder.get_insert_block();
-                let loop_block = self.module.append_basic_block(function);
-
-                self.builder.build_br(loop_block);
-                self.builder.position_at_end(loop_block);
+                if !start.is_f64() {
+                    return Err("Start of a for loop must be a number".to_string());
+                }
 
-                let variable = self.builder.phi(self.module.type_f64(), &[(start, entry_block)]);
-                let old_value = named_values.insert(var.clone(), variable);
+                let type_f64 = self.module.type_f64();
+                let entry_block = self.builder.get_insert_block();
+                let function = entry_block.get_parent();
+                let cond_block = self.module.append_basic_block(function);
+                let body_block = self.module.append_basic_block(function);
+                let latch_block = self.module.append_basic_block(function);
+                let after_block = self.module.append_basic_block(function);
 
-                self.irgen_expr(body, named_values)?;
+                // The condition is checked before the body, so an empty range skips the loop.
+                self.builder.br(cond_block);
+                self.builder.pos_at_end(cond_block);
+                let variable = self.builder.phi(type_f64, &[(start, entry_block)]);
+                let old_value = named_values.insert(variable_name.clone(), *variable);
 
-                let step = self.irgen_expr(step, named_values)?;
-                let next_var = self.builder.build_add(variable, step);
+                let step = match step {
+                    Some(step) => self.irgen_expr(step, named_values)?,
+                    None => type_f64.const_f64(1.0),
+                };
+                if !step.is_f64() {
+                    return Err("Step of a for loop must be a number".to_string());
+                }
 
                 let end = self.irgen_expr(end, named_values)?;
-                let end_cond = self.builder.build_fcmp_gt(end, next_var);
-                let loop_end_block = self.builder.get_insert_block();
-                let after_block = self.module.append_basic_block(function);
+                let in_range = if end.is_bool() {
+                    end
+                } else if end.is_f64() {
+                    self.irgen_in_range(*variable, end, step)
+                } else {
+                    return Err("End of a for loop must be a bool or a number".to_string());
+                };
+                self.builder.cond_br(in_range, body_block, after_block);
+
+                self.builder.pos_at_end(body_block);
+                self.irgen_loop_body(body, after_block, latch_block, named_values)?;
 
-                self.builder.build_cond_br(end_cond, loop_block, after_block);
-                self.builder.position_at_end(after_block);
+                self.builder.pos_at_end(latch_block);
+                let next = self.builder.fadd(*variable, step);
+                variable.add_incoming(next, latch_block);
+                self.builder.br(cond_block);
+                self.builder.pos_at_end(after_block);
 
                 if let Some(old_value) = old_value {
-                    named_values.insert(var.clone(), old_value);
+                    named_values.insert(variable_name.clone(), old_value);
                 } else {
-                    named_values.remove(&var);
+                    named_values.remove(variable_name);
                 }
 
-                Ok(self.builder.build_const_f64(0.0))
-            }
+                Ok(type_f64.const_f64(0.0))
+            },
+            ExprAST::While { condition, body } => {
+                let function = self.builder.get_insert_block().get_parent();
+                let cond_block = self.module.append_basic_block(function);
+                let body_block = self.module.append_basic_block(function);
+                let after_block = self.module.append_basic_block(function);
+
+                self.builder.br(cond_block);
+                self.builder.pos_at_end(cond_block);
+                let condition = self.irgen_scoped(condition, named_values)?;
+                let condition = self.irgen_condition(condition)?;
+                self.builder.cond_br(condition, body_block, after_block);
+
+                // The condition block doubles as the latch, `continue` re-checks the condition.
+                self.builder.pos_at_end(body_block);
+                self.irgen_loop_body(body, after_block, cond_block, named_values)?;
+                self.builder.pos_at_end(after_block);
+
+                Ok(self.module.type_f64().const_f64(0.0))
+            },
+            ExprAST::Break | ExprAST::Continue => {
+                let exit = if matches!(expr, ExprAST::Break) { Exit::Break } else { Exit::Continue };
+                self.irgen_jump(exit)?;
+
+                // Code following the jump is unreachable, but still needs a block to go into.
+                let function = self.builder.get_insert_block().get_parent();
+                self.builder.pos_at_end(self.module.append_basic_block(function));
+                Ok(self.module.type_f64().const_f64(0.0))
+            },
+            ExprAST::Block(statements) => {
+                let mut scope = named_values.clone();
+                let mut value = self.module.type_f64().const_f64(0.0);
+                for statement in statements {
+                    value = self.irgen_expr(statement, &mut scope)?;
+                }
+
+                // The statements run in sequence, so updates of struct fields of outer variables
+                // stay visible after the block, unlike the variables it binds.
+                for (name, outer) in named_values.iter_mut() {
+                    let shadowed = statements
+                        .iter()
+                        .any(|statement| matches!(statement, ExprAST::Let(bound, _) if bound == name));
+                    if !shadowed {
+                        *outer = scope[name];
+                    }
+                }
+                Ok(value)
+            },
+            ExprAST::Let(name, value) => {
+                let value = self.irgen_expr(value, named_values)?;
+                named_values.insert(name.clone(), value);
+                Ok(value)
+            },
+            ExprAST::Return(value) => {
+                let value = self.irgen_expr(value, named_values)?;
+                self.irgen_return(value)?;
+
+                // Like after `break`, the following code is unreachable. Its value is never used,
+                // but has the type of the returned value so `if c then return x else y` checks.
+                let function = self.builder.get_insert_block().get_parent();
+                self.builder.pos_at_end(self.module.append_basic_block(function));
+                Ok(value.type_of().undef())
+            },
+            ExprAST::Raise(message, loc) => {
+                let message = self.irgen_expr(message, named_values)?;
+                if !self.is_str(message) {
+                    return Err("raise expects a string message".to_string());
+                }
+                self.irgen_panic(ErrorCode::Raised, *loc, Some(message));
+
+                let function = self.builder.get_insert_block().get_parent();
+                self.builder.pos_at_end(self.module.append_basic_block(function));
+                Ok(self.module.type_f64().const_f64(0.0))
+            },
+            ExprAST::Assert { condition, message, source, loc } => {
+                let condition = self.irgen_expr(condition, named_values)?;
+                let condition = self.irgen_condition(condition)?;
+
+                let function = self.builder.get_insert_block().get_parent();
+                let ok_block = self.module.append_basic_block(function);
+                let fail_block = self.module.append_basic_block(function);
+                self.builder.cond_br(condition, ok_block, fail_block);
+
+                // Like in Python, the message is only evaluated if the assertion fails.
+                self.builder.pos_at_end(fail_block);
+                let detail = match message {
+                    Some(message) => {
+                        let message = self.irgen_scoped(message, named_values)?;
+                        if !self.is_str(message) {
+                            return Err("Assertion message must be a string".to_string());
+                        }
+                        let prefix = self.module.add_global_str(&format!("{}, ", source));
+                        self.irgen_str_concat(prefix, message)
+                    }
+                    None => self.module.add_global_str(source),
+                };
+                self.irgen_panic(ErrorCode::AssertionFailed, *loc, Some(detail));
+
+                self.builder.pos_at_end(ok_block);
+                Ok(self.module.type_f64().const_f64(0.0))
+            },
+            ExprAST::Try { body, handler, finally } => {
+                let handler = handler.as_ref().map(|(name, handler)| (name.as_deref(), &**handler));
+                self.irgen_try(body, handler, finally.as_deref(), named_values)
+            },
         }
     }
 
-    fn irgen_proto(&self, PrototypeAST(name, args): &PrototypeAST) -> FnValue<'llvm> {
-        let type_f64 = self.module.type_f64();
-        let mut doubles = Vec::new();
+    /// Get the LLVM constant for a value evaluated at compile time.
+    fn const_value(module: &'llvm Module, value: &ConstValue) -> Value<'llvm> {
+        match value {
+            ConstValue::Number(value) => module.type_f64().const_f64(*value),
+            ConstValue::Bool(value) => module.type_i1().const_int(*value as u64),
+            ConstValue::Str(value) => module.add_global_str(value),
+        }
+    }
 
-        let function_type = self.module.type_function(type_f64, &doubles);
-        let function = self.module.add_function(name, function_type);
+    /// Get a pointer to the global variable `name`, declaring it in the module on first use.
+    fn irgen_global(&self, name: &str, ty: Type<'llvm>) -> Value<'llvm> {
+        match self.module.get_global(name) {
+            Some(global) => global,
+            None => self.module.add_global(ty, name),
+        }
+    }
 
-        for i in 0..function.args() {
-            function.arg(i).set_name(&args[i]);
+    /// Generate the body of `function` at the current insert position and return its result:
+    /// the value of `body` merged with the values of the `return`s in it, which must all have
+    /// the same type.
+    ///
+    /// The caller emits the actual `ret`, so it can still check the returned type.
+    fn irgen_body(
+        &self,
+        function: FnValue<'llvm>,
+        body: &ExprAST,
+        named_values: &mut HashMap<String, Value<'llvm>>,
+    ) -> IRGenResult<Value<'llvm>> {
+        // Errors raised by a top-level expression are passed to the host, errors raised by other
+        // functions unwind to their callers.
+        let outer_handlers = self.handlers.take();
+        let outer_finallies = self.finallies.take();
+        let uncaught = function.get_name().starts_with(TOP_LEVEL_PREFIX).then(|| {
+            let landing_pad = self.module.append_basic_block(function);
+            self.handlers.borrow_mut().push(Handler { landing_pad });
+            landing_pad
+        });
+
+        let entry = self.irgen_entry(function);
+        let return_block = self.module.create_basic_block();
+        self.returns.borrow_mut().push(ReturnState { block: return_block, incoming: Vec::new() });
+        self.frames.borrow_mut().push(entry);
+        let res = self.irgen_expr(body, named_values);
+        self.frames.borrow_mut().pop();
+        *self.handlers.borrow_mut() = outer_handlers;
+        *self.finallies.borrow_mut() = outer_finallies;
+        if let Some(landing_pad) = uncaught {
+            self.irgen_uncaught(landing_pad);
+        }
+        let mut incoming = self.returns.borrow_mut().pop().map(|state| state.incoming).unwrap_or_default();
+
+        // Only append the block now, so it is placed after all blocks of the body.
+        function.append_basic_block(return_block);
+        let value = res?;
+        incoming.push((value, self.builder.get_insert_block()));
+        self.builder.br(return_block);
+        self.builder.pos_at_end(return_block);
+
+        let ret_type = value.type_of();
+        if let Some((other, _)) = incoming.iter().find(|(other, _)| other.type_of() != ret_type) {
+            return Err(format!("Function returns values of different types: {} and {}", other.type_of(), ret_type));
+        }
+        let value = match incoming[..] {
+            [(value, _)] => value,
+            _ => *self.builder.phi(ret_type, &incoming),
+        };
+
+        // Restore the call depth and frame of the caller.
+        let (type_i64, type_ptr) = (self.module.type_i64(), self.module.type_ptr());
+        self.builder.store(entry.depth, self.irgen_global("cobra_call_depth", type_i64));
+        self.builder.store(entry.prev_frame, self.irgen_global("cobra_frame", type_ptr));
+        Ok(value)
+    }
+
+    /// Emit the entry of `function` (see `cobra_runtime::limits` and `cobra_runtime::error`):
+    /// raise an error if the watchdog interrupted the evaluation or the call depth exceeds its
+    /// limit, then increment the call depth and push the stack frame of the function.
+    fn irgen_entry(&self, function: FnValue<'llvm>) -> FunctionEntry<'llvm> {
+        self.irgen_interrupt_check();
+
+        let (type_i64, type_ptr) = (self.module.type_i64(), self.module.type_ptr());
+        let name = self.module.add_global_str(function.get_name());
+        let depth_ptr = self.irgen_global("cobra_call_depth", type_i64);
+        let depth = self.builder.load(type_i64, depth_ptr);
+        let limit = self.builder.load(type_i64, self.irgen_global("cobra_max_call_depth", type_i64));
+        let ok_block = self.module.append_basic_block(function);
+        let fail_block = self.module.append_basic_block(function);
+        self.builder.cond_br(self.builder.icmpult(depth, limit), ok_block, fail_block);
+
+        self.builder.pos_at_end(fail_block);
+        let (name_ptr, name_len) = self.irgen_str_parts(name);
+        let fail = self.runtime_fn("cobra_recursion_limit", &mut [type_ptr, type_i64], self.module.type_void());
+        self.irgen_call(fail, &mut [name_ptr, name_len]);
+        self.builder.unreachable();
+
+        self.builder.pos_at_end(ok_block);
+        self.builder.store(self.builder.add(depth, type_i64.const_int(1)), depth_ptr);
+
+        let type_frame = self.type_frame();
+        let frame = self.builder.alloca(type_frame);
+        let frame_ptr = self.irgen_global("cobra_frame", type_ptr);
+        let prev_frame = self.builder.load(type_ptr, frame_ptr);
+        self.builder.store(prev_frame, self.builder.struct_gep(type_frame, frame, 0));
+        self.builder.store(name, self.builder.struct_gep(type_frame, frame, 1));
+        self.builder.store(type_i64.const_int(0), self.builder.struct_gep(type_frame, frame, 2));
+        self.builder.store(frame, frame_ptr);
+
+        FunctionEntry { depth, frame, prev_frame }
+    }
+
+    /// Get the type of the stack frames pushed by every function, `cobra_runtime::error::Frame`:
+    /// the previous frame, the function name and the line of the current call.
+    fn type_frame(&self) -> Type<'llvm> {
+        let mut fields = [self.module.type_ptr(), self.module.type_str(), self.module.type_i64()];
+        self.module.type_named_struct("frame", &mut fields)
+    }
+
+    /// Record the line of `loc` in the stack frame of the current function, before a call.
+    fn irgen_set_line(&self, loc: SourceLoc) {
+        if let Some(entry) = self.frames.borrow().last() {
+            let line = self.module.type_i64().const_int(loc.line.into());
+            self.builder.store(line, self.builder.struct_gep(self.type_frame(), entry.frame, 2));
+        }
+    }
+
+    /// Call `function`, unwinding to the landing pad of the innermost `try` block if it raises
+    /// an error.
+    fn irgen_call(&self, function: FnValue<'llvm>, args: &mut [Value<'llvm>]) -> Value<'llvm> {
+        let landing_pad = match self.handlers.borrow().last() {
+            Some(handler) => handler.landing_pad,
+            None => return self.builder.call(function, args),
+        };
+        let then_block = self.module.append_basic_block(self.builder.get_insert_block().get_parent());
+        let value = self.builder.invoke(function, args, then_block, landing_pad);
+        self.builder.pos_at_end(then_block);
+        value
+    }
+
+    /// Call the function pointer `callee` like [`IRGen::irgen_call`].
+    fn irgen_call_indirect(&self, fn_type: Type<'llvm>, callee: Value<'llvm>, args: &mut [Value<'llvm>]) -> Value<'llvm> {
+        let landing_pad = match self.handlers.borrow().last() {
+            Some(handler) => handler.landing_pad,
+            None => return self.builder.call_indirect(fn_type, callee, args),
+        };
+        let then_block = self.module.append_basic_block(self.builder.get_insert_block().get_parent());
+        let value = self.builder.invoke_indirect(fn_type, callee, args, then_block, landing_pad);
+        self.builder.pos_at_end(then_block);
+        value
+    }
+
+    /// Emit a landing pad at the end of the current block, catching every error if `catch` is
+    /// set and cleaning up otherwise, see `cobra_runtime::exception`.
+    fn irgen_landing_pad(&self, catch: bool) -> Value<'llvm> {
+        let type_ptr = self.module.type_ptr();
+        let personality = self.runtime_fn("cobra_personality", &mut [], self.module.type_i32());
+        self.builder.get_insert_block().get_parent().set_personality(personality);
+
+        let ty = self.module.type_named_struct("exception", &mut [type_ptr, self.module.type_i32()]);
+        let landing_pad = self.builder.landing_pad(ty, personality, catch.then(|| type_ptr.const_null()));
+
+        // The unwound calls didn't restore the call depth and frame of this function.
+        if let Some(entry) = self.frames.borrow().last() {
+            let type_i64 = self.module.type_i64();
+            let depth = self.builder.add(entry.depth, type_i64.const_int(1));
+            self.builder.store(depth, self.irgen_global("cobra_call_depth", type_i64));
+            self.builder.store(entry.frame, self.irgen_global("cobra_frame", type_ptr));
         }
-        function
+        landing_pad
+    }
+
+    /// Emit the landing pad `block` of a top-level expression, passing the errors it didn't
+    /// handle to the host.
+    fn irgen_uncaught(&self, block: BasicBlock<'llvm>) {
+        let insert_block = self.builder.get_insert_block();
+        self.builder.pos_at_end(block);
+
+        let landing_pad = self.irgen_landing_pad(true);
+        let exception = self.builder.extract_value(landing_pad, 0);
+        let uncaught = self.runtime_fn("cobra_uncaught", &mut [self.module.type_ptr()], self.module.type_void());
+        self.builder.call(uncaught, &mut [exception]);
+        self.builder.unreachable();
+
+        self.builder.pos_at_end(insert_block);
     }
 
-    fn irgen_function(
-        &mut self,
-        FunctionAST(proto, body): &FunctionAST,
+    /// Generate `try: body except [name]: handler finally: finally`.
+    ///
+    /// Calls of `body` unwind to a landing pad running `handler`. If there is a `finally`
+    /// clause, it is generated once in a block which every exit of the `try` block branches to:
+    /// the end of `body` and `handler`, a cleanup landing pad which calls of `handler` (or
+    /// `body` without `except` clause) unwind to, and `return`, `break` and `continue`. After
+    /// running the clause, a selector continues at the exit it was entered from.
+    fn irgen_try(
+        &self,
+        body: &ExprAST,
+        handler: Option<(Option<&str>, &ExprAST)>,
+        finally: Option<&ExprAST>,
         named_values: &mut HashMap<String, Value<'llvm>>,
-    ) -> IRGenResult<FnValue<'llvm>> {
+    ) -> IRGenResult<Value<'llvm>> {
+        let function = self.builder.get_insert_block().get_parent();
+        let cleanup_block = finally.map(|_| self.module.append_basic_block(function));
+        let catch_block = handler.map(|_| self.module.append_basic_block(function));
+        // The end of the clauses branches to the `finally` block if there is one.
+        let merge_block = self.module.create_basic_block();
 
-        self.fn_proto_map.insert(proto.name.clone(), proto.clone());
-        let function = self.get_function(proto.name.as_str());
+        // The cleanup landing pad is pushed first, so it also covers the `except` clause.
+        if let Some(landing_pad) = cleanup_block {
+            self.handlers.borrow_mut().push(Handler { landing_pad });
+            let loops = self.loops.borrow().len();
+            self.finallies.borrow_mut().push(FinallyState { block: merge_block, loops, exits: Vec::new() });
+        }
+        if let Some(landing_pad) = catch_block {
+            self.handlers.borrow_mut().push(Handler { landing_pad });
+        }
+        let res = self.irgen_try_clauses(body, handler, catch_block, merge_block, named_values);
+        let state = cleanup_block.and_then(|_| {
+            self.handlers.borrow_mut().pop();
+            self.finallies.borrow_mut().pop()
+        });
+        let incoming = res?;
 
-        if function.count_basic_blocks() != 0 {
-            return Err(format!("Redefinition of function {}", proto.name));
+        function.append_basic_block(merge_block);
+        let (Some(finally), Some(cleanup_block), Some(state)) = (finally, cleanup_block, state) else {
+            self.builder.pos_at_end(merge_block);
+            return Ok(match incoming[..] {
+                [(value, _)] => value,
+                _ => *self.builder.phi(incoming[0].0.type_of(), &incoming),
+            });
+        };
+
+        // Errors unwinding from the clauses run `finally` before unwinding further.
+        self.builder.pos_at_end(cleanup_block);
+        let landing_pad = self.irgen_landing_pad(false);
+        self.builder.br(merge_block);
+
+        let mut exits: Vec<_> = incoming
+            .iter()
+            .map(|&(value, block)| (Exit::Normal, Some(value), block))
+            .collect();
+        exits.push((Exit::Unwind, Some(landing_pad), self.builder.get_insert_block()));
+        exits.extend(state.exits);
+
+        self.builder.pos_at_end(merge_block);
+        let type_i32 = self.module.type_i32();
+        let selectors: Vec<_> = exits
+            .iter()
+            .map(|&(exit, _, block)| (type_i32.const_int(exit as u64), block))
+            .collect();
+        let selector = *self.builder.phi(type_i32, &selectors);
+        let value = self.irgen_exit_value(&exits, Exit::Normal)?;
+        let exception = self.irgen_exit_value(&exits, Exit::Unwind)?;
+        let returned = self.irgen_exit_value(&exits, Exit::Return)?;
+
+        self.irgen_scoped(finally, named_values)?;
+
+        let after_block = self.module.append_basic_block(function);
+        let mut cases = Vec::new();
+        for exit in [Exit::Unwind, Exit::Return, Exit::Break, Exit::Continue] {
+            if exits.iter().any(|&(taken, _, _)| taken == exit) {
+                cases.push((exit, self.module.append_basic_block(function)));
+            }
         }
+        let switch_cases: Vec<_> = cases
+            .iter()
+            .map(|&(exit, block)| (type_i32.const_int(exit as u64), block))
+            .collect();
+        self.builder.switch(selector, after_block, &switch_cases);
 
-        let basic_block = self.module.append_basic_block(function);
-        self.builder.build_br(basic_block);
+        // Exits other than the end of the clauses continue to leave the enclosing `try` blocks.
+        for (exit, block) in cases {
+            self.builder.pos_at_end(block);
+            match exit {
+                Exit::Unwind => self.builder.resume(exception.expect("unwind exit")),
+                Exit::Return => self.irgen_return(returned.expect("return exit"))?,
+                _ => self.irgen_jump(exit)?,
+            }
+        }
 
-        named_values.clear();
+        self.builder.pos_at_end(after_block);
+        Ok(value.expect("normal exit"))
+    }
 
-        for i in 0..function.args() {
-            let arg = function.arg(i);
-            named_values.insert(arg.get_name().into(), arg);
+    /// Generate the `try` and `except` clauses of a `try` block, branching to `merge_block` at
+    /// their end. Return their values and the blocks they end in.
+    fn irgen_try_clauses(
+        &self,
+        body: &ExprAST,
+        handler: Option<(Option<&str>, &ExprAST)>,
+        catch_block: Option<BasicBlock<'llvm>>,
+        merge_block: BasicBlock<'llvm>,
+        named_values: &mut HashMap<String, Value<'llvm>>,
+    ) -> IRGenResult<Vec<(Value<'llvm>, BasicBlock<'llvm>)>> {
+        let res = self.irgen_scoped(body, named_values);
+        if catch_block.is_some() {
+            self.handlers.borrow_mut().pop();
         }
+        let value = res?;
+        let mut incoming = vec![(value, self.builder.get_insert_block())];
+        self.builder.br(merge_block);
 
-        if let Ok(ret) = self.irgen_expr(body, named_values) {
-            self.builder.build_ret(ret);
-            self.fpm.run_on(function);
-            Ok(function)
-        } else {
-            function.delete();
-            Err(format!("Error generating code for function {}", proto.name))
+        if let (Some(block), Some((name, handler))) = (catch_block, handler) {
+            self.builder.pos_at_end(block);
+            let landing_pad = self.irgen_landing_pad(true);
+            let exception = self.builder.extract_value(landing_pad, 0);
+            let begin_catch = self.runtime_fn("cobra_begin_catch", &mut [self.module.type_ptr()], self.module.type_str());
+            let message = self.irgen_call(begin_catch, &mut [exception]);
+
+            let mut scope = named_values.clone();
+            if let Some(name) = name {
+                scope.insert(name.to_string(), message);
+            }
+            let handler_value = self.irgen_expr(handler, &mut scope)?;
+            if handler_value.type_of() != value.type_of() {
+                return Err("Both clauses of a try expression must have the same type".to_string());
+            }
+            incoming.push((handler_value, self.builder.get_insert_block()));
+            self.builder.br(merge_block);
         }
+        Ok(incoming)
     }
 
-    fn get_function(&self, name: &str) -> Option<FnValue<'llvm>> {
-        let callee = self.module.get_function(name);
-        if callee.count_basic_blocks() != 0 {
-            Some(callee)
-        } else {
-            None
+    /// Merge the values of the `exit`s of a `try` block entering its `finally` block, `None` if
+    /// the block is never left that way. The values are undefined when entering otherwise.
+    fn irgen_exit_value(
+        &self,
+        exits: &[(Exit, Option<Value<'llvm>>, BasicBlock<'llvm>)],
+        exit: Exit,
+    ) -> IRGenResult<Option<Value<'llvm>>> {
+        let mut values = exits.iter().filter(|&&(taken, _, _)| taken == exit).filter_map(|&(_, value, _)| value);
+        let Some(ty) = values.next().map(|value| value.type_of()) else {
+            return Ok(None);
+        };
+        if let Some(other) = values.find(|value| value.type_of() != ty) {
+            return Err(format!("Function returns values of different types: {} and {}", ty, other.type_of()));
+        }
+
+        let incoming: Vec<_> = exits
+            .iter()
+            .map(|&(taken, value, block)| (value.filter(|_| taken == exit).unwrap_or_else(|| ty.undef()), block))
+            .collect();
+        Ok(Some(*self.builder.phi(ty, &incoming)))
+    }
+
+    /// Return `value` from the function being generated, running the `finally` clauses of the
+    /// enclosing `try` blocks first. Ends the current block.
+    fn irgen_return(&self, value: Value<'llvm>) -> IRGenResult<()> {
+        let block = self.builder.get_insert_block();
+        if let Some(state) = self.finallies.borrow_mut().last_mut() {
+            state.exits.push((Exit::Return, Some(value), block));
+            self.builder.br(state.block);
+            return Ok(());
+        }
+
+        let mut returns = self.returns.borrow_mut();
+        let state = returns
+            .last_mut()
+            .ok_or_else(|| "'return' outside of a function".to_string())?;
+        state.incoming.push((value, block));
+        self.builder.br(state.block);
+        Ok(())
+    }
+
+    /// Jump out of the innermost loop for `break`, or to its next iteration for `continue`,
+    /// running the `finally` clauses of the `try` blocks in the loop first. Ends the current
+    /// block.
+    fn irgen_jump(&self, exit: Exit) -> IRGenResult<()> {
+        let loops = self.loops.borrow().len();
+        let name = if exit == Exit::Break { "Break" } else { "Continue" };
+        if let Some(state) = self.finallies.borrow_mut().last_mut().filter(|state| state.loops == loops && loops > 0) {
+            state.exits.push((exit, None, self.builder.get_insert_block()));
+            self.builder.br(state.block);
+            return Ok(());
         }
+
+        let target = match self.loops.borrow().last() {
+            Some(blocks) if exit == Exit::Break => blocks.exit,
+            Some(blocks) => blocks.latch,
+            None => return Err(format!("{} outside of a loop", name)),
+        };
+        self.builder.br(target);
+        Ok(())
+    }
+
+    /// Raise the runtime error `code` at `loc` unless `ok` holds, continuing in a new block.
+    fn irgen_check(&self, ok: Value<'llvm>, code: ErrorCode, loc: SourceLoc) {
+        let function = self.builder.get_insert_block().get_parent();
+        let ok_block = self.module.append_basic_block(function);
+        let fail_block = self.module.append_basic_block(function);
+        self.builder.cond_br(ok, ok_block, fail_block);
+
+        self.builder.pos_at_end(fail_block);
+        self.irgen_panic(code, loc, None);
+        self.builder.pos_at_end(ok_block);
+    }
+
+    /// Emit a call of `cobra_panic`, raising the runtime error `code` at `loc` with an optional
+    /// `str` message. Ends the current block.
+    fn irgen_panic(&self, code: ErrorCode, loc: SourceLoc, message: Option<Value<'llvm>>) {
+        let (type_i32, type_i64, type_ptr) = (self.module.type_i32(), self.module.type_i64(), self.module.type_ptr());
+        let (ptr, len) = match message {
+            Some(message) => self.irgen_str_parts(message),
+            None => (type_ptr.const_null(), type_i64.const_int(0)),
+        };
+
+        let panic = self.runtime_fn(
+            "cobra_panic",
+            &mut [type_i32, type_i32, type_i32, type_ptr, type_i64],
+            self.module.type_void(),
+        );
+        let code = type_i32.const_int(code as u64);
+        let (line, col) = (type_i32.const_int(loc.line.into()), type_i32.const_int(loc.col.into()));
+        self.irgen_call(panic, &mut [code, line, col, ptr, len]);
+        self.builder.unreachable();
+    }
+
+    /// Emit a check of the interrupt flag set by the watchdog, raising an error if it is set.
+    ///
+    /// Checked at function entries and in every loop iteration, so any long running code is
+    /// interrupted.
+    fn irgen_interrupt_check(&self) {
+        let type_i8 = self.module.type_i8();
+        let flag = self.builder.load_volatile(type_i8, self.irgen_global("cobra_interrupt", type_i8));
+
+        let function = self.builder.get_insert_block().get_parent();
+        let interrupted_block = self.module.append_basic_block(function);
+        let continue_block = self.module.append_basic_block(function);
+        let interrupted = self.builder.icmpne(flag, type_i8.const_int(0));
+        self.builder.cond_br(interrupted, interrupted_block, continue_block);
+
+        self.builder.pos_at_end(interrupted_block);
+        let interrupt = self.runtime_fn("cobra_interrupted", &mut [], self.module.type_void());
+        self.irgen_call(interrupt, &mut []);
+        self.builder.unreachable();
+
+        self.builder.pos_at_end(continue_block);
+    }
+
+    /// Generate the body of a loop, ending with a jump to `latch`.
+    ///
+    /// The body starts with a check of the interrupt flag, see [`IRGen::irgen_interrupt_check`].
+    ///
+    /// `break` and `continue` in the body jump to `exit` and `latch`.
+    fn irgen_loop_body(
+        &self,
+        body: &ExprAST,
+        exit: BasicBlock<'llvm>,
+        latch: BasicBlock<'llvm>,
+        named_values: &mut HashMap<String, Value<'llvm>>,
+    ) -> IRGenResult<()> {
+        // Every iteration (including those started by `continue`) passes through here.
+        self.irgen_interrupt_check();
+        self.loops.borrow_mut().push(LoopBlocks { exit, latch });
+        let res = self.irgen_scoped(body, named_values);
+        self.loops.borrow_mut().pop();
+
+        res?;
+        self.builder.br(latch);
+        Ok(())
     }
-}
\ No newline at end of file
+
+    /// Check whether a counter `variable` is still before the exclusive bound `end`.
+    ///
+    /// The bound is approached from below or above depending on the direction of `step`.
+    fn irgen_in_range(&self, variable: Value<'llvm>, end: Value<'llvm>, step: Value<'llvm>) -> Value<'llvm> {
+        let ascending = self.builder.fcmpoge(step, self.module.type_f64().const_f64(0.0));
+        let below = self.builder.fcmpolt(variable, end);
+        let above = self.builder.fcmpogt(variable, end);
+        self.builder.select(ascending, below, above)
+    }
+
+    /// Generate a `for x in iterable:` loop.
+    ///
+    /// `range(...)` (unless a function of that name is defined) is lowered to a counted loop
+    /// without creating any range value. Arrays and strings are iterated by index, and values of
+    /// any other type through the iterator protocol (see [`IRGen::iterator_fn`]).
+    fn irgen_for_in(
+        &self,
+        variable_name: &str,
+        iterable: &ExprAST,
+        body: &ExprAST,
+        named_values: &mut HashMap<String, Value<'llvm>>,
+    ) -> IRGenResult<Value<'llvm>> {
+        if let ExprAST::Call(callee, args, _) = iterable {
+            if callee == "range" && !self.fn_proto_map.contains_key(callee) {
+                return self.irgen_range_loop(variable_name, args, body, named_values);
+            }
+        }
+
+        let iterable = self.irgen_expr(iterable, named_values)?;
+        if self.is_array(iterable) || self.is_str(iterable) {
+            return self.irgen_indexed_loop(variable_name, iterable, body, named_values);
+        }
+
+        let has_next = self.iterator_fn("has_next", iterable);
+        let next = self.iterator_fn("next", iterable);
+        let (has_next, next) = match has_next.zip(next) {
+            Some(fns) => fns,
+            None => {
+                return Err(format!(
+                    "Values of type {} cannot be iterated: define has_next(it) and next(it) for it",
+                    iterable.type_of(),
+                ));
+            }
+        };
+
+        let function = self.builder.get_insert_block().get_parent();
+        let cond_block = self.module.append_basic_block(function);
+        let body_block = self.module.append_basic_block(function);
+        let after_block = self.module.append_basic_block(function);
+
+        self.builder.br(cond_block);
+        self.builder.pos_at_end(cond_block);
+        let more = self.irgen_protocol_call(has_next, iterable)?;
+        let more = self.irgen_condition(more)
+            .map_err(|_| format!("{} must return a bool or a number", has_next.name))?;
+        self.builder.cond_br(more, body_block, after_block);
+
+        // `continue` re-checks `has_next`, so the condition block doubles as the latch.
+        self.builder.pos_at_end(body_block);
+        let element = self.irgen_protocol_call(next, iterable)?;
+        let mut scope = named_values.clone();
+        scope.insert(variable_name.to_string(), element);
+        self.irgen_loop_body(body, after_block, cond_block, &mut scope)?;
+        self.builder.pos_at_end(after_block);
+
+        Ok(self.module.type_f64().const_f64(0.0))
+    }
+
+    /// Generate a counted loop over `range(end)`, `range(start, end)` or
+    /// `range(start, end, step)`. The arguments are evaluated once, before the loop.
+    fn irgen_range_loop(
+        &self,
+        variable_name: &str,
+        args: &[ExprAST],
+        body: &ExprAST,
+        named_values: &mut HashMap<String, Value<'llvm>>,
+    ) -> IRGenResult<Value<'llvm>> {
+        if args.is_empty() || args.len() > 3 {
+            return Err(format!("range() expects 1 to 3 arguments, found {}", args.len()));
+        }
+
+        let mut bounds = Vec::new();
+        for arg in args {
+            let value = self.irgen_expr(arg, named_values)?;
+            if !value.is_f64() {
+                return Err("Arguments of range() must be numbers".to_string());
+            }
+            bounds.push(value);
+        }
+
+        let type_f64 = self.module.type_f64();
+        let (start, end, step) = match bounds[..] {
+            [end] => (type_f64.const_f64(0.0), end, type_f64.const_f64(1.0)),
+            [start, end] => (start, end, type_f64.const_f64(1.0)),
+            [start, end, step] => (start, end, step),
+            _ => unreachable!(),
+        };
+
+        let entry_block = self.builder.get_insert_block();
+        let function = entry_block.get_parent();
+        let cond_block = self.module.append_basic_block(function);
+        let body_block = self.module.append_basic_block(function);
+        let latch_block = self.module.append_basic_block(function);
+        let after_block = self.module.append_basic_block(function);
+
+        self.builder.br(cond_block);
+        self.builder.pos_at_end(cond_block);
+        let variable = self.builder.phi(type_f64, &[(start, entry_block)]);
+        let in_range = self.irgen_in_range(*variable, end, step);
+        self.builder.cond_br(in_range, body_block, after_block);
+
+        self.builder.pos_at_end(body_block);
+        let mut scope = named_values.clone();
+        scope.insert(variable_name.to_string(), *variable);
+        self.irgen_loop_body(body, after_block, latch_block, &mut scope)?;
+
+        self.builder.pos_at_end(latch_block);
+        let next = self.builder.fadd(*variable, step);
+        variable.add_incoming(next, latch_block);
+        self.builder.br(cond_block);
+        self.builder.pos_at_end(after_block);
+
+        Ok(type_f64.const_f64(0.0))
+    }
+
+    /// Generate a loop over the elements of an array or the characters of a string.
+    fn irgen_indexed_loop(
+        &self,
+        variable_name: &str,
+        iterable: Value<'llvm>,
+        body: &ExprAST,
+        named_values: &mut HashMap<String, Value<'llvm>>,
+    ) -> IRGenResult<Value<'llvm>> {
+        let type_i64 = self.module.type_i64();
+        let ptr = self.builder.extract_value(iterable, 0);
+        let len = self.builder.extract_value(iterable, 1);
+
+        let entry_block = self.builder.get_insert_block();
+        let function = entry_block.get_parent();
+        let cond_block = self.module.append_basic_block(function);
+        let body_block = self.module.append_basic_block(function);
+        let latch_block = self.module.append_basic_block(function);
+        let after_block = self.module.append_basic_block(function);
+
+        // The condition is checked before the body, so an empty array or string skips the loop.
+        self.builder.br(cond_block);
+        self.builder.pos_at_end(cond_block);
+        let idx = self.builder.phi(type_i64, &[(type_i64.const_int(0), entry_block)]);
+        let in_range = self.builder.icmpult(*idx, len);
+        self.builder.cond_br(in_range, body_block, after_block);
+
+        self.builder.pos_at_end(body_block);
+        let element = if self.is_str(iterable) {
+            // Like indexing, each character is a one byte string pointing into the original.
+            let type_i8 = self.module.type_i8();
+            let type_str = self.module.type_str();
+            let char_ptr = self.builder.gep(type_i8, ptr, *idx);
+            let element = self.builder.insert_value(type_str.undef(), char_ptr, 0);
+            self.builder.insert_value(element, type_i64.const_int(1), 1)
+        } else {
+            let type_f64 = self.module.type_f64();
+            self.builder.load(type_f64, self.builder.gep(type_f64, ptr, *idx))
+        };
+        let mut scope = named_values.clone();
+        scope.insert(variable_name.to_string(), element);
+        self.irgen_loop_body(body, after_block, latch_block, &mut scope)?;
+
+        self.builder.pos_at_end(latch_block);
+        let next_idx = self.builder.add(*idx, type_i64.const_int(1));
+        idx.add_incoming(next_idx, latch_block);
+        self.builder.br(cond_block);
+        self.builder.pos_at_end(after_block);
+
+        Ok(self.module.type_f64().const_f64(0.0))
+    }
+
+    /// Find the iterator protocol function `name` (`has_next` or `next`) taking `iterator`.
+    ///
+    /// The function may be defined in any module, and is picked by the type of its only
+    /// argument, so several iterator types can each have their own `has_next` and `next`.
+    /// Iterators that keep state are usually passed by reference (`it: &Counter`).
+    fn iterator_fn(&self, name: &str, iterator: Value<'llvm>) -> Option<&PrototypeAST> {
+        let suffix = format!(".{}", name);
+        let mut candidates = self.fn_proto_map.values().filter(|proto| {
+            (proto.name == name || proto.name.ends_with(&suffix))
+                && proto.type_params.is_empty()
+                && proto.arg_types.len() == 1
+        });
+
+        // An exact match wins over one which needs the reference to be loaded.
+        let exact = candidates.clone().find(|proto| {
+            self.irgen_type(&proto.arg_types[0]).is_ok_and(|ty| ty == iterator.type_of())
+        });
+        exact.or_else(|| {
+            candidates.find(|proto| match (&proto.arg_types[0], self.struct_def_of(iterator)) {
+                (TypeAST::Struct(name), Some((def, true))) => def.name == *name,
+                _ => false,
+            })
+        })
+    }
+
+    /// Call an iterator protocol function found by [`IRGen::iterator_fn`].
+    fn irgen_protocol_call(&self, proto: &PrototypeAST, iterator: Value<'llvm>) -> IRGenResult<Value<'llvm>> {
+        let arg = self.irgen_coerce(iterator, &proto.arg_types[0])
+            .ok_or_else(|| format!("Argument 1 of {} must be of type {}", proto.name, proto.arg_types[0]))?;
+        let function = match self.module.get_fn(&proto.name) {
+            Some(function) => function,
+            None => self.irgen_proto(proto)?,
+        };
+        Ok(self.irgen_call(function, &mut [arg]))
+    }
+
+    /// Convert `value` to an `i1` for use as a condition. Numbers are true unless they are zero.
+    fn irgen_condition(&self, value: Value<'llvm>) -> IRGenResult<Value<'llvm>> {
+        if value.is_bool() {
+            Ok(value)
+        } else if value.is_f64() {
+            Ok(self.builder.fcmpone(value, self.module.type_f64().const_f64(0.0)))
+        } else {
+            Err("Condition must be a bool or a number".to_string())
+        }
+    }
+
+    /// Call the `f64` intrinsic `name`, eg `llvm.pow.f64`.
+    fn irgen_intrinsic(&self, name: &str, args: &mut [Value<'llvm>]) -> Value<'llvm> {
+        let mut arg_types: Vec<_> = args.iter().map(|arg| arg.type_of()).collect();
+        let function = self.runtime_fn(name, &mut arg_types, self.module.type_f64());
+        self.builder.call(function, args)
+    }
+
+    /// Generate `lhs % rhs`, which like in Python has the sign of `rhs` (`-1 % 3` is `2`).
+    fn irgen_modulo(&self, lhs: Value<'llvm>, rhs: Value<'llvm>) -> Value<'llvm> {
+        let zero = self.module.type_f64().const_f64(0.0);
+        let rem = self.builder.frem(lhs, rhs);
+
+        // `frem` has the sign of `lhs`, so a non-zero remainder of the wrong sign is moved into
+        // the range of `rhs`.
+        let rem_negative = self.builder.fcmpolt(rem, zero);
+        let rhs_negative = self.builder.fcmpolt(rhs, zero);
+        let wrong_sign = self.builder.icmpne(rem_negative, rhs_negative);
+        let adjust = self.builder.and(self.builder.fcmpone(rem, zero), wrong_sign);
+        self.builder.select(adjust, self.builder.fadd(rem, rhs), rem)
+    }
+
+    /// Generate a bitwise operator on numbers, which are truncated to 64-bit integers first.
+    ///
+    /// Shift amounts are taken modulo 64, so shifting never produces poison.
+    fn irgen_bitwise(&self, op: &str, lhs: Value<'llvm>, rhs: Value<'llvm>) -> Value<'llvm> {
+        let type_i64 = self.module.type_i64();
+        let lhs = self.builder.fptosi(lhs, type_i64);
+        let rhs = self.builder.fptosi(rhs, type_i64);
+
+        let value = match op {
+            "&" => self.builder.and(lhs, rhs),
+            "|" => self.builder.or(lhs, rhs),
+            "^" => self.builder.xor(lhs, rhs),
+            _ => {
+                let amount = self.builder.and(rhs, type_i64.const_int(63));
+                if op == "<<" {
+                    self.builder.shl(lhs, amount)
+                } else {
+                    self.builder.ashr(lhs, amount)
+                }
+            }
+        };
+        self.builder.sitofp(value, self.module.type_f64())
+    }
+
+    /// Generate a short-circuiting `and` (or `or`) of two conditions.
+    fn irgen_logical(
+        &self,
+        is_and: bool,
+        lhs: &ExprAST,
+        rhs: &ExprAST,
+        named_values: &mut HashMap<String, Value<'llvm>>,
+    ) -> IRGenResult<Value<'llvm>> {
+        let lhs = self.irgen_expr(lhs, named_values)?;
+        let lhs = self.irgen_condition(lhs)?;
+        let lhs_block = self.builder.get_insert_block();
+
+        let function = lhs_block.get_parent();
+        let rhs_block = self.module.append_basic_block(function);
+        let merge_block = self.module.append_basic_block(function);
+        // The rhs is only evaluated if the lhs doesn't decide the result already.
+        if is_and {
+            self.builder.cond_br(lhs, rhs_block, merge_block);
+        } else {
+            self.builder.cond_br(lhs, merge_block, rhs_block);
+        }
+
+        self.builder.pos_at_end(rhs_block);
+        let rhs = self.irgen_scoped(rhs, named_values)?;
+        let rhs = self.irgen_condition(rhs)?;
+        self.builder.br(merge_block);
+        let rhs_block = self.builder.get_insert_block();
+
+        self.builder.pos_at_end(merge_block);
+        let type_i1 = self.module.type_i1();
+        let short_circuit = type_i1.const_int(!is_and as u64);
+        Ok(*self.builder.phi(type_i1, &[(short_circuit, lhs_block), (rhs, rhs_block)]))
+    }
+
+    /// Generate `expr` in a nested scope, eg a loop body or a branch.
+    ///
+    /// Variables rebound in the scope (by updating a field of a struct value) are restored
+    /// afterwards, as the rebound values are not available outside of the scope's blocks.
+    fn irgen_scoped(
+        &self,
+        expr: &ExprAST,
+        named_values: &mut HashMap<String, Value<'llvm>>,
+    ) -> IRGenResult<Value<'llvm>> {
+        let saved = named_values.clone();
+        let value = self.irgen_expr(expr, named_values);
+        *named_values = saved;
+        value
+    }
+
+    /// Get the instance of the generic function `name` for the arguments `args`, generating it
+    /// in the module on first use.
+    ///
+    /// Type parameters are inferred from the argument types. Each instance is a private copy of
+    /// the function named after its type arguments, eg `max[f64]`.
+    fn irgen_instance(&self, name: &str, args: &[Value<'llvm>]) -> IRGenResult<FnValue<'llvm>> {
+        let FunctionAST(proto, body) = self.generics
+            .get(name)
+            .ok_or_else(|| format!("Unknown generic function {}", name))?;
+
+        let mut bindings = Vec::new();
+        for (value, ty) in args.iter().zip(&proto.arg_types) {
+            self.infer_type_args(ty, value.type_of(), &proto.type_params, &mut bindings)?;
+        }
+
+        let instance = proto.instantiate(&bindings)?;
+        // Also ends the recursion of generic functions calling themselves.
+        if let Some(function) = self.module.get_fn(&instance.name) {
+            return Ok(function);
+        }
+
+        let function = self.irgen_proto(&instance)?;
+        function.set_private();
+
+        // Loops of the enclosing function can't be left from within another function.
+        let insert_block = self.builder.get_insert_block();
+        let loops = self.loops.take();
+        self.builder.pos_at_end(self.module.append_basic_block(function));
+
+        let mut scope = HashMap::new();
+        for (i, arg) in instance.args.iter().enumerate() {
+            scope.insert(arg.clone(), function.arg(i));
+        }
+
+        let res = match self.irgen_body(function, body, &mut scope) {
+            Ok(ret) if ret.type_of() == function.ret_type() => {
+                self.builder.ret(ret);
+                self.fpm.run(function);
+                Ok(function)
+            }
+            Ok(_) => {
+                function.delete();
+                Err(format!("Function {} must return a value of type {}", instance.name, instance.ret_type))
+            }
+            Err(err) => {
+                function.delete();
+                Err(format!("Error instantiating {}: {}", instance.name, err))
+            }
+        };
+        self.builder.pos_at_end(insert_block);
+        *self.loops.borrow_mut() = loops;
+        res
+    }
+
+    /// Bind the type parameters `params` occurring in `ty` by matching it against the type of an
+    /// argument.
+    fn infer_type_args(
+        &self,
+        ty: &TypeAST,
+        actual: Type<'llvm>,
+        params: &[String],
+        bindings: &mut Vec<(String, TypeAST)>,
+    ) -> IRGenResult<()> {
+        match ty {
+            TypeAST::Struct(name) if params.contains(name) => {
+                let actual = self.type_ast_of(actual)
+                    .ok_or_else(|| format!("Cannot infer type parameter {} from type {}", name, actual))?;
+                match bindings.iter().find(|(param, _)| param == name) {
+                    Some((_, bound)) if *bound != actual => {
+                        Err(format!("Conflicting types for type parameter {}: {} and {}", name, bound, actual))
+                    }
+                    Some(_) => Ok(()),
+                    None => {
+                        bindings.push((name.clone(), actual));
+                        Ok(())
+                    }
+                }
+            }
+            TypeAST::Fn(args, ret) => {
+                let signature = actual
+                    .struct_name()
+                    .and_then(|name| self.fn_signatures.borrow().get(name).cloned());
+                // Mismatches are reported when the arguments are checked against the instance.
+                if let Some((arg_types, ret_type)) = signature.filter(|(arg_types, _)| arg_types.len() == args.len()) {
+                    for (arg, arg_type) in args.iter().zip(arg_types) {
+                        self.infer_type_args(arg, arg_type, params, bindings)?;
+                    }
+                    self.infer_type_args(ret, ret_type, params, bindings)?;
+                }
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Get the Cobra type of values of the LLVM type `ty`.
+    fn type_ast_of(&self, ty: Type<'llvm>) -> Option<TypeAST> {
+        if ty == self.module.type_f64() {
+            return Some(TypeAST::F64);
+        }
+        if ty == self.module.type_i1() {
+            return Some(TypeAST::Bool);
+        }
+        if ty == self.module.type_str() {
+            return Some(TypeAST::Str);
+        }
+        if ty == self.module.type_array() {
+            return Some(TypeAST::Array);
+        }
+
+        let name = ty.struct_name()?;
+        if let Some(name) = name.strip_prefix(STRUCT_PREFIX).or_else(|| name.strip_prefix(ENUM_PREFIX)) {
+            return Some(TypeAST::Struct(name.to_string()));
+        }
+        if let Some(name) = name.strip_prefix(REF_PREFIX) {
+            return Some(TypeAST::Ref(name.to_string()));
+        }
+
+        let (args, ret) = self.fn_signatures.borrow().get(name).cloned()?;
+        let args = args.into_iter().map(|arg| self.type_ast_of(arg)).collect::<Option<Vec<_>>>()?;
+        Some(TypeAST::Fn(args, Box::new(self.type_ast_of(ret)?)))
+    }
+
+    /// Get the closure type `{ ptr code, ptr env }` of functions with the given signature.
+    ///
+    /// The code of a closure takes the environment pointer as an additional first argument.
+    fn closure_type(&self, args: &[Type<'llvm>], ret: Type<'llvm>) -> Type<'llvm> {
+        let args_names: Vec<_> = args.iter().map(|arg| arg.to_string()).collect();
+        let name = format!("fn({})->{}", args_names.join(","), ret);
+
+        let type_ptr = self.module.type_ptr();
+        let closure_type = self.module.type_named_struct(&name, &mut [type_ptr, type_ptr]);
+        self.fn_signatures.borrow_mut().insert(name, (args.to_vec(), ret));
+        closure_type
+    }
+
+    /// Get the type of the code of a closure with the given signature.
+    fn closure_code_type(&self, args: &[Type<'llvm>], ret: Type<'llvm>) -> Type<'llvm> {
+        let mut code_args = vec![self.module.type_ptr()];
+        code_args.extend_from_slice(args);
+        self.module.type_fn(&mut code_args, ret)
+    }
+
+    /// Call the function value `closure` held by the variable `name`.
+    fn irgen_closure_call(
+        &self,
+        name: &str,
+        closure: Value<'llvm>,
+        mut args: Vec<Value<'llvm>>,
+    ) -> IRGenResult<Value<'llvm>> {
+        let (arg_types, ret) = closure
+            .type_of()
+            .struct_name()
+            .and_then(|name| self.fn_signatures.borrow().get(name).cloned())
+            .ok_or_else(|| format!("{} is not a function", name))?;
+
+        if arg_types.len() != args.len() {
+            return Err(format!("Incorrect # of arguments passed to {}: expected {}, found {}", name, arg_types.len(), args.len()));
+        }
+        for (i, (arg, ty)) in args.iter().zip(&arg_types).enumerate() {
+            if arg.type_of() != *ty {
+                return Err(format!("Argument {} of {} must be of type {}", i + 1, name, ty));
+            }
+        }
+
+        let code = self.builder.extract_value(closure, 0);
+        args.insert(0, self.builder.extract_value(closure, 1));
+        let code_type = self.closure_code_type(&arg_types, ret);
+        Ok(self.irgen_call_indirect(code_type, code, &mut args))
+    }
+
+    /// Get the global function `proto` as a function value.
+    ///
+    /// The closure calls the function through a trampoline which drops the (null) environment.
+    fn irgen_fn_value(&self, proto: &PrototypeAST) -> IRGenResult<Value<'llvm>> {
+        let function = match self.module.get_fn(&proto.name) {
+            Some(function) => function,
+            None => self.irgen_proto(proto)?,
+        };
+        let arg_types: Vec<_> = (0..function.args()).map(|i| function.arg(i).type_of()).collect();
+        let ret = function.ret_type();
+
+        let name = format!("{}.closure", proto.name);
+        let trampoline = match self.module.get_fn(&name) {
+            Some(trampoline) => trampoline,
+            None => {
+                let trampoline = self.module.add_fn(&name, self.closure_code_type(&arg_types, ret));
+                trampoline.set_private();
+
+                let insert_block = self.builder.get_insert_block();
+                self.builder.pos_at_end(self.module.append_basic_block(trampoline));
+                let mut args: Vec<_> = (1..trampoline.args()).map(|i| trampoline.arg(i)).collect();
+                let value = self.builder.call(function, &mut args);
+                self.builder.ret(value);
+                self.builder.pos_at_end(insert_block);
+                trampoline
+            }
+        };
+
+        let closure = self.closure_type(&arg_types, ret).undef();
+        let closure = self.builder.insert_value(closure, *trampoline, 0);
+        Ok(self.builder.insert_value(closure, self.module.type_ptr().const_null(), 1))
+    }
+
+    /// Generate a `lambda` expression.
+    ///
+    /// The variables of the enclosing scope referenced by the body are copied into a heap
+    /// allocated environment when the lambda is evaluated.
+    fn irgen_lambda(
+        &self,
+        params: &[String],
+        body: &ExprAST,
+        named_values: &HashMap<String, Value<'llvm>>,
+    ) -> IRGenResult<Value<'llvm>> {
+        let mut names = Vec::new();
+        collect_variables(body, &mut names);
+        let captures: Vec<_> = names
+            .into_iter()
+            .filter(|name| !params.contains(name))
+            .filter_map(|name| named_values.get(&name).map(|value| (name, *value)))
+            .collect();
+
+        // The return type is only known once the body is generated, so the lambda is generated
+        // again if it doesn't return a number.
+        let function = match self.irgen_lambda_fn(params, body, &captures, self.module.type_f64())? {
+            Either::Left(function) => function,
+            Either::Right(ret) => match self.irgen_lambda_fn(params, body, &captures, ret)? {
+                Either::Left(function) => function,
+                Either::Right(_) => unreachable!("Lambda body type must not depend on the return type"),
+            },
+        };
+
+        let mut capture_types: Vec<_> = captures.iter().map(|(_, value)| value.type_of()).collect();
+        let env_type = self.module.type_named_struct(&format!("{}.env", function.get_name()), &mut capture_types);
+        let env = if captures.is_empty() {
+            self.module.type_ptr().const_null()
+        } else {
+            let mut env_value = env_type.undef();
+            for (i, (_, value)) in captures.iter().enumerate() {
+                env_value = self.builder.insert_value(env_value, *value, i as u32);
+            }
+
+            let type_i64 = self.module.type_i64();
+            let alloc = self.runtime_fn("cobra_alloc", &mut [type_i64, type_i64], self.module.type_ptr());
+            let env = self.builder.call(alloc, &mut [type_i64.const_int(1), env_type.size_of()]);
+            self.builder.store(env_value, env);
+            env
+        };
+
+        let param_types = vec![self.module.type_f64(); params.len()];
+        let closure = self.closure_type(&param_types, function.ret_type()).undef();
+        let closure = self.builder.insert_value(closure, *function, 0);
+        Ok(self.builder.insert_value(closure, env, 1))
+    }
+
+    /// Generate the code of a lambda returning `ret`, or get the actual return type of the body
+    /// if it differs.
+    fn irgen_lambda_fn(
+        &self,
+        params: &[String],
+        body: &ExprAST,
+        captures: &[(String, Value<'llvm>)],
+        ret: Type<'llvm>,
+    ) -> IRGenResult<Either<FnValue<'llvm>, Type<'llvm>>> {
+        let param_types = vec![self.module.type_f64(); params.len()];
+        // Lambdas are private, so LLVM can give each of them a unique name within the module.
+        let function = self.module.add_fn("lambda", self.closure_code_type(&param_types, ret));
+        function.set_private();
+
+        // Loops of the enclosing function can't be left from within another function.
+        let insert_block = self.builder.get_insert_block();
+        let loops = self.loops.take();
+        self.builder.pos_at_end(self.module.append_basic_block(function));
+
+        let mut scope = HashMap::new();
+        if !captures.is_empty() {
+            let mut capture_types: Vec<_> = captures.iter().map(|(_, value)| value.type_of()).collect();
+            let env_type = self.module.type_named_struct(&format!("{}.env", function.get_name()), &mut capture_types);
+            let env = self.builder.load(env_type, function.arg(0));
+            for (i, (name, _)) in captures.iter().enumerate() {
+                scope.insert(name.clone(), self.builder.extract_value(env, i as u32));
+            }
+        }
+        for (i, param) in params.iter().enumerate() {
+            let arg = function.arg(i + 1);
+            arg.set_name(param);
+            scope.insert(param.clone(), arg);
+        }
+
+        let res = match self.irgen_body(function, body, &mut scope) {
+            Ok(value) if value.type_of() == ret => {
+                self.builder.ret(value);
+                self.fpm.run(function);
+                Ok(Either::Left(function))
+            }
+            Ok(value) => {
+                function.delete();
+                Ok(Either::Right(value.type_of()))
+            }
+            Err(err) => {
+                function.delete();
+                Err(err)
+            }
+        };
+        self.builder.pos_at_end(insert_block);
+        *self.loops.borrow_mut() = loops;
+        res
+    }
+
+    /// Build a value of the struct `name` from the constructor arguments `args`.
+    fn irgen_struct(
+        &self,
+        name: &str,
+        args: &[ExprAST],
+        named_values: &mut HashMap<String, Value<'llvm>>,
+    ) -> IRGenResult<Value<'llvm>> {
+        let def = self.struct_def(name)?;
+        if def.fields.len() != args.len() {
+            return Err(format!("Incorrect # of fields passed to {}: expected {}, found {}", name, def.fields.len(), args.len()));
+        }
+
+        let mut value = self.irgen_type(&TypeAST::Struct(name.to_string()))?.undef();
+        for (i, ((field, ty), arg)) in def.fields.iter().zip(args).enumerate() {
+            let arg = self.irgen_expr(arg, named_values)?;
+            let arg = self.irgen_coerce(arg, ty)
+                .ok_or_else(|| format!("Field {} of {} must be of type {:?}", field, name, ty))?;
+            value = self.builder.insert_value(value, arg, i as u32);
+        }
+        Ok(value)
+    }
+
+    /// Build a value of the enum variant `name` from the constructor arguments `args`.
+    ///
+    /// The fields of the variant are stored on the heap, the enum value only holds the tag and
+    /// the pointer to the fields.
+    fn irgen_variant(
+        &self,
+        name: &str,
+        args: &[ExprAST],
+        named_values: &mut HashMap<String, Value<'llvm>>,
+    ) -> IRGenResult<Value<'llvm>> {
+        let (def, tag) = self.type_defs.variant(name).ok_or_else(|| format!("Unknown variant {}", name))?;
+        let variant = &def.variants[tag];
+        if variant.fields.len() != args.len() {
+            return Err(format!("Incorrect # of fields passed to {}: expected {}, found {}", name, variant.fields.len(), args.len()));
+        }
+
+        let type_ptr = self.module.type_ptr();
+        let ptr = if variant.fields.is_empty() {
+            type_ptr.const_null()
+        } else {
+            let payload_type = self.variant_payload_type(def, tag)?;
+            let mut payload = payload_type.undef();
+            for (i, ((field, ty), arg)) in variant.fields.iter().zip(args).enumerate() {
+                let arg = self.irgen_expr(arg, named_values)?;
+                let arg = self.irgen_coerce(arg, ty)
+                    .ok_or_else(|| format!("Field {} of {} must be of type {:?}", field, name, ty))?;
+                payload = self.builder.insert_value(payload, arg, i as u32);
+            }
+
+            let type_i64 = self.module.type_i64();
+            let alloc = self.runtime_fn("cobra_alloc", &mut [type_i64, type_i64], type_ptr);
+            let ptr = self.builder.call(alloc, &mut [type_i64.const_int(1), payload_type.size_of()]);
+            self.builder.store(payload, ptr);
+            ptr
+        };
+
+        let value = self.irgen_type(&TypeAST::Struct(def.name.clone()))?.undef();
+        let value = self.builder.insert_value(value, self.module.type_i64().const_int(tag as u64), 0);
+        Ok(self.builder.insert_value(value, ptr, 1))
+    }
+
+    /// Get the type of the heap allocated fields of the variant at `tag` of the enum `def`.
+    fn variant_payload_type(&self, def: &EnumAST, tag: usize) -> IRGenResult<Type<'llvm>> {
+        let variant = &def.variants[tag];
+        let mut fields = variant.fields
+            .iter()
+            .map(|(_, ty)| self.irgen_type(ty))
+            .collect::<IRGenResult<Vec<_>>>()?;
+        let name = format!("{}{}.{}", ENUM_PREFIX, def.name, variant.name);
+        Ok(self.module.type_named_struct(&name, &mut fields))
+    }
+
+    /// Generate a `match` expression.
+    ///
+    /// Matches on enums `switch` on the tag, then try the arms applicable to each variant in
+    /// order. Matches on numbers try all arms in order.
+    fn irgen_match(
+        &self,
+        value: &ExprAST,
+        arms: &[MatchArm],
+        named_values: &mut HashMap<String, Value<'llvm>>,
+    ) -> IRGenResult<Value<'llvm>> {
+        let def = typeck::check_match(arms, self.type_defs)?;
+        let value = self.irgen_expr(value, named_values)?;
+
+        let function = self.builder.get_insert_block().get_parent();
+        let merge_block = self.module.append_basic_block(function);
+        let mut state = MatchState {
+            value,
+            bodies: vec![None; arms.len()],
+            incoming: Vec::new(),
+            merge_block,
+        };
+
+        match def {
+            Some(def) => {
+                if value.type_of() != self.irgen_type(&TypeAST::Struct(def.name.clone()))? {
+                    return Err(format!("Cannot match a value which is not a {} against its variants", def.name));
+                }
+
+                let tag = self.builder.extract_value(value, 0);
+                let payload = self.builder.extract_value(value, 1);
+                let match_block = self.builder.get_insert_block();
+
+                // The type checker guarantees all variants are covered, so the tag never takes
+                // any other value.
+                let default_block = self.module.append_basic_block(function);
+                self.builder.pos_at_end(default_block);
+                self.builder.unreachable();
+
+                let mut cases = Vec::new();
+                for (idx, variant) in def.variants.iter().enumerate() {
+                    let block = self.module.append_basic_block(function);
+                    cases.push((self.module.type_i64().const_int(idx as u64), block));
+                    self.builder.pos_at_end(block);
+
+                    let payload_type = self.variant_payload_type(def, idx)?;
+                    let fields: Vec<_> = (0..variant.fields.len())
+                        .map(|i| {
+                            let ptr = self.builder.struct_gep(payload_type, payload, i as u32);
+                            self.builder.load(payload_type.field_type(i as u32), ptr)
+                        })
+                        .collect();
+                    self.irgen_match_arms(arms, Some((&variant.name, &fields)), &mut state, named_values)?;
+                }
+
+                self.builder.pos_at_end(match_block);
+                self.builder.switch(tag, default_block, &cases);
+            }
+            None => {
+                if !value.is_f64() {
+                    return Err("Only numbers and enums can be matched".to_string());
+                }
+                self.irgen_match_arms(arms, None, &mut state, named_values)?;
+            }
+        }
+
+        let ty = match state.incoming.first() {
+            Some((value, _)) => value.type_of(),
+            None => return Err("Match expression has no reachable arms".to_string()),
+        };
+        if state.incoming.iter().any(|(value, _)| value.type_of() != ty) {
+            return Err("All arms of a match expression must have the same type".to_string());
+        }
+
+        self.builder.pos_at_end(merge_block);
+        Ok(*self.builder.phi(ty, &state.incoming))
+    }
+
+    /// Generate the chain of tests of the `arms` applicable to `variant` (its name and its loaded
+    /// fields), or of all arms when matching a number.
+    fn irgen_match_arms(
+        &self,
+        arms: &[MatchArm],
+        variant: Option<(&str, &[Value<'llvm>])>,
+        state: &mut MatchState<'llvm>,
+        named_values: &HashMap<String, Value<'llvm>>,
+    ) -> IRGenResult<()> {
+        let function = self.builder.get_insert_block().get_parent();
+
+        for (i, arm) in arms.iter().enumerate() {
+            let mut scope = named_values.clone();
+            let mut tests = Vec::new();
+
+            match (&arm.pattern, variant) {
+                (PatternAST::Wildcard, _) => {}
+                (PatternAST::Binding(name), Some((variant, _))) if self.type_defs.variants.contains_key(name) => {
+                    if name != variant {
+                        continue;
+                    }
+                }
+                (PatternAST::Binding(name), _) => {
+                    scope.insert(name.clone(), state.value);
+                }
+                (PatternAST::Literal(literal), None) => {
+                    tests.push(self.builder.fcmpoeq(state.value, self.module.type_f64().const_f64(*literal)));
+                }
+                (PatternAST::Variant(name, patterns), Some((variant, fields))) => {
+                    if name != variant {
+                        continue;
+                    }
+                    for (pattern, field) in patterns.iter().zip(fields) {
+                        match pattern {
+                            PatternAST::Wildcard => {}
+                            // Nested variant patterns are rejected by the type checker.
+                            PatternAST::Binding(nested) | PatternAST::Variant(nested, _)
+                                if self.type_defs.variants.contains_key(nested) =>
+                            {
+                                return Err(format!("Nested variant pattern {} is not supported", nested));
+                            }
+                            PatternAST::Binding(name) => {
+                                scope.insert(name.clone(), *field);
+                            }
+                            PatternAST::Literal(literal) if field.is_f64() => {
+                                tests.push(self.builder.fcmpoeq(*field, self.module.type_f64().const_f64(*literal)));
+                            }
+                            PatternAST::Literal(_) => {
+                                return Err(format!("Literal pattern in {} must match a number field", name));
+                            }
+                            PatternAST::Variant(nested, _) => {
+                                return Err(format!("Unknown variant {} in pattern", nested));
+                            }
+                        }
+                    }
+                }
+                // Rejected by the type checker.
+                _ => unreachable!("Pattern {:?} does not match the matched value", arm.pattern),
+            }
+
+            // Literal tests are checked before the guard, the guard can rely on them.
+            let next_block = match tests.is_empty() && arm.guard.is_none() {
+                true => None,
+                false => Some(self.module.append_basic_block(function)),
+            };
+            if let Some(next_block) = next_block {
+                for test in tests {
+                    let ok_block = self.module.append_basic_block(function);
+                    self.builder.cond_br(test, ok_block, next_block);
+                    self.builder.pos_at_end(ok_block);
+                }
+                if let Some(guard) = &arm.guard {
+                    let guard = self.irgen_expr(guard, &mut scope)?;
+                    let guard = self.irgen_condition(guard)?;
+                    let ok_block = self.module.append_basic_block(function);
+                    self.builder.cond_br(guard, ok_block, next_block);
+                    self.builder.pos_at_end(ok_block);
+                }
+            }
+
+            // Each body is generated once. Bodies of catch-all arms only refer to the matched
+            // value, so they can be shared by the chains of all variants.
+            match state.bodies[i] {
+                Some(body_block) => self.builder.br(body_block),
+                None => {
+                    let body_block = self.module.append_basic_block(function);
+                    state.bodies[i] = Some(body_block);
+                    self.builder.br(body_block);
+                    self.builder.pos_at_end(body_block);
+
+                    let body = self.irgen_expr(&arm.body, &mut scope)?;
+                    self.builder.br(state.merge_block);
+                    state.incoming.push((body, self.builder.get_insert_block()));
+                }
+            }
+
+            match next_block {
+                Some(next_block) => self.builder.pos_at_end(next_block),
+                // The arm always matches, the remaining arms are unreachable for this chain.
+                None => return Ok(()),
+            }
+        }
+
+        // Falling through all arms is ruled out by the exhaustiveness check.
+        self.builder.unreachable();
+        Ok(())
+    }
+
+    /// Get a pointer to the field at `idx` of the struct referenced by `reference`.
+    fn irgen_field_ptr(&self, reference: Value<'llvm>, def: &StructAST, idx: usize) -> IRGenResult<Value<'llvm>> {
+        let struct_type = self.irgen_type(&TypeAST::Struct(def.name.clone()))?;
+        let ptr = self.builder.extract_value(reference, 0);
+        Ok(self.builder.struct_gep(struct_type, ptr, idx as u32))
+    }
+
+    /// Convert `value` to the type `ty` where that is allowed implicitly, returning `None` if the
+    /// types don't match.
+    ///
+    /// A `&Name` reference is accepted where a `Name` value is expected by copying the struct.
+    fn irgen_coerce(&self, value: Value<'llvm>, ty: &TypeAST) -> Option<Value<'llvm>> {
+        let expected = self.irgen_type(ty).ok()?;
+        if value.type_of() == expected {
+            return Some(value);
+        }
+
+        match (ty, self.struct_def_of(value)) {
+            (TypeAST::Struct(name), Some((def, true))) if def.name == *name => {
+                let ptr = self.builder.extract_value(value, 0);
+                Some(self.builder.load(expected, ptr))
+            }
+            _ => None,
+        }
+    }
+
+    fn struct_def(&self, name: &str) -> IRGenResult<&'a StructAST> {
+        self.type_defs.structs.get(name).ok_or_else(|| format!("Unknown type {}", name))
+    }
+
+    /// Get the definition of the struct `value` is an instance of, and whether `value` is a
+    /// reference to it.
+    fn struct_def_of(&self, value: Value<'llvm>) -> Option<(&'a StructAST, bool)> {
+        let name = value.type_of().struct_name()?;
+        let (name, is_ref) = match name.strip_prefix(REF_PREFIX) {
+            Some(name) => (name, true),
+            None => (name.strip_prefix(STRUCT_PREFIX)?, false),
+        };
+        self.type_defs.structs.get(name).map(|def| (def, is_ref))
+    }
+
+    fn field_index(def: &StructAST, field: &str) -> IRGenResult<usize> {
+        def.fields
+            .iter()
+            .position(|(name, _)| name == field)
+            .ok_or_else(|| format!("Struct {} has no field {}", def.name, field))
+    }
+
+    /// Get the `len` of a string or array as `f64`.
+    fn irgen_len(
+        &self,
+        value: &ExprAST,
+        named_values: &mut HashMap<String, Value<'llvm>>,
+    ) -> IRGenResult<Value<'llvm>> {
+        let value = self.irgen_expr(value, named_values)?;
+        if !self.is_str(value) && !self.is_array(value) {
+            return Err("len() expects a string or an array".to_string());
+        }
+
+        // Strings and arrays both store their length in the second field.
+        let len = self.builder.extract_value(value, 1);
+        Ok(self.builder.uitofp(len, self.module.type_f64()))
+    }
+
+    /// Allocate a zero initialized array of `len` (`i64`) elements on the heap.
+    fn irgen_array_alloc(&self, len: Value<'llvm>) -> Value<'llvm> {
+        let type_i64 = self.module.type_i64();
+        let alloc = self.runtime_fn("cobra_alloc", &mut [type_i64, type_i64], self.module.type_ptr());
+        let ptr = self.builder.call(alloc, &mut [len, type_i64.const_int(8)]);
+
+        let array = self.module.type_array().undef();
+        let array = self.builder.insert_value(array, ptr, 0);
+        self.builder.insert_value(array, len, 1)
+    }
+
+    /// Get a pointer to the element at `index` (`f64`) of `array`.
+    ///
+    /// Emits a bounds check which aborts with the source location `loc` if `index` is out of
+    /// bounds.
+    fn irgen_element_ptr(&self, array: Value<'llvm>, index: Value<'llvm>, loc: SourceLoc) -> Value<'llvm> {
+        let (type_i32, type_i64) = (self.module.type_i32(), self.module.type_i64());
+        let ptr = self.builder.extract_value(array, 0);
+        let len = self.builder.extract_value(array, 1);
+        let idx = self.builder.fptosi(index, type_i64);
+
+        let function = self.builder.get_insert_block().get_parent();
+        let fail_block = self.module.append_basic_block(function);
+        let ok_block = self.module.append_basic_block(function);
+
+        // Negative indices wrap around to large unsigned values and fail the check as well.
+        let in_bounds = self.builder.icmpult(idx, len);
+        self.builder.cond_br(in_bounds, ok_block, fail_block);
+
+        self.builder.pos_at_end(fail_block);
+        let fail = self.runtime_fn(
+            "cobra_index_out_of_bounds",
+            &mut [type_i64, type_i64, type_i32, type_i32],
+            self.module.type_void(),
+        );
+        self.irgen_call(fail, &mut [idx, len, type_i32.const_int(loc.line.into()), type_i32.const_int(loc.col.into())]);
+        self.builder.unreachable();
+
+        self.builder.pos_at_end(ok_block);
+        self.builder.gep(self.module.type_f64(), ptr, idx)
+    }
+
+    fn is_array(&self, value: Value<'llvm>) -> bool {
+        value.type_of() == self.module.type_array()
+    }
+
+    fn irgen_str_concat(&self, lhs: Value<'llvm>, rhs: Value<'llvm>) -> Value<'llvm> {
+        let (lhs_ptr, lhs_len) = self.irgen_str_parts(lhs);
+        let (rhs_ptr, rhs_len) = self.irgen_str_parts(rhs);
+        let (type_ptr, type_i64) = (self.module.type_ptr(), self.module.type_i64());
+
+        let function = self.runtime_fn(
+            "cobra_str_concat",
+            &mut [type_ptr, type_i64, type_ptr, type_i64],
+            self.module.type_str(),
+        );
+        self.builder.call(function, &mut [lhs_ptr, lhs_len, rhs_ptr, rhs_len])
+    }
+
+    fn irgen_str_copy(&self, value: Value<'llvm>) -> Value<'llvm> {
+        let (ptr, len) = self.irgen_str_parts(value);
+        let (type_ptr, type_i64) = (self.module.type_ptr(), self.module.type_i64());
+
+        let function = self.runtime_fn("cobra_str_copy", &mut [type_ptr, type_i64], self.module.type_str());
+        self.builder.call(function, &mut [ptr, len])
+    }
+
+    /// Split a string into its pointer and length, the form runtime functions expect strings in.
+    fn irgen_str_parts(&self, value: Value<'llvm>) -> (Value<'llvm>, Value<'llvm>) {
+        (self.builder.extract_value(value, 0), self.builder.extract_value(value, 1))
+    }
+
+    fn is_str(&self, value: Value<'llvm>) -> bool {
+        value.type_of() == self.module.type_str()
+    }
+
+    /// Get the runtime function `name`, declaring it in the module on first use.
+    fn runtime_fn(&self, name: &str, args: &mut [Type<'llvm>], ret: Type<'llvm>) -> FnValue<'llvm> {
+        match self.module.get_fn(name) {
+            Some(function) => function,
+            None => self.module.add_fn(name, self.module.type_fn(args, ret)),
+        }
+    }
+
+    fn irgen_type(&self, ty: &TypeAST) -> IRGenResult<Type<'llvm>> {
+        Ok(match ty {
+            TypeAST::F64 => self.module.type_f64(),
+            TypeAST::Bool => self.module.type_i1(),
+            TypeAST::Str => self.module.type_str(),
+            TypeAST::Array => self.module.type_array(),
+            TypeAST::Struct(name) if self.type_defs.enums.contains_key(name) => {
+                let mut fields = [self.module.type_i64(), self.module.type_ptr()];
+                self.module.type_named_struct(&format!("{}{}", ENUM_PREFIX, name), &mut fields)
+            }
+            TypeAST::Struct(name) => {
+                let def = self.struct_def(name)?;
+                let mut fields = def.fields
+                    .iter()
+                    .map(|(_, ty)| self.irgen_type(ty))
+                    .collect::<IRGenResult<Vec<_>>>()?;
+                self.module.type_named_struct(&format!("{}{}", STRUCT_PREFIX, name), &mut fields)
+            }
+            TypeAST::Ref(name) => {
+                self.struct_def(name)?;
+                self.module.type_named_struct(&format!("{}{}", REF_PREFIX, name), &mut [self.module.type_ptr()])
+            }
+            TypeAST::Fn(args, ret) => {
+                let args = args.iter().map(|ty| self.irgen_type(ty)).collect::<IRGenResult<Vec<_>>>()?;
+                self.closure_type(&args, self.irgen_type(ret)?)
+            }
+        })
+    }
+
+    fn irgen_proto(&self, proto: &PrototypeAST) -> IRGenResult<FnValue<'llvm>> {
+        let mut arg_types = proto.arg_types
+            .iter()
+            .map(|ty| self.irgen_type(ty))
+            .collect::<IRGenResult<Vec<_>>>()?;
+        let ret_type = self.irgen_type(&proto.ret_type)?;
+
+        let function_type = self.module.type_fn(&mut arg_types, ret_type);
+        let function = self.module.add_fn(&proto.name, function_type);
+
+        for i in 0..function.args() {
+            function.arg(i).set_name(&proto.args[i]);
+        }
+        Ok(function)
+    }
+
+    fn irgen_function(&mut self, FunctionAST(proto, body): &FunctionAST) -> IRGenResult<FnValue<'llvm>> {
+        self.fn_proto_map.insert(proto.name.clone(), proto.clone());
+        // Calls earlier in the same module may have declared the function already.
+        let function = match self.module.get_fn(&proto.name) {
+            Some(function) => function,
+            None => self.irgen_proto(proto)?,
+        };
+
+        if function.basic_blocks() != 0 {
+            return Err(format!("Redefinition of function {}", proto.name));
+        }
+        self.builder.pos_at_end(self.module.append_basic_block(function));
+
+        let mut named_values = HashMap::new();
+        for (i, arg) in proto.args.iter().enumerate() {
+            named_values.insert(arg.clone(), function.arg(i));
+        }
+
+        match self.irgen_body(function, body, &mut named_values) {
+            Ok(ret) if ret.type_of() == function.ret_type() => {
+                self.builder.ret(ret);
+                self.fpm.run(function);
+                Ok(function)
+            }
+            Ok(_) => {
+                function.delete();
+                Err(format!("Function {} must return a value of type {}", proto.name, proto.ret_type))
+            }
+            Err(err) => {
+                function.delete();
+                Err(format!("Error generating code for function {}: {}", proto.name, err))
+            }
+        }
+    }
+}
+
+/// Evaluate the arithmetic operator `op` on two constants the way the generated code does.
+///
+/// Returns `None` for operators which are left to the builder.
+pub(crate) fn fold_binary_op(op: &str, lhs: f64, rhs: f64) -> Option<f64> {
+    let (a, b) = (lhs as i64, rhs as i64);
+    Some(match op {
+        "//" => (lhs / rhs).floor(),
+        "%" => {
+            let rem = lhs % rhs;
+            if rem != 0.0 && (rem < 0.0) != (rhs < 0.0) { rem + rhs } else { rem }
+        }
+        "**" => lhs.powf(rhs),
+        "&" => (a & b) as f64,
+        "|" => (a | b) as f64,
+        "^" => (a ^ b) as f64,
+        "<<" => a.wrapping_shl((b & 63) as u32) as f64,
+        ">>" => a.wrapping_shr((b & 63) as u32) as f64,
+        _ => return None,
+    })
+}
+
+/// Evaluate the unary operator `op` on a constant.
+pub(crate) fn fold_unary_op(op: &str, operand: f64) -> Option<f64> {
+    match op {
+        "-" => Some(-operand),
+        "~" => Some(!(operand as i64) as f64),
+        _ => None,
+    }
+}
+
+/// Collect the names of all variables (and called local functions) referenced in `expr`.
+pub(crate) fn collect_variables(expr: &ExprAST, names: &mut Vec<String>) {
+    let add = |names: &mut Vec<String>, name: &String| {
+        if !names.contains(name) {
+            names.push(name.clone());
+        }
+    };
+
+    match expr {
+        ExprAST::Number(_) | ExprAST::Str(_) | ExprAST::Bool(_) => {}
+        ExprAST::Variable(name) => add(names, name),
+        ExprAST::UnaryOp(_, operand) => collect_variables(operand, names),
+        ExprAST::Call(callee, args, _) => {
+            add(names, callee);
+            for arg in args {
+                collect_variables(arg, names);
+            }
+        }
+        ExprAST::BinaryOp(_, lhs, rhs, _) | ExprAST::Index(lhs, rhs, _) | ExprAST::Assign(lhs, rhs) => {
+            collect_variables(lhs, names);
+            collect_variables(rhs, names);
+        }
+        ExprAST::Array(elements) | ExprAST::New(_, elements) => {
+            for element in elements {
+                collect_variables(element, names);
+            }
+        }
+        ExprAST::Field(value, _) => collect_variables(value, names),
+        ExprAST::If { condition, then, else_ } => {
+            collect_variables(condition, names);
+            collect_variables(then, names);
+            collect_variables(else_, names);
+        }
+        ExprAST::For { start, end, step, body, .. } => {
+            collect_variables(start, names);
+            collect_variables(end, names);
+            if let Some(step) = step {
+                collect_variables(step, names);
+            }
+            collect_variables(body, names);
+        }
+        ExprAST::While { condition, body } => {
+            collect_variables(condition, names);
+            collect_variables(body, names);
+        }
+        ExprAST::Break | ExprAST::Continue => {}
+        ExprAST::ForIn { iterable, body, .. } => {
+            collect_variables(iterable, names);
+            collect_variables(body, names);
+        }
+        ExprAST::Lambda { body, .. } => collect_variables(body, names),
+        ExprAST::Match { value, arms } => {
+            collect_variables(value, names);
+            for arm in arms {
+                if let Some(guard) = &arm.guard {
+                    collect_variables(guard, names);
+                }
+                collect_variables(&arm.body, names);
+            }
+        }
+        ExprAST::Block(statements) => {
+            for statement in statements {
+                collect_variables(statement, names);
+            }
+        }
+        ExprAST::Let(_, value) | ExprAST::Return(value) | ExprAST::Raise(value, _) => collect_variables(value, names),
+        ExprAST::Try { body, handler, finally } => {
+            collect_variables(body, names);
+            if let Some((_, handler)) = handler {
+                collect_variables(handler, names);
+            }
+            if let Some(finally) = finally {
+                collect_variables(finally, names);
+            }
+        }
+        ExprAST::Assert { condition, message, .. } => {
+            collect_variables(condition, names);
+            if let Some(message) = message {
+                collect_variables(message, names);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+
+    fn define_struct(type_defs: &mut TypeDefs, source: &str) -> Result<(), String> {
+        let mut parser = Parser::new(Lexer::new(source.chars()));
+        parser.get_next_token();
+        type_defs.define_struct(parser.parse_struct().unwrap())
+    }
+
+    #[test]
+    fn struct_definitions() {
+        let mut type_defs = TypeDefs::default();
+        define_struct(&mut type_defs, "struct Point: x: f64, y: f64").unwrap();
+        define_struct(&mut type_defs, "struct Node: pos: Point, next: &Node").unwrap();
+        assert_eq!(type_defs.structs["Node"].fields.len(), 2);
+
+        let error = |source| define_struct(&mut TypeDefs::default(), source).unwrap_err();
+        assert_eq!(error("struct str: x: f64"), "Cannot redefine builtin type str");
+        assert_eq!(error("struct P: x: f64, x: f64"), "Duplicate field x in P");
+        assert_eq!(error("struct Node: next: Node"), "Struct Node cannot contain itself by value, use &Node");
+        assert_eq!(error("struct Line: a: Point"), "Unknown type Point of field a");
+        assert_eq!(error("struct Line: a: &Point"), "Unknown type Point of field a");
+        assert_eq!(
+            define_struct(&mut type_defs, "struct Point: x: f64"),
+            Err("Redefinition of Point".to_string())
+        );
+    }
+}
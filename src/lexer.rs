@@ -1,15 +1,43 @@
+use std::fmt;
 use std::iter::Peekable;
 
+/// Position of a token in the source (1-based line and column).
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub struct SourceLoc {
+    pub line: u32,
+    pub col: u32,
+}
+
+impl fmt::Display for SourceLoc {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.col)
+    }
+}
+
 #[derive(Debug, PartialEq)]
 pub enum Token {
     Def,
     Extern,
+    Import,
+    From,
+    Struct,
+    New,
+    Enum,
+    Match,
+    Case,
+    Lambda,
+    And,
+    Or,
+    Not,
+    True,
+    False,
     Delimiter,
     OpeningParenthesis,
     ClosingParenthesis,
     Comma,
     Identifier(String),
     Number(f64),
+    Str(String),
     Operator(String),
     Char(char),
     If,
@@ -17,13 +45,50 @@ pub enum Token {
     Else,
     For,
     In,
+    While,
+    Break,
+    Continue,
+    Let,
+    Return,
+    Const,
+    Global,
+    Try,
+    Except,
+    Finally,
+    Raise,
+    Assert,
+    Test,
+    /// A malformed literal, eg an unterminated string, with the error message.
+    Error(String),
+    /// End of the input.
+    Eof,
 }
 
+/// Reserved words, lexed as their own tokens instead of identifiers.
+pub const KEYWORDS: [&str; 33] = [
+    "def", "extern", "import", "from", "struct", "new", "enum", "match", "case", "lambda", "and",
+    "or", "not", "true", "false", "if", "then", "else", "for", "in", "while", "break", "continue",
+    "let", "return", "const", "global", "try", "except", "finally", "raise", "assert", "test",
+];
+
 pub struct Lexer<I>
     where I: Iterator<Item=char>
 {
     input: Peekable<I>,
     last_char: Option<char>,
+    /// Location of `last_char`.
+    loc: SourceLoc,
+    /// Location of the first character of the last token returned by `gettok`.
+    token_loc: SourceLoc,
+    /// Location just past the last character of the last token returned by `gettok`.
+    token_end: SourceLoc,
+    /// Byte offset of `last_char` in the input.
+    offset: usize,
+    /// Byte offset of the last token returned by `gettok`.
+    token_offset: usize,
+    /// Source text read since each call of `start_recording` without matching
+    /// `stop_recording`, innermost last.
+    recordings: Vec<(usize, String)>,
 }
 
 impl<I> Lexer<I>
@@ -33,11 +98,60 @@ impl<I> Lexer<I>
         let last_char = input.next();
         Lexer {
             input: input.peekable(),
-            last_char: last_char,
+            last_char,
+            loc: SourceLoc { line: 1, col: 1 },
+            token_loc: SourceLoc { line: 1, col: 1 },
+            token_end: SourceLoc { line: 1, col: 1 },
+            offset: 0,
+            token_offset: 0,
+            recordings: Vec::new(),
         }
     }
 
+    /// Get the location of the last token returned by [`gettok`][Lexer::gettok].
+    pub fn token_loc(&self) -> SourceLoc {
+        self.token_loc
+    }
+
+    /// Get the location just past the end of the last token returned by
+    /// [`gettok`][Lexer::gettok].
+    pub fn token_end(&self) -> SourceLoc {
+        self.token_end
+    }
+
+    /// Get the byte offset of the last token returned by [`gettok`][Lexer::gettok] in the input.
+    pub fn token_offset(&self) -> usize {
+        self.token_offset
+    }
+
+    /// Start recording the source text following the last token returned by
+    /// [`gettok`][Lexer::gettok].
+    pub fn start_recording(&mut self) {
+        self.recordings.push((self.offset, String::new()));
+    }
+
+    /// Stop the innermost recording and return the source text recorded up to the byte offset
+    /// `end` (eg the [offset][Lexer::token_offset] of the token following the recorded ones).
+    pub fn stop_recording(&mut self, end: usize) -> String {
+        let (start, mut text) = self.recordings.pop().expect("Lexer: Not recording");
+        text.truncate(end.saturating_sub(start));
+        text
+    }
+
     fn step(&mut self) -> Option<char> {
+        if let Some(c) = self.last_char {
+            for (_, text) in &mut self.recordings {
+                text.push(c);
+            }
+        }
+        self.offset += self.last_char.map_or(0, char::len_utf8);
+        if self.last_char == Some('\n') {
+            self.loc.line += 1;
+            self.loc.col = 1;
+        } else {
+            self.loc.col += 1;
+        }
+
         self.last_char = self.input.next();
         self.last_char
     }
@@ -46,6 +160,9 @@ impl<I> Lexer<I>
         while matches!(self.last_char, Some(' ') | Some('\t') | Some('\n')) {
             self.step();
         }
+        self.token_loc = self.loc;
+        self.token_end = self.loc;
+        self.token_offset = self.offset;
 
         let last_char = if let Some(c) = self.last_char {
             c
@@ -53,12 +170,13 @@ impl<I> Lexer<I>
             return Token::Eof;
         };
 
-        if last_char.is_ascii_alphabetic() {
+        let token = if last_char.is_ascii_alphabetic() || last_char == '_' {
             let mut identifier = String::new();
             identifier.push(last_char);
-            
+
+            // Qualified names (eg `math.sqrt`) and field accesses are joined by the parser.
             while let Some(&c) = self.input.peek() {
-                if c.is_ascii_alphanumeric() {
+                if c.is_ascii_alphanumeric() || c == '_' {
                     identifier.push(c);
                     self.step();
                 } else {
@@ -69,15 +187,43 @@ impl<I> Lexer<I>
             match identifier.as_str() {
                 "def" => Token::Def,
                 "extern" => Token::Extern,
+                "import" => Token::Import,
+                "from" => Token::From,
+                "struct" => Token::Struct,
+                "new" => Token::New,
+                "enum" => Token::Enum,
+                "match" => Token::Match,
+                "case" => Token::Case,
+                "lambda" => Token::Lambda,
+                "and" => Token::And,
+                "or" => Token::Or,
+                "not" => Token::Not,
+                "true" => Token::True,
+                "false" => Token::False,
                 "if" => Token::If,
                 "then" => Token::Then,
                 "else" => Token::Else,
                 "for" => Token::For,
                 "in" => Token::In,
+                "while" => Token::While,
+                "break" => Token::Break,
+                "continue" => Token::Continue,
+                "let" => Token::Let,
+                "return" => Token::Return,
+                "const" => Token::Const,
+                "global" => Token::Global,
+                "try" => Token::Try,
+                "except" => Token::Except,
+                "finally" => Token::Finally,
+                "raise" => Token::Raise,
+                "assert" => Token::Assert,
+                "test" => Token::Test,
                 _ => Token::Identifier(identifier),
             }
 
-        } else if last_char.is_ascii_digit() || last_char == '.' {
+        } else if last_char.is_ascii_digit()
+            || (last_char == '.' && self.input.peek().is_some_and(char::is_ascii_digit))
+        {
             let mut number = String::new();
             number.push(last_char);
             while let Some(&c) = self.input.peek() {
@@ -88,7 +234,43 @@ impl<I> Lexer<I>
                     break;
                 }
             }
-            Token::Number(number.parse().expect("Lexer: Invalid number"))
+            match number.parse() {
+                Ok(value) => Token::Number(value),
+                Err(_) => Token::Error(format!("Invalid number {}", number)),
+            }
+
+        } else if last_char == '"' {
+            // The whole literal is read even if it contains an invalid escape sequence.
+            let mut string = String::new();
+            let mut error = None;
+            loop {
+                match self.step() {
+                    Some('"') => break,
+                    Some('\\') => match self.step() {
+                        Some('n') => string.push('\n'),
+                        Some('t') => string.push('\t'),
+                        Some('r') => string.push('\r'),
+                        Some('0') => string.push('\0'),
+                        Some('\\') => string.push('\\'),
+                        Some('"') => string.push('"'),
+                        Some(c) => {
+                            error.get_or_insert_with(|| format!("Unknown escape sequence '\\{}'", c));
+                        }
+                        None => break,
+                    },
+                    Some(c) => string.push(c),
+                    None => break,
+                }
+            }
+            if self.last_char.is_none() {
+                // The literal ends with the input, there is no closing quote to move past.
+                self.token_end = self.loc;
+                return Token::Error("Unterminated string literal".to_string());
+            }
+            match error {
+                Some(message) => Token::Error(message),
+                None => Token::Str(string),
+            }
 
         } else if last_char == '#' {
             while let Some(&c) = self.input.peek() {
@@ -98,21 +280,159 @@ impl<I> Lexer<I>
                     break;
                 }
             }
-            self.gettok()
+            self.step();
+            return self.gettok();
 
         } else {
-            let mut operator = String::new();
-            operator.push(last_char);
-            while let Some(&c) = self.input.peek() {
-                if !c.is_ascii_alphanumeric() {
-                    operator.push(c);
+            // Comparisons, `//`, `**`, `<<`, `>>` and `->` are made of two punctuation characters.
+            match (last_char, self.input.peek()) {
+                ('=' | '!' | '<' | '>', Some('=')) => {
                     self.step();
-                } else {
-                    break;
+                    Token::Operator(format!("{}=", last_char))
                 }
+                ('/', Some('/')) | ('*', Some('*')) | ('<', Some('<')) | ('>', Some('>')) => {
+                    self.step();
+                    Token::Operator(format!("{0}{0}", last_char))
+                }
+                ('-', Some('>')) => {
+                    self.step();
+                    Token::Operator("->".to_string())
+                }
+                _ => Token::Char(last_char),
+            }
+        };
+
+        // Move past the last character of the token.
+        self.step();
+        self.token_end = self.loc;
+        token
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens(source: &str) -> Vec<Token> {
+        let mut lexer = Lexer::new(source.chars());
+        let mut tokens = Vec::new();
+        loop {
+            match lexer.gettok() {
+                Token::Eof => return tokens,
+                token => tokens.push(token),
             }
+        }
+    }
+
+    fn ident(name: &str) -> Token {
+        Token::Identifier(name.to_string())
+    }
+
+    #[test]
+    fn dots_are_separate_tokens() {
+        assert_eq!(
+            tokens("math.sqrt(p.x)"),
+            vec![
+                ident("math"),
+                Token::Char('.'),
+                ident("sqrt"),
+                Token::Char('('),
+                ident("p"),
+                Token::Char('.'),
+                ident("x"),
+                Token::Char(')'),
+            ]
+        );
+        assert_eq!(tokens("foo."), vec![ident("foo"), Token::Char('.')]);
+    }
 
-            Token::Operator(operator)
+    #[test]
+    fn numbers_with_dots() {
+        assert_eq!(tokens("1.5 .25 3."), vec![Token::Number(1.5), Token::Number(0.25), Token::Number(3.0)]);
+    }
+
+    #[test]
+    fn string_escapes() {
+        assert_eq!(
+            tokens(r#""a\tb\n" "\"q\" \\ \0\r" """#),
+            vec![
+                Token::Str("a\tb\n".to_string()),
+                Token::Str("\"q\" \\ \0\r".to_string()),
+                Token::Str(String::new()),
+            ]
+        );
+        assert_eq!(tokens("\"# not a comment\""), vec![Token::Str("# not a comment".to_string())]);
+    }
+
+    #[test]
+    fn two_character_operators() {
+        let op = |op: &str| Token::Operator(op.to_string());
+        assert_eq!(
+            tokens("a // b ** c << d >> e == f != g <= h >= i"),
+            vec![
+                ident("a"), op("//"), ident("b"), op("**"), ident("c"), op("<<"), ident("d"), op(">>"),
+                ident("e"), op("=="), ident("f"), op("!="), ident("g"), op("<="), ident("h"), op(">="), ident("i"),
+            ]
+        );
+        assert_eq!(
+            tokens("a / * < > = % & | ^ ~"),
+            vec![
+                ident("a"), Token::Char('/'), Token::Char('*'), Token::Char('<'), Token::Char('>'),
+                Token::Char('='), Token::Char('%'), Token::Char('&'), Token::Char('|'), Token::Char('^'),
+                Token::Char('~'),
+            ]
+        );
+        assert_eq!(tokens("***"), vec![op("**"), Token::Char('*')]);
+        assert_eq!(tokens(") -> -x"), vec![Token::Char(')'), op("->"), Token::Char('-'), ident("x")]);
+    }
+
+    #[test]
+    fn token_locations() {
+        let mut lexer = Lexer::new("def f(x)\n  x + 1".chars());
+        assert_eq!(lexer.gettok(), Token::Def);
+        assert_eq!(lexer.token_loc(), SourceLoc { line: 1, col: 1 });
+        assert_eq!(lexer.token_end(), SourceLoc { line: 1, col: 4 });
+        for _ in 0..4 {
+            lexer.gettok();
+        }
+        assert_eq!(lexer.gettok(), ident("x"));
+        assert_eq!(lexer.token_loc(), SourceLoc { line: 2, col: 3 });
+        assert_eq!(lexer.token_offset(), 11);
+    }
+
+    #[test]
+    fn keywords_are_lexed_as_keywords() {
+        for keyword in KEYWORDS {
+            assert_ne!(tokens(keyword), vec![ident(keyword)], "{}", keyword);
+        }
+        assert_eq!(tokens("tests"), vec![ident("tests")]);
+    }
+
+    #[test]
+    fn malformed_literals() {
+        let error = |message: &str| Token::Error(message.to_string());
+        assert_eq!(tokens("1.2.3 + 1"), vec![error("Invalid number 1.2.3"), Token::Char('+'), Token::Number(1.0)]);
+        assert_eq!(
+            tokens(r#""a\qb\x" + "c""#),
+            vec![error("Unknown escape sequence '\\q'"), Token::Char('+'), Token::Str("c".to_string())]
+        );
+        assert_eq!(tokens(r#"x + "abc"#), vec![ident("x"), Token::Char('+'), error("Unterminated string literal")]);
+        assert_eq!(tokens(r#""abc\"#), vec![error("Unterminated string literal")]);
+    }
+
+    #[test]
+    fn malformed_literal_locations() {
+        let mut lexer = Lexer::new("x = \"a\\qb\"\n  \"tail".chars());
+        for _ in 0..2 {
+            lexer.gettok();
         }
+        assert!(matches!(lexer.gettok(), Token::Error(_)));
+        assert_eq!(lexer.token_loc(), SourceLoc { line: 1, col: 5 });
+        assert_eq!(lexer.token_end(), SourceLoc { line: 1, col: 11 });
+
+        assert!(matches!(lexer.gettok(), Token::Error(_)));
+        assert_eq!(lexer.token_loc(), SourceLoc { line: 2, col: 3 });
+        assert_eq!(lexer.token_end(), SourceLoc { line: 2, col: 8 });
+        assert_eq!(lexer.gettok(), Token::Eof);
     }
-}
\ No newline at end of file
+}
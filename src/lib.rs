@@ -1,44 +1,164 @@
 use std::convert::TryFrom;
 
+pub mod aot;
+pub mod cache;
+pub mod consteval;
+pub mod import;
 pub mod ir_gen;
+#[path = "llvm_wrapper/mod.rs"]
 pub mod llvm;
 pub mod parser;
 pub mod lexer;
+pub mod lsp;
+pub mod parallel;
+pub mod prelude;
+pub mod snapshot;
+pub mod typeck;
 
+/// Maximum number of bytes (including the terminating null byte) stored inline by a [`SmallCStr`].
 pub const SMALL_STR_SIZE: usize = 16;
 
+/// A null terminated C string which keeps short strings inline and spills longer ones to the
+/// heap.
+///
+/// Most identifiers handed to the LLVM C API are short, so we avoid an allocation for the common
+/// case while still supporting arbitrary-length (eg mangled or unicode) names.
 #[derive(Debug, PartialEq)]
-pub struct SmalLCStr([u8; SMALL_STR_SIZE]);
+pub enum SmallCStr {
+    Inline([u8; SMALL_STR_SIZE]),
+    Heap(Box<[u8]>),
+}
 
-impl SmalLCStr {
-    pub fn new<T: AsRef<[u8]>>(src: &T) -> Option<SmalLCStr> {
+impl SmallCStr {
+    /// Create a new C string from `src`, appending the terminating null byte.
+    ///
+    /// Returns `None` if `src` contains an interior null byte.
+    pub fn new<T: AsRef<[u8]>>(src: &T) -> Option<SmallCStr> {
         let src = src.as_ref();
         let len = src.len();
 
         let contains_null = unsafe { !libc::memchr(src.as_ptr() as *const libc::c_void, 0, len).is_null() };
-        if contains_null || len >= SMALL_STR_SIZE {
+        if contains_null {
             None
-        } else {
+        } else if len < SMALL_STR_SIZE {
             let mut buf = [0; SMALL_STR_SIZE];
             buf[..len].copy_from_slice(src);
-            Some(SmalLCStr(buf))
+            Some(SmallCStr::Inline(buf))
+        } else {
+            let mut buf = Vec::with_capacity(len + 1);
+            buf.extend_from_slice(src);
+            buf.push(0);
+            Some(SmallCStr::Heap(buf.into_boxed_slice()))
         }
     }
 
-    pub const fn as_ptr(&self) -> *const u8 {
-        self.0.as_ptr()
+    /// Check if the string is stored inline (without a heap allocation).
+    pub const fn is_inline(&self) -> bool {
+        matches!(self, SmallCStr::Inline(_))
+    }
+
+    /// Get the length of the string in bytes, excluding the terminating null byte.
+    pub fn len(&self) -> usize {
+        self.as_bytes().len()
+    }
+
+    /// Check if the string is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Get the bytes of the string, excluding the terminating null byte.
+    pub fn as_bytes(&self) -> &[u8] {
+        match self {
+            SmallCStr::Inline(buf) => {
+                let len = buf.iter().position(|&b| b == 0).unwrap_or(SMALL_STR_SIZE);
+                &buf[..len]
+            }
+            SmallCStr::Heap(buf) => &buf[..buf.len() - 1],
+        }
+    }
+
+    /// Get a pointer to the null terminated string, suitable to be passed to the LLVM C API.
+    pub fn as_ptr(&self) -> *const libc::c_char {
+        match self {
+            SmallCStr::Inline(buf) => buf.as_ptr().cast(),
+            SmallCStr::Heap(buf) => buf.as_ptr().cast(),
+        }
     }
 }
 
-impl TryFrom<&str> for SmalLCStr {
+impl TryFrom<&str> for SmallCStr {
     type Error = ();
 
-    fn try_from(src: &str) -> Result<SmalLCStr, ()> {
-        SmalLCStr::new(src).ok_or(())
+    fn try_from(src: &str) -> Result<SmallCStr, ()> {
+        SmallCStr::new(&src).ok_or(())
     }
 }
 
 pub enum Either<L, R> {
     Left(L),
     Right(R),
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::CStr;
+
+    /// Read back the null terminated string passed to LLVM.
+    fn read_c_str(s: &SmallCStr) -> &[u8] {
+        unsafe { CStr::from_ptr(s.as_ptr()) }.to_bytes()
+    }
+
+    #[test]
+    fn inline_heap_boundary() {
+        // 15 bytes and the null byte fill the inline buffer, longer strings spill to the heap.
+        for (len, inline) in [(15, true), (16, false), (17, false)] {
+            let name = "a".repeat(len);
+            let s = SmallCStr::try_from(name.as_str()).unwrap();
+            assert_eq!(s.is_inline(), inline, "{} bytes", len);
+            assert_eq!(s.len(), len);
+            assert_eq!(s.as_bytes(), name.as_bytes());
+            assert_eq!(read_c_str(&s), name.as_bytes());
+        }
+    }
+
+    #[test]
+    fn empty_string() {
+        let s = SmallCStr::try_from("").unwrap();
+        assert!(s.is_inline());
+        assert!(s.is_empty());
+        assert_eq!(read_c_str(&s), b"");
+    }
+
+    #[test]
+    fn multibyte_utf8_name() {
+        // 7 characters, but 10 bytes.
+        let short = "ñandú_π";
+        let s = SmallCStr::try_from(short).unwrap();
+        assert_eq!(s.len(), 10);
+        assert!(s.is_inline());
+        assert_eq!(read_c_str(&s), short.as_bytes());
+
+        let long = "größe_der_äpfel_λ";
+        let s = SmallCStr::try_from(long).unwrap();
+        assert!(!s.is_inline());
+        assert_eq!(std::str::from_utf8(read_c_str(&s)).unwrap(), long);
+    }
+
+    #[test]
+    fn mangled_generic_instance_name() {
+        let name = "util.max[fn(f64) -> f64, &Point]";
+        let s = SmallCStr::try_from(name).unwrap();
+        assert!(!s.is_inline());
+        assert_eq!(s.len(), name.len());
+        assert_eq!(std::str::from_utf8(read_c_str(&s)).unwrap(), name);
+    }
+
+    #[test]
+    fn interior_null_byte() {
+        assert_eq!(SmallCStr::new(&"fib\0"), None);
+        assert_eq!(SmallCStr::new(&"a\0b"), None);
+        assert_eq!(SmallCStr::try_from("a_very_long_name_with\0a_null_byte"), Err(()));
+    }
+}
@@ -1,11 +1,17 @@
 use llvm_sys::{
     core::{
-        LLVMAddIncoming, LLVMBuildBr, LLVMBuildCondBr, LLVMBuildFAdd, LLVMBuildFCmp, LLVMBuildFMul,
-        LLVMBuildFSub, LLVMBuildPhi, LLVMBuildRet, LLVMBuildUIToFP, LLVMCreateBuilderInContext,
-        LLVMDisposeBuilder, LLVMGetInsertBlock, LLVMPositionBuilderAtEnd,
+        LLVMAddCase, LLVMAddClause, LLVMAddIncoming, LLVMBuildAShr, LLVMBuildAdd, LLVMBuildAlloca,
+        LLVMBuildAnd, LLVMBuildBr, LLVMBuildCondBr, LLVMBuildExtractValue, LLVMBuildFAdd,
+        LLVMBuildFCmp, LLVMBuildFDiv, LLVMBuildFMul, LLVMBuildFNeg, LLVMBuildFPToSI, LLVMBuildFRem,
+        LLVMBuildFSub, LLVMBuildGEP2, LLVMBuildICmp, LLVMBuildInsertValue, LLVMBuildLandingPad,
+        LLVMBuildLoad2, LLVMBuildNot, LLVMBuildOr, LLVMBuildPhi, LLVMBuildResume, LLVMBuildRet,
+        LLVMBuildSIToFP, LLVMBuildSelect, LLVMBuildShl, LLVMBuildStore, LLVMBuildStructGEP2,
+        LLVMBuildSwitch, LLVMBuildUIToFP, LLVMBuildUnreachable, LLVMBuildXor,
+        LLVMCreateBuilderInContext, LLVMDisposeBuilder, LLVMGetInsertBlock,
+        LLVMPositionBuilderAtEnd, LLVMSetCleanup, LLVMSetVolatile,
     },
-    prelude::{LLVMBuilderRef, LLVMValueRef},
-    LLVMRealPredicate,
+    prelude::{LLVMBasicBlockRef, LLVMBuilderRef, LLVMValueRef},
+    LLVMIntPredicate, LLVMRealPredicate, LLVMTypeKind,
 };
 
 use std::marker::PhantomData;
@@ -22,6 +28,17 @@ extern "C" {
         NumArgs: ::libc::c_uint,
         Name: *const ::libc::c_char,
     ) -> LLVMValueRef;
+
+    fn LLVMBuildInvoke2(
+        arg1: LLVMBuilderRef,
+        Ty: Type<'_>,
+        Fn: FnValue<'_>,
+        Args: *mut Value<'_>,
+        NumArgs: ::libc::c_uint,
+        Then: LLVMBasicBlockRef,
+        Catch: LLVMBasicBlockRef,
+        Name: *const ::libc::c_char,
+    ) -> LLVMValueRef;
 }
 
 /// Wrapper for a LLVM IR Builder.
@@ -79,7 +96,7 @@ impl<'llvm> IRBuilder<'llvm> {
                 self.builder,
                 lhs.value_ref(),
                 rhs.value_ref(),
-                b"fadd\0".as_ptr().cast(),
+                c"fadd".as_ptr().cast(),
             )
         };
         Value::new(value_ref)
@@ -99,7 +116,7 @@ impl<'llvm> IRBuilder<'llvm> {
                 self.builder,
                 lhs.value_ref(),
                 rhs.value_ref(),
-                b"fsub\0".as_ptr().cast(),
+                c"fsub".as_ptr().cast(),
             )
         };
         Value::new(value_ref)
@@ -119,12 +136,64 @@ impl<'llvm> IRBuilder<'llvm> {
                 self.builder,
                 lhs.value_ref(),
                 rhs.value_ref(),
-                b"fmul\0".as_ptr().cast(),
+                c"fmul".as_ptr().cast(),
+            )
+        };
+        Value::new(value_ref)
+    }
+
+    /// Emit a [fdiv](https://llvm.org/docs/LangRef.html#fdiv-instruction) instruction.
+    ///
+    /// # Panics
+    ///
+    /// Panics if LLVM API returns a `null` pointer.
+    pub fn fdiv(&self, lhs: Value<'llvm>, rhs: Value<'llvm>) -> Value<'llvm> {
+        debug_assert!(lhs.is_f64(), "fdiv: Expected f64 as lhs operand!");
+        debug_assert!(rhs.is_f64(), "fdiv: Expected f64 as rhs operand!");
+
+        let value_ref = unsafe {
+            LLVMBuildFDiv(
+                self.builder,
+                lhs.value_ref(),
+                rhs.value_ref(),
+                c"fdiv".as_ptr().cast(),
             )
         };
         Value::new(value_ref)
     }
 
+    /// Emit a [frem](https://llvm.org/docs/LangRef.html#frem-instruction) instruction.
+    ///
+    /// # Panics
+    ///
+    /// Panics if LLVM API returns a `null` pointer.
+    pub fn frem(&self, lhs: Value<'llvm>, rhs: Value<'llvm>) -> Value<'llvm> {
+        debug_assert!(lhs.is_f64(), "frem: Expected f64 as lhs operand!");
+        debug_assert!(rhs.is_f64(), "frem: Expected f64 as rhs operand!");
+
+        let value_ref = unsafe {
+            LLVMBuildFRem(
+                self.builder,
+                lhs.value_ref(),
+                rhs.value_ref(),
+                c"frem".as_ptr().cast(),
+            )
+        };
+        Value::new(value_ref)
+    }
+
+    /// Emit a [fneg](https://llvm.org/docs/LangRef.html#fneg-instruction) instruction.
+    ///
+    /// # Panics
+    ///
+    /// Panics if LLVM API returns a `null` pointer.
+    pub fn fneg(&self, val: Value<'llvm>) -> Value<'llvm> {
+        debug_assert!(val.is_f64(), "fneg: Expected f64 operand!");
+
+        let value_ref = unsafe { LLVMBuildFNeg(self.builder, val.value_ref(), c"fneg".as_ptr().cast()) };
+        Value::new(value_ref)
+    }
+
     /// Emit a [fcmpult](https://llvm.org/docs/LangRef.html#fcmp-instruction) instruction.
     ///
     /// # Panics
@@ -140,7 +209,7 @@ impl<'llvm> IRBuilder<'llvm> {
                 LLVMRealPredicate::LLVMRealULT,
                 lhs.value_ref(),
                 rhs.value_ref(),
-                b"fcmpult\0".as_ptr().cast(),
+                c"fcmpult".as_ptr().cast(),
             )
         };
         Value::new(value_ref)
@@ -161,7 +230,348 @@ impl<'llvm> IRBuilder<'llvm> {
                 LLVMRealPredicate::LLVMRealONE,
                 lhs.value_ref(),
                 rhs.value_ref(),
-                b"fcmpone\0".as_ptr().cast(),
+                c"fcmpone".as_ptr().cast(),
+            )
+        };
+        Value::new(value_ref)
+    }
+
+    /// Emit a [fcmpolt](https://llvm.org/docs/LangRef.html#fcmp-instruction) instruction.
+    ///
+    /// # Panics
+    ///
+    /// Panics if LLVM API returns a `null` pointer.
+    pub fn fcmpolt(&self, lhs: Value<'llvm>, rhs: Value<'llvm>) -> Value<'llvm> {
+        debug_assert!(lhs.is_f64(), "fcmpolt: Expected f64 as lhs operand!");
+        debug_assert!(rhs.is_f64(), "fcmpolt: Expected f64 as rhs operand!");
+
+        let value_ref = unsafe {
+            LLVMBuildFCmp(
+                self.builder,
+                LLVMRealPredicate::LLVMRealOLT,
+                lhs.value_ref(),
+                rhs.value_ref(),
+                c"fcmpolt".as_ptr().cast(),
+            )
+        };
+        Value::new(value_ref)
+    }
+
+    /// Emit a [fcmpogt](https://llvm.org/docs/LangRef.html#fcmp-instruction) instruction.
+    ///
+    /// # Panics
+    ///
+    /// Panics if LLVM API returns a `null` pointer.
+    pub fn fcmpogt(&self, lhs: Value<'llvm>, rhs: Value<'llvm>) -> Value<'llvm> {
+        debug_assert!(lhs.is_f64(), "fcmpogt: Expected f64 as lhs operand!");
+        debug_assert!(rhs.is_f64(), "fcmpogt: Expected f64 as rhs operand!");
+
+        let value_ref = unsafe {
+            LLVMBuildFCmp(
+                self.builder,
+                LLVMRealPredicate::LLVMRealOGT,
+                lhs.value_ref(),
+                rhs.value_ref(),
+                c"fcmpogt".as_ptr().cast(),
+            )
+        };
+        Value::new(value_ref)
+    }
+
+    /// Emit a [fcmpole](https://llvm.org/docs/LangRef.html#fcmp-instruction) instruction.
+    ///
+    /// # Panics
+    ///
+    /// Panics if LLVM API returns a `null` pointer.
+    pub fn fcmpole(&self, lhs: Value<'llvm>, rhs: Value<'llvm>) -> Value<'llvm> {
+        debug_assert!(lhs.is_f64(), "fcmpole: Expected f64 as lhs operand!");
+        debug_assert!(rhs.is_f64(), "fcmpole: Expected f64 as rhs operand!");
+
+        let value_ref = unsafe {
+            LLVMBuildFCmp(
+                self.builder,
+                LLVMRealPredicate::LLVMRealOLE,
+                lhs.value_ref(),
+                rhs.value_ref(),
+                c"fcmpole".as_ptr().cast(),
+            )
+        };
+        Value::new(value_ref)
+    }
+
+    /// Emit a [fcmpoge](https://llvm.org/docs/LangRef.html#fcmp-instruction) instruction.
+    ///
+    /// # Panics
+    ///
+    /// Panics if LLVM API returns a `null` pointer.
+    pub fn fcmpoge(&self, lhs: Value<'llvm>, rhs: Value<'llvm>) -> Value<'llvm> {
+        debug_assert!(lhs.is_f64(), "fcmpoge: Expected f64 as lhs operand!");
+        debug_assert!(rhs.is_f64(), "fcmpoge: Expected f64 as rhs operand!");
+
+        let value_ref = unsafe {
+            LLVMBuildFCmp(
+                self.builder,
+                LLVMRealPredicate::LLVMRealOGE,
+                lhs.value_ref(),
+                rhs.value_ref(),
+                c"fcmpoge".as_ptr().cast(),
+            )
+        };
+        Value::new(value_ref)
+    }
+
+    /// Emit a [fcmpune](https://llvm.org/docs/LangRef.html#fcmp-instruction) instruction.
+    ///
+    /// # Panics
+    ///
+    /// Panics if LLVM API returns a `null` pointer.
+    pub fn fcmpune(&self, lhs: Value<'llvm>, rhs: Value<'llvm>) -> Value<'llvm> {
+        debug_assert!(lhs.is_f64(), "fcmpune: Expected f64 as lhs operand!");
+        debug_assert!(rhs.is_f64(), "fcmpune: Expected f64 as rhs operand!");
+
+        let value_ref = unsafe {
+            LLVMBuildFCmp(
+                self.builder,
+                LLVMRealPredicate::LLVMRealUNE,
+                lhs.value_ref(),
+                rhs.value_ref(),
+                c"fcmpune".as_ptr().cast(),
+            )
+        };
+        Value::new(value_ref)
+    }
+
+    /// Emit a [fcmpoeq](https://llvm.org/docs/LangRef.html#fcmp-instruction) instruction.
+    ///
+    /// # Panics
+    ///
+    /// Panics if LLVM API returns a `null` pointer.
+    pub fn fcmpoeq(&self, lhs: Value<'llvm>, rhs: Value<'llvm>) -> Value<'llvm> {
+        debug_assert!(lhs.is_f64(), "fcmpoeq: Expected f64 as lhs operand!");
+        debug_assert!(rhs.is_f64(), "fcmpoeq: Expected f64 as rhs operand!");
+
+        let value_ref = unsafe {
+            LLVMBuildFCmp(
+                self.builder,
+                LLVMRealPredicate::LLVMRealOEQ,
+                lhs.value_ref(),
+                rhs.value_ref(),
+                c"fcmpoeq".as_ptr().cast(),
+            )
+        };
+        Value::new(value_ref)
+    }
+
+    /// Emit an [add](https://llvm.org/docs/LangRef.html#add-instruction) instruction.
+    ///
+    /// # Panics
+    ///
+    /// Panics if LLVM API returns a `null` pointer.
+    pub fn add(&self, lhs: Value<'llvm>, rhs: Value<'llvm>) -> Value<'llvm> {
+        debug_assert!(lhs.is_int(), "add: Expected integer as lhs operand!");
+        debug_assert!(rhs.is_int(), "add: Expected integer as rhs operand!");
+
+        let value_ref = unsafe {
+            LLVMBuildAdd(
+                self.builder,
+                lhs.value_ref(),
+                rhs.value_ref(),
+                c"add".as_ptr().cast(),
+            )
+        };
+        Value::new(value_ref)
+    }
+
+    /// Emit an [and](https://llvm.org/docs/LangRef.html#and-instruction) instruction.
+    ///
+    /// # Panics
+    ///
+    /// Panics if LLVM API returns a `null` pointer.
+    pub fn and(&self, lhs: Value<'llvm>, rhs: Value<'llvm>) -> Value<'llvm> {
+        debug_assert!(lhs.is_int(), "and: Expected integer as lhs operand!");
+        debug_assert!(rhs.is_int(), "and: Expected integer as rhs operand!");
+
+        let value_ref = unsafe {
+            LLVMBuildAnd(
+                self.builder,
+                lhs.value_ref(),
+                rhs.value_ref(),
+                c"and".as_ptr().cast(),
+            )
+        };
+        Value::new(value_ref)
+    }
+
+    /// Emit an [or](https://llvm.org/docs/LangRef.html#or-instruction) instruction.
+    ///
+    /// # Panics
+    ///
+    /// Panics if LLVM API returns a `null` pointer.
+    pub fn or(&self, lhs: Value<'llvm>, rhs: Value<'llvm>) -> Value<'llvm> {
+        debug_assert!(lhs.is_int(), "or: Expected integer as lhs operand!");
+        debug_assert!(rhs.is_int(), "or: Expected integer as rhs operand!");
+
+        let value_ref = unsafe {
+            LLVMBuildOr(
+                self.builder,
+                lhs.value_ref(),
+                rhs.value_ref(),
+                c"or".as_ptr().cast(),
+            )
+        };
+        Value::new(value_ref)
+    }
+
+    /// Emit a [xor](https://llvm.org/docs/LangRef.html#xor-instruction) instruction.
+    ///
+    /// # Panics
+    ///
+    /// Panics if LLVM API returns a `null` pointer.
+    pub fn xor(&self, lhs: Value<'llvm>, rhs: Value<'llvm>) -> Value<'llvm> {
+        debug_assert!(lhs.is_int(), "xor: Expected integer as lhs operand!");
+        debug_assert!(rhs.is_int(), "xor: Expected integer as rhs operand!");
+
+        let value_ref = unsafe {
+            LLVMBuildXor(
+                self.builder,
+                lhs.value_ref(),
+                rhs.value_ref(),
+                c"xor".as_ptr().cast(),
+            )
+        };
+        Value::new(value_ref)
+    }
+
+    /// Emit a [shl](https://llvm.org/docs/LangRef.html#shl-instruction) instruction.
+    ///
+    /// # Panics
+    ///
+    /// Panics if LLVM API returns a `null` pointer.
+    pub fn shl(&self, lhs: Value<'llvm>, rhs: Value<'llvm>) -> Value<'llvm> {
+        debug_assert!(lhs.is_int(), "shl: Expected integer as lhs operand!");
+        debug_assert!(rhs.is_int(), "shl: Expected integer as rhs operand!");
+
+        let value_ref = unsafe {
+            LLVMBuildShl(
+                self.builder,
+                lhs.value_ref(),
+                rhs.value_ref(),
+                c"shl".as_ptr().cast(),
+            )
+        };
+        Value::new(value_ref)
+    }
+
+    /// Emit an [ashr](https://llvm.org/docs/LangRef.html#ashr-instruction) instruction.
+    ///
+    /// # Panics
+    ///
+    /// Panics if LLVM API returns a `null` pointer.
+    pub fn ashr(&self, lhs: Value<'llvm>, rhs: Value<'llvm>) -> Value<'llvm> {
+        debug_assert!(lhs.is_int(), "ashr: Expected integer as lhs operand!");
+        debug_assert!(rhs.is_int(), "ashr: Expected integer as rhs operand!");
+
+        let value_ref = unsafe {
+            LLVMBuildAShr(
+                self.builder,
+                lhs.value_ref(),
+                rhs.value_ref(),
+                c"ashr".as_ptr().cast(),
+            )
+        };
+        Value::new(value_ref)
+    }
+
+    /// Emit a [icmpult](https://llvm.org/docs/LangRef.html#icmp-instruction) instruction.
+    ///
+    /// # Panics
+    ///
+    /// Panics if LLVM API returns a `null` pointer.
+    pub fn icmpult(&self, lhs: Value<'llvm>, rhs: Value<'llvm>) -> Value<'llvm> {
+        debug_assert!(lhs.is_int(), "icmpult: Expected integer as lhs operand!");
+        debug_assert!(rhs.is_int(), "icmpult: Expected integer as rhs operand!");
+
+        let value_ref = unsafe {
+            LLVMBuildICmp(
+                self.builder,
+                LLVMIntPredicate::LLVMIntULT,
+                lhs.value_ref(),
+                rhs.value_ref(),
+                c"icmpult".as_ptr().cast(),
+            )
+        };
+        Value::new(value_ref)
+    }
+
+    /// Emit a [icmpeq](https://llvm.org/docs/LangRef.html#icmp-instruction) instruction.
+    ///
+    /// # Panics
+    ///
+    /// Panics if LLVM API returns a `null` pointer.
+    pub fn icmpeq(&self, lhs: Value<'llvm>, rhs: Value<'llvm>) -> Value<'llvm> {
+        debug_assert!(lhs.is_int(), "icmpeq: Expected integer as lhs operand!");
+        debug_assert!(rhs.is_int(), "icmpeq: Expected integer as rhs operand!");
+
+        let value_ref = unsafe {
+            LLVMBuildICmp(
+                self.builder,
+                LLVMIntPredicate::LLVMIntEQ,
+                lhs.value_ref(),
+                rhs.value_ref(),
+                c"icmpeq".as_ptr().cast(),
+            )
+        };
+        Value::new(value_ref)
+    }
+
+    /// Emit a [icmpne](https://llvm.org/docs/LangRef.html#icmp-instruction) instruction.
+    ///
+    /// # Panics
+    ///
+    /// Panics if LLVM API returns a `null` pointer.
+    pub fn icmpne(&self, lhs: Value<'llvm>, rhs: Value<'llvm>) -> Value<'llvm> {
+        debug_assert!(lhs.is_int(), "icmpne: Expected integer as lhs operand!");
+        debug_assert!(rhs.is_int(), "icmpne: Expected integer as rhs operand!");
+
+        let value_ref = unsafe {
+            LLVMBuildICmp(
+                self.builder,
+                LLVMIntPredicate::LLVMIntNE,
+                lhs.value_ref(),
+                rhs.value_ref(),
+                c"icmpne".as_ptr().cast(),
+            )
+        };
+        Value::new(value_ref)
+    }
+
+    /// Emit a bitwise [not](https://llvm.org/docs/LangRef.html#xor-instruction) (`xor` with all
+    /// ones) instruction, the logical negation of an `i1`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if LLVM API returns a `null` pointer.
+    pub fn not(&self, val: Value<'llvm>) -> Value<'llvm> {
+        debug_assert!(val.is_int(), "not: Expected integer operand!");
+
+        let value_ref = unsafe { LLVMBuildNot(self.builder, val.value_ref(), c"not".as_ptr().cast()) };
+        Value::new(value_ref)
+    }
+
+    /// Emit a [fptosi](https://llvm.org/docs/LangRef.html#fptosi-to-instruction) instruction.
+    ///
+    /// # Panics
+    ///
+    /// Panics if LLVM API returns a `null` pointer.
+    pub fn fptosi(&self, val: Value<'llvm>, dest_type: Type<'llvm>) -> Value<'llvm> {
+        debug_assert!(val.is_f64(), "fptosi: Expected f64 operand!");
+
+        let value_ref = unsafe {
+            LLVMBuildFPToSI(
+                self.builder,
+                val.value_ref(),
+                dest_type.type_ref(),
+                c"fptosi".as_ptr().cast(),
             )
         };
         Value::new(value_ref)
@@ -180,7 +590,26 @@ impl<'llvm> IRBuilder<'llvm> {
                 self.builder,
                 val.value_ref(),
                 dest_type.type_ref(),
-                b"uitofp\0".as_ptr().cast(),
+                c"uitofp".as_ptr().cast(),
+            )
+        };
+        Value::new(value_ref)
+    }
+
+    /// Emit a [sitofp](https://llvm.org/docs/LangRef.html#sitofp-to-instruction) instruction.
+    ///
+    /// # Panics
+    ///
+    /// Panics if LLVM API returns a `null` pointer.
+    pub fn sitofp(&self, val: Value<'llvm>, dest_type: Type<'llvm>) -> Value<'llvm> {
+        debug_assert!(val.is_int(), "sitofp: Expected integer operand!");
+
+        let value_ref = unsafe {
+            LLVMBuildSIToFP(
+                self.builder,
+                val.value_ref(),
+                dest_type.type_ref(),
+                c"sitofp".as_ptr().cast(),
             )
         };
         Value::new(value_ref)
@@ -192,19 +621,298 @@ impl<'llvm> IRBuilder<'llvm> {
     ///
     /// Panics if LLVM API returns a `null` pointer.
     pub fn call(&self, fn_value: FnValue<'llvm>, args: &mut [Value<'llvm>]) -> Value<'llvm> {
+        // Values of type void must not be named.
+        let name: &[u8] = if fn_value.ret_type().kind() == LLVMTypeKind::LLVMVoidTypeKind {
+            b"\0"
+        } else {
+            b"call\0"
+        };
+
         let value_ref = unsafe {
             LLVMBuildCall2(
                 self.builder,
-                fn_value.ret_type(),
+                fn_value.fn_type(),
+                fn_value,
+                args.as_mut_ptr(),
+                args.len() as libc::c_uint,
+                name.as_ptr().cast(),
+            )
+        };
+        Value::new(value_ref)
+    }
+
+    /// Emit an [invoke](https://llvm.org/docs/LangRef.html#invoke-instruction) instruction,
+    /// continuing at `then` when the call returns and at the landing pad `catch` when it unwinds.
+    ///
+    /// # Panics
+    ///
+    /// Panics if LLVM API returns a `null` pointer.
+    pub fn invoke(
+        &self,
+        fn_value: FnValue<'llvm>,
+        args: &mut [Value<'llvm>],
+        then: BasicBlock<'llvm>,
+        catch: BasicBlock<'llvm>,
+    ) -> Value<'llvm> {
+        // Values of type void must not be named.
+        let name: &[u8] = if fn_value.ret_type().kind() == LLVMTypeKind::LLVMVoidTypeKind {
+            b"\0"
+        } else {
+            b"invoke\0"
+        };
+
+        let value_ref = unsafe {
+            LLVMBuildInvoke2(
+                self.builder,
+                fn_value.fn_type(),
                 fn_value,
                 args.as_mut_ptr(),
                 args.len() as libc::c_uint,
-                b"call\0".as_ptr().cast(),
+                then.bb_ref(),
+                catch.bb_ref(),
+                name.as_ptr().cast(),
             )
         };
         Value::new(value_ref)
     }
 
+    /// Emit an indirect [invoke](https://llvm.org/docs/LangRef.html#invoke-instruction)
+    /// instruction of the function pointer `callee` with the function type `fn_type`, see
+    /// [`invoke`][IRBuilder::invoke].
+    ///
+    /// # Panics
+    ///
+    /// Panics if LLVM API returns a `null` pointer.
+    pub fn invoke_indirect(
+        &self,
+        fn_type: Type<'llvm>,
+        callee: Value<'llvm>,
+        args: &mut [Value<'llvm>],
+        then: BasicBlock<'llvm>,
+        catch: BasicBlock<'llvm>,
+    ) -> Value<'llvm> {
+        let value_ref = unsafe {
+            llvm_sys::core::LLVMBuildInvoke2(
+                self.builder,
+                fn_type.type_ref(),
+                callee.value_ref(),
+                // `Value` is `repr(transparent)`, so a slice of values is a slice of value refs.
+                args.as_mut_ptr().cast(),
+                args.len() as libc::c_uint,
+                then.bb_ref(),
+                catch.bb_ref(),
+                c"invoke".as_ptr().cast(),
+            )
+        };
+        Value::new(value_ref)
+    }
+
+    /// Emit a [landingpad](https://llvm.org/docs/LangRef.html#landingpad-instruction) instruction
+    /// of type `ty` with the personality function `personality`, which must also be set on the
+    /// current function with [`FnValue::set_personality`].
+    ///
+    /// The landing pad catches exceptions matching the type info `catch`, a null pointer catching
+    /// every exception. Without `catch` it is a cleanup, which must end with
+    /// [`resume`][IRBuilder::resume].
+    ///
+    /// # Panics
+    ///
+    /// Panics if LLVM API returns a `null` pointer.
+    pub fn landing_pad(
+        &self,
+        ty: Type<'llvm>,
+        personality: FnValue<'llvm>,
+        catch: Option<Value<'llvm>>,
+    ) -> Value<'llvm> {
+        let value_ref = unsafe {
+            let value_ref = LLVMBuildLandingPad(
+                self.builder,
+                ty.type_ref(),
+                personality.value_ref(),
+                catch.is_some() as libc::c_uint,
+                c"landingpad".as_ptr().cast(),
+            );
+            match catch {
+                Some(type_info) => LLVMAddClause(value_ref, type_info.value_ref()),
+                None => LLVMSetCleanup(value_ref, 1),
+            }
+            value_ref
+        };
+        Value::new(value_ref)
+    }
+
+    /// Emit a [resume](https://llvm.org/docs/LangRef.html#resume-instruction) instruction,
+    /// continuing the unwinding of the exception caught by the landing pad `value`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if LLVM API returns a `null` pointer.
+    pub fn resume(&self, value: Value<'llvm>) {
+        let resume_ref = unsafe { LLVMBuildResume(self.builder, value.value_ref()) };
+        assert!(!resume_ref.is_null());
+    }
+
+    /// Emit an indirect [call](https://llvm.org/docs/LangRef.html#call-instruction) instruction
+    /// of the function pointer `callee` with the function type `fn_type`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if LLVM API returns a `null` pointer.
+    pub fn call_indirect(
+        &self,
+        fn_type: Type<'llvm>,
+        callee: Value<'llvm>,
+        args: &mut [Value<'llvm>],
+    ) -> Value<'llvm> {
+        let value_ref = unsafe {
+            llvm_sys::core::LLVMBuildCall2(
+                self.builder,
+                fn_type.type_ref(),
+                callee.value_ref(),
+                // `Value` is `repr(transparent)`, so a slice of values is a slice of value refs.
+                args.as_mut_ptr().cast(),
+                args.len() as libc::c_uint,
+                c"call".as_ptr().cast(),
+            )
+        };
+        Value::new(value_ref)
+    }
+
+    /// Emit an [extractvalue](https://llvm.org/docs/LangRef.html#extractvalue-instruction)
+    /// instruction, reading the field at index `idx` of the aggregate `agg`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if LLVM API returns a `null` pointer.
+    pub fn extract_value(&self, agg: Value<'llvm>, idx: u32) -> Value<'llvm> {
+        let value_ref = unsafe {
+            LLVMBuildExtractValue(
+                self.builder,
+                agg.value_ref(),
+                idx as libc::c_uint,
+                c"extractvalue".as_ptr().cast(),
+            )
+        };
+        Value::new(value_ref)
+    }
+
+    /// Emit an [insertvalue](https://llvm.org/docs/LangRef.html#insertvalue-instruction)
+    /// instruction, returning the aggregate `agg` with the field at index `idx` set to `val`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if LLVM API returns a `null` pointer.
+    pub fn insert_value(&self, agg: Value<'llvm>, val: Value<'llvm>, idx: u32) -> Value<'llvm> {
+        let value_ref = unsafe {
+            LLVMBuildInsertValue(
+                self.builder,
+                agg.value_ref(),
+                val.value_ref(),
+                idx as libc::c_uint,
+                c"insertvalue".as_ptr().cast(),
+            )
+        };
+        Value::new(value_ref)
+    }
+
+    /// Emit a [getelementptr](https://llvm.org/docs/LangRef.html#getelementptr-instruction)
+    /// instruction, computing the address of the element at index `idx` of an array of
+    /// `elem_type` starting at `ptr`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if LLVM API returns a `null` pointer.
+    pub fn gep(&self, elem_type: Type<'llvm>, ptr: Value<'llvm>, idx: Value<'llvm>) -> Value<'llvm> {
+        debug_assert!(idx.is_int(), "gep: Expected integer index!");
+
+        let value_ref = unsafe {
+            LLVMBuildGEP2(
+                self.builder,
+                elem_type.type_ref(),
+                ptr.value_ref(),
+                &mut idx.value_ref() as _,
+                1,
+                c"gep".as_ptr().cast(),
+            )
+        };
+        Value::new(value_ref)
+    }
+
+    /// Emit a [getelementptr](https://llvm.org/docs/LangRef.html#getelementptr-instruction)
+    /// instruction, computing the address of the field at index `idx` of a struct of type
+    /// `struct_type` at `ptr`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if LLVM API returns a `null` pointer.
+    pub fn struct_gep(&self, struct_type: Type<'llvm>, ptr: Value<'llvm>, idx: u32) -> Value<'llvm> {
+        debug_assert!(struct_type.is_struct(), "struct_gep: Expected a struct type!");
+
+        let value_ref = unsafe {
+            LLVMBuildStructGEP2(
+                self.builder,
+                struct_type.type_ref(),
+                ptr.value_ref(),
+                idx as libc::c_uint,
+                c"structgep".as_ptr().cast(),
+            )
+        };
+        Value::new(value_ref)
+    }
+
+    /// Emit an [alloca](https://llvm.org/docs/LangRef.html#alloca-instruction) instruction,
+    /// allocating a value of type `ty` on the stack of the current function.
+    ///
+    /// # Panics
+    ///
+    /// Panics if LLVM API returns a `null` pointer.
+    pub fn alloca(&self, ty: Type<'llvm>) -> Value<'llvm> {
+        let value_ref = unsafe { LLVMBuildAlloca(self.builder, ty.type_ref(), c"alloca".as_ptr().cast()) };
+        Value::new(value_ref)
+    }
+
+    /// Emit a [load](https://llvm.org/docs/LangRef.html#load-instruction) instruction, reading
+    /// a value of type `ty` from `ptr`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if LLVM API returns a `null` pointer.
+    pub fn load(&self, ty: Type<'llvm>, ptr: Value<'llvm>) -> Value<'llvm> {
+        let value_ref = unsafe {
+            LLVMBuildLoad2(
+                self.builder,
+                ty.type_ref(),
+                ptr.value_ref(),
+                c"load".as_ptr().cast(),
+            )
+        };
+        Value::new(value_ref)
+    }
+
+    /// Emit a volatile [load](https://llvm.org/docs/LangRef.html#load-instruction) instruction,
+    /// which is never removed or merged with other loads, eg to read a flag set by another
+    /// thread.
+    ///
+    /// # Panics
+    ///
+    /// Panics if LLVM API returns a `null` pointer.
+    pub fn load_volatile(&self, ty: Type<'llvm>, ptr: Value<'llvm>) -> Value<'llvm> {
+        let value = self.load(ty, ptr);
+        unsafe { LLVMSetVolatile(value.value_ref(), 1) };
+        value
+    }
+
+    /// Emit a [store](https://llvm.org/docs/LangRef.html#store-instruction) instruction,
+    /// writing `val` to `ptr`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if LLVM API returns a `null` pointer.
+    pub fn store(&self, val: Value<'llvm>, ptr: Value<'llvm>) {
+        let store_ref = unsafe { LLVMBuildStore(self.builder, val.value_ref(), ptr.value_ref()) };
+        assert!(!store_ref.is_null());
+    }
+
     /// Emit a [ret](https://llvm.org/docs/LangRef.html#ret-instruction) instruction.
     ///
     /// # Panics
@@ -242,6 +950,66 @@ impl<'llvm> IRBuilder<'llvm> {
         assert!(!br_ref.is_null());
     }
 
+    /// Emit a [select](https://llvm.org/docs/LangRef.html#select-instruction) instruction.
+    ///
+    /// # Panics
+    ///
+    /// Panics if LLVM API returns a `null` pointer.
+    pub fn select(&self, cond: Value<'llvm>, then: Value<'llvm>, else_: Value<'llvm>) -> Value<'llvm> {
+        debug_assert!(cond.is_bool(), "select: Expected i1 as condition operand!");
+
+        let value_ref = unsafe {
+            LLVMBuildSelect(
+                self.builder,
+                cond.value_ref(),
+                then.value_ref(),
+                else_.value_ref(),
+                c"select".as_ptr().cast(),
+            )
+        };
+        Value::new(value_ref)
+    }
+
+    /// Emit a [switch](https://llvm.org/docs/LangRef.html#switch-instruction) instruction
+    /// jumping to the block of the case matching the integer `value`, or to `default`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if LLVM API returns a `null` pointer.
+    pub fn switch(
+        &self,
+        value: Value<'llvm>,
+        default: BasicBlock<'llvm>,
+        cases: &[(Value<'llvm>, BasicBlock<'llvm>)],
+    ) {
+        debug_assert!(value.is_int(), "switch: Expected integer as value operand!");
+
+        let switch_ref = unsafe {
+            LLVMBuildSwitch(
+                self.builder,
+                value.value_ref(),
+                default.bb_ref(),
+                cases.len() as libc::c_uint,
+            )
+        };
+        assert!(!switch_ref.is_null());
+
+        for (case, bb) in cases {
+            unsafe { LLVMAddCase(switch_ref, case.value_ref(), bb.bb_ref()) };
+        }
+    }
+
+    /// Emit an [unreachable](https://llvm.org/docs/LangRef.html#unreachable-instruction)
+    /// instruction.
+    ///
+    /// # Panics
+    ///
+    /// Panics if LLVM API returns a `null` pointer.
+    pub fn unreachable(&self) {
+        let unreachable_ref = unsafe { LLVMBuildUnreachable(self.builder) };
+        assert!(!unreachable_ref.is_null());
+    }
+
     /// Emit a [phi](https://llvm.org/docs/LangRef.html#phi-instruction) instruction.
     ///
     /// # Panics
@@ -253,7 +1021,7 @@ impl<'llvm> IRBuilder<'llvm> {
         incoming: &[(Value<'llvm>, BasicBlock<'llvm>)],
     ) -> PhiValue<'llvm> {
         let phi_ref =
-            unsafe { LLVMBuildPhi(self.builder, phi_type.type_ref(), b"phi\0".as_ptr().cast()) };
+            unsafe { LLVMBuildPhi(self.builder, phi_type.type_ref(), c"phi".as_ptr().cast()) };
         assert!(!phi_ref.is_null());
 
         for (val, bb) in incoming {
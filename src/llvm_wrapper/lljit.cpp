@@ -0,0 +1,16 @@
+// Parts of the LLJIT API which are missing from the LLVM C API.
+
+#include "llvm-c/LLJIT.h"
+#include "llvm/ExecutionEngine/Orc/LLJIT.h"
+
+// The C API wraps an `LLJITBuilder *` without conversion.
+static llvm::orc::LLJITBuilder *unwrap(LLVMOrcLLJITBuilderRef Builder) {
+  return reinterpret_cast<llvm::orc::LLJITBuilder *>(Builder);
+}
+
+// Compile IR modules on a pool of `NumThreads` threads, or on the thread looking up a symbol
+// if `NumThreads` is 0.
+extern "C" void cobra_LLJITBuilderSetNumCompileThreads(LLVMOrcLLJITBuilderRef Builder,
+                                                       unsigned NumThreads) {
+  unwrap(Builder)->setNumCompileThreads(NumThreads);
+}
@@ -0,0 +1,360 @@
+use llvm_sys::core::LLVMCreateMemoryBufferWithMemoryRangeCopy;
+use llvm_sys::orc2::{
+    lljit::{
+        LLVMOrcCreateLLJIT, LLVMOrcCreateLLJITBuilder, LLVMOrcDisposeLLJIT,
+        LLVMOrcLLJITAddLLVMIRModuleWithRT, LLVMOrcLLJITAddObjectFileWithRT, LLVMOrcLLJITBuilderRef,
+        LLVMOrcLLJITGetExecutionSession, LLVMOrcLLJITGetGlobalPrefix,
+        LLVMOrcLLJITGetMainJITDylib, LLVMOrcLLJITLookup, LLVMOrcLLJITMangleAndIntern,
+        LLVMOrcLLJITRef,
+    },
+    LLVMJITEvaluatedSymbol, LLVMJITSymbolFlags, LLVMJITSymbolGenericFlags, LLVMOrcAbsoluteSymbols,
+    LLVMOrcCJITDylibSearchOrderElement, LLVMOrcCLookupSetElement, LLVMOrcCSymbolMapPairs,
+    LLVMOrcCSymbolMapPair, LLVMOrcCreateDynamicLibrarySearchGeneratorForProcess,
+    LLVMOrcDefinitionGeneratorRef, LLVMOrcDisposeMaterializationUnit, LLVMOrcExecutionSessionLookup,
+    LLVMOrcJITDylibAddGenerator, LLVMOrcJITDylibCreateResourceTracker, LLVMOrcJITDylibDefine,
+    LLVMOrcJITDylibLookupFlags, LLVMOrcJITDylibRef, LLVMOrcLookupKind,
+    LLVMOrcReleaseResourceTracker, LLVMOrcReleaseSymbolStringPoolEntry, LLVMOrcResourceTrackerRef,
+    LLVMOrcResourceTrackerRemove, LLVMOrcSymbolLookupFlags,
+};
+use llvm_sys::error::LLVMErrorRef;
+
+use std::convert::TryFrom;
+use std::marker::PhantomData;
+use std::sync::mpsc;
+
+use super::{Error, Module};
+use crate::SmallCStr;
+
+// Defined by the C++ shim in `lljit.cpp`.
+extern "C" {
+    fn cobra_LLJITBuilderSetNumCompileThreads(Builder: LLVMOrcLLJITBuilderRef, NumThreads: libc::c_uint);
+}
+
+/// Marker trait to constrain function signatures that can be looked up in the JIT.
+pub trait JitFn {}
+
+impl JitFn for unsafe extern "C" fn() -> f64 {}
+
+/// Functions raising Cobra runtime errors unwind through the JIT'd code to the caller.
+impl JitFn for unsafe extern "C-unwind" fn() -> f64 {}
+
+/// Top-level expressions returning a `bool`, only the lowest bit of the result is defined.
+impl JitFn for unsafe extern "C-unwind" fn() -> u8 {}
+
+/// Top-level expressions returning a `str`.
+impl JitFn for unsafe extern "C-unwind" fn() -> cobra_runtime::CobraStr {}
+
+/// Wrapper for a LLVM [LLJIT](https://www.llvm.org/docs/ORCv2.html#lljit-and-lllazyjit).
+pub struct LLJit {
+    jit: LLVMOrcLLJITRef,
+    dylib: LLVMOrcJITDylibRef,
+}
+
+impl LLJit {
+    /// Create a new LLJit instance.
+    ///
+    /// # Panics
+    ///
+    /// Panics if LLVM API returns a `null` pointer or an error.
+    pub fn new() -> LLJit {
+        LLJit::with_builder(std::ptr::null_mut() /* builder: nullptr -> default */)
+    }
+
+    /// Create a new LLJit instance, which compiles IR modules concurrently on a pool of
+    /// `threads` threads. See [`LLJit::materialize`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if LLVM API returns a `null` pointer or an error.
+    pub fn with_compile_threads(threads: usize) -> LLJit {
+        let builder = unsafe { LLVMOrcCreateLLJITBuilder() };
+        assert!(!builder.is_null());
+
+        let threads = libc::c_uint::try_from(threads).expect("Too many compile threads");
+        unsafe { cobra_LLJITBuilderSetNumCompileThreads(builder, threads) };
+        LLJit::with_builder(builder)
+    }
+
+    /// Create a new LLJit instance from `builder`, which is consumed.
+    fn with_builder(builder: LLVMOrcLLJITBuilderRef) -> LLJit {
+        let (jit, dylib) = unsafe {
+            let mut jit = std::ptr::null_mut();
+            let err = LLVMOrcCreateLLJIT(&mut jit as _, builder);
+
+            if let Some(err) = Error::from(err) {
+                panic!("Error: {}", err.as_str());
+            }
+
+            let dylib = LLVMOrcLLJITGetMainJITDylib(jit);
+            assert!(!dylib.is_null());
+
+            (jit, dylib)
+        };
+
+        LLJit { jit, dylib }
+    }
+
+    /// Add an LLVM IR module to the JIT. Return a [`ResourceTracker`], which when dropped, will
+    /// remove the code of the LLVM IR module from the JIT.
+    ///
+    /// # Panics
+    ///
+    /// Panics if LLVM API returns a `null` pointer or an error.
+    pub fn add_module(&self, module: Module) -> ResourceTracker<'_> {
+        let tsmod = module.into_raw_thread_safe_module();
+
+        let rt = unsafe {
+            let rt = LLVMOrcJITDylibCreateResourceTracker(self.dylib);
+            let err = LLVMOrcLLJITAddLLVMIRModuleWithRT(self.jit, rt, tsmod);
+
+            if let Some(err) = Error::from(err) {
+                panic!("Error: {}", err.as_str());
+            }
+
+            rt
+        };
+
+        ResourceTracker::new(rt)
+    }
+
+    /// Add a native object file, eg emitted by
+    /// [`TargetMachine::emit_object_to_memory`][super::TargetMachine::emit_object_to_memory], to
+    /// the JIT. Return a [`ResourceTracker`], which when dropped, will remove the code of the
+    /// object from the JIT.
+    ///
+    /// # Panics
+    ///
+    /// Panics if LLVM API returns a `null` pointer or an error.
+    pub fn add_object(&self, object: &[u8]) -> ResourceTracker<'_> {
+        let rt = unsafe {
+            // The JIT takes ownership of the buffer.
+            let buf = LLVMCreateMemoryBufferWithMemoryRangeCopy(
+                object.as_ptr() as *const libc::c_char,
+                object.len(),
+                c"object".as_ptr() as *const libc::c_char,
+            );
+            assert!(!buf.is_null());
+
+            let rt = LLVMOrcJITDylibCreateResourceTracker(self.dylib);
+            let err = LLVMOrcLLJITAddObjectFileWithRT(self.jit, rt, buf);
+
+            if let Some(err) = Error::from(err) {
+                panic!("Error: {}", err.as_str());
+            }
+
+            rt
+        };
+
+        ResourceTracker::new(rt)
+    }
+
+    /// Find the symbol with the name `sym` in the JIT.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the symbol is not found in the JIT or `sym` contains a null byte.
+    pub fn find_symbol<F: JitFn>(&self, sym: &str) -> F {
+        let addr = self.lookup(sym);
+
+        unsafe {
+            debug_assert_eq!(core::mem::size_of_val(&addr), core::mem::size_of::<F>());
+            std::mem::transmute_copy(&addr)
+        }
+    }
+
+    /// Get the address of the global variable with the name `sym` in the JIT.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the symbol is not found in the JIT or `sym` contains a null byte.
+    pub fn find_global<T>(&self, sym: &str) -> *mut T {
+        self.lookup(sym) as *mut T
+    }
+
+    fn lookup(&self, sym: &str) -> u64 {
+        let sym = SmallCStr::try_from(sym)
+            .expect("Failed to convert 'sym' argument to C string (contains a null byte)!");
+
+        unsafe {
+            let mut addr = 0u64;
+            let err = LLVMOrcLLJITLookup(self.jit, &mut addr as _, sym.as_ptr());
+
+            if let Some(err) = Error::from(err) {
+                panic!("Error: {}", err.as_str());
+            }
+
+            addr
+        }
+    }
+
+    /// Look up all the symbols `syms` at once, so the IR modules defining them are compiled
+    /// concurrently if the JIT has a compile thread pool. Otherwise modules are compiled one by one
+    /// when their symbols are first looked up.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a symbol is not found in the JIT or contains a null byte.
+    pub fn materialize(&self, syms: &[&str]) {
+        let mut lookup_set: Vec<_> = syms
+            .iter()
+            .map(|&sym| {
+                let sym = SmallCStr::try_from(sym)
+                    .expect("Failed to convert 'sym' argument to C string (contains a null byte)!");
+
+                LLVMOrcCLookupSetElement {
+                    Name: unsafe { LLVMOrcLLJITMangleAndIntern(self.jit, sym.as_ptr()) },
+                    LookupFlags: LLVMOrcSymbolLookupFlags::LLVMOrcSymbolLookupFlagsRequiredSymbol,
+                }
+            })
+            .collect();
+        let mut search_order = [LLVMOrcCJITDylibSearchOrderElement {
+            JD: self.dylib,
+            JDLookupFlags: LLVMOrcJITDylibLookupFlags::LLVMOrcJITDylibLookupFlagsMatchExportedSymbolsOnly,
+        }];
+
+        // The result may be reported from a compile thread.
+        extern "C" fn handle_result(err: LLVMErrorRef, _: LLVMOrcCSymbolMapPairs, _: usize, ctx: *mut libc::c_void) {
+            let tx = unsafe { Box::from_raw(ctx as *mut mpsc::Sender<Option<String>>) };
+            let _ = tx.send(Error::from(err).map(|err| err.as_str().to_string()));
+        }
+
+        let (tx, rx) = mpsc::channel::<Option<String>>();
+        unsafe {
+            LLVMOrcExecutionSessionLookup(
+                LLVMOrcLLJITGetExecutionSession(self.jit),
+                LLVMOrcLookupKind::LLVMOrcLookupKindStatic,
+                search_order.as_mut_ptr(),
+                search_order.len(),
+                lookup_set.as_mut_ptr(),
+                lookup_set.len(),
+                handle_result,
+                Box::into_raw(Box::new(tx)) as *mut libc::c_void,
+            );
+        }
+        let err = rx.recv().expect("Lookup result was not reported");
+
+        // The lookup retains the names it needs.
+        for element in lookup_set {
+            unsafe { LLVMOrcReleaseSymbolStringPoolEntry(element.Name) };
+        }
+        if let Some(err) = err {
+            panic!("Error: {}", err);
+        }
+    }
+
+    /// Enable lookup of dynamic symbols available in the current process from the JIT.
+    ///
+    /// # Panics
+    ///
+    /// Panics if LLVM API returns an error.
+    pub fn enable_process_symbols(&self) {
+        unsafe {
+            let mut proc_syms_gen: LLVMOrcDefinitionGeneratorRef = std::ptr::null_mut();
+            let err = LLVMOrcCreateDynamicLibrarySearchGeneratorForProcess(
+                &mut proc_syms_gen as _,
+                self.global_prefix(),
+                None,                 /* filter */
+                std::ptr::null_mut(), /* filter ctx */
+            );
+
+            if let Some(err) = Error::from(err) {
+                panic!("Error: {}", err.as_str());
+            }
+
+            LLVMOrcJITDylibAddGenerator(self.dylib, proc_syms_gen);
+        }
+    }
+
+    /// Define absolute symbols in the JIT, eg to make functions of the host process available to
+    /// JIT'd code independent of the symbols exported by the process.
+    ///
+    /// # Panics
+    ///
+    /// Panics if LLVM API returns an error (eg a symbol is already defined) or a name contains a
+    /// null byte.
+    pub fn define_symbols(&self, symbols: &[(&str, *const libc::c_void)]) {
+        let mut pairs: Vec<_> = symbols
+            .iter()
+            .map(|&(name, addr)| {
+                let name = SmallCStr::try_from(name).expect(
+                    "Failed to convert symbol name to C string (contains a null byte)!",
+                );
+
+                LLVMOrcCSymbolMapPair {
+                    // Takes ownership of the interned name.
+                    Name: unsafe { LLVMOrcLLJITMangleAndIntern(self.jit, name.as_ptr()) },
+                    Sym: LLVMJITEvaluatedSymbol {
+                        Address: addr as u64,
+                        // `LLVMJITSymbolFlags` isn't `Copy`, so it is created for every symbol.
+                        Flags: LLVMJITSymbolFlags {
+                            GenericFlags: LLVMJITSymbolGenericFlags::LLVMJITSymbolGenericFlagsExported as u8
+                                | LLVMJITSymbolGenericFlags::LLVMJITSymbolGenericFlagsCallable as u8,
+                            TargetFlags: 0,
+                        },
+                    },
+                }
+            })
+            .collect();
+
+        unsafe {
+            let mu = LLVMOrcAbsoluteSymbols(pairs.as_mut_ptr(), pairs.len());
+            let err = LLVMOrcJITDylibDefine(self.dylib, mu);
+
+            if let Some(err) = Error::from(err) {
+                // Ownership of the materialization unit is only transferred on success.
+                LLVMOrcDisposeMaterializationUnit(mu);
+                panic!("Error: {}", err.as_str());
+            }
+        }
+    }
+
+    /// Return the global prefix character according to the LLJITs data layout.
+    fn global_prefix(&self) -> libc::c_char {
+        unsafe { LLVMOrcLLJITGetGlobalPrefix(self.jit) }
+    }
+}
+
+impl Default for LLJit {
+    fn default() -> LLJit {
+        LLJit::new()
+    }
+}
+
+impl Drop for LLJit {
+    fn drop(&mut self) {
+        unsafe {
+            let err = LLVMOrcDisposeLLJIT(self.jit);
+
+            if let Some(err) = Error::from(err) {
+                panic!("Error: {}", err.as_str());
+            }
+        }
+    }
+}
+
+/// A resource handle for code added to an [`LLJit`] instance.
+///
+/// When a `ResourceTracker` handle is dropped, the code corresponding to the handle will be
+/// removed from the JIT.
+pub struct ResourceTracker<'jit>(LLVMOrcResourceTrackerRef, PhantomData<&'jit ()>);
+
+impl<'jit> ResourceTracker<'jit> {
+    fn new(rt: LLVMOrcResourceTrackerRef) -> ResourceTracker<'jit> {
+        assert!(!rt.is_null());
+        ResourceTracker(rt, PhantomData)
+    }
+}
+
+impl Drop for ResourceTracker<'_> {
+    fn drop(&mut self) {
+        unsafe {
+            let err = LLVMOrcResourceTrackerRemove(self.0);
+
+            if let Some(err) = Error::from(err) {
+                panic!("Error: {}", err.as_str());
+            }
+
+            LLVMOrcReleaseResourceTracker(self.0);
+        };
+    }
+}
\ No newline at end of file
@@ -22,6 +22,7 @@ mod builder;
 mod lljit;
 mod module;
 mod pass_manager;
+mod target_machine;
 mod type_;
 mod value;
 
@@ -30,6 +31,7 @@ pub use builder::IRBuilder;
 pub use lljit::{LLJit, ResourceTracker};
 pub use module::Module;
 pub use pass_manager::FunctionPassManager;
+pub use target_machine::TargetMachine;
 pub use type_::Type;
 pub use value::{FnValue, PhiValue, Value};
 
@@ -1,8 +1,13 @@
 use llvm_sys::{
     core::{
-        LLVMAddFunction, LLVMAppendBasicBlockInContext, LLVMCreateBasicBlockInContext,
+        LLVMAddFunction, LLVMAddGlobal, LLVMAppendBasicBlockInContext, LLVMConstInt,
+        LLVMConstNamedStruct, LLVMConstStringInContext, LLVMCreateBasicBlockInContext,
         LLVMDisposeModule, LLVMDoubleTypeInContext, LLVMDumpModule, LLVMGetNamedFunction,
-        LLVMModuleCreateWithNameInContext,
+        LLVMGetNamedGlobal, LLVMGetTypeByName2, LLVMInt1TypeInContext, LLVMInt32TypeInContext,
+        LLVMInt64TypeInContext, LLVMInt8TypeInContext, LLVMModuleCreateWithNameInContext,
+        LLVMPointerTypeInContext, LLVMSetGlobalConstant, LLVMSetInitializer, LLVMSetLinkage,
+        LLVMSetUnnamedAddress, LLVMStructCreateNamed, LLVMStructSetBody, LLVMTypeOf,
+        LLVMVoidTypeInContext,
     },
     orc2::{
         LLVMOrcCreateNewThreadSafeContext, LLVMOrcCreateNewThreadSafeModule,
@@ -10,12 +15,12 @@ use llvm_sys::{
         LLVMOrcThreadSafeContextRef, LLVMOrcThreadSafeModuleRef,
     },
     prelude::{LLVMBool, LLVMContextRef, LLVMModuleRef, LLVMTypeRef},
-    LLVMTypeKind,
+    LLVMLinkage, LLVMTypeKind, LLVMUnnamedAddr,
 };
 
 use std::convert::TryFrom;
 
-use super::{BasicBlock, FnValue, Type};
+use super::{BasicBlock, FnValue, Type, Value};
 use crate::SmallCStr;
 
 // Definition of LLVM C API functions using our `repr(transparent)` types.
@@ -42,6 +47,18 @@ impl<'llvm> Module {
     ///
     /// Panics if creating the context or the module fails.
     pub fn new() -> Self {
+        Module::with_name("module")
+    }
+
+    /// Create a new Module instance with the given module identifier `name`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if creating the context or the module fails or `name` contains a null byte.
+    pub fn with_name(name: &str) -> Self {
+        let name = SmallCStr::try_from(name)
+            .expect("Failed to convert 'name' argument to C string (contains a null byte)!");
+
         let (tsctx, ctx, module) = unsafe {
             // We generate a thread safe context because we are going to jit this IR module and
             // there is no method to create a thread safe context wrapper from an existing context
@@ -53,7 +70,7 @@ impl<'llvm> Module {
             assert!(!tc.is_null());
 
             let c = LLVMOrcThreadSafeContextGetContext(tc);
-            let m = LLVMModuleCreateWithNameInContext(b"module\0".as_ptr().cast(), c);
+            let m = LLVMModuleCreateWithNameInContext(name.as_ptr(), c);
             assert!(!c.is_null() && !m.is_null());
             (tc, c, m)
         };
@@ -107,6 +124,114 @@ impl<'llvm> Module {
         Type::new(type_ref)
     }
 
+    /// Get a type reference representing `void` (eg the return type of a function without
+    /// return value).
+    ///
+    /// # Panics
+    ///
+    /// Panics if LLVM API returns a `null` pointer.
+    pub fn type_void(&self) -> Type<'llvm> {
+        let type_ref = unsafe { LLVMVoidTypeInContext(self.ctx) };
+        Type::new(type_ref)
+    }
+
+    /// Get a type reference representing a `i1` integer, the type of booleans.
+    ///
+    /// # Panics
+    ///
+    /// Panics if LLVM API returns a `null` pointer.
+    pub fn type_i1(&self) -> Type<'llvm> {
+        let type_ref = unsafe { LLVMInt1TypeInContext(self.ctx) };
+        Type::new(type_ref)
+    }
+
+    /// Get a type reference representing a `i8` integer (a byte).
+    ///
+    /// # Panics
+    ///
+    /// Panics if LLVM API returns a `null` pointer.
+    pub fn type_i8(&self) -> Type<'llvm> {
+        let type_ref = unsafe { LLVMInt8TypeInContext(self.ctx) };
+        Type::new(type_ref)
+    }
+
+    /// Get a type reference representing a `i32` integer.
+    ///
+    /// # Panics
+    ///
+    /// Panics if LLVM API returns a `null` pointer.
+    pub fn type_i32(&self) -> Type<'llvm> {
+        let type_ref = unsafe { LLVMInt32TypeInContext(self.ctx) };
+        Type::new(type_ref)
+    }
+
+    /// Get a type reference representing a `i64` integer.
+    ///
+    /// # Panics
+    ///
+    /// Panics if LLVM API returns a `null` pointer.
+    pub fn type_i64(&self) -> Type<'llvm> {
+        let type_ref = unsafe { LLVMInt64TypeInContext(self.ctx) };
+        Type::new(type_ref)
+    }
+
+    /// Get a type reference representing an (opaque) pointer.
+    ///
+    /// # Panics
+    ///
+    /// Panics if LLVM API returns a `null` pointer.
+    pub fn type_ptr(&self) -> Type<'llvm> {
+        let type_ref = unsafe { LLVMPointerTypeInContext(self.ctx, 0 /* AddressSpace */) };
+        Type::new(type_ref)
+    }
+
+    /// Get a type reference representing the named struct `name`. If the struct type does not
+    /// exist in the context yet it is created with the given `fields`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if LLVM API returns a `null` pointer or `name` contains a null byte.
+    pub fn type_named_struct(&self, name: &str, fields: &mut [Type<'llvm>]) -> Type<'llvm> {
+        let name = SmallCStr::try_from(name)
+            .expect("Failed to convert 'name' argument to C string (contains a null byte)!");
+
+        let type_ref = unsafe {
+            let type_ref = LLVMGetTypeByName2(self.ctx, name.as_ptr());
+            if type_ref.is_null() {
+                let type_ref = LLVMStructCreateNamed(self.ctx, name.as_ptr());
+                LLVMStructSetBody(
+                    type_ref,
+                    fields.as_mut_ptr().cast(),
+                    fields.len() as libc::c_uint,
+                    0, /* Packed */
+                );
+                type_ref
+            } else {
+                type_ref
+            }
+        };
+        Type::new(type_ref)
+    }
+
+    /// Get a type reference representing a Cobra `str`, a `{ ptr, i64 }` pointer/length pair.
+    ///
+    /// # Panics
+    ///
+    /// Panics if LLVM API returns a `null` pointer.
+    pub fn type_str(&self) -> Type<'llvm> {
+        self.type_named_struct("str", &mut [self.type_ptr(), self.type_i64()])
+    }
+
+    /// Get a type reference representing a Cobra array of `f64`, a `{ ptr, i64 }` pointer/length
+    /// pair referencing heap allocated elements.
+    ///
+    /// # Panics
+    ///
+    /// Panics if LLVM API returns a `null` pointer.
+    pub fn type_array(&self) -> Type<'llvm> {
+        self.type_named_struct("array", &mut [self.type_ptr(), self.type_i64()])
+    }
+
     /// Get a type reference representing a `fn(args) -> ret` function.
     ///
     /// # Panics
@@ -129,8 +254,7 @@ impl<'llvm> Module {
     ///
     /// # Panics
     ///
-    /// Panics if LLVM API returns a `null` pointer or `name` could not be converted to a
-    /// [`SmallCStr`].
+    /// Panics if LLVM API returns a `null` pointer or `name` contains a null byte.
     pub fn add_fn(&'llvm self, name: &str, fn_type: Type<'llvm>) -> FnValue<'llvm> {
         debug_assert_eq!(
             fn_type.kind(),
@@ -139,7 +263,7 @@ impl<'llvm> Module {
         );
 
         let name = SmallCStr::try_from(name)
-            .expect("Failed to convert 'name' argument to small C string!");
+            .expect("Failed to convert 'name' argument to C string (contains a null byte)!");
 
         let value_ref = unsafe { LLVMAddFunction(self.module, name.as_ptr(), fn_type.type_ref()) };
         FnValue::new(value_ref)
@@ -150,16 +274,89 @@ impl<'llvm> Module {
     ///
     /// # Panics
     ///
-    /// Panics if `name` could not be converted to a [`SmallCStr`].
+    /// Panics if `name` contains a null byte.
     pub fn get_fn(&'llvm self, name: &str) -> Option<FnValue<'llvm>> {
         let name = SmallCStr::try_from(name)
-            .expect("Failed to convert 'name' argument to small C string!");
+            .expect("Failed to convert 'name' argument to C string (contains a null byte)!");
 
         let value_ref = unsafe { LLVMGetNamedFunction(self.module, name.as_ptr()) };
 
         (!value_ref.is_null()).then(|| FnValue::new(value_ref))
     }
 
+    /// Add a global variable `name` of type `ty` to the module.
+    ///
+    /// Until an initializer is set with [`set_initializer`][Module::set_initializer], the global
+    /// is a declaration of a global variable defined in another module.
+    ///
+    /// # Panics
+    ///
+    /// Panics if LLVM API returns a `null` pointer or `name` contains a null byte.
+    pub fn add_global(&'llvm self, ty: Type<'llvm>, name: &str) -> Value<'llvm> {
+        let name = SmallCStr::try_from(name)
+            .expect("Failed to convert 'name' argument to C string (contains a null byte)!");
+
+        let value_ref = unsafe { LLVMAddGlobal(self.module, ty.type_ref(), name.as_ptr()) };
+        Value::new(value_ref)
+    }
+
+    /// Get the global variable with the given `name` if it was previously added to the module
+    /// with [`add_global`][Module::add_global].
+    ///
+    /// # Panics
+    ///
+    /// Panics if `name` contains a null byte.
+    pub fn get_global(&'llvm self, name: &str) -> Option<Value<'llvm>> {
+        let name = SmallCStr::try_from(name)
+            .expect("Failed to convert 'name' argument to C string (contains a null byte)!");
+
+        let value_ref = unsafe { LLVMGetNamedGlobal(self.module, name.as_ptr()) };
+
+        (!value_ref.is_null()).then(|| Value::new(value_ref))
+    }
+
+    /// Set the initial value of the global variable `global`, turning a declaration into a
+    /// definition.
+    pub fn set_initializer(&'llvm self, global: Value<'llvm>, value: Value<'llvm>) {
+        debug_assert_eq!(
+            global.type_of().kind(),
+            LLVMTypeKind::LLVMPointerTypeKind,
+            "Expected a global variable when setting an initializer!"
+        );
+
+        unsafe { LLVMSetInitializer(global.value_ref(), value.value_ref()) };
+    }
+
+    /// Add a private constant global holding the bytes of `value` (without a terminating null
+    /// byte) to the module and return a const `str` value referencing it.
+    ///
+    /// # Panics
+    ///
+    /// Panics if LLVM API returns a `null` pointer.
+    pub fn add_global_str(&'llvm self, value: &str) -> Value<'llvm> {
+        let value_ref = unsafe {
+            let data = LLVMConstStringInContext(
+                self.ctx,
+                value.as_ptr().cast(),
+                value.len() as libc::c_uint,
+                1, /* DontNullTerminate */
+            );
+            assert!(!data.is_null());
+
+            let global = LLVMAddGlobal(self.module, LLVMTypeOf(data), c".str".as_ptr().cast());
+            assert!(!global.is_null());
+            LLVMSetInitializer(global, data);
+            LLVMSetGlobalConstant(global, 1);
+            LLVMSetLinkage(global, LLVMLinkage::LLVMPrivateLinkage);
+            LLVMSetUnnamedAddress(global, LLVMUnnamedAddr::LLVMGlobalUnnamedAddr);
+
+            let len = LLVMConstInt(self.type_i64().type_ref(), value.len() as u64, 0);
+            let mut fields = [global, len];
+            LLVMConstNamedStruct(self.type_str().type_ref(), fields.as_mut_ptr(), 2)
+        };
+        Value::new(value_ref)
+    }
+
     /// Append a Basic Block to the end of the function referenced by the value reference
     /// `fn_value`.
     ///
@@ -171,7 +368,7 @@ impl<'llvm> Module {
             LLVMAppendBasicBlockInContext(
                 self.ctx,
                 fn_value.value_ref(),
-                b"block\0".as_ptr().cast(),
+                c"block".as_ptr().cast(),
             )
         };
         assert!(!block.is_null());
@@ -187,13 +384,23 @@ impl<'llvm> Module {
     ///
     /// Panics if LLVM API returns a `null` pointer.
     pub fn create_basic_block(&self) -> BasicBlock<'llvm> {
-        let block = unsafe { LLVMCreateBasicBlockInContext(self.ctx, b"block\0".as_ptr().cast()) };
+        let block = unsafe { LLVMCreateBasicBlockInContext(self.ctx, c"block".as_ptr().cast()) };
         assert!(!block.is_null());
 
         BasicBlock::new(block)
     }
 }
 
+impl Default for Module {
+    fn default() -> Self {
+        Module::new()
+    }
+}
+
+// The module and its context are only referenced through the `Module`, so it can be moved to
+// another thread, eg from a compile worker to the thread adding it to the JIT.
+unsafe impl Send for Module {}
+
 impl Drop for Module {
     fn drop(&mut self) {
         unsafe {
@@ -0,0 +1,157 @@
+use llvm_sys::{
+    core::{
+        LLVMDisposeMemoryBuffer, LLVMDisposeMessage, LLVMGetBufferSize, LLVMGetBufferStart,
+        LLVMSetTarget,
+    },
+    target::{LLVMDisposeTargetData, LLVMSetModuleDataLayout},
+    target_machine::{
+        LLVMCodeGenFileType, LLVMCodeGenOptLevel, LLVMCodeModel, LLVMCreateTargetDataLayout,
+        LLVMCreateTargetMachine, LLVMDisposeTargetMachine, LLVMGetDefaultTargetTriple,
+        LLVMGetHostCPUFeatures, LLVMGetHostCPUName, LLVMGetTargetFromTriple, LLVMRelocMode,
+        LLVMTargetMachineEmitToFile, LLVMTargetMachineEmitToMemoryBuffer, LLVMTargetMachineRef,
+    },
+};
+
+use std::convert::TryFrom;
+use std::ffi::CStr;
+use std::path::Path;
+
+use super::Module;
+use crate::SmallCStr;
+
+/// Wrapper for a LLVM Target Machine, used to emit object files for ahead-of-time compilation
+/// and the object cache of the JIT.
+pub struct TargetMachine {
+    tm: LLVMTargetMachineRef,
+    triple: *mut libc::c_char,
+}
+
+impl TargetMachine {
+    /// Create a Target Machine for the host. The native target must have been initialized with
+    /// [`initialize_native_taget`][super::initialize_native_taget].
+    ///
+    /// # Panics
+    ///
+    /// Panics if LLVM API returns a `null` pointer or an error.
+    pub fn native() -> TargetMachine {
+        unsafe {
+            let triple = LLVMGetDefaultTargetTriple();
+            assert!(!triple.is_null());
+
+            let mut target = std::ptr::null_mut();
+            let mut err = std::ptr::null_mut();
+            if LLVMGetTargetFromTriple(triple, &mut target as _, &mut err as _) != 0 {
+                panic!("Error: {}", take_message(err));
+            }
+
+            let cpu = LLVMGetHostCPUName();
+            let features = LLVMGetHostCPUFeatures();
+            let tm = LLVMCreateTargetMachine(
+                target,
+                triple,
+                cpu,
+                features,
+                LLVMCodeGenOptLevel::LLVMCodeGenLevelDefault,
+                LLVMRelocMode::LLVMRelocPIC,
+                LLVMCodeModel::LLVMCodeModelDefault,
+            );
+            LLVMDisposeMessage(cpu);
+            LLVMDisposeMessage(features);
+            assert!(!tm.is_null());
+
+            TargetMachine { tm, triple }
+        }
+    }
+
+    /// Emit the code of `module` as a native object file at `path`.
+    ///
+    /// Sets the target triple and data layout of `module` to the ones of the Target Machine.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `path` is not valid UTF8 or contains a null byte.
+    pub fn emit_object(&self, module: &Module, path: &Path) -> Result<(), String> {
+        let path = path.to_str().expect("Expected UTF8 path for object file!");
+        let path = SmallCStr::try_from(path)
+            .expect("Failed to convert 'path' argument to C string (contains a null byte)!");
+
+        self.set_target(module);
+        unsafe {
+            let mut err = std::ptr::null_mut();
+            if LLVMTargetMachineEmitToFile(
+                self.tm,
+                module.module(),
+                path.as_ptr() as *mut _,
+                LLVMCodeGenFileType::LLVMObjectFile,
+                &mut err as _,
+            ) != 0
+            {
+                return Err(take_message(err));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Emit the code of `module` as a native object file in memory.
+    ///
+    /// Sets the target triple and data layout of `module` to the ones of the Target Machine.
+    pub fn emit_object_to_memory(&self, module: &Module) -> Result<Vec<u8>, String> {
+        self.set_target(module);
+        unsafe {
+            let mut buf = std::ptr::null_mut();
+            let mut err = std::ptr::null_mut();
+            if LLVMTargetMachineEmitToMemoryBuffer(
+                self.tm,
+                module.module(),
+                LLVMCodeGenFileType::LLVMObjectFile,
+                &mut err as _,
+                &mut buf as _,
+            ) != 0
+            {
+                return Err(take_message(err));
+            }
+
+            let start = LLVMGetBufferStart(buf) as *const u8;
+            let object = std::slice::from_raw_parts(start, LLVMGetBufferSize(buf)).to_vec();
+            LLVMDisposeMemoryBuffer(buf);
+            Ok(object)
+        }
+    }
+
+    /// Get the target triple of the Target Machine.
+    pub fn triple(&self) -> &str {
+        unsafe { CStr::from_ptr(self.triple) }
+            .to_str()
+            .expect("Expected valid UTF8 string from LLVM API")
+    }
+
+    fn set_target(&self, module: &Module) {
+        unsafe {
+            LLVMSetTarget(module.module(), self.triple);
+            let data_layout = LLVMCreateTargetDataLayout(self.tm);
+            LLVMSetModuleDataLayout(module.module(), data_layout);
+            LLVMDisposeTargetData(data_layout);
+        }
+    }
+}
+
+impl Drop for TargetMachine {
+    fn drop(&mut self) {
+        unsafe {
+            LLVMDisposeTargetMachine(self.tm);
+            LLVMDisposeMessage(self.triple);
+        }
+    }
+}
+
+/// Copy and dispose an error message returned by the LLVM API.
+unsafe fn take_message(msg: *mut libc::c_char) -> String {
+    if msg.is_null() {
+        return String::from("unknown error");
+    }
+
+    let s = CStr::from_ptr(msg).to_string_lossy().into_owned();
+    LLVMDisposeMessage(msg);
+    s
+}
@@ -1,15 +1,24 @@
 use llvm_sys::{
-    core::{LLVMConstReal, LLVMDumpType, LLVMGetTypeKind},
+    core::{
+        LLVMConstInt, LLVMConstNull, LLVMConstReal, LLVMDumpType, LLVMGetStructName,
+        LLVMDisposeMessage, LLVMGetTypeKind, LLVMGetUndef, LLVMPrintTypeToString, LLVMSizeOf,
+        LLVMStructGetTypeAtIndex,
+    },
     prelude::LLVMTypeRef,
     LLVMTypeKind,
 };
 
+use std::ffi::CStr;
+use std::fmt;
 use std::marker::PhantomData;
 
 use super::Value;
 
 /// Wrapper for a LLVM Type Reference.
-#[derive(Copy, Clone)]
+///
+/// Types are uniqued in their context, so two type references are equal if they represent the
+/// same type.
+#[derive(Copy, Clone, PartialEq)]
 #[repr(transparent)]
 pub struct Type<'llvm>(LLVMTypeRef, PhantomData<&'llvm ()>);
 
@@ -35,6 +44,49 @@ impl<'llvm> Type<'llvm> {
         unsafe { LLVMGetTypeKind(self.type_ref()) }
     }
 
+    /// Check if type is a struct type.
+    pub fn is_struct(&self) -> bool {
+        self.kind() == LLVMTypeKind::LLVMStructTypeKind
+    }
+
+    /// Get the name of a named struct type, `None` for any other type.
+    pub fn struct_name(&self) -> Option<&'llvm str> {
+        if !self.is_struct() {
+            return None;
+        }
+
+        let name = unsafe { LLVMGetStructName(self.type_ref()) };
+        if name.is_null() {
+            return None;
+        }
+
+        // Struct names live as long as the struct type in the context.
+        let name = unsafe { CStr::from_ptr(name) };
+        Some(name.to_str().expect("Expected valid UTF8 string from LLVM API"))
+    }
+
+    /// Get the type of the field at index `idx` of a struct type.
+    ///
+    /// # Panics
+    ///
+    /// Panics if LLVM API returns a `null` pointer.
+    pub fn field_type(&self, idx: u32) -> Type<'llvm> {
+        debug_assert!(self.is_struct(), "Expected a struct type when getting a field type!");
+
+        let type_ref = unsafe { LLVMStructGetTypeAtIndex(self.type_ref(), idx as libc::c_uint) };
+        Type::new(type_ref)
+    }
+
+    /// Get the allocation size of this type in bytes as a const `i64` value.
+    ///
+    /// # Panics
+    ///
+    /// Panics if LLVM API returns a `null` pointer.
+    pub fn size_of(self) -> Value<'llvm> {
+        let value_ref = unsafe { LLVMSizeOf(self.type_ref()) };
+        Value::new(value_ref)
+    }
+
     /// Dump the LLVM Type to stdout.
     pub fn dump(&self) {
         unsafe { LLVMDumpType(self.type_ref()) };
@@ -55,4 +107,51 @@ impl<'llvm> Type<'llvm> {
         let value_ref = unsafe { LLVMConstReal(self.type_ref(), n) };
         Value::new(value_ref)
     }
-}
\ No newline at end of file
+
+    /// Get a value reference representing the const integer value `n`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if LLVM API returns a `null` pointer.
+    pub fn const_int(self, n: u64) -> Value<'llvm> {
+        debug_assert_eq!(
+            self.kind(),
+            LLVMTypeKind::LLVMIntegerTypeKind,
+            "Expected an integer type when creating const int value!"
+        );
+
+        let value_ref = unsafe { LLVMConstInt(self.type_ref(), n, 0 /* SignExtend */) };
+        Value::new(value_ref)
+    }
+
+    /// Get the null value of this type, eg a null pointer.
+    ///
+    /// # Panics
+    ///
+    /// Panics if LLVM API returns a `null` pointer.
+    pub fn const_null(self) -> Value<'llvm> {
+        let value_ref = unsafe { LLVMConstNull(self.type_ref()) };
+        Value::new(value_ref)
+    }
+
+    /// Get an [undef](https://llvm.org/docs/LangRef.html#undefined-values) value of this type,
+    /// eg as the initial aggregate to build a struct value with `insertvalue`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if LLVM API returns a `null` pointer.
+    pub fn undef(self) -> Value<'llvm> {
+        let value_ref = unsafe { LLVMGetUndef(self.type_ref()) };
+        Value::new(value_ref)
+    }
+}
+
+impl fmt::Display for Type<'_> {
+    /// Format the type in LLVM IR syntax, eg `double` or `%str`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let message = unsafe { LLVMPrintTypeToString(self.type_ref()) };
+        let res = write!(f, "{}", unsafe { CStr::from_ptr(message) }.to_string_lossy());
+        unsafe { LLVMDisposeMessage(message) };
+        res
+    }
+}
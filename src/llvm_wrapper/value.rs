@@ -3,12 +3,14 @@
 use llvm_sys::{
     analysis::{LLVMVerifierFailureAction, LLVMVerifyFunction},
     core::{
-        LLVMAddIncoming, LLVMAppendExistingBasicBlock, LLVMCountBasicBlocks, LLVMCountParams,
-        LLVMDumpValue, LLVMGetParam, LLVMGetReturnType, LLVMGetValueKind, LLVMGetValueName2,
-        LLVMIsAFunction, LLVMIsAPHINode, LLVMSetValueName2, LLVMTypeOf,
+        LLVMAddIncoming, LLVMAppendExistingBasicBlock, LLVMConstRealGetDouble,
+        LLVMCountBasicBlocks, LLVMCountParams, LLVMDeleteFunction, LLVMDumpValue,
+        LLVMGetIntTypeWidth, LLVMGetParam, LLVMGetReturnType, LLVMGetValueKind, LLVMGetValueName2,
+        LLVMGlobalGetValueType, LLVMIsAConstantFP, LLVMIsAFunction, LLVMIsAPHINode, LLVMSetLinkage,
+        LLVMSetPersonalityFn, LLVMSetValueName2, LLVMTypeOf,
     },
     prelude::LLVMValueRef,
-    LLVMTypeKind, LLVMValueKind,
+    LLVMLinkage, LLVMTypeKind, LLVMValueKind,
 };
 
 use std::ffi::CStr;
@@ -17,6 +19,7 @@ use std::ops::Deref;
 
 use super::BasicBlock;
 use super::Type;
+use crate::SmallCStr;
 
 /// Wrapper for a LLVM Value Reference.
 #[derive(Copy, Clone)]
@@ -76,9 +79,11 @@ impl<'llvm> Value<'llvm> {
     ///
     /// # Panics
     ///
-    /// Panics if LLVM API returns a `null` pointer.
+    /// Panics if `name` contains a null byte.
     pub fn set_name(&self, name: &str) {
-        unsafe { LLVMSetValueName2(self.value_ref(), name.as_ptr().cast(), name.len()) };
+        let name = SmallCStr::try_from(name)
+            .expect("Failed to convert 'name' argument to C string (contains a null byte)!");
+        unsafe { LLVMSetValueName2(self.value_ref(), name.as_ptr(), name.len()) };
     }
 
     /// Get the name for the given value reference.
@@ -109,6 +114,22 @@ impl<'llvm> Value<'llvm> {
     pub fn is_int(&self) -> bool {
         self.type_of().kind() == LLVMTypeKind::LLVMIntegerTypeKind
     }
+
+    /// Check if value is a boolean (of `i1` type).
+    pub fn is_bool(&self) -> bool {
+        self.is_int() && unsafe { LLVMGetIntTypeWidth(self.type_of().type_ref()) } == 1
+    }
+
+    /// Get the value of a floating point constant, or `None` if the value is not one.
+    pub fn const_f64_value(&self) -> Option<f64> {
+        unsafe {
+            if LLVMIsAConstantFP(self.value_ref()).is_null() {
+                return None;
+            }
+            let mut loses_info = 0;
+            Some(LLVMConstRealGetDouble(self.value_ref(), &mut loses_info))
+        }
+    }
 }
 
 /// Wrapper for a LLVM Value Reference specialized for contexts where function values are needed.
@@ -139,13 +160,25 @@ impl<'llvm> FnValue<'llvm> {
         FnValue(value)
     }
 
+    /// Get a type reference representing the function type of the given function value.
+    ///
+    /// # Panics
+    ///
+    /// Panics if LLVM API returns a `null` pointer.
+    pub fn fn_type(&self) -> Type<'llvm> {
+        // With opaque pointers the type of a function value is `ptr`, the function type is the
+        // value type of the global.
+        let type_ref = unsafe { LLVMGlobalGetValueType(self.value_ref()) };
+        Type::new(type_ref)
+    }
+
     /// Get a type reference representing the return value of the given function value.
     ///
     /// # Panics
     ///
     /// Panics if LLVM API returns a `null` pointer.
     pub fn ret_type(&self) -> Type<'llvm> {
-        let type_ref = unsafe { LLVMGetReturnType(LLVMTypeOf(self.value_ref())) };
+        let type_ref = unsafe { LLVMGetReturnType(self.fn_type().type_ref()) };
         Type::new(type_ref)
     }
 
@@ -178,6 +211,21 @@ impl<'llvm> FnValue<'llvm> {
         }
     }
 
+    /// Give the function private linkage, so it is only visible within its module.
+    pub fn set_private(&self) {
+        unsafe { LLVMSetLinkage(self.value_ref(), LLVMLinkage::LLVMPrivateLinkage) };
+    }
+
+    /// Set the personality function called by the unwinder for the landing pads of the function.
+    pub fn set_personality(&self, personality: FnValue<'llvm>) {
+        unsafe { LLVMSetPersonalityFn(self.value_ref(), personality.value_ref()) };
+    }
+
+    /// Delete the function from its module.
+    pub fn delete(self) {
+        unsafe { LLVMDeleteFunction(self.value_ref()) };
+    }
+
     /// Verify that the given function is valid.
     pub fn verify(&self) -> bool {
         unsafe {
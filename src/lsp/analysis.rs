@@ -0,0 +1,249 @@
+//! Analysis of Cobra documents for the language server.
+//!
+//! Documents are parsed with the [`Parser`] of the compiler, without compiling them. Parse errors
+//! become diagnostics, and the top-level definitions become symbols located by the source
+//! locations of their tokens.
+
+use std::collections::HashMap;
+
+use crate::lexer::{Lexer, SourceLoc, Token};
+use crate::parser::{Parser, PrototypeAST};
+use crate::prelude;
+
+/// A span of source text, `end` is exclusive.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Span {
+    pub start: SourceLoc,
+    pub end: SourceLoc,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum SymbolKind {
+    Function,
+    Extern,
+    Struct,
+    Enum,
+    Const,
+    Global,
+    Test,
+}
+
+/// A top-level definition of a document.
+#[derive(Debug, PartialEq)]
+pub struct Symbol {
+    pub name: String,
+    pub kind: SymbolKind,
+    /// The whole definition.
+    pub span: Span,
+    /// The name in the definition.
+    pub name_span: Span,
+}
+
+/// A parse error of a document.
+#[derive(Debug, PartialEq)]
+pub struct Diagnostic {
+    pub span: Span,
+    pub message: String,
+}
+
+/// The result of analyzing a document.
+pub struct Analysis {
+    pub symbols: Vec<Symbol>,
+    pub diagnostics: Vec<Diagnostic>,
+    /// Prototypes of the prelude functions and the functions defined by the document, by name.
+    pub fn_protos: HashMap<String, PrototypeAST>,
+}
+
+impl Analysis {
+    /// Find the definition of `name` in the document.
+    pub fn find_symbol(&self, name: &str) -> Option<&Symbol> {
+        self.symbols.iter().find(|symbol| symbol.name == name && symbol.kind != SymbolKind::Test)
+    }
+
+    /// Get the signature of the function `name`, eg `def max[T](a: T, b: T) -> T`.
+    pub fn signature(&self, name: &str) -> Option<String> {
+        let proto = self.fn_protos.get(name)?;
+        let keyword = match self.find_symbol(name) {
+            Some(symbol) if symbol.kind == SymbolKind::Extern => "extern",
+            _ => "def",
+        };
+
+        // Prelude prototypes are named after the implementing symbol, not the Cobra name.
+        let mut signature = format!("{} {}", keyword, name);
+        if !proto.type_params.is_empty() {
+            signature.push_str(&format!("[{}]", proto.type_params.join(", ")));
+        }
+        let args: Vec<_> = proto
+            .args
+            .iter()
+            .zip(&proto.arg_types)
+            .map(|(arg, ty)| format!("{}: {}", arg, ty))
+            .collect();
+        signature.push_str(&format!("({}) -> {}", args.join(", "), proto.ret_type));
+        Some(signature)
+    }
+}
+
+/// Parse `source`, collecting its top-level definitions and parse errors.
+///
+/// Like the interpreter, parsing continues with the next token after an error. The diagnostic
+/// spans the token at which the error was found, a malformed literal is reported with the error
+/// of the lexer.
+pub fn analyze(source: &str) -> Analysis {
+    let mut analysis = Analysis {
+        symbols: Vec::new(),
+        diagnostics: Vec::new(),
+        fn_protos: HashMap::new(),
+    };
+    prelude::register_prelude(&mut analysis.fn_protos);
+
+    let mut parser = Parser::new(Lexer::new(source.chars()));
+    parser.get_next_token();
+    while *parser.current_token() != Token::Eof {
+        if let Err(message) = parse_entry(&mut parser, source, &mut analysis) {
+            let message = match parser.current_token() {
+                Token::Error(error) => error.clone(),
+                _ => message,
+            };
+            let start = parser.current_loc();
+            parser.get_next_token();
+            let span = Span { start, end: parser.previous_end() };
+            analysis.diagnostics.push(Diagnostic { span, message });
+        }
+    }
+    analysis
+}
+
+/// Parse the top-level entry at the current token, adding the symbol it defines to `analysis`.
+fn parse_entry<I>(parser: &mut Parser<I>, source: &str, analysis: &mut Analysis) -> Result<(), String>
+where
+    I: Iterator<Item = char>,
+{
+    let start = parser.current_loc();
+    let offset = parser.current_offset();
+
+    let (name, kind) = match parser.current_token() {
+        Token::Char(';') => {
+            parser.get_next_token();
+            return Ok(());
+        }
+        // Commands of interactive sessions, their arguments are parsed as expressions.
+        Token::Char(':') => {
+            parser.get_next_token();
+            parser.get_next_token();
+            return Ok(());
+        }
+        Token::Import | Token::From => {
+            parser.parse_import()?;
+            return Ok(());
+        }
+        Token::Def => {
+            let function = parser.parse_definition()?;
+            let name = function.0.name.clone();
+            analysis.fn_protos.insert(name.clone(), function.0);
+            (name, SymbolKind::Function)
+        }
+        Token::Extern => {
+            let proto = parser.parse_external()?;
+            let name = proto.name.clone();
+            analysis.fn_protos.insert(name.clone(), proto);
+            (name, SymbolKind::Extern)
+        }
+        Token::Struct => (parser.parse_struct()?.name, SymbolKind::Struct),
+        Token::Enum => (parser.parse_enum()?.name, SymbolKind::Enum),
+        Token::Const => (parser.parse_const()?.name, SymbolKind::Const),
+        Token::Global => (parser.parse_global()?.name, SymbolKind::Global),
+        Token::Test => (parser.parse_test()?.name, SymbolKind::Test),
+        _ => {
+            parser.parse_top_level_expr()?;
+            return Ok(());
+        }
+    };
+
+    let span = Span { start, end: parser.previous_end() };
+    // The name follows the keyword, tests are named by a string literal.
+    let name_span = match source[offset..].find(&name) {
+        Some(found) if kind != SymbolKind::Test => {
+            let name_start = advance(start, &source[offset..offset + found]);
+            Span { start: name_start, end: advance(name_start, &name) }
+        }
+        _ => span,
+    };
+    analysis.symbols.push(Symbol { name, kind, span, name_span });
+    Ok(())
+}
+
+/// Get the location after the text `text` starting at `loc`.
+fn advance(mut loc: SourceLoc, text: &str) -> SourceLoc {
+    for c in text.chars() {
+        if c == '\n' {
+            loc.line += 1;
+            loc.col = 1;
+        } else {
+            loc.col += 1;
+        }
+    }
+    loc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn loc(line: u32, col: u32) -> SourceLoc {
+        SourceLoc { line, col }
+    }
+
+    fn span(start: (u32, u32), end: (u32, u32)) -> Span {
+        Span { start: loc(start.0, start.1), end: loc(end.0, end.1) }
+    }
+
+    #[test]
+    fn signatures() {
+        let analysis = analyze("def max[T](a: T, b: T) -> T if a > b then a else b\nextern cos(x)\ndef add(a b) a + b");
+        assert_eq!(analysis.signature("max").unwrap(), "def max[T](a: T, b: T) -> T");
+        assert_eq!(analysis.signature("cos").unwrap(), "extern cos(x: f64) -> f64");
+        assert_eq!(analysis.signature("add").unwrap(), "def add(a: f64, b: f64) -> f64");
+        assert_eq!(analysis.signature("sub"), None);
+    }
+
+    #[test]
+    fn symbols_are_located_by_their_name() {
+        let analysis = analyze("def add(a, b)\n  a + b\n\ntest \"add\": assert add(1, 2) == 3");
+        let function = analysis.find_symbol("add").unwrap();
+        assert_eq!(function.kind, SymbolKind::Function);
+        assert_eq!(function.span, span((1, 1), (2, 8)));
+        assert_eq!(function.name_span, span((1, 5), (1, 8)));
+
+        // Tests are named by their string literal.
+        let test = &analysis.symbols[1];
+        assert_eq!(test.kind, SymbolKind::Test);
+        assert_eq!(test.name_span, test.span);
+        assert!(analysis.diagnostics.is_empty());
+    }
+
+    #[test]
+    fn parse_errors_continue_with_the_next_token() {
+        let analysis = analyze("def f(x) x + ;\ndef g(y) y");
+        assert_eq!(
+            analysis.diagnostics,
+            [Diagnostic { span: span((1, 14), (1, 15)), message: "Expected primary expression, found Char(';')".to_string() }]
+        );
+        assert!(analysis.find_symbol("f").is_none());
+        assert!(analysis.find_symbol("g").is_some());
+    }
+
+    #[test]
+    fn malformed_literals_are_reported_at_the_literal() {
+        let analysis = analyze("def f(x) x + \"a\\qb\"\nconst c = 1.2.3\ndef g(y) y\ndef h() \"abc");
+        assert_eq!(
+            analysis.diagnostics,
+            [
+                Diagnostic { span: span((1, 14), (1, 20)), message: "Unknown escape sequence '\\q'".to_string() },
+                Diagnostic { span: span((2, 11), (2, 16)), message: "Invalid number 1.2.3".to_string() },
+                Diagnostic { span: span((4, 9), (4, 13)), message: "Unterminated string literal".to_string() },
+            ]
+        );
+        assert!(analysis.find_symbol("g").is_some());
+    }
+}
@@ -0,0 +1,355 @@
+//! Minimal JSON values for the messages of the language server.
+
+use std::fmt::{self, Write};
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    /// Members in source order.
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    /// Create an object from `(key, value)` pairs.
+    pub fn object(members: Vec<(&str, Json)>) -> Json {
+        Json::Object(members.into_iter().map(|(key, value)| (key.to_string(), value)).collect())
+    }
+
+    /// Get the member `key` of an object, `None` if `self` isn't an object or has no such member.
+    pub fn get(&self, key: &str) -> Option<&Json> {
+        match self {
+            Json::Object(members) => members.iter().find(|(k, _)| k == key).map(|(_, value)| value),
+            _ => None,
+        }
+    }
+
+    /// Follow the members named by `path`, eg `["textDocument", "uri"]`.
+    pub fn path(&self, path: &[&str]) -> Option<&Json> {
+        path.iter().try_fold(self, |value, key| value.get(key))
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Json::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Json::Number(n) => Some(*n),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[Json]> {
+        match self {
+            Json::Array(values) => Some(values),
+            _ => None,
+        }
+    }
+
+    /// Parse the JSON document `text`.
+    pub fn parse(text: &str) -> Result<Json, String> {
+        let mut parser = JsonParser { text, pos: 0 };
+        let value = parser.parse_value()?;
+        parser.skip_whitespace();
+        if parser.pos < text.len() {
+            return Err(format!("Unexpected trailing characters at {}", parser.pos));
+        }
+        Ok(value)
+    }
+}
+
+impl From<&str> for Json {
+    fn from(s: &str) -> Json {
+        Json::String(s.to_string())
+    }
+}
+
+impl From<String> for Json {
+    fn from(s: String) -> Json {
+        Json::String(s)
+    }
+}
+
+impl From<u32> for Json {
+    fn from(n: u32) -> Json {
+        Json::Number(n as f64)
+    }
+}
+
+impl From<bool> for Json {
+    fn from(b: bool) -> Json {
+        Json::Bool(b)
+    }
+}
+
+impl fmt::Display for Json {
+    /// Serialize the value without whitespace.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Json::Null => f.write_str("null"),
+            Json::Bool(b) => write!(f, "{}", b),
+            // JSON has no infinities or NaN.
+            Json::Number(n) if !n.is_finite() => f.write_str("null"),
+            Json::Number(n) => write!(f, "{}", n),
+            Json::String(s) => write_string(f, s),
+            Json::Array(values) => {
+                f.write_char('[')?;
+                for (i, value) in values.iter().enumerate() {
+                    if i > 0 {
+                        f.write_char(',')?;
+                    }
+                    write!(f, "{}", value)?;
+                }
+                f.write_char(']')
+            }
+            Json::Object(members) => {
+                f.write_char('{')?;
+                for (i, (key, value)) in members.iter().enumerate() {
+                    if i > 0 {
+                        f.write_char(',')?;
+                    }
+                    write_string(f, key)?;
+                    write!(f, ":{}", value)?;
+                }
+                f.write_char('}')
+            }
+        }
+    }
+}
+
+fn write_string(f: &mut fmt::Formatter<'_>, s: &str) -> fmt::Result {
+    f.write_char('"')?;
+    for c in s.chars() {
+        match c {
+            '"' => f.write_str("\\\"")?,
+            '\\' => f.write_str("\\\\")?,
+            '\n' => f.write_str("\\n")?,
+            '\r' => f.write_str("\\r")?,
+            '\t' => f.write_str("\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => f.write_char(c)?,
+        }
+    }
+    f.write_char('"')
+}
+
+struct JsonParser<'a> {
+    text: &'a str,
+    /// Byte offset of the next character.
+    pos: usize,
+}
+
+impl JsonParser<'_> {
+    fn peek(&self) -> Option<char> {
+        self.text[self.pos..].chars().next()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += c.len_utf8();
+        Some(c)
+    }
+
+    fn skip_whitespace(&mut self) {
+        while matches!(self.peek(), Some(' ' | '\t' | '\n' | '\r')) {
+            self.pos += 1;
+        }
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), String> {
+        match self.next() {
+            Some(c) if c == expected => Ok(()),
+            Some(c) => Err(format!("Expected '{}', found '{}' at {}", expected, c, self.pos - c.len_utf8())),
+            None => Err(format!("Expected '{}', found end of input", expected)),
+        }
+    }
+
+    fn parse_value(&mut self) -> Result<Json, String> {
+        self.skip_whitespace();
+        match self.peek() {
+            Some('n') => self.parse_literal("null", Json::Null),
+            Some('t') => self.parse_literal("true", Json::Bool(true)),
+            Some('f') => self.parse_literal("false", Json::Bool(false)),
+            Some('"') => self.parse_string().map(Json::String),
+            Some('[') => {
+                self.pos += 1;
+                let mut values = Vec::new();
+                self.skip_whitespace();
+                if self.peek() == Some(']') {
+                    self.pos += 1;
+                    return Ok(Json::Array(values));
+                }
+                loop {
+                    values.push(self.parse_value()?);
+                    self.skip_whitespace();
+                    match self.next() {
+                        Some(',') => continue,
+                        Some(']') => return Ok(Json::Array(values)),
+                        _ => return Err(format!("Expected ',' or ']' at {}", self.pos)),
+                    }
+                }
+            }
+            Some('{') => {
+                self.pos += 1;
+                let mut members = Vec::new();
+                self.skip_whitespace();
+                if self.peek() == Some('}') {
+                    self.pos += 1;
+                    return Ok(Json::Object(members));
+                }
+                loop {
+                    self.skip_whitespace();
+                    let key = self.parse_string()?;
+                    self.skip_whitespace();
+                    self.expect(':')?;
+                    members.push((key, self.parse_value()?));
+                    self.skip_whitespace();
+                    match self.next() {
+                        Some(',') => continue,
+                        Some('}') => return Ok(Json::Object(members)),
+                        _ => return Err(format!("Expected ',' or '}}' at {}", self.pos)),
+                    }
+                }
+            }
+            Some(c) if c == '-' || c.is_ascii_digit() => {
+                let start = self.pos;
+                while matches!(self.peek(), Some('0'..='9' | '-' | '+' | '.' | 'e' | 'E')) {
+                    self.pos += 1;
+                }
+                self.text[start..self.pos]
+                    .parse()
+                    .map(Json::Number)
+                    .map_err(|_| format!("Invalid number at {}", start))
+            }
+            Some(c) => Err(format!("Unexpected character '{}' at {}", c, self.pos)),
+            None => Err("Unexpected end of input".to_string()),
+        }
+    }
+
+    fn parse_literal(&mut self, literal: &str, value: Json) -> Result<Json, String> {
+        if self.text[self.pos..].starts_with(literal) {
+            self.pos += literal.len();
+            Ok(value)
+        } else {
+            Err(format!("Invalid literal at {}", self.pos))
+        }
+    }
+
+    fn parse_string(&mut self) -> Result<String, String> {
+        self.expect('"')?;
+        let mut string = String::new();
+        loop {
+            match self.next() {
+                Some('"') => return Ok(string),
+                Some('\\') => match self.next() {
+                    Some('"') => string.push('"'),
+                    Some('\\') => string.push('\\'),
+                    Some('/') => string.push('/'),
+                    Some('b') => string.push('\u{8}'),
+                    Some('f') => string.push('\u{c}'),
+                    Some('n') => string.push('\n'),
+                    Some('r') => string.push('\r'),
+                    Some('t') => string.push('\t'),
+                    Some('u') => {
+                        let mut code = self.parse_hex4()?;
+                        // Characters outside the BMP are escaped as UTF-16 surrogate pairs.
+                        if (0xd800..0xdc00).contains(&code) && self.text[self.pos..].starts_with("\\u") {
+                            let escape = self.pos;
+                            self.pos += 2;
+                            match self.parse_hex4()? {
+                                low @ 0xdc00..=0xdfff => code = 0x10000 + ((code - 0xd800) << 10) + (low - 0xdc00),
+                                // An unpaired surrogate, the next escape is a character of its own.
+                                _ => self.pos = escape,
+                            }
+                        }
+                        // Unpaired surrogates aren't characters.
+                        string.push(char::from_u32(code).unwrap_or('\u{fffd}'));
+                    }
+                    _ => return Err(format!("Invalid escape sequence at {}", self.pos)),
+                },
+                Some(c) => string.push(c),
+                None => return Err("Unterminated string".to_string()),
+            }
+        }
+    }
+
+    fn parse_hex4(&mut self) -> Result<u32, String> {
+        let digits = self.text.get(self.pos..self.pos + 4).ok_or("Truncated unicode escape")?;
+        // `from_str_radix` accepts a sign.
+        if !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(format!("Invalid unicode escape at {}", self.pos));
+        }
+        let code = u32::from_str_radix(digits, 16).expect("Expected hex digits");
+        self.pos += 4;
+        Ok(code)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn string(json: &str) -> Result<String, String> {
+        Json::parse(json).map(|value| value.as_str().expect("Expected a string").to_string())
+    }
+
+    #[test]
+    fn values() {
+        let value = Json::parse(r#" {"a": [1, -2.5e3, true, null], "b": {}, "c": []} "#).unwrap();
+        assert_eq!(
+            value,
+            Json::object(vec![
+                ("a", Json::Array(vec![Json::Number(1.0), Json::Number(-2500.0), true.into(), Json::Null])),
+                ("b", Json::object(Vec::new())),
+                ("c", Json::Array(Vec::new())),
+            ])
+        );
+        assert_eq!(value.to_string(), r#"{"a":[1,-2500,true,null],"b":{},"c":[]}"#);
+        assert_eq!(value.path(&["a"]).and_then(Json::as_array).map(<[Json]>::len), Some(4));
+        assert_eq!(value.path(&["b", "x"]), None);
+    }
+
+    #[test]
+    fn escapes() {
+        assert_eq!(string(r#""\"\\\/\b\f\n\r\t\u0041\u00e9""#).unwrap(), "\"\\/\u{8}\u{c}\n\r\tAé");
+        assert_eq!(Json::from("\"\\\n\r\t\u{1}é").to_string(), r#""\"\\\n\r\t\u0001é""#);
+        assert_eq!(string(r#""\x""#), Err("Invalid escape sequence at 3".to_string()));
+        assert_eq!(string(r#""\u+041""#), Err("Invalid unicode escape at 3".to_string()));
+        assert_eq!(string(r#""\u00"#), Err("Truncated unicode escape".to_string()));
+    }
+
+    #[test]
+    fn surrogate_pairs() {
+        assert_eq!(string(r#""\ud83d\ude00""#).unwrap(), "😀");
+        // Unpaired surrogates are replaced, the following escape is kept.
+        assert_eq!(string(r#""\ud83d\u0041""#).unwrap(), "\u{fffd}A");
+        assert_eq!(string(r#""\ud83dA""#).unwrap(), "\u{fffd}A");
+        assert_eq!(string(r#""\ude00""#).unwrap(), "\u{fffd}");
+        // Characters outside the BMP are written as they are.
+        assert_eq!(Json::from("😀").to_string(), "\"😀\"");
+    }
+
+    #[test]
+    fn non_finite_numbers_are_null() {
+        let values = Json::Array(vec![Json::Number(f64::NAN), Json::Number(f64::INFINITY), Json::Number(f64::NEG_INFINITY)]);
+        assert_eq!(values.to_string(), "[null,null,null]");
+        assert_eq!(Json::Number(0.5).to_string(), "0.5");
+    }
+
+    #[test]
+    fn syntax_errors() {
+        assert_eq!(Json::parse("[1, 2"), Err("Expected ',' or ']' at 5".to_string()));
+        assert_eq!(Json::parse("{\"a\" 1}"), Err("Expected ':', found '1' at 5".to_string()));
+        assert_eq!(Json::parse("\"abc"), Err("Unterminated string".to_string()));
+        assert_eq!(Json::parse("1 2"), Err("Unexpected trailing characters at 2".to_string()));
+        assert_eq!(Json::parse("nul"), Err("Invalid literal at 0".to_string()));
+        assert_eq!(Json::parse(""), Err("Unexpected end of input".to_string()));
+    }
+}
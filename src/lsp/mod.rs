@@ -0,0 +1,498 @@
+//! Language server for Cobra (`cobra lsp`).
+//!
+//! Speaks the Language Server Protocol over stdin and stdout: JSON-RPC messages framed by a
+//! `Content-Length` header. Open documents are analyzed (see [`analysis`]) whenever they change,
+//! providing diagnostics, hover, go-to-definition and completion for functions, and document
+//! symbols.
+//!
+//! The server only reads and writes the streams it is given, so it can be driven by a scripted
+//! client, eg a file of framed requests piped into `cobra lsp`.
+
+pub mod analysis;
+pub mod json;
+
+use std::collections::HashMap;
+use std::io::{self, BufRead, Write};
+
+use crate::lexer::{SourceLoc, KEYWORDS};
+
+use self::analysis::{Analysis, Span, SymbolKind};
+use self::json::Json;
+
+// JSON-RPC error codes.
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+
+/// `TextDocumentSyncKind.Full`: every change sends the whole document.
+const SYNC_FULL: u32 = 1;
+/// `DiagnosticSeverity.Error`.
+const SEVERITY_ERROR: u32 = 1;
+
+/// An open document.
+struct Document {
+    text: String,
+    /// Byte offset of the start of each line.
+    line_starts: Vec<usize>,
+    analysis: Analysis,
+}
+
+impl Document {
+    fn new(text: String) -> Document {
+        let line_starts = std::iter::once(0)
+            .chain(text.match_indices('\n').map(|(i, _)| i + 1))
+            .collect();
+        let analysis = analysis::analyze(&text);
+        Document { text, line_starts, analysis }
+    }
+
+    /// Get the text of the 0-based line `line`, without the line break.
+    fn line(&self, line: usize) -> &str {
+        match self.line_starts.get(line) {
+            Some(&start) => {
+                let end = self.line_starts.get(line + 1).map_or(self.text.len(), |&end| end - 1);
+                &self.text[start..end]
+            }
+            None => "",
+        }
+    }
+
+    /// Convert a source location to an LSP position, whose columns count UTF-16 code units.
+    fn position(&self, loc: SourceLoc) -> Json {
+        let line = loc.line.saturating_sub(1);
+        let character: usize = self
+            .line(line as usize)
+            .chars()
+            .take(loc.col.saturating_sub(1) as usize)
+            .map(char::len_utf16)
+            .sum();
+        Json::object(vec![("line", line.into()), ("character", (character as u32).into())])
+    }
+
+    fn range(&self, span: Span) -> Json {
+        Json::object(vec![("start", self.position(span.start)), ("end", self.position(span.end))])
+    }
+
+    /// Get the identifier (including qualifying dots, like the lexer) at an LSP position.
+    fn word_at(&self, position: &Json) -> Option<String> {
+        let line = position.get("line")?.as_f64()? as usize;
+        let character = position.get("character")?.as_f64()? as usize;
+        let chars: Vec<char> = self.line(line).chars().collect();
+
+        let mut units = 0;
+        let index = chars.iter().take_while(|c| {
+            units += c.len_utf16();
+            units <= character
+        }).count();
+
+        let is_ident = |c: &char| c.is_ascii_alphanumeric() || *c == '_' || *c == '.';
+        let start = chars[..index].iter().rev().take_while(|c| is_ident(c)).count();
+        let end = chars[index..].iter().take_while(|c| is_ident(c)).count();
+        let word: String = chars[index - start..index + end].iter().collect();
+        (!word.is_empty()).then_some(word)
+    }
+}
+
+/// Run the language server until the client sends `exit` or closes `input`.
+///
+/// Returns the exit code required by the protocol: 0 if `shutdown` was requested first, 1
+/// otherwise.
+pub fn run<R: BufRead, W: Write>(input: R, output: W) -> io::Result<i32> {
+    let mut server = Server {
+        input,
+        output,
+        documents: HashMap::new(),
+        shutdown: false,
+    };
+
+    while let Some(body) = server.read_message()? {
+        let message = match Json::parse(&body) {
+            Ok(message) => message,
+            Err(err) => {
+                server.send_error(Json::Null, PARSE_ERROR, &err)?;
+                continue;
+            }
+        };
+        let method = message.get("method").and_then(Json::as_str).unwrap_or("");
+        let params = message.get("params").unwrap_or(&Json::Null);
+
+        match message.get("id") {
+            Some(id) => {
+                let res = if server.shutdown {
+                    Err((INVALID_REQUEST, "Server is shut down".to_string()))
+                } else {
+                    server.handle_request(method, params)
+                };
+                match res {
+                    Ok(result) => server.send(Json::object(vec![
+                        ("jsonrpc", "2.0".into()),
+                        ("id", id.clone()),
+                        ("result", result),
+                    ]))?,
+                    Err((code, message)) => server.send_error(id.clone(), code, &message)?,
+                }
+            }
+            None if method == "exit" => break,
+            None => server.handle_notification(method, params)?,
+        }
+    }
+
+    Ok(if server.shutdown { 0 } else { 1 })
+}
+
+struct Server<R, W> {
+    input: R,
+    output: W,
+    /// Open documents by URI.
+    documents: HashMap<String, Document>,
+    /// Whether `shutdown` was requested, after which only `exit` is accepted.
+    shutdown: bool,
+}
+
+type RequestResult = Result<Json, (i64, String)>;
+
+impl<R: BufRead, W: Write> Server<R, W> {
+    /// Read the body of the next message, `None` at the end of the input.
+    fn read_message(&mut self) -> io::Result<Option<String>> {
+        let mut len = None;
+        loop {
+            let mut header = String::new();
+            if self.input.read_line(&mut header)? == 0 {
+                return Ok(None);
+            }
+            let header = header.trim_end();
+            if header.is_empty() {
+                break;
+            }
+            if let Some((name, value)) = header.split_once(':') {
+                if name.trim().eq_ignore_ascii_case("Content-Length") {
+                    len = value.trim().parse::<usize>().ok();
+                }
+            }
+        }
+
+        let len = len.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Missing Content-Length header"))?;
+        let mut body = vec![0; len];
+        self.input.read_exact(&mut body)?;
+        String::from_utf8(body)
+            .map(Some)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+
+    fn send(&mut self, message: Json) -> io::Result<()> {
+        let body = message.to_string();
+        write!(self.output, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+        self.output.flush()
+    }
+
+    fn send_error(&mut self, id: Json, code: i64, message: &str) -> io::Result<()> {
+        let error = Json::object(vec![("code", Json::Number(code as f64)), ("message", message.into())]);
+        self.send(Json::object(vec![("jsonrpc", "2.0".into()), ("id", id), ("error", error)]))
+    }
+
+    fn notify(&mut self, method: &str, params: Json) -> io::Result<()> {
+        self.send(Json::object(vec![("jsonrpc", "2.0".into()), ("method", method.into()), ("params", params)]))
+    }
+
+    fn handle_notification(&mut self, method: &str, params: &Json) -> io::Result<()> {
+        let uri = match params.path(&["textDocument", "uri"]).and_then(Json::as_str) {
+            Some(uri) => uri.to_string(),
+            // `initialized`, `$/cancelRequest`, ... need no handling.
+            None => return Ok(()),
+        };
+
+        let text = match method {
+            "textDocument/didOpen" => params.path(&["textDocument", "text"]).and_then(Json::as_str),
+            // Changes are full documents (see `SYNC_FULL`), the last one is the current text.
+            "textDocument/didChange" => params
+                .get("contentChanges")
+                .and_then(Json::as_array)
+                .and_then(|changes| changes.last())
+                .and_then(|change| change.get("text"))
+                .and_then(Json::as_str),
+            "textDocument/didClose" => {
+                self.documents.remove(&uri);
+                return self.publish_diagnostics(&uri);
+            }
+            _ => return Ok(()),
+        };
+
+        if let Some(text) = text {
+            self.documents.insert(uri.clone(), Document::new(text.to_string()));
+            self.publish_diagnostics(&uri)?;
+        }
+        Ok(())
+    }
+
+    /// Send the parse errors of the document `uri`, or no errors if it isn't open.
+    fn publish_diagnostics(&mut self, uri: &str) -> io::Result<()> {
+        let diagnostics = match self.documents.get(uri) {
+            Some(document) => document
+                .analysis
+                .diagnostics
+                .iter()
+                .map(|diagnostic| {
+                    Json::object(vec![
+                        ("range", document.range(diagnostic.span)),
+                        ("severity", SEVERITY_ERROR.into()),
+                        ("source", "cobra".into()),
+                        ("message", diagnostic.message.as_str().into()),
+                    ])
+                })
+                .collect(),
+            None => Vec::new(),
+        };
+        let params = Json::object(vec![("uri", uri.into()), ("diagnostics", Json::Array(diagnostics))]);
+        self.notify("textDocument/publishDiagnostics", params)
+    }
+
+    fn handle_request(&mut self, method: &str, params: &Json) -> RequestResult {
+        match method {
+            "initialize" => Ok(initialize_result()),
+            "shutdown" => {
+                self.shutdown = true;
+                Ok(Json::Null)
+            }
+            "textDocument/hover" => self.with_document(params, |document, _| {
+                let word = match params.get("position").and_then(|position| document.word_at(position)) {
+                    Some(word) => word,
+                    None => return Json::Null,
+                };
+                match document.analysis.signature(&word) {
+                    Some(signature) => {
+                        let contents = Json::object(vec![
+                            ("kind", "markdown".into()),
+                            ("value", format!("```cobra\n{}\n```", signature).into()),
+                        ]);
+                        Json::object(vec![("contents", contents)])
+                    }
+                    None => Json::Null,
+                }
+            }),
+            "textDocument/definition" => self.with_document(params, |document, uri| {
+                let symbol = params
+                    .get("position")
+                    .and_then(|position| document.word_at(position))
+                    .and_then(|word| document.analysis.find_symbol(&word));
+                match symbol {
+                    Some(symbol) => Json::object(vec![("uri", uri.into()), ("range", document.range(symbol.name_span))]),
+                    // Prelude functions and imported definitions have no source in the workspace.
+                    None => Json::Null,
+                }
+            }),
+            "textDocument/completion" => self.with_document(params, |document, _| completion_items(document)),
+            "textDocument/documentSymbol" => self.with_document(params, |document, _| {
+                let symbols = document.analysis.symbols.iter().map(|symbol| {
+                    let (name, kind) = match symbol.kind {
+                        SymbolKind::Function | SymbolKind::Extern => (symbol.name.clone(), 12),
+                        SymbolKind::Struct => (symbol.name.clone(), 23),
+                        SymbolKind::Enum => (symbol.name.clone(), 10),
+                        SymbolKind::Const => (symbol.name.clone(), 14),
+                        SymbolKind::Global => (symbol.name.clone(), 13),
+                        SymbolKind::Test => (format!("test {:?}", symbol.name), 12),
+                    };
+                    let mut members = vec![
+                        ("name", name.into()),
+                        ("kind", Json::Number(kind as f64)),
+                        ("range", document.range(symbol.span)),
+                        ("selectionRange", document.range(symbol.name_span)),
+                    ];
+                    if let Some(signature) = document.analysis.signature(&symbol.name) {
+                        members.push(("detail", signature.into()));
+                    }
+                    Json::object(members)
+                });
+                Json::Array(symbols.collect())
+            }),
+            _ => Err((METHOD_NOT_FOUND, format!("Unknown method {}", method))),
+        }
+    }
+
+    /// Answer a request about the open document named by `params.textDocument.uri` with `f`.
+    fn with_document<F>(&self, params: &Json, f: F) -> RequestResult
+    where
+        F: FnOnce(&Document, &str) -> Json,
+    {
+        let uri = params
+            .path(&["textDocument", "uri"])
+            .and_then(Json::as_str)
+            .ok_or((INVALID_PARAMS, "Missing textDocument.uri".to_string()))?;
+        match self.documents.get(uri) {
+            Some(document) => Ok(f(document, uri)),
+            None => Err((INVALID_PARAMS, format!("Document {} is not open", uri))),
+        }
+    }
+}
+
+fn initialize_result() -> Json {
+    let capabilities = Json::object(vec![
+        ("textDocumentSync", SYNC_FULL.into()),
+        ("hoverProvider", true.into()),
+        ("definitionProvider", true.into()),
+        ("completionProvider", Json::object(Vec::new())),
+        ("documentSymbolProvider", true.into()),
+    ]);
+    let server_info = Json::object(vec![("name", "cobra".into()), ("version", env!("CARGO_PKG_VERSION").into())]);
+    Json::object(vec![("capabilities", capabilities), ("serverInfo", server_info)])
+}
+
+/// Complete keywords, known functions and the other definitions of `document`. The client
+/// filters the items by the word being typed.
+fn completion_items(document: &Document) -> Json {
+    // `CompletionItemKind` values.
+    const FUNCTION: u32 = 3;
+    const VARIABLE: u32 = 6;
+    const ENUM: u32 = 13;
+    const KEYWORD: u32 = 14;
+    const CONSTANT: u32 = 21;
+    const STRUCT: u32 = 22;
+
+    let item = |label: &str, kind: u32, detail: Option<String>| {
+        let mut members = vec![("label", label.into()), ("kind", kind.into())];
+        if let Some(detail) = detail {
+            members.push(("detail", detail.into()));
+        }
+        Json::object(members)
+    };
+
+    let mut functions: Vec<_> = document.analysis.fn_protos.keys().collect();
+    functions.sort();
+
+    let mut items: Vec<_> = KEYWORDS.iter().map(|keyword| item(keyword, KEYWORD, None)).collect();
+    items.extend(functions.into_iter().map(|name| item(name, FUNCTION, document.analysis.signature(name))));
+    for symbol in &document.analysis.symbols {
+        let kind = match symbol.kind {
+            SymbolKind::Struct => STRUCT,
+            SymbolKind::Enum => ENUM,
+            SymbolKind::Const => CONSTANT,
+            SymbolKind::Global => VARIABLE,
+            SymbolKind::Function | SymbolKind::Extern | SymbolKind::Test => continue,
+        };
+        items.push(item(&symbol.name, kind, None));
+    }
+    Json::Array(items)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Frame the JSON-RPC messages `messages` like a client.
+    fn script(messages: &[Json]) -> Vec<u8> {
+        let mut input = Vec::new();
+        for message in messages {
+            let body = message.to_string();
+            write!(input, "Content-Length: {}\r\n\r\n{}", body.len(), body).unwrap();
+        }
+        input
+    }
+
+    /// Run the server on `messages`, return its exit code and the messages it sent.
+    fn run_script(messages: &[Json]) -> (i32, Vec<Json>) {
+        let mut output = Vec::new();
+        let code = run(io::Cursor::new(script(messages)), &mut output).unwrap();
+
+        let mut rest = std::str::from_utf8(&output).unwrap();
+        let mut sent = Vec::new();
+        while !rest.is_empty() {
+            let (len, body) = rest
+                .strip_prefix("Content-Length: ")
+                .and_then(|header| header.split_once("\r\n\r\n"))
+                .expect("Expected a Content-Length header");
+            let len: usize = len.parse().unwrap();
+            sent.push(Json::parse(&body[..len]).unwrap());
+            rest = &body[len..];
+        }
+        (code, sent)
+    }
+
+    fn request(id: u32, method: &str, params: Json) -> Json {
+        Json::object(vec![("jsonrpc", "2.0".into()), ("id", id.into()), ("method", method.into()), ("params", params)])
+    }
+
+    fn notification(method: &str, params: Json) -> Json {
+        Json::object(vec![("jsonrpc", "2.0".into()), ("method", method.into()), ("params", params)])
+    }
+
+    fn position_params(uri: &str, line: u32, character: u32) -> Json {
+        Json::object(vec![
+            ("textDocument", Json::object(vec![("uri", uri.into())])),
+            ("position", Json::object(vec![("line", line.into()), ("character", character.into())])),
+        ])
+    }
+
+    fn position(line: u32, character: u32) -> Json {
+        Json::object(vec![("line", line.into()), ("character", character.into())])
+    }
+
+    const URI: &str = "file:///add.ks";
+
+    #[test]
+    fn scripted_session() {
+        let text = "def add(a, b) a + b\nadd(1, 2)\n\"bad\\q\"\n";
+        let did_open = Json::object(vec![(
+            "textDocument",
+            Json::object(vec![("uri", URI.into()), ("languageId", "cobra".into()), ("version", 1.into()), ("text", text.into())]),
+        )]);
+        let (code, sent) = run_script(&[
+            request(1, "initialize", Json::object(Vec::new())),
+            notification("initialized", Json::object(Vec::new())),
+            notification("textDocument/didOpen", did_open),
+            request(2, "textDocument/hover", position_params(URI, 1, 1)),
+            request(3, "textDocument/definition", position_params(URI, 1, 2)),
+            request(4, "textDocument/completion", position_params(URI, 1, 0)),
+            request(5, "shutdown", Json::Null),
+            notification("exit", Json::Null),
+        ]);
+        assert_eq!(code, 0);
+        assert_eq!(sent.len(), 6);
+
+        assert_eq!(sent[0].get("id"), Some(&Json::Number(1.0)));
+        assert_eq!(sent[0].path(&["result", "capabilities", "hoverProvider"]), Some(&Json::Bool(true)));
+
+        assert_eq!(sent[1].get("method").and_then(Json::as_str), Some("textDocument/publishDiagnostics"));
+        let diagnostics = sent[1].path(&["params", "diagnostics"]).and_then(Json::as_array).unwrap();
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].get("message").and_then(Json::as_str), Some("Unknown escape sequence '\\q'"));
+        assert_eq!(diagnostics[0].path(&["range", "start"]), Some(&position(2, 0)));
+        assert_eq!(diagnostics[0].path(&["range", "end"]), Some(&position(2, 7)));
+
+        let hover = sent[2].path(&["result", "contents", "value"]).and_then(Json::as_str);
+        assert_eq!(hover, Some("```cobra\ndef add(a: f64, b: f64) -> f64\n```"));
+
+        assert_eq!(sent[3].path(&["result", "uri"]).and_then(Json::as_str), Some(URI));
+        assert_eq!(sent[3].path(&["result", "range", "start"]), Some(&position(0, 4)));
+        assert_eq!(sent[3].path(&["result", "range", "end"]), Some(&position(0, 7)));
+
+        let items = sent[4].get("result").and_then(Json::as_array).unwrap();
+        let item = |label: &str| items.iter().find(|item| item.get("label").and_then(Json::as_str) == Some(label));
+        assert_eq!(item("add").and_then(|item| item.get("detail")).and_then(Json::as_str), Some("def add(a: f64, b: f64) -> f64"));
+        assert!(item("def").is_some());
+
+        assert_eq!(sent[5].get("id"), Some(&Json::Number(5.0)));
+        assert_eq!(sent[5].get("result"), Some(&Json::Null));
+    }
+
+    #[test]
+    fn errors() {
+        let (code, sent) = run_script(&[
+            request(1, "textDocument/unknown", Json::Null),
+            request(2, "textDocument/hover", position_params("file:///closed.ks", 0, 0)),
+            request(3, "shutdown", Json::Null),
+            request(4, "textDocument/hover", position_params(URI, 0, 0)),
+        ]);
+        // The end of the input is an `exit` after `shutdown`.
+        assert_eq!(code, 0);
+
+        let error_code = |message: &Json| message.path(&["error", "code"]).and_then(Json::as_f64);
+        assert_eq!(error_code(&sent[0]), Some(METHOD_NOT_FOUND as f64));
+        assert_eq!(error_code(&sent[1]), Some(INVALID_PARAMS as f64));
+        assert_eq!(sent[2].get("result"), Some(&Json::Null));
+        assert_eq!(error_code(&sent[3]), Some(INVALID_REQUEST as f64));
+
+        // Exiting without `shutdown` is an error.
+        let (code, sent) = run_script(&[notification("exit", Json::Null)]);
+        assert_eq!((code, sent.len()), (1, 0));
+    }
+}
@@ -1,119 +1,822 @@
 use cobra_lang::{
-    ir_gen::IRGen,
+    aot,
+    cache::ObjectCache,
+    consteval::{self, ConstValue},
+    import::{ModuleLoader, Namespace},
+    ir_gen::{IRGen, TypeDefs},
     lexer::{Lexer, Token},
-    parser::{Parser, PrototypeAST},
+    lsp,
+    parallel::{self, Compiled},
+    parser::{ExprAST, FunctionAST, GlobalAST, ImportAST, Parser, PrototypeAST, TestAST, TypeAST},
+    prelude,
+    snapshot::{Definition, History},
     Either,
     llvm
-}
+};
+
+use cobra_runtime::error::TOP_LEVEL_PREFIX;
+use cobra_runtime::limits::{Watchdog, MAX_CALL_DEPTH};
 
 use std::collections::HashMap;
-use std::io::{Read, Write};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+/// Name of the global variable holding the result of the last top-level expression.
+const LAST_RESULT: &str = "_";
+
+/// Command line options of running and testing programs.
+#[derive(Clone, Copy)]
+struct Options {
+    /// Number of threads compiling consecutive function definitions (`-j <jobs>`).
+    jobs: usize,
+    /// Whether compiled functions are cached (disabled by `--no-cache`).
+    cache: bool,
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options { jobs: 1, cache: true }
+    }
+}
 
-#[no_mangle]
-#[inline(never)]
-pub extern "C" fn putchard(c: libc::c_double) -> f64 {
-    std::io:std::stdout().write(&[c as u8]).unwrap();
-    0f64
+impl Options {
+    /// Create the JIT, with a pool of [`Options::jobs`] compile threads if there is more than one.
+    fn new_jit(&self) -> llvm::LLJit {
+        match self.jobs {
+            0 | 1 => llvm::LLJit::new(),
+            jobs => llvm::LLJit::with_compile_threads(jobs),
+        }
+    }
 }
 
-fn main_loop<I>(mut parser: Parser<I>)
+/// State shared between the main file and all the modules it imports.
+struct Session<'jit> {
+    jit: &'jit llvm::LLJit,
+    loader: ModuleLoader,
+    fn_protos: HashMap<String, PrototypeAST>,
+    type_defs: TypeDefs,
+    generics: HashMap<String, FunctionAST>,
+    /// Definitions of all compiled functions, for evaluating `const` declarations.
+    functions: HashMap<String, FunctionAST>,
+    consts: HashMap<String, ConstValue>,
+    globals: HashMap<String, TypeAST>,
+    fn_jit_rs: HashMap<String, llvm::ResourceTracker<'jit>>,
+    /// Trackers of the modules defining global variables, which are never removed.
+    global_jit_rs: Vec<llvm::ResourceTracker<'jit>>,
+    /// Definitions entered in the main file, for `:save`.
+    history: History,
+    /// Cache of compiled functions, `None` if disabled.
+    cache: Option<ObjectCache>,
+    /// Number of threads compiling consecutive function definitions (`-j <jobs>`).
+    jobs: usize,
+    /// Time limit of each top-level expression (`:timeout`), `None` if unlimited.
+    timeout: Option<Duration>,
+    /// `test` blocks of the main file collected by `cobra test`, `None` if they are ignored.
+    tests: Option<Vec<TestAST>>,
+    /// Number of errors reported while compiling and running the program, which fail `cobra test`.
+    errors: usize,
+}
+
+impl Session<'_> {
+    /// Record a definition of the main file. Definitions of imported modules are not recorded,
+    /// they are loaded again by the recorded `import`.
+    fn record(&mut self, ns: &Namespace, definition: Definition) {
+        if ns.is_root() {
+            self.history.record(definition);
+        }
+    }
+
+    /// Print an error of a definition or top-level expression.
+    fn report(&mut self, err: &str) {
+        self.errors += 1;
+        eprintln!("Error: {}", err);
+    }
+}
+
+/// Compile the function definitions starting at the current token on [`Session::jobs`] threads,
+/// and add them to the JIT, replacing previous definitions.
+///
+/// The prototypes of all consecutive definitions are registered first, so they may call each
+/// other in any order whatever the number of threads. Native code is loaded from the object
+/// cache for functions compiled before with the same definitions, otherwise it is stored in the
+/// cache. The IR is dumped if the functions are compiled on a single thread without the cache.
+fn define_functions<I>(parser: &mut Parser<I>, source: &str, session: &mut Session<'_>, ns: &mut Namespace)
 where
     I: Iterator<Item = char>,
 {
-    let mut module = llvm::Module::new();
-    let jit = llvm::LLJit::new();
+    let mut batch = Vec::new();
+    loop {
+        let start = parser.current_offset();
+        match parser.current_token() {
+            Token::Char(';') => {
+                parser.get_next_token();
+            }
+            Token::Def => match parser.parse_definition() {
+                Ok(mut function) => {
+                    ns.mangle_function(&mut function);
+                    let name = function.0.name.clone();
+                    let definition = Definition::function(&function, &source[start..parser.current_offset()]);
+                    session.fn_protos.insert(name.clone(), function.0.clone());
+                    if function.0.type_params.is_empty() {
+                        batch.push((function, definition));
+                    } else {
+                        session.record(ns, definition);
+                        session.generics.insert(name, function);
+                    }
+                }
+                Err(err) => {
+                    session.report(&format!("{:?}", err));
+                    parser.get_next_token();
+                }
+            },
+            _ => break,
+        }
+    }
 
-    jit.enable_process_symbols();
+    let keys: Vec<_> = batch
+        .iter()
+        .map(|(function, _)| {
+            session.cache.as_ref().map(|cache| {
+                cache.key(function, &session.fn_protos, &session.type_defs, &session.generics, &session.consts, &session.globals)
+            })
+        })
+        .collect();
+    let cached: Vec<_> = keys
+        .iter()
+        .map(|key| match (&session.cache, key) {
+            (Some(cache), Some(key)) => cache.load(key),
+            _ => None,
+        })
+        .collect();
+
+    let misses: Vec<_> = batch
+        .iter()
+        .zip(&cached)
+        .filter(|(_, object)| object.is_none())
+        .map(|((function, _), _)| function)
+        .collect();
+    // The JIT doesn't return the native code it compiles, so it is only compiled by the workers
+    // if it is stored in the cache.
+    let emit_objects = session.cache.is_some();
+    let mut compiled = parallel::compile(&misses, session.jobs, emit_objects, ns.name(), &session.fn_protos, &session.type_defs, &session.generics, &session.consts, &session.globals)
+        .into_iter();
+    let mut results: Vec<_> = keys
+        .iter()
+        .zip(cached)
+        .map(|(key, object)| match object {
+            Some(object) => Ok(Compiled::Object(object)),
+            None => {
+                let result = compiled.next().expect("Missing compiled function");
+                if let (Ok(Compiled::Object(object)), Some(cache), Some(key)) = (&result, &session.cache, key) {
+                    cache.store_object(key, object);
+                }
+                result
+            }
+        })
+        .collect();
+
+    let definitions: Vec<_> = batch.iter().map(|(_, definition)| definition).collect();
+    parallel::fail_callers(&definitions, &mut results);
+
+    let mut modules = Vec::new();
+    for ((function, definition), result) in batch.into_iter().zip(results) {
+        let name = function.0.name.clone();
+        let tracker = match result {
+            Ok(Compiled::Object(object)) => session.jit.add_object(&object),
+            Ok(Compiled::Module(module)) => {
+                if session.jobs <= 1 {
+                    module.dump();
+                }
+                modules.push(name.clone());
+                session.jit.add_module(module)
+            }
+            Err(e) => {
+                session.report(&e);
+                forget_prototype(session, &name);
+                continue;
+            }
+        };
+
+        // Replacing the tracker of a previous definition removes its code.
+        session.fn_jit_rs.insert(name.clone(), tracker);
+        session.functions.insert(name, function);
+        session.record(ns, definition);
+    }
 
-    let mut fn_protos: HashMap<String, PrototypeAST> = HashMap::new();
-    let mut fn_jit_rs: HashMap<String, llvm::ResourceTracker> = HashMap::new();
+    // Compile the modules concurrently, instead of one by one when they are first called.
+    let modules: Vec<_> = modules.iter().map(String::as_str).collect();
+    session.jit.materialize(&modules);
+}
+
+/// Restore the prototype of the previous definition of the function `name` after a new
+/// definition failed to compile, or remove it if there is none, so it can't be called.
+fn forget_prototype(session: &mut Session<'_>, name: &str) {
+    match session.functions.get(name) {
+        Some(FunctionAST(proto, _)) => session.fn_protos.insert(name.to_string(), proto.clone()),
+        None => session.fn_protos.remove(name),
+    };
+}
+
+/// Define the global variable `def` in a module of its own, so it keeps its value when the
+/// functions using it are redefined.
+fn define_global(session: &mut Session<'_>, ns: &Namespace, def: GlobalAST) -> Result<(), String> {
+    if session.globals.contains_key(&def.name) || session.consts.contains_key(&def.name) {
+        return Err(format!("{} is already defined", def.name));
+    }
+
+    let init = consteval::eval_const(&def.value, &session.functions, &session.consts)?;
+    let module = llvm::Module::with_name(ns.name());
+    IRGen::define_global(&module, &def.name, &init);
+    session.global_jit_rs.push(session.jit.add_module(module));
+    session.globals.insert(def.name, init.type_ast());
+    Ok(())
+}
+
+/// Define the global variable assigned by a top-level `name = value`, unless it exists already.
+/// The variable has the type of `value`, which must be a number, bool or string.
+///
+/// This binds the results of top-level expressions to names which later entries can use. `func`
+/// must be mangled already, its assignment is redirected to the new global.
+fn define_binding(session: &mut Session<'_>, ns: &mut Namespace, FunctionAST(_, body): &mut FunctionAST) -> Result<(), String> {
+    let ExprAST::Assign(target, value) = body else {
+        return Ok(());
+    };
+    let ExprAST::Variable(ref mut name) = **target else {
+        return Ok(());
+    };
+    if session.globals.contains_key(name.as_str()) {
+        return Ok(());
+    }
 
+    let init = match infer_type(session, ns, value)? {
+        TypeAST::F64 => ExprAST::Number(0.0),
+        TypeAST::Bool => ExprAST::Bool(false),
+        TypeAST::Str => ExprAST::Str(String::new()),
+        ty => return Err(format!("Cannot bind a value of type {} to the global variable {}", ty, name)),
+    };
+    let mut def = GlobalAST { name: name.clone(), value: init };
+    ns.mangle_global(&mut def);
+    name.clone_from(&def.name);
+    define_global(session, ns, def)?;
+    session.record(ns, Definition::global(name));
+    Ok(())
+}
+
+/// Infer the type of the (mangled) top-level expression `expr`.
+fn infer_type(session: &mut Session<'_>, ns: &Namespace, expr: &ExprAST) -> Result<TypeAST, String> {
+    let module = llvm::Module::with_name(ns.name());
+    IRGen::infer_type(&module, &mut session.fn_protos, &session.type_defs, &session.generics, &session.consts, &session.globals, expr)
+}
+
+/// Compile and run the (mangled) top-level expression `func` and print its value.
+///
+/// Numbers are kept in `_` for the following entries. The function returns bools and strings
+/// directly, values of other types are only evaluated for their effects.
+fn run_top_level(session: &mut Session<'_>, ns: &Namespace, mut func: FunctionAST) -> Result<(), String> {
+    let ty = infer_type(session, ns, &func.1)?;
+    let body = std::mem::replace(&mut func.1, ExprAST::Number(0.0));
+    func.1 = match ty {
+        TypeAST::F64 => ExprAST::Assign(Box::new(ExprAST::Variable(LAST_RESULT.to_string())), Box::new(body)),
+        TypeAST::Bool | TypeAST::Str => {
+            func.0.ret_type = ty.clone();
+            body
+        }
+        _ => ExprAST::Block(vec![body, ExprAST::Number(0.0)]),
+    };
+
+    let module = llvm::Module::with_name(ns.name());
+    let function = IRGen::compile(&module, &mut session.fn_protos, &session.type_defs, &session.generics, &session.consts, &session.globals, Either::Right(&func))?;
+    function.dump();
+
+    let _rt = session.jit.add_module(module);
+    let name = &func.0.name;
+    let _watchdog = session.timeout.map(Watchdog::start);
+    let res = unsafe {
+        match ty {
+            TypeAST::F64 => {
+                let fp = session.jit.find_symbol::<unsafe extern "C-unwind" fn() -> f64>(name);
+                cobra_runtime::error::catch(|| Some(ConstValue::Number(fp())))
+            }
+            TypeAST::Bool => {
+                let fp = session.jit.find_symbol::<unsafe extern "C-unwind" fn() -> u8>(name);
+                cobra_runtime::error::catch(|| Some(ConstValue::Bool(fp() & 1 != 0)))
+            }
+            TypeAST::Str => {
+                let fp = session.jit.find_symbol::<unsafe extern "C-unwind" fn() -> cobra_runtime::CobraStr>(name);
+                cobra_runtime::error::catch(|| Some(ConstValue::Str(str_value(fp()))))
+            }
+            _ => {
+                let fp = session.jit.find_symbol::<unsafe extern "C-unwind" fn() -> f64>(name);
+                cobra_runtime::error::catch(|| fp()).map(|_| None)
+            }
+        }
+    };
+    match res {
+        Ok(Some(ConstValue::Number(value))) => println!("Evaluated to {}", value),
+        Ok(Some(value)) => println!("Evaluated to {}", value),
+        Ok(None) => println!("Evaluated to a value of type {}", ty),
+        Err(err) => {
+            session.errors += 1;
+            eprintln!("Runtime error: {}", err);
+        }
+    }
+    Ok(())
+}
+
+/// Copy the contents of a Cobra string.
+///
+/// # Safety
+///
+/// `value` must be a valid Cobra string.
+unsafe fn str_value(value: cobra_runtime::CobraStr) -> String {
+    let bytes = match value.len {
+        0 => &[][..],
+        len => std::slice::from_raw_parts(value.ptr, len as usize),
+    };
+    String::from_utf8_lossy(bytes).into_owned()
+}
+
+/// Read the current value of the global variable `name` of type `ty` from the JIT.
+fn read_global(session: &Session<'_>, name: &str, ty: &TypeAST) -> ConstValue {
+    unsafe {
+        match ty {
+            TypeAST::Bool => ConstValue::Bool(*session.jit.find_global::<u8>(name) != 0),
+            TypeAST::Str => ConstValue::Str(str_value(*session.jit.find_global::<cobra_runtime::CobraStr>(name))),
+            _ => ConstValue::Number(*session.jit.find_global::<f64>(name)),
+        }
+    }
+}
+
+/// Write the definitions of the session to `path` in dependency order, with the current values
+/// of its global variables.
+fn save_session(session: &Session<'_>, path: &str) -> Result<(), String> {
+    let mut contents = String::new();
+    for definition in session.history.sorted() {
+        let source = definition.render(|name| read_global(session, name, &session.globals[name]));
+        contents.push_str(&source);
+        contents.push('\n');
+    }
+    std::fs::write(path, contents).map_err(|err| format!("Failed to write {}: {}", path, err))
+}
+
+/// Replay the definitions saved in `path` into the session.
+fn restore_session(session: &mut Session<'_>, ns: &mut Namespace, path: &str) -> Result<(), String> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|err| format!("Failed to read {}: {}", path, err))?;
+
+    let mut parser = Parser::new(Lexer::new(contents.chars()));
+    parser.get_next_token();
+    main_loop(parser, &contents, session, ns);
+    Ok(())
+}
+
+/// Run the REPL command following a `:`.
+///
+/// `:vars` lists the constants and global variables of the session with their current values.
+/// `:save <file>` writes the definitions of the session to a file, which `:restore <file>`
+/// replays in a new session. `:timeout <seconds>` limits the execution time of each top-level
+/// expression (0 removes the limit) and `:max_depth <calls>` limits the depth of nested calls.
+fn run_command<I>(parser: &mut Parser<I>, session: &mut Session<'_>, ns: &mut Namespace) -> Result<(), String>
+where
+    I: Iterator<Item = char>,
+{
+    parser.get_next_token();
+    let command = match parser.current_token() {
+        Token::Identifier(command) => command.clone(),
+        token => return Err(format!("Expected command after ':', found {:?}", token)),
+    };
+    parser.get_next_token();
+
+    match command.as_str() {
+        "vars" => {
+            let mut names: Vec<_> = session.consts.keys().chain(session.globals.keys()).collect();
+            names.sort();
+            for name in names {
+                match session.consts.get(name) {
+                    Some(value) => println!("const {} = {}", name, value),
+                    None => println!("{} = {}", name, read_global(session, name, &session.globals[name])),
+                }
+            }
+            Ok(())
+        }
+        "timeout" | "max_depth" => {
+            let value = match parser.current_token() {
+                Token::Number(value) if *value >= 0.0 => *value,
+                token => return Err(format!("Expected a number after :{}, found {:?}", command, token)),
+            };
+            parser.get_next_token();
+            if command == "timeout" {
+                session.timeout = (value > 0.0).then(|| Duration::from_secs_f64(value));
+            } else {
+                MAX_CALL_DEPTH.store(value as u64, Ordering::Relaxed);
+            }
+            Ok(())
+        }
+        "save" | "restore" => {
+            let path = match parser.current_token() {
+                Token::Identifier(_) => parser.parse_dotted_name()?,
+                Token::Str(path) => {
+                    let path = path.clone();
+                    parser.get_next_token();
+                    path
+                }
+                token => return Err(format!("Expected file name after :{}, found {:?}", command, token)),
+            };
+            if command == "save" {
+                save_session(session, &path)
+            } else {
+                restore_session(session, ns, &path)
+            }
+        }
+        _ => Err(format!("Unknown command :{}", command)),
+    }
+}
+
+/// Compile the module referenced by `import` into the session, unless it was loaded before.
+fn load_module(session: &mut Session<'_>, import: &ImportAST) -> Result<(), String> {
+    if !session.loader.mark_loaded(&import.module) {
+        return Ok(());
+    }
+
+    let path = session.loader.resolve(&import.module)?;
+    let contents = std::fs::read_to_string(&path)
+        .map_err(|err| format!("Failed to read {}: {}", path.display(), err))?;
+
+    let mut parser = Parser::new(Lexer::new(contents.chars()));
+    parser.get_next_token();
+    let mut ns = Namespace::new(&import.module);
+    ns.declare(&contents);
+    main_loop(parser, &contents, session, &mut ns);
+    Ok(())
+}
+
+/// Compile the entries parsed from `source` into the session.
+fn main_loop<I>(mut parser: Parser<I>, source: &str, session: &mut Session<'_>, ns: &mut Namespace)
+where
+    I: Iterator<Item = char>,
+{
     loop {
+        // Source text of the entry, from its first token up to the next one.
+        let start = parser.current_offset();
+        let text = |parser: &Parser<I>| &source[start..parser.current_offset()];
+
         match parser.current_token() {
-            Token::EOF => break,
-            Token::Char(";") => {
+            Token::Eof => break,
+            Token::Char(';') => {
                 parser.get_next_token();
             }
-            Token::Def => match parser.parse_definition() {
-                Ok(function) => {
-                    let name = function.0.name.clone();
-                    fn_protos.insert(name.clone(), function.0);
-                    fn_jit_rs.insert(name.clone(), jit.add_module(&module));
-                    let mut codegen = Codegen::new(&mut module, &mut fn_protos, &mut fn_jit_rs);
-                    match codegen.generate_code(&function.1) {
-                        Ok(_) => {
-                            jit.remove_module(&module);
-                            module = llvm::Module::new();
-                        }
-                        Err(e) => {
-                            println!("Error: {}", e);
-                            jit.remove_module(&module);
-                            module = llvm::Module::new();
+            Token::Char(':') => {
+                if let Err(err) = run_command(&mut parser, session, ns) {
+                    session.report(&err);
+                }
+            }
+            Token::Import | Token::From => match parser.parse_import() {
+                Ok(import) => {
+                    let res = load_module(session, &import)
+                        .and_then(|_| ns.import(&import, &session.fn_protos, &session.consts, &session.globals));
+                    match res {
+                        Ok(()) => session.record(ns, Definition::import(text(&parser))),
+                        Err(err) => session.report(&err),
+                    }
+                }
+                Err(err) => {
+                    session.report(&format!("{:?}", err));
+                    parser.get_next_token();
+                }
+            },
+            Token::Def => define_functions(&mut parser, source, session, ns),
+            Token::Const => match parser.parse_const() {
+                Ok(mut def) => {
+                    ns.mangle_const(&mut def);
+                    // Uses of a constant are compiled to its value, so it can't change later.
+                    let res = if session.consts.contains_key(&def.name) || session.globals.contains_key(&def.name) {
+                        Err(format!("{} is already defined", def.name))
+                    } else {
+                        consteval::eval_const(&def.value, &session.functions, &session.consts)
+                    };
+                    match res {
+                        Ok(value) => {
+                            session.record(ns, Definition::constant(&def.name, &value));
+                            session.consts.insert(def.name, value);
                         }
+                        Err(err) => session.report(&err),
                     }
                 }
-            }
-            Token::Extern => match parser.parse_extern() {
+                Err(err) => {
+                    session.report(&format!("{:?}", err));
+                    parser.get_next_token();
+                }
+            },
+            Token::Global => match parser.parse_global() {
+                Ok(mut def) => {
+                    ns.mangle_global(&mut def);
+                    let name = def.name.clone();
+                    match define_global(session, ns, def) {
+                        Ok(()) => session.record(ns, Definition::global(&name)),
+                        Err(err) => session.report(&err),
+                    }
+                }
+                Err(err) => {
+                    session.report(&format!("{:?}", err));
+                    parser.get_next_token();
+                }
+            },
+            Token::Struct => match parser.parse_struct() {
+                Ok(def) => {
+                    // Struct names are global, they are shared by all modules of the session.
+                    let definition = Definition::type_def(&def.name, text(&parser));
+                    match session.type_defs.define_struct(def) {
+                        Ok(()) => session.record(ns, definition),
+                        Err(err) => session.report(&err),
+                    }
+                }
+                Err(err) => {
+                    session.report(&format!("{:?}", err));
+                    parser.get_next_token();
+                }
+            },
+            Token::Enum => match parser.parse_enum() {
+                Ok(def) => {
+                    let definition = Definition::type_def(&def.name, text(&parser));
+                    match session.type_defs.define_enum(def) {
+                        Ok(()) => session.record(ns, definition),
+                        Err(err) => session.report(&err),
+                    }
+                }
+                Err(err) => {
+                    session.report(&format!("{:?}", err));
+                    parser.get_next_token();
+                }
+            },
+            Token::Test => match parser.parse_test() {
+                Ok(mut test) => {
+                    // Tests of imported modules are not run.
+                    if let Some(tests) = session.tests.as_mut().filter(|_| ns.is_root()) {
+                        ns.mangle_test(&mut test);
+                        tests.push(test);
+                    }
+                }
+                Err(err) => {
+                    session.report(&format!("{:?}", err));
+                    parser.get_next_token();
+                }
+            },
+            Token::Extern => match parser.parse_external() {
                 Ok(function) => {
-                    let name = function.name.clone();
-                    fn_protos.insert(name.clone(), function);
-                    fn_jit_rs.insert(name.clone(), jit.add_module(&module));
-                    module = llvm::Module::new();
+                    // Externs name symbols outside of Cobra and are never mangled.
+                    session.record(ns, Definition::external(&function.name, text(&parser)));
+                    session.fn_protos.insert(function.name.clone(), function);
+                }
+                Err(err) => {
+                    session.report(&format!("{:?}", err));
+                    parser.get_next_token();
                 }
             },
             _ => match parser.parse_top_level_expr() {
-                Ok(func) => {
+                Ok(mut func) => {
                     println!("Parse top-level expression");
-                    if let Ok(func) = Codegen::compile(&module, &mut fn_protos, Either::B(&func)) {
-                        func.dump();
-
-                        let _rt = jit.add_module(module);
-                        module = llvm::Module::new();
-                        let fp = jit.find_symbol::<unsafe extern "C" fn() -> f64>("__anon_expr");
-                        unsafe {
-                            println!("Evaluated to {}", fp());
-                        }
+                    ns.mangle_top_level(&mut func);
+                    let res = define_binding(session, ns, &mut func).and_then(|()| run_top_level(session, ns, func));
+                    if let Err(err) = res {
+                        session.report(&err);
                     }
                 }
                 Err(err) => {
-                    eprintln!("Error: {:?}", err);
+                    session.report(&format!("{:?}", err));
                     parser.get_next_token();
                 }
             },
         };
     }
-    module.dump();
 }
 
-fn run_cobra<I>(lexer: Lexer<I>)
-where
-    I: Iterator<Item = char>,
-{
-    let mut parser = Parser::new(lexer);
+/// Create a session compiling into `jit`, with the prelude and `_` defined.
+fn new_session<'jit>(jit: &'jit llvm::LLJit, root: &Path, options: Options) -> Session<'jit> {
+    jit.enable_process_symbols();
+    jit.define_symbols(&prelude::jit_symbols());
+
+    let mut session = Session {
+        jit,
+        loader: ModuleLoader::with_root(root),
+        fn_protos: HashMap::new(),
+        type_defs: TypeDefs::default(),
+        generics: HashMap::new(),
+        functions: HashMap::new(),
+        consts: HashMap::new(),
+        globals: HashMap::new(),
+        fn_jit_rs: HashMap::new(),
+        global_jit_rs: Vec::new(),
+        history: History::default(),
+        cache: options.cache.then(ObjectCache::from_env).flatten(),
+        jobs: options.jobs,
+        timeout: None,
+        tests: None,
+        errors: 0,
+    };
+    prelude::register_prelude(&mut session.fn_protos);
+
+    let last_result = GlobalAST { name: LAST_RESULT.to_string(), value: ExprAST::Number(0.0) };
+    define_global(&mut session, &Namespace::root(), last_result).expect("Failed to define _");
+    session
+}
+
+fn run_cobra(source: &str, root: &Path, options: Options) {
+    let mut parser = Parser::new(Lexer::new(source.chars()));
+    parser.get_next_token();
+
+    llvm::initialize_native_taget();
+
+    let jit = options.new_jit();
+    let mut session = new_session(&jit, root, options);
+    main_loop(parser, source, &mut session, &mut Namespace::root());
+
+    // Code must be removed from the JIT before it is destroyed.
+    drop(session);
+    drop(jit);
+    llvm::shutdown();
+}
+
+/// Size of the value of a global variable of type `ty` in the JIT.
+fn global_size(ty: &TypeAST) -> usize {
+    match ty {
+        TypeAST::Bool => std::mem::size_of::<u8>(),
+        TypeAST::Str => std::mem::size_of::<cobra_runtime::CobraStr>(),
+        _ => std::mem::size_of::<f64>(),
+    }
+}
+
+/// Copy the values of all global variables of the session, to restore them with
+/// [`restore_globals`].
+fn save_globals(session: &Session<'_>) -> Vec<(*mut u8, Vec<u8>)> {
+    session
+        .globals
+        .iter()
+        .map(|(name, ty)| {
+            let ptr = session.jit.find_global::<u8>(name);
+            (ptr, unsafe { std::slice::from_raw_parts(ptr, global_size(ty)) }.to_vec())
+        })
+        .collect()
+}
+
+/// Write back the values of global variables copied by [`save_globals`].
+fn restore_globals(saved: &[(*mut u8, Vec<u8>)]) {
+    for (ptr, value) in saved {
+        unsafe { std::ptr::copy_nonoverlapping(value.as_ptr(), *ptr, value.len()) };
+    }
+}
+
+/// Compile and run the body of a `test` block in its own module, which is removed afterwards.
+/// Global variables are restored afterwards, so tests don't depend on each other. Return the
+/// error which failed the test, if any.
+fn run_test(session: &mut Session<'_>, body: ExprAST) -> Result<(), String> {
+    // Tests are top-level expressions, so errors are passed to the host. Their value is ignored.
+    let proto = PrototypeAST {
+        name: TOP_LEVEL_PREFIX.to_string(),
+        type_params: Vec::new(),
+        args: Vec::new(),
+        arg_types: Vec::new(),
+        ret_type: TypeAST::F64,
+    };
+    let function = FunctionAST(proto, ExprAST::Block(vec![body, ExprAST::Number(0.0)]));
+
+    let module = llvm::Module::with_name("test");
+    IRGen::compile(&module, &mut session.fn_protos, &session.type_defs, &session.generics, &session.consts, &session.globals, Either::Right(&function))?;
+
+    let _rt = session.jit.add_module(module);
+    let fp = session.jit.find_symbol::<unsafe extern "C-unwind" fn() -> f64>(TOP_LEVEL_PREFIX);
+    let globals = save_globals(session);
+    let _watchdog = session.timeout.map(Watchdog::start);
+    let res = cobra_runtime::error::catch(|| unsafe { fp() });
+    restore_globals(&globals);
+    res.map(|_| ()).map_err(|err| err.to_string())
+}
+
+/// Run the `test` blocks of `source` (`cobra test <filename>`) after compiling and running the
+/// rest of the file, and print a summary. Return whether all tests passed and the rest of the
+/// file had no errors.
+fn test_cobra(source: &str, root: &Path, options: Options) -> bool {
+    let mut parser = Parser::new(Lexer::new(source.chars()));
     parser.get_next_token();
 
-    llvm:initialize_native_taget();
-    main_loop(parser);
+    llvm::initialize_native_taget();
+
+    let jit = options.new_jit();
+    let mut session = new_session(&jit, root, options);
+    session.tests = Some(Vec::new());
+    main_loop(parser, source, &mut session, &mut Namespace::root());
 
+    let tests = session.tests.take().unwrap_or_default();
+    let count = tests.len();
+    println!("\nrunning {} tests", count);
+    let mut failures = Vec::new();
+    for TestAST { name, body } in tests {
+        match run_test(&mut session, body) {
+            Ok(()) => println!("test {} ... ok", name),
+            Err(err) => {
+                println!("test {} ... FAILED", name);
+                failures.push((name, err));
+            }
+        }
+    }
+
+    if !failures.is_empty() {
+        println!("\nfailures:");
+        for (name, err) in &failures {
+            println!("\n---- {} ----\n{}", name, err);
+        }
+    }
+    let passed = failures.is_empty() && session.errors == 0;
+    let result = if passed { "ok" } else { "FAILED" };
+    println!(
+        "\ntest result: {}. {} passed; {} failed; {} errors",
+        result,
+        count - failures.len(),
+        failures.len(),
+        session.errors
+    );
+
+    // Code must be removed from the JIT before it is destroyed.
+    drop(session);
+    drop(jit);
     llvm::shutdown();
+    passed
+}
+
+/// Compile the script `filename` into a native executable (`cobra build <filename> [-o <output>]`).
+fn build(filename: &str, output: Option<String>) {
+    let source = Path::new(filename);
+    let output = output
+        .map(PathBuf::from)
+        .unwrap_or_else(|| source.with_extension(""));
+
+    llvm::initialize_native_taget();
+    let res = aot::build(source, &output);
+    llvm::shutdown();
+
+    if let Err(err) = res {
+        eprintln!("Error: {}", err);
+        std::process::exit(1);
+    }
 }
 
 fn main() {
-    match std::env::args().nth(1) {
+    let mut args = std::env::args().skip(1).peekable();
+
+    // Options of running and testing programs come first.
+    let mut options = Options::default();
+    loop {
+        match args.peek().map(String::as_str) {
+            Some("-j") => {
+                args.next();
+                let usage = "Usage: cobra -j <jobs> <filename>";
+                options.jobs = args.next().and_then(|jobs| jobs.parse().ok()).expect(usage);
+            }
+            Some("--no-cache") => {
+                args.next();
+                options.cache = false;
+            }
+            _ => break,
+        }
+    }
+
+    match args.next() {
+        Some(cmd) if cmd == "build" => {
+            let filename = args.next().expect("Usage: cobra build <filename> [-o <output>]");
+            let output = match args.next() {
+                Some(flag) if flag == "-o" => args.next(),
+                _ => None,
+            };
+            build(&filename, output);
+        }
+        Some(cmd) if cmd == "test" => {
+            let filename = args.next().expect("Usage: cobra test <filename>");
+            let contents = std::fs::read_to_string(&filename).unwrap();
+            let root = Path::new(&filename).parent().unwrap_or(Path::new("."));
+            if !test_cobra(&contents, root, options) {
+                std::process::exit(1);
+            }
+        }
+        Some(cmd) if cmd == "lsp" => {
+            let stdin = std::io::stdin();
+            let stdout = std::io::stdout();
+            let code = lsp::run(stdin.lock(), stdout.lock()).expect("Language server I/O error");
+            std::process::exit(code);
+        }
         Some(filename) => {
-            let mut file = std::fs::File::open(filename).unwrap();
+            let mut file = std::fs::File::open(&filename).unwrap();
             let mut contents = String::new();
             file.read_to_string(&mut contents).unwrap();
-            let lexer = Lexer::new(contents.chars());
-            run_cobra(lexer);
+            let root = Path::new(&filename).parent().unwrap_or(Path::new("."));
+            run_cobra(&contents, root, options);
         }
         None => {
             let stdin = std::io::stdin();
             let mut handle = stdin.lock();
             let mut contents = String::new();
             handle.read_to_string(&mut contents).unwrap();
-            let lexer = Lexer::new(contents.chars());
-            run_cobra(lexer);
+            run_cobra(&contents, Path::new("."), options);
         }
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,150 @@
+//! Parallel compilation of independent functions.
+//!
+//! Every [`Module`] has its own `ThreadSafeContext`, so functions can be lowered and optimized on
+//! worker threads, each with its own modules and [`IRGen`]. The modules are then handed to an
+//! [`LLJit`][crate::llvm::LLJit] with a compile thread pool, which compiles them to native code
+//! concurrently.
+//!
+//! Functions stored in the object cache are compiled to native objects by the workers instead,
+//! since the JIT doesn't return the code it compiles.
+
+use std::collections::{HashMap, HashSet};
+use std::thread;
+
+use crate::consteval::ConstValue;
+use crate::ir_gen::{IRGen, TypeDefs};
+use crate::llvm::{Module, TargetMachine};
+use crate::parser::{FunctionAST, PrototypeAST, TypeAST};
+use crate::snapshot::Definition;
+use crate::Either;
+
+/// A function compiled on a worker thread.
+pub enum Compiled {
+    /// An optimized IR module, to be compiled to native code by the JIT.
+    Module(Module),
+    /// A native object, eg to be stored in the object cache.
+    Object(Vec<u8>),
+}
+
+/// Compile `functions` on `jobs` worker threads, to native objects if `emit_objects` is set or
+/// else to IR modules.
+///
+/// The prototypes of all `functions` must be in `fn_protos`, so they can call each other. The
+/// results are returned in the order of `functions`. The native target must have been
+/// initialized.
+#[allow(clippy::too_many_arguments)]
+pub fn compile(
+    functions: &[&FunctionAST],
+    jobs: usize,
+    emit_objects: bool,
+    module_name: &str,
+    fn_protos: &HashMap<String, PrototypeAST>,
+    type_defs: &TypeDefs,
+    generics: &HashMap<String, FunctionAST>,
+    consts: &HashMap<String, ConstValue>,
+    globals: &HashMap<String, TypeAST>,
+) -> Vec<Result<Compiled, String>> {
+    let jobs = jobs.clamp(1, functions.len().max(1));
+
+    let mut results: Vec<_> = functions.iter().map(|_| None).collect();
+    thread::scope(|scope| {
+        let workers: Vec<_> = (0..jobs)
+            .map(|worker| {
+                // Functions are dealt round-robin, so neighbouring (often similar) definitions
+                // are spread over the workers.
+                let assigned: Vec<_> = (worker..functions.len()).step_by(jobs).collect();
+                scope.spawn(move || {
+                    let target = emit_objects.then(TargetMachine::native);
+                    // IRGen inserts the prototypes of instantiated generics, so every worker
+                    // needs its own table.
+                    let mut fn_protos = fn_protos.clone();
+                    assigned
+                        .into_iter()
+                        .map(|i| {
+                            let module = Module::with_name(module_name);
+                            let res = match IRGen::compile(&module, &mut fn_protos, type_defs, generics, consts, globals, Either::Right(functions[i])) {
+                                Ok(_) => match &target {
+                                    Some(target) => target.emit_object_to_memory(&module).map(Compiled::Object),
+                                    None => Ok(Compiled::Module(module)),
+                                },
+                                Err(e) => Err(e),
+                            };
+                            (i, res)
+                        })
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+
+        for worker in workers {
+            for (i, res) in worker.join().expect("Compile worker panicked") {
+                results[i] = Some(res);
+            }
+        }
+    });
+
+    results.into_iter().map(|res| res.expect("Function was not compiled")).collect()
+}
+
+/// Fail the functions of `definitions` calling a function which failed to compile, directly or
+/// indirectly, since their code would fail to link. `results` are in the order of `definitions`.
+pub fn fail_callers<T>(definitions: &[&Definition], results: &mut [Result<T, String>]) {
+    let mut failed = HashSet::new();
+    while let Some(i) = definitions.iter().zip(results.iter()).position(|(definition, result)| {
+        !failed.contains(&definition.name)
+            && (result.is_err() || definition.uses.iter().any(|callee| failed.contains(callee)))
+    }) {
+        if results[i].is_ok() {
+            results[i] = Err(format!("Function {} calls a function which failed to compile", definitions[i].name));
+        }
+        failed.insert(definitions[i].name.clone());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+
+    fn definition(source: &str) -> Definition {
+        let mut parser = Parser::new(Lexer::new(source.chars()));
+        parser.get_next_token();
+        Definition::function(&parser.parse_definition().unwrap(), source)
+    }
+
+    #[test]
+    fn callers_of_failed_functions_fail() {
+        let definitions = [
+            definition("def f(x) g(x) + 1"),
+            definition("def g(x) h(x)"),
+            definition("def h(x) x"),
+            definition("def k(x) x * 2"),
+        ];
+        let definitions: Vec<_> = definitions.iter().collect();
+        let mut results = vec![Ok(()), Ok(()), Err("Unknown variable y".to_string()), Ok(())];
+        fail_callers(&definitions, &mut results);
+        assert_eq!(
+            results,
+            [
+                Err("Function f calls a function which failed to compile".to_string()),
+                Err("Function g calls a function which failed to compile".to_string()),
+                Err("Unknown variable y".to_string()),
+                Ok(()),
+            ]
+        );
+    }
+
+    #[test]
+    fn recursive_functions_are_failed_once() {
+        let definitions = [definition("def even(n) if n == 0 then 1 else odd(n - 1)"), definition("def odd(n) if n == 0 then 0 else even(n - 1)")];
+        let definitions: Vec<_> = definitions.iter().collect();
+        let mut results = vec![Ok(()), Err("Type mismatch".to_string())];
+        fail_callers(&definitions, &mut results);
+        assert_eq!(results, [Err("Function even calls a function which failed to compile".to_string()), Err("Type mismatch".to_string())]);
+
+        let mut results: Vec<Result<(), String>> = vec![Ok(()), Ok(())];
+        fail_callers(&definitions, &mut results);
+        assert_eq!(results, [Ok(()), Ok(())]);
+    }
+}
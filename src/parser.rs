@@ -1,38 +1,258 @@
-use crate::lexer::{Lexer, Token};
+use std::collections::HashSet;
+use std::fmt;
+
+use crate::lexer::{Lexer, SourceLoc, Token};
 
 #[derive(Debug, PartialEq)]
 pub enum ExprAST {
     Number(f64),
+    Str(String),
+    Bool(bool),
     Variable(String),
-    BinaryOp(char, Box<ExprAST>, Box<ExprAST>),
-    Call(String, Vec<ExprAST>),
+    /// Binary operator, `and` and `or` short-circuit. The location of the operator is reported
+    /// by runtime errors (eg division by zero).
+    BinaryOp(String, Box<ExprAST>, Box<ExprAST>, SourceLoc),
+    /// Unary operator, eg `not x`.
+    UnaryOp(String, Box<ExprAST>),
+    /// Function call, with the location of the callee name for stack traces.
+    Call(String, Vec<ExprAST>, SourceLoc),
+    Index(Box<ExprAST>, Box<ExprAST>, SourceLoc),
+    Array(Vec<ExprAST>),
+    /// Field access `value.field`.
+    Field(Box<ExprAST>, String),
+    /// Heap allocated struct `new Name(args)`, evaluating to a `&Name` reference.
+    New(String, Vec<ExprAST>),
+    /// Assignment to an element `a[i] = value`, a field `p.x = value` or a global variable.
+    Assign(Box<ExprAST>, Box<ExprAST>),
     If {
         condition: Box<ExprAST>,
         then: Box<ExprAST>,
         else_: Box<ExprAST>,
     },
+    /// `for i = start, end[, step] in body`, `end` is either an exclusive bound or a condition.
     For {
         variable_name: String,
         start: Box<ExprAST>,
         end: Box<ExprAST>,
-        step: Box<ExprAST>,
+        step: Option<Box<ExprAST>>,
+        body: Box<ExprAST>,
+    },
+    /// `while condition: body`
+    While {
+        condition: Box<ExprAST>,
+        body: Box<ExprAST>,
+    },
+    Break,
+    Continue,
+    /// `for x in iterable: body`
+    ForIn {
+        variable_name: String,
+        iterable: Box<ExprAST>,
+        body: Box<ExprAST>,
+    },
+    /// Anonymous function `lambda x, y: body`, capturing the variables of the enclosing scope it
+    /// refers to.
+    Lambda {
+        params: Vec<String>,
+        body: Box<ExprAST>,
+    },
+    /// `match value: case pattern [if guard]: body ...`
+    Match {
+        value: Box<ExprAST>,
+        arms: Vec<MatchArm>,
+    },
+    /// Block `{ statement; ... }`, evaluating to the value of its last statement.
+    Block(Vec<ExprAST>),
+    /// Immutable binding `let name = value`, visible until the end of the enclosing block.
+    Let(String, Box<ExprAST>),
+    /// `return value`, leaving the enclosing function.
+    Return(Box<ExprAST>),
+    /// `raise message`, raising an error with a `str` message.
+    Raise(Box<ExprAST>, SourceLoc),
+    /// `try: body except [name]: handler finally: cleanup`, with at least one of the clauses.
+    /// `name` is bound to the message of the caught error.
+    Try {
         body: Box<ExprAST>,
+        handler: Option<(Option<String>, Box<ExprAST>)>,
+        finally: Option<Box<ExprAST>>,
     },
+    /// `assert condition[, message]`, raising an error reporting the source text of the
+    /// condition and the `str` message if the condition doesn't hold.
+    Assert {
+        condition: Box<ExprAST>,
+        message: Option<Box<ExprAST>>,
+        source: String,
+        loc: SourceLoc,
+    },
+}
+
+/// A `case` of a `match` expression.
+#[derive(Debug, PartialEq)]
+pub struct MatchArm {
+    pub pattern: PatternAST,
+    pub guard: Option<ExprAST>,
+    pub body: ExprAST,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum PatternAST {
+    /// `_`, matches anything.
+    Wildcard,
+    /// A name, binding the matched value. Names of nullary variants (eg `Nil`) match the variant
+    /// instead.
+    Binding(String),
+    /// Number literal, matches an equal `f64`.
+    Literal(f64),
+    /// Constructor pattern `Rect(w, h)` matching a variant of an enum.
+    Variant(String, Vec<PatternAST>),
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum TypeAST {
+    F64,
+    Bool,
+    Str,
+    /// Array of `f64`, written `[f64]`.
+    Array,
+    /// Struct or enum passed by value, written `Name`.
+    Struct(String),
+    /// Reference to a heap allocated struct, written `&Name`.
+    Ref(String),
+    /// Function value, written `fn(f64, str) -> f64`.
+    Fn(Vec<TypeAST>, Box<TypeAST>),
+}
+
+impl TypeAST {
+    /// Replace the type parameters in this type by the types they are bound to.
+    pub fn substitute(&self, bindings: &[(String, TypeAST)]) -> TypeAST {
+        match self {
+            TypeAST::Struct(name) => bindings
+                .iter()
+                .find(|(param, _)| param == name)
+                .map_or_else(|| self.clone(), |(_, ty)| ty.clone()),
+            TypeAST::Fn(args, ret) => TypeAST::Fn(
+                args.iter().map(|arg| arg.substitute(bindings)).collect(),
+                Box::new(ret.substitute(bindings)),
+            ),
+            _ => self.clone(),
+        }
+    }
+}
+
+impl fmt::Display for TypeAST {
+    /// Format the type in Cobra syntax.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TypeAST::F64 => write!(f, "f64"),
+            TypeAST::Bool => write!(f, "bool"),
+            TypeAST::Str => write!(f, "str"),
+            TypeAST::Array => write!(f, "[f64]"),
+            TypeAST::Struct(name) => write!(f, "{}", name),
+            TypeAST::Ref(name) => write!(f, "&{}", name),
+            TypeAST::Fn(args, ret) => {
+                write!(f, "fn(")?;
+                for (i, arg) in args.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", arg)?;
+                }
+                write!(f, ") -> {}", ret)
+            }
+        }
+    }
 }
 
 #[derive(Debug, PartialEq, Clone)]
-pub struct PrototypeAST(pub String, pub Vec<String>);
+pub struct PrototypeAST {
+    pub name: String,
+    /// Type parameters of a generic function `def max[T](a: T, b: T) -> T`.
+    pub type_params: Vec<String>,
+    pub args: Vec<String>,
+    pub arg_types: Vec<TypeAST>,
+    pub ret_type: TypeAST,
+}
+
+impl PrototypeAST {
+    /// Get the prototype of the instance of this generic function for `bindings` of its type
+    /// parameters. The instance is named after its type arguments, eg `max[f64]`.
+    pub fn instantiate(&self, bindings: &[(String, TypeAST)]) -> Result<PrototypeAST, String> {
+        let mut type_args = Vec::new();
+        for param in &self.type_params {
+            match bindings.iter().find(|(bound, _)| bound == param) {
+                Some((_, ty)) => type_args.push(ty.to_string()),
+                None => return Err(format!("Cannot infer type parameter {} of {}", param, self.name)),
+            }
+        }
+
+        Ok(PrototypeAST {
+            name: format!("{}[{}]", self.name, type_args.join(", ")),
+            type_params: Vec::new(),
+            args: self.args.clone(),
+            arg_types: self.arg_types.iter().map(|ty| ty.substitute(bindings)).collect(),
+            ret_type: self.ret_type.substitute(bindings),
+        })
+    }
+}
 
 #[derive(Debug, PartialEq)]
 pub struct FunctionAST(pub PrototypeAST, pub ExprAST);
 
+/// `struct Name: field: type, ...`
+#[derive(Debug, PartialEq, Clone)]
+pub struct StructAST {
+    pub name: String,
+    pub fields: Vec<(String, TypeAST)>,
+}
+
+/// `enum Name = Variant(field, ...) | ...`
+#[derive(Debug, PartialEq, Clone)]
+pub struct EnumAST {
+    pub name: String,
+    pub variants: Vec<StructAST>,
+}
+
+/// `const NAME = value`, evaluated at compile time.
+#[derive(Debug, PartialEq)]
+pub struct ConstAST {
+    pub name: String,
+    pub value: ExprAST,
+}
+
+/// `global name = value`, a variable shared by all functions. The initial value is evaluated
+/// at compile time.
+#[derive(Debug, PartialEq)]
+pub struct GlobalAST {
+    pub name: String,
+    pub value: ExprAST,
+}
+
+/// `test "name": body`, only compiled and run by `cobra test`.
+#[derive(Debug, PartialEq)]
+pub struct TestAST {
+    pub name: String,
+    pub body: ExprAST,
+}
+
+/// `import module` (with empty `names`) or `from module import name, ...`.
+#[derive(Debug, PartialEq, Clone)]
+pub struct ImportAST {
+    pub module: String,
+    pub names: Vec<String>,
+}
+
 type ParseResult<T> = Result<T, String>;
 
 pub struct Parser<I>
     where I: Iterator<Item=char>
 {
-    lexer: I,
-    current_token: Option<token>,
+    lexer: Lexer<I>,
+    current_token: Option<Token>,
+    /// Location just past the token before the current one.
+    previous_end: SourceLoc,
+    /// Modules imported by the parsed source. `module.name` is a qualified name if `module` is
+    /// one of them, and a field access otherwise.
+    modules: HashSet<String>,
 }
 
 impl<I> Parser<I>
@@ -40,8 +260,10 @@ impl<I> Parser<I>
 {
     pub fn new(lexer: Lexer<I>) -> Self {
         Parser {
-            lexer: lexer,
-            current_token: None
+            lexer,
+            current_token: None,
+            previous_end: SourceLoc::default(),
+            modules: HashSet::new(),
         }
     }
 
@@ -49,9 +271,25 @@ impl<I> Parser<I>
         self.current_token.as_ref().expect("Parser: No current token")
     }
 
+    /// Get the source location of the current token.
+    pub fn current_loc(&self) -> SourceLoc {
+        self.lexer.token_loc()
+    }
+
+    /// Get the byte offset of the current token in the source.
+    pub fn current_offset(&self) -> usize {
+        self.lexer.token_offset()
+    }
+
+    /// Get the location just past the last token consumed by the parser, ie the end of the last
+    /// parsed construct.
+    pub fn previous_end(&self) -> SourceLoc {
+        self.previous_end
+    }
+
     pub fn get_next_token(&mut self) {
-        self.current_token = self.lexer.next();
-        self.current_token.clone()
+        self.previous_end = self.lexer.token_end();
+        self.current_token = Some(self.lexer.gettok());
     }
 
     fn parse_number(&mut self) -> ParseResult<ExprAST> {
@@ -64,6 +302,17 @@ impl<I> Parser<I>
         }
     }
 
+    fn parse_str(&mut self) -> ParseResult<ExprAST> {
+        match *self.current_token() {
+            Token::Str(ref value) => {
+                let value = value.clone();
+                self.get_next_token();
+                Ok(ExprAST::Str(value))
+            }
+            ref token => Err(format!("Expected string, found {:?}", token)),
+        }
+    }
+
     fn parse_paren_expr(&mut self) -> ParseResult<ExprAST> {
         assert_eq!(*self.current_token(), Token::Char('('));
         self.get_next_token();
@@ -77,16 +326,86 @@ impl<I> Parser<I>
     }
 
     fn parse_identifier_expr(&mut self) -> ParseResult<ExprAST> {
+        let loc = self.current_loc();
         let identifier = match *self.current_token() {
             Token::Identifier(ref name) => name.clone(),
             ref token => return Err(format!("Expected identifier, found {:?}", token)),
         };
         self.get_next_token();
 
+        // Names of imported modules are joined with the following names, eg `util.math.sqrt`.
+        // Other dots are field accesses, the following ones are parsed by `parse_primary`.
+        let mut name = identifier;
+        while *self.current_token() == Token::Char('.') && self.is_module_prefix(&name, true) {
+            self.get_next_token();
+            let part = match *self.current_token() {
+                Token::Identifier(ref part) => part.clone(),
+                ref token => return Err(format!("Expected name after '{}.', found {:?}", name, token)),
+            };
+            self.get_next_token();
+
+            if !self.is_module_prefix(&name, false) && !self.is_module_prefix(&format!("{}.{}", name, part), true) {
+                // `name` is only the start of a module name, eg `util` of `util.math`.
+                return Ok(ExprAST::Field(Box::new(ExprAST::Variable(name)), part));
+            }
+            name.push('.');
+            name.push_str(&part);
+        }
+
+        if *self.current_token() != Token::Char('(') {
+            return Ok(ExprAST::Variable(name));
+        }
+
+        let args = self.parse_call_args()?;
+        Ok(ExprAST::Call(name, args, loc))
+    }
+
+    /// Check if `name` is an imported module or, if `partial`, the start of the dotted name of
+    /// one.
+    fn is_module_prefix(&self, name: &str, partial: bool) -> bool {
+        self.modules.iter().any(|module| {
+            module == name || (partial && module.starts_with(name) && module[name.len()..].starts_with('.'))
+        })
+    }
+
+    /// Parse a name made of identifiers joined by dots, eg `util.math`.
+    pub fn parse_dotted_name(&mut self) -> ParseResult<String> {
+        let mut name = String::new();
+        loop {
+            match *self.current_token() {
+                Token::Identifier(ref part) => name.push_str(part),
+                ref token => return Err(format!("Expected identifier, found {:?}", token)),
+            }
+            self.get_next_token();
+
+            if *self.current_token() != Token::Char('.') {
+                return Ok(name);
+            }
+            name.push('.');
+            self.get_next_token();
+        }
+    }
+
+    fn parse_new_expr(&mut self) -> ParseResult<ExprAST> {
+        assert_eq!(*self.current_token(), Token::New);
+        self.get_next_token();
+
+        let name = match *self.current_token() {
+            Token::Identifier(ref name) => name.clone(),
+            ref token => return Err(format!("Expected struct name, found {:?}", token)),
+        };
+        self.get_next_token();
+
         if *self.current_token() != Token::Char('(') {
-            return Ok(ExprAST::Variable(identifier));
+            return Err(format!("Expected '(', found {:?}", self.current_token()));
         }
+        let args = self.parse_call_args()?;
+        Ok(ExprAST::New(name, args))
+    }
 
+    /// Parse a parenthesized, comma separated argument list.
+    fn parse_call_args(&mut self) -> ParseResult<Vec<ExprAST>> {
+        assert_eq!(*self.current_token(), Token::Char('('));
         self.get_next_token();
         let mut args = Vec::new();
         if *self.current_token() != Token::Char(')') {
@@ -102,7 +421,7 @@ impl<I> Parser<I>
             }
         }
         self.get_next_token();
-        Ok(ExprAST::Call(identifier, args))
+        Ok(args)
     }
 
     fn parse_if_expr(&mut self) -> ParseResult<ExprAST> {
@@ -129,6 +448,218 @@ impl<I> Parser<I>
         })
     }
 
+    fn parse_lambda_expr(&mut self) -> ParseResult<ExprAST> {
+        assert_eq!(*self.current_token(), Token::Lambda);
+        self.get_next_token();
+
+        // Like in Python, lambda parameters can't be annotated, they are `f64`.
+        let mut params = Vec::new();
+        while let Token::Identifier(ref name) = *self.current_token() {
+            params.push(name.clone());
+            self.get_next_token();
+
+            if *self.current_token() != Token::Char(',') {
+                break;
+            }
+            self.get_next_token();
+        }
+
+        if *self.current_token() != Token::Char(':') {
+            return Err(format!("Expected ':', found {:?}", self.current_token()));
+        }
+        self.get_next_token();
+
+        let body = self.parse_expression()?;
+        Ok(ExprAST::Lambda { params, body: Box::new(body) })
+    }
+
+    fn parse_block(&mut self) -> ParseResult<ExprAST> {
+        assert_eq!(*self.current_token(), Token::Char('{'));
+        self.get_next_token();
+
+        // Statements are separated by `;`, which is optional after the last one.
+        let mut statements = Vec::new();
+        while *self.current_token() != Token::Char('}') {
+            statements.push(self.parse_statement()?);
+
+            match *self.current_token() {
+                Token::Char(';') => self.get_next_token(),
+                Token::Char('}') => break,
+                ref token => return Err(format!("Expected ';' or '}}', found {:?}", token)),
+            };
+        }
+        self.get_next_token();
+        Ok(ExprAST::Block(statements))
+    }
+
+    fn parse_statement(&mut self) -> ParseResult<ExprAST> {
+        if *self.current_token() != Token::Let {
+            return self.parse_expression();
+        }
+        self.get_next_token();
+
+        let name = match *self.current_token() {
+            Token::Identifier(ref name) => name.clone(),
+            ref token => return Err(format!("Expected identifier, found {:?}", token)),
+        };
+        self.get_next_token();
+
+        if *self.current_token() != Token::Char('=') {
+            return Err(format!("Expected '=', found {:?}", self.current_token()));
+        }
+        self.get_next_token();
+
+        let value = self.parse_expression()?;
+        Ok(ExprAST::Let(name, Box::new(value)))
+    }
+
+    fn parse_match_expr(&mut self) -> ParseResult<ExprAST> {
+        assert_eq!(*self.current_token(), Token::Match);
+        self.get_next_token();
+
+        let value = self.parse_expression()?;
+        if *self.current_token() != Token::Char(':') {
+            return Err(format!("Expected ':', found {:?}", self.current_token()));
+        }
+        self.get_next_token();
+
+        let mut arms = Vec::new();
+        while *self.current_token() == Token::Case {
+            self.get_next_token();
+            let pattern = self.parse_pattern()?;
+
+            let guard = if *self.current_token() == Token::If {
+                self.get_next_token();
+                Some(self.parse_expression()?)
+            } else {
+                None
+            };
+
+            if *self.current_token() != Token::Char(':') {
+                return Err(format!("Expected ':', found {:?}", self.current_token()));
+            }
+            self.get_next_token();
+
+            let body = self.parse_expression()?;
+            arms.push(MatchArm { pattern, guard, body });
+        }
+
+        if arms.is_empty() {
+            return Err(format!("Expected 'case', found {:?}", self.current_token()));
+        }
+        Ok(ExprAST::Match { value: Box::new(value), arms })
+    }
+
+    fn parse_try_expr(&mut self) -> ParseResult<ExprAST> {
+        assert_eq!(*self.current_token(), Token::Try);
+        self.get_next_token();
+
+        if *self.current_token() != Token::Char(':') {
+            return Err(format!("Expected ':', found {:?}", self.current_token()));
+        }
+        self.get_next_token();
+        let body = self.parse_expression()?;
+
+        let handler = if *self.current_token() == Token::Except {
+            self.get_next_token();
+            let name = match *self.current_token() {
+                Token::Identifier(ref name) => Some(name.clone()),
+                _ => None,
+            };
+            if name.is_some() {
+                self.get_next_token();
+            }
+
+            if *self.current_token() != Token::Char(':') {
+                return Err(format!("Expected ':', found {:?}", self.current_token()));
+            }
+            self.get_next_token();
+            Some((name, Box::new(self.parse_expression()?)))
+        } else {
+            None
+        };
+
+        let finally = if *self.current_token() == Token::Finally {
+            self.get_next_token();
+            if *self.current_token() != Token::Char(':') {
+                return Err(format!("Expected ':', found {:?}", self.current_token()));
+            }
+            self.get_next_token();
+            Some(Box::new(self.parse_expression()?))
+        } else {
+            None
+        };
+
+        if handler.is_none() && finally.is_none() {
+            return Err(format!("Expected 'except' or 'finally', found {:?}", self.current_token()));
+        }
+        Ok(ExprAST::Try { body: Box::new(body), handler, finally })
+    }
+
+    fn parse_assert_expr(&mut self) -> ParseResult<ExprAST> {
+        assert_eq!(*self.current_token(), Token::Assert);
+        let loc = self.current_loc();
+        self.lexer.start_recording();
+        self.get_next_token();
+
+        let condition = self.parse_expression();
+        let source = self.lexer.stop_recording(self.current_offset());
+        let condition = condition?;
+
+        let message = if *self.current_token() == Token::Char(',') {
+            self.get_next_token();
+            Some(Box::new(self.parse_expression()?))
+        } else {
+            None
+        };
+        Ok(ExprAST::Assert {
+            condition: Box::new(condition),
+            message,
+            source: source.trim().to_string(),
+            loc,
+        })
+    }
+
+    fn parse_pattern(&mut self) -> ParseResult<PatternAST> {
+        let pattern = match *self.current_token() {
+            Token::Identifier(ref name) if name == "_" => PatternAST::Wildcard,
+            Token::Identifier(ref name) => PatternAST::Binding(name.clone()),
+            Token::Number(value) => PatternAST::Literal(value),
+            Token::Char('-') => {
+                self.get_next_token();
+                match *self.current_token() {
+                    Token::Number(value) => PatternAST::Literal(-value),
+                    ref token => return Err(format!("Expected number, found {:?}", token)),
+                }
+            }
+            ref token => return Err(format!("Expected pattern, found {:?}", token)),
+        };
+        self.get_next_token();
+
+        let name = match pattern {
+            PatternAST::Binding(name) if *self.current_token() == Token::Char('(') => name,
+            pattern => return Ok(pattern),
+        };
+
+        self.get_next_token();
+        let mut fields = Vec::new();
+        if *self.current_token() != Token::Char(')') {
+            loop {
+                fields.push(self.parse_pattern()?);
+
+                if *self.current_token() == Token::Char(')') {
+                    break;
+                }
+                if *self.current_token() != Token::Char(',') {
+                    return Err(format!("Expected ',' or ')', found {:?}", self.current_token()));
+                }
+                self.get_next_token();
+            }
+        }
+        self.get_next_token();
+        Ok(PatternAST::Variant(name, fields))
+    }
+
     fn parse_for_expr(&mut self) -> ParseResult<ExprAST> {
         assert_eq!(*self.current_token(), Token::For);
         self.get_next_token();
@@ -139,6 +670,10 @@ impl<I> Parser<I>
         };
         self.get_next_token();
 
+        if *self.current_token() == Token::In {
+            return self.parse_for_in_expr(variable_name);
+        }
+
         if *self.current_token() != Token::Char('=') {
             return Err(format!("Expected '=', found {:?}", self.current_token()));
         }
@@ -165,49 +700,269 @@ impl<I> Parser<I>
 
         let body = self.parse_expression()?;
         Ok(ExprAST::For {
-            variable_name: variable_name,
+            variable_name,
             start: Box::new(start),
             end: Box::new(end),
-            step: Box::new(step),
+            step: step.map(Box::new),
             body: Box::new(body),
         })
     }
 
-    fn parse_expression(&mut self) -> ParseResult<ExprAST> {
-        let lhs = self.parse_primary()?;
-        self.parse_bin_op_rhs(0, lhs)
-    }
+    fn parse_while_expr(&mut self) -> ParseResult<ExprAST> {
+        assert_eq!(*self.current_token(), Token::While);
+        self.get_next_token();
 
-    fn parse_bin_op_rhs(&mut self, expr_prec: u8, lhs: ExprAST) -> ParseResult<ExprAST> {
-        let mut lhs = lhs;
-        loop {
-            let token_prec = get_token_precedence(self.current_token());
-            if token_prec < expr_prec {
-                return Ok(lhs);
-            }
-            let bin_op = match *self.current_token() {
-                Token::Char(c) => c,
-                ref token => return Err(format!("Expected operator, found {:?}", token)),
-            };
-            self.get_next_token();
-            let mut rhs = self.parse_primary()?;
-            let next_prec = get_token_precedence(self.current_token());
-            if token_prec < next_prec {
-                rhs = self.parse_bin_op_rhs(token_prec + 1, rhs)?;
-            }
-            lhs = ExprAST::BinaryOp(bin_op, Box::new(lhs), Box::new(rhs));
+        let condition = self.parse_expression()?;
+        if *self.current_token() != Token::Char(':') {
+            return Err(format!("Expected ':', found {:?}", self.current_token()));
         }
+        self.get_next_token();
+
+        let body = self.parse_expression()?;
+        Ok(ExprAST::While {
+            condition: Box::new(condition),
+            body: Box::new(body),
+        })
     }
 
-    fn parse_primary(&mut self) -> ParseResult<ExprAST> {
-        match *self.current_token() {
-            Token::Identifier(_) => self.parse_identifier_expr(),
-            Token::Number(_) => self.parse_num_expr(),
-            Token::Char('(') => self.parse_paren_expr(),
-            Token::If => self.parse_if_expr(),
-            Token::For => self.parse_for_expr(),
-            _ => Err(format!("Expected primary expression, found {:?}", self.current_token())),
+    fn parse_for_in_expr(&mut self, variable_name: String) -> ParseResult<ExprAST> {
+        assert_eq!(*self.current_token(), Token::In);
+        self.get_next_token();
+
+        let iterable = self.parse_expression()?;
+        if *self.current_token() != Token::Char(':') {
+            return Err(format!("Expected ':', found {:?}", self.current_token()));
         }
+        self.get_next_token();
+
+        let body = self.parse_expression()?;
+        Ok(ExprAST::ForIn {
+            variable_name,
+            iterable: Box::new(iterable),
+            body: Box::new(body),
+        })
+    }
+
+    fn parse_array_expr(&mut self) -> ParseResult<ExprAST> {
+        assert_eq!(*self.current_token(), Token::Char('['));
+        self.get_next_token();
+
+        let mut elements = Vec::new();
+        if *self.current_token() != Token::Char(']') {
+            loop {
+                elements.push(self.parse_expression()?);
+                if *self.current_token() == Token::Char(']') {
+                    break;
+                }
+                if *self.current_token() != Token::Char(',') {
+                    return Err(format!("Expected ']' or ',', found {:?}", self.current_token()));
+                }
+                self.get_next_token();
+            }
+        }
+        self.get_next_token();
+        Ok(ExprAST::Array(elements))
+    }
+
+    fn parse_expression(&mut self) -> ParseResult<ExprAST> {
+        let lhs = self.parse_unary()?;
+        let expr = self.parse_bin_op_rhs(0, lhs)?;
+
+        if *self.current_token() == Token::Char('=') {
+            if !matches!(expr, ExprAST::Index(..) | ExprAST::Field(..) | ExprAST::Variable(..)) {
+                return Err(format!("Invalid assignment target {:?}", expr));
+            }
+            self.get_next_token();
+            let value = self.parse_expression()?;
+            return Ok(ExprAST::Assign(Box::new(expr), Box::new(value)));
+        }
+
+        Ok(expr)
+    }
+
+    fn parse_bin_op_rhs(&mut self, expr_prec: i32, lhs: ExprAST) -> ParseResult<ExprAST> {
+        let mut lhs = lhs;
+        loop {
+            let token_prec = get_token_precedence(self.current_token());
+            if token_prec < expr_prec {
+                return Ok(lhs);
+            }
+            let loc = self.current_loc();
+            let bin_op = match *self.current_token() {
+                Token::Char(c) => c.to_string(),
+                Token::Operator(ref op) => op.clone(),
+                Token::And => "and".to_string(),
+                Token::Or => "or".to_string(),
+                ref token => return Err(format!("Expected operator, found {:?}", token)),
+            };
+            self.get_next_token();
+            let mut rhs = self.parse_unary()?;
+            let next_prec = get_token_precedence(self.current_token());
+            if token_prec < next_prec {
+                rhs = self.parse_bin_op_rhs(token_prec + 1, rhs)?;
+            } else if token_prec == next_prec && bin_op == "**" {
+                // `**` is right-associative: `a ** b ** c` is `a ** (b ** c)`.
+                rhs = self.parse_bin_op_rhs(token_prec, rhs)?;
+            }
+            lhs = ExprAST::BinaryOp(bin_op, Box::new(lhs), Box::new(rhs), loc);
+        }
+    }
+
+    /// Parse a primary expression with an optional prefix operator.
+    fn parse_unary(&mut self) -> ParseResult<ExprAST> {
+        // Like in Python, `not` binds looser than comparisons: `not a == b` is `not (a == b)`,
+        // while `-` and `~` bind tighter than anything but `**`: `-a ** 2` is `-(a ** 2)`.
+        let (op, operand_prec) = match *self.current_token() {
+            Token::Not => ("not", COMPARISON_PRECEDENCE),
+            Token::Char('-') => ("-", POWER_PRECEDENCE),
+            Token::Char('~') => ("~", POWER_PRECEDENCE),
+            _ => return self.parse_primary(),
+        };
+        self.get_next_token();
+
+        let operand = self.parse_unary()?;
+        let operand = self.parse_bin_op_rhs(operand_prec, operand)?;
+        Ok(ExprAST::UnaryOp(op.to_string(), Box::new(operand)))
+    }
+
+    fn parse_primary(&mut self) -> ParseResult<ExprAST> {
+        let mut expr = match *self.current_token() {
+            Token::Identifier(_) => self.parse_identifier_expr(),
+            Token::Number(_) => self.parse_number(),
+            Token::Str(_) => self.parse_str(),
+            Token::True | Token::False => {
+                let value = *self.current_token() == Token::True;
+                self.get_next_token();
+                Ok(ExprAST::Bool(value))
+            }
+            Token::Char('(') => self.parse_paren_expr(),
+            Token::Char('[') => self.parse_array_expr(),
+            Token::New => self.parse_new_expr(),
+            Token::If => self.parse_if_expr(),
+            Token::For => self.parse_for_expr(),
+            Token::While => self.parse_while_expr(),
+            Token::Break | Token::Continue => {
+                let expr = match *self.current_token() {
+                    Token::Break => ExprAST::Break,
+                    _ => ExprAST::Continue,
+                };
+                self.get_next_token();
+                Ok(expr)
+            }
+            Token::Match => self.parse_match_expr(),
+            Token::Lambda => self.parse_lambda_expr(),
+            Token::Char('{') => self.parse_block(),
+            Token::Return => {
+                self.get_next_token();
+                Ok(ExprAST::Return(Box::new(self.parse_expression()?)))
+            }
+            Token::Raise => {
+                let loc = self.current_loc();
+                self.get_next_token();
+                Ok(ExprAST::Raise(Box::new(self.parse_expression()?), loc))
+            }
+            Token::Try => self.parse_try_expr(),
+            Token::Assert => self.parse_assert_expr(),
+            Token::Error(ref message) => Err(message.clone()),
+            _ => Err(format!("Expected primary expression, found {:?}", self.current_token())),
+        }?;
+
+        loop {
+            match *self.current_token() {
+                Token::Char('[') => {
+                    let loc = self.current_loc();
+                    self.get_next_token();
+                    let index = self.parse_expression()?;
+                    if *self.current_token() != Token::Char(']') {
+                        return Err(format!("Expected ']', found {:?}", self.current_token()));
+                    }
+                    self.get_next_token();
+                    expr = ExprAST::Index(Box::new(expr), Box::new(index), loc);
+                }
+                Token::Char('.') => {
+                    self.get_next_token();
+                    let field = match *self.current_token() {
+                        Token::Identifier(ref field) => field.clone(),
+                        ref token => return Err(format!("Expected field name after '.', found {:?}", token)),
+                    };
+                    self.get_next_token();
+                    expr = ExprAST::Field(Box::new(expr), field);
+                }
+                _ => return Ok(expr),
+            }
+        }
+    }
+
+    fn parse_type(&mut self) -> ParseResult<TypeAST> {
+        if *self.current_token() == Token::Char('[') {
+            self.get_next_token();
+            if self.parse_type()? != TypeAST::F64 {
+                return Err("Only arrays of f64 are supported".to_string());
+            }
+            if *self.current_token() != Token::Char(']') {
+                return Err(format!("Expected ']', found {:?}", self.current_token()));
+            }
+            self.get_next_token();
+            return Ok(TypeAST::Array);
+        }
+
+        if *self.current_token() == Token::Char('&') {
+            self.get_next_token();
+            return match self.parse_type()? {
+                TypeAST::Struct(name) => Ok(TypeAST::Ref(name)),
+                ty => Err(format!("Only structs can be referenced, found {:?}", ty)),
+            };
+        }
+
+        let ty = match *self.current_token() {
+            Token::Identifier(ref name) => match name.as_str() {
+                "f64" => TypeAST::F64,
+                "bool" => TypeAST::Bool,
+                "str" => TypeAST::Str,
+                "fn" => return self.parse_fn_type(),
+                _ => TypeAST::Struct(name.clone()),
+            },
+            ref token => return Err(format!("Expected type, found {:?}", token)),
+        };
+        self.get_next_token();
+        Ok(ty)
+    }
+
+    /// Parse a function type `fn(type, ...) -> type`.
+    fn parse_fn_type(&mut self) -> ParseResult<TypeAST> {
+        self.get_next_token();
+        if *self.current_token() != Token::Char('(') {
+            return Err(format!("Expected '(', found {:?}", self.current_token()));
+        }
+        self.get_next_token();
+
+        let mut args = Vec::new();
+        if *self.current_token() != Token::Char(')') {
+            loop {
+                args.push(self.parse_type()?);
+
+                if *self.current_token() == Token::Char(')') {
+                    break;
+                }
+                if *self.current_token() != Token::Char(',') {
+                    return Err(format!("Expected ',' or ')', found {:?}", self.current_token()));
+                }
+                self.get_next_token();
+            }
+        }
+        self.get_next_token();
+
+        let ret = self.parse_ret_type()?;
+        Ok(TypeAST::Fn(args, Box::new(ret)))
+    }
+
+    /// Parse an optional `-> type` return type annotation, defaulting to `f64`.
+    fn parse_ret_type(&mut self) -> ParseResult<TypeAST> {
+        if !matches!(*self.current_token(), Token::Operator(ref op) if op == "->") {
+            return Ok(TypeAST::F64);
+        }
+        self.get_next_token();
+        self.parse_type()
     }
 
     fn parse_prototype(&mut self) -> ParseResult<PrototypeAST> {
@@ -217,15 +972,48 @@ impl<I> Parser<I>
         };
         self.get_next_token();
 
+        // Optional type parameters `[T, U]`.
+        let mut type_params = Vec::new();
+        if *self.current_token() == Token::Char('[') {
+            self.get_next_token();
+            while let Token::Identifier(ref param) = *self.current_token() {
+                type_params.push(param.clone());
+                self.get_next_token();
+
+                if *self.current_token() != Token::Char(',') {
+                    break;
+                }
+                self.get_next_token();
+            }
+
+            if *self.current_token() != Token::Char(']') {
+                return Err(format!("Expected ']', found {:?}", self.current_token()));
+            }
+            self.get_next_token();
+        }
+
         if *self.current_token() != Token::Char('(') {
             return Err(format!("Expected '(', found {:?}", self.current_token()));
         }
         self.get_next_token();
 
+        // Arguments are `f64` unless annotated with `: type`, and separated by whitespace or `,`.
         let mut args = Vec::new();
+        let mut arg_types = Vec::new();
         while let Token::Identifier(ref name) = *self.current_token() {
             args.push(name.clone());
             self.get_next_token();
+
+            if *self.current_token() == Token::Char(':') {
+                self.get_next_token();
+                arg_types.push(self.parse_type()?);
+            } else {
+                arg_types.push(TypeAST::F64);
+            }
+
+            if *self.current_token() == Token::Char(',') {
+                self.get_next_token();
+            }
         }
 
         if *self.current_token() != Token::Char(')') {
@@ -233,7 +1021,9 @@ impl<I> Parser<I>
         }
         self.get_next_token();
 
-        Ok(PrototypeAST(name, args))
+        let ret_type = self.parse_ret_type()?;
+
+        Ok(PrototypeAST { name, type_params, args, arg_types, ret_type })
     }
 
     pub fn parse_definition(&mut self) -> ParseResult<FunctionAST> {
@@ -245,27 +1035,650 @@ impl<I> Parser<I>
         Ok(FunctionAST(proto, body))
     }
 
+    pub fn parse_const(&mut self) -> ParseResult<ConstAST> {
+        assert_eq!(*self.current_token(), Token::Const);
+        self.get_next_token();
+
+        let name = match *self.current_token() {
+            Token::Identifier(ref name) => name.clone(),
+            ref token => return Err(format!("Expected constant name, found {:?}", token)),
+        };
+        self.get_next_token();
+
+        if *self.current_token() != Token::Char('=') {
+            return Err(format!("Expected '=', found {:?}", self.current_token()));
+        }
+        self.get_next_token();
+
+        let value = self.parse_expression()?;
+        Ok(ConstAST { name, value })
+    }
+
+    pub fn parse_global(&mut self) -> ParseResult<GlobalAST> {
+        assert_eq!(*self.current_token(), Token::Global);
+        self.get_next_token();
+
+        let name = match *self.current_token() {
+            Token::Identifier(ref name) => name.clone(),
+            ref token => return Err(format!("Expected global name, found {:?}", token)),
+        };
+        self.get_next_token();
+
+        if *self.current_token() != Token::Char('=') {
+            return Err(format!("Expected '=', found {:?}", self.current_token()));
+        }
+        self.get_next_token();
+
+        let value = self.parse_expression()?;
+        Ok(GlobalAST { name, value })
+    }
+
+    pub fn parse_struct(&mut self) -> ParseResult<StructAST> {
+        assert_eq!(*self.current_token(), Token::Struct);
+        self.get_next_token();
+
+        let name = match *self.current_token() {
+            Token::Identifier(ref name) => name.clone(),
+            ref token => return Err(format!("Expected struct name, found {:?}", token)),
+        };
+        self.get_next_token();
+
+        if *self.current_token() != Token::Char(':') {
+            return Err(format!("Expected ':', found {:?}", self.current_token()));
+        }
+        self.get_next_token();
+
+        let mut fields = Vec::new();
+        loop {
+            let field = match *self.current_token() {
+                Token::Identifier(ref name) => name.clone(),
+                ref token => return Err(format!("Expected field name, found {:?}", token)),
+            };
+            self.get_next_token();
+
+            if *self.current_token() != Token::Char(':') {
+                return Err(format!("Expected ':', found {:?}", self.current_token()));
+            }
+            self.get_next_token();
+            fields.push((field, self.parse_type()?));
+
+            if *self.current_token() != Token::Char(',') {
+                break;
+            }
+            self.get_next_token();
+        }
+
+        Ok(StructAST { name, fields })
+    }
+
+    pub fn parse_enum(&mut self) -> ParseResult<EnumAST> {
+        assert_eq!(*self.current_token(), Token::Enum);
+        self.get_next_token();
+
+        let name = match *self.current_token() {
+            Token::Identifier(ref name) => name.clone(),
+            ref token => return Err(format!("Expected enum name, found {:?}", token)),
+        };
+        self.get_next_token();
+
+        if *self.current_token() != Token::Char('=') {
+            return Err(format!("Expected '=', found {:?}", self.current_token()));
+        }
+        self.get_next_token();
+
+        let mut variants = Vec::new();
+        loop {
+            let variant = match *self.current_token() {
+                Token::Identifier(ref name) => name.clone(),
+                ref token => return Err(format!("Expected variant name, found {:?}", token)),
+            };
+            self.get_next_token();
+
+            // Fields are `f64` unless annotated with `: type`, variants without fields may omit
+            // the parentheses.
+            let mut fields = Vec::new();
+            if *self.current_token() == Token::Char('(') {
+                self.get_next_token();
+                while let Token::Identifier(ref field) = *self.current_token() {
+                    let field = field.clone();
+                    self.get_next_token();
+
+                    let ty = if *self.current_token() == Token::Char(':') {
+                        self.get_next_token();
+                        self.parse_type()?
+                    } else {
+                        TypeAST::F64
+                    };
+                    fields.push((field, ty));
+
+                    if *self.current_token() != Token::Char(',') {
+                        break;
+                    }
+                    self.get_next_token();
+                }
+
+                if *self.current_token() != Token::Char(')') {
+                    return Err(format!("Expected ')', found {:?}", self.current_token()));
+                }
+                self.get_next_token();
+            }
+            variants.push(StructAST { name: variant, fields });
+
+            if *self.current_token() != Token::Char('|') {
+                break;
+            }
+            self.get_next_token();
+        }
+
+        Ok(EnumAST { name, variants })
+    }
+
     pub fn parse_external(&mut self) -> ParseResult<PrototypeAST> {
         assert_eq!(*self.current_token(), Token::Extern);
         self.get_next_token();
-        self.parse_prototype()
+
+        let proto = self.parse_prototype()?;
+        if !proto.type_params.is_empty() {
+            return Err(format!("Extern function {} cannot be generic", proto.name));
+        }
+        Ok(proto)
+    }
+
+    pub fn parse_import(&mut self) -> ParseResult<ImportAST> {
+        let from = match *self.current_token() {
+            Token::Import => false,
+            Token::From => true,
+            ref token => return Err(format!("Expected 'import' or 'from', found {:?}", token)),
+        };
+        self.get_next_token();
+
+        let module = match *self.current_token() {
+            Token::Identifier(_) => self.parse_dotted_name()?,
+            ref token => return Err(format!("Expected module name, found {:?}", token)),
+        };
+        self.modules.insert(module.clone());
+
+        if !from {
+            return Ok(ImportAST { module, names: Vec::new() });
+        }
+
+        if *self.current_token() != Token::Import {
+            return Err(format!("Expected 'import', found {:?}", self.current_token()));
+        }
+        self.get_next_token();
+
+        let mut names = Vec::new();
+        loop {
+            match *self.current_token() {
+                Token::Identifier(ref name) => names.push(name.clone()),
+                ref token => return Err(format!("Expected identifier, found {:?}", token)),
+            }
+            self.get_next_token();
+
+            if *self.current_token() != Token::Char(',') {
+                break;
+            }
+            self.get_next_token();
+        }
+
+        Ok(ImportAST { module, names })
+    }
+
+    pub fn parse_test(&mut self) -> ParseResult<TestAST> {
+        assert_eq!(*self.current_token(), Token::Test);
+        self.get_next_token();
+
+        let name = match *self.current_token() {
+            Token::Str(ref name) => name.clone(),
+            ref token => return Err(format!("Expected test name, found {:?}", token)),
+        };
+        self.get_next_token();
+
+        if *self.current_token() != Token::Char(':') {
+            return Err(format!("Expected ':', found {:?}", self.current_token()));
+        }
+        self.get_next_token();
+
+        let body = self.parse_expression()?;
+        Ok(TestAST { name, body })
     }
 
     pub fn parse_top_level_expr(&mut self) -> ParseResult<FunctionAST> {
-        let proto = PrototypeAST("".to_string(), Vec::new());
+        let proto = PrototypeAST {
+            name: "__anon_expr".to_string(),
+            type_params: Vec::new(),
+            args: Vec::new(),
+            arg_types: Vec::new(),
+            ret_type: TypeAST::F64,
+        };
         Ok(FunctionAST(proto, self.parse_expression()?))
     }
 }
 
-fn get_token_precedence(token: &Token) -> u8 {
+/// Precedence of the comparison operators, the operand of `not` extends over them.
+const COMPARISON_PRECEDENCE: i32 = 10;
+/// Precedence of `**`, the only operator binding tighter than unary `-` and `~`.
+const POWER_PRECEDENCE: i32 = 60;
+
+/// Get the precedence of a binary operator, following Python: bitwise operators bind tighter
+/// than comparisons, but looser than arithmetic.
+fn get_token_precedence(token: &Token) -> i32 {
     match *token {
+        Token::Or => 2,
+        Token::And => 3,
+        Token::Operator(ref op) => match op.as_str() {
+            "==" | "!=" | "<=" | ">=" => COMPARISON_PRECEDENCE,
+            "<<" | ">>" => 18,
+            "//" => 40,
+            "**" => POWER_PRECEDENCE,
+            _ => -1,
+        },
         Token::Char(c) => match c {
-                '<' => 10,
+                '<' | '>' => COMPARISON_PRECEDENCE,
+                '|' => 12,
+                '^' => 14,
+                '&' => 16,
                 '+' => 20,
                 '-' => 20,
-                '*' => 40,
+                '*' | '/' | '%' => 40,
                 _ => -1,
         },
         _ => -1,
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parser(source: &str) -> Parser<std::str::Chars<'_>> {
+        let mut parser = Parser::new(Lexer::new(source.chars()));
+        parser.get_next_token();
+        parser
+    }
+
+    /// Parse `source` as a single expression.
+    fn expr(source: &str) -> ExprAST {
+        let mut parser = parser(source);
+        let expr = parser.parse_expression().unwrap();
+        assert_eq!(*parser.current_token(), Token::Eof, "trailing tokens in {}", source);
+        expr
+    }
+
+    fn var(name: &str) -> ExprAST {
+        ExprAST::Variable(name.to_string())
+    }
+
+    fn field(value: ExprAST, name: &str) -> ExprAST {
+        ExprAST::Field(Box::new(value), name.to_string())
+    }
+
+    #[test]
+    fn field_accesses() {
+        assert_eq!(expr("p.pos.x"), field(field(var("p"), "pos"), "x"));
+        assert!(matches!(
+            expr("ps[0].x"),
+            ExprAST::Field(ref value, ref name) if name == "x" && matches!(**value, ExprAST::Index(..))
+        ));
+        assert!(matches!(expr("p.x = 1"), ExprAST::Assign(ref target, _) if **target == field(var("p"), "x")));
+    }
+
+    #[test]
+    fn qualified_names_of_imported_modules() {
+        let mut parser = parser("import util.math\nutil.math.sqrt(2) + util.math.pi + p.x");
+        assert_eq!(
+            parser.parse_import().unwrap(),
+            ImportAST { module: "util.math".to_string(), names: Vec::new() }
+        );
+        let ExprAST::BinaryOp(_, lhs, p_x, _) = parser.parse_expression().unwrap() else {
+            panic!("Expected a binary operation");
+        };
+        let ExprAST::BinaryOp(_, call, pi, _) = *lhs else {
+            panic!("Expected a binary operation");
+        };
+        assert!(matches!(*call, ExprAST::Call(ref name, ref args, _) if name == "util.math.sqrt" && args.len() == 1));
+        assert_eq!(*pi, var("util.math.pi"));
+        assert_eq!(*p_x, field(var("p"), "x"));
+    }
+
+    #[test]
+    fn dots_of_unknown_modules_are_field_accesses() {
+        assert_eq!(expr("math.pi"), field(var("math"), "pi"));
+        // `util` alone is not imported, only `util.math` is.
+        let mut parser = parser("import util.math\nutil.x.y");
+        parser.parse_import().unwrap();
+        assert_eq!(parser.parse_expression().unwrap(), field(field(var("util"), "x"), "y"));
+    }
+
+    #[test]
+    fn from_import() {
+        let mut parser = parser("from util.math import sqrt, pi");
+        assert_eq!(
+            parser.parse_import().unwrap(),
+            ImportAST { module: "util.math".to_string(), names: vec!["sqrt".to_string(), "pi".to_string()] }
+        );
+    }
+
+    #[test]
+    fn missing_field_name() {
+        assert!(parser("p.").parse_expression().is_err());
+        assert!(parser("p.(x)").parse_expression().is_err());
+    }
+
+    fn definition(source: &str) -> FunctionAST {
+        let mut parser = parser(source);
+        let function = parser.parse_definition().unwrap();
+        assert_eq!(*parser.current_token(), Token::Eof, "trailing tokens in {}", source);
+        function
+    }
+
+    fn fn_type(args: Vec<TypeAST>, ret: TypeAST) -> TypeAST {
+        TypeAST::Fn(args, Box::new(ret))
+    }
+
+    #[test]
+    fn arguments_with_and_without_commas() {
+        let spaced = definition("def max[T](a: T b: T) -> T if a > b then a else b");
+        let commas = definition("def max[T](a: T, b: T) -> T if a > b then a else b");
+        assert_eq!(spaced.0, commas.0);
+
+        let PrototypeAST { type_params, args, arg_types, ret_type, .. } = commas.0;
+        assert_eq!(type_params, ["T"]);
+        assert_eq!(args, ["a", "b"]);
+        let t = TypeAST::Struct("T".to_string());
+        assert_eq!(arg_types, [t.clone(), t.clone()]);
+        assert_eq!(ret_type, t);
+
+        assert_eq!(definition("def add(x, y) x + y").0.args, ["x", "y"]);
+        assert_eq!(definition("def add(x y) x + y").0.args, ["x", "y"]);
+        assert_eq!(definition("def add(x, y,) x + y").0.args, ["x", "y"]);
+        assert!(parser("def add(x, , y) x + y").parse_definition().is_err());
+    }
+
+    #[test]
+    fn function_typed_arguments() {
+        let FunctionAST(proto, _) = definition("def apply(f: fn(f64) -> f64, x) f(x)");
+        assert_eq!(proto.args, ["f", "x"]);
+        assert_eq!(proto.arg_types, [fn_type(vec![TypeAST::F64], TypeAST::F64), TypeAST::F64]);
+
+        let FunctionAST(proto, _) = definition("def fold(f: fn(f64, f64) -> f64, xs: [f64], init) init");
+        assert_eq!(proto.args, ["f", "xs", "init"]);
+        assert_eq!(proto.arg_types[0], fn_type(vec![TypeAST::F64, TypeAST::F64], TypeAST::F64));
+        assert_eq!(proto.arg_types[1..], [TypeAST::Array, TypeAST::F64]);
+    }
+
+    #[test]
+    fn functions_and_lambdas_as_arguments() {
+        let FunctionAST(_, body) = definition("def twice(n) apply(fib, n) + apply(lambda y: y + n, 1)");
+        let ExprAST::BinaryOp(_, fib_call, lambda_call, _) = body else {
+            panic!("Expected a binary operation");
+        };
+        let ExprAST::Call(callee, args, _) = *fib_call else {
+            panic!("Expected a call");
+        };
+        assert_eq!(callee, "apply");
+        assert_eq!(args, [var("fib"), var("n")]);
+
+        let ExprAST::Call(callee, args, _) = *lambda_call else {
+            panic!("Expected a call");
+        };
+        assert_eq!(callee, "apply");
+        let ExprAST::Lambda { ref params, ref body } = args[0] else {
+            panic!("Expected a lambda");
+        };
+        assert_eq!(params, &["y"]);
+        // The lambda captures `n` of the enclosing function.
+        let mut names = Vec::new();
+        crate::ir_gen::collect_variables(body, &mut names);
+        assert_eq!(names, ["y", "n"]);
+    }
+
+    #[test]
+    fn mangled_instances() {
+        let FunctionAST(mut proto, _) = definition("def max[T](a: T, b: T) -> T if a > b then a else b");
+        proto.name = crate::import::mangle("util", &proto.name);
+
+        let instance = proto.instantiate(&[("T".to_string(), TypeAST::F64)]).unwrap();
+        assert_eq!(instance.name, "util.max[f64]");
+        assert!(instance.type_params.is_empty());
+        assert_eq!(instance.arg_types, [TypeAST::F64, TypeAST::F64]);
+        assert_eq!(instance.ret_type, TypeAST::F64);
+
+        let callback = fn_type(vec![TypeAST::F64], TypeAST::F64);
+        let instance = proto.instantiate(&[("T".to_string(), callback.clone())]).unwrap();
+        assert_eq!(instance.name, "util.max[fn(f64) -> f64]");
+        assert_eq!(instance.arg_types, [callback.clone(), callback]);
+
+        let FunctionAST(proto, _) = definition("def pick[T, U](a: T, b: &U) -> T a");
+        let bindings = [("U".to_string(), TypeAST::Struct("Point".to_string())), ("T".to_string(), TypeAST::Str)];
+        let instance = proto.instantiate(&bindings).unwrap();
+        assert_eq!(instance.name, "pick[str, Point]");
+        assert_eq!(proto.instantiate(&bindings[..1]), Err("Cannot infer type parameter T of pick".to_string()));
+    }
+
+    #[test]
+    fn asserts_keep_the_source_of_their_condition() {
+        let ExprAST::Assert { condition, message, source, loc } = expr("assert fib(10) ==  55, \"fib(10)\"") else {
+            panic!("Expected assert");
+        };
+        assert_eq!(source, "fib(10) ==  55");
+        assert!(matches!(*condition, ExprAST::BinaryOp(..)));
+        assert_eq!(message, Some(Box::new(ExprAST::Str("fib(10)".to_string()))));
+        assert_eq!(loc, SourceLoc { line: 1, col: 1 });
+
+        let ExprAST::Assert { message, source, .. } = expr("assert ok") else {
+            panic!("Expected assert");
+        };
+        assert_eq!(source, "ok");
+        assert_eq!(message, None);
+    }
+
+    #[test]
+    fn test_blocks() {
+        let TestAST { name, body } = parser("test \"globals\": { count = 1; assert count == 1 }").parse_test().unwrap();
+        assert_eq!(name, "globals");
+        assert!(matches!(body, ExprAST::Block(ref exprs) if exprs.len() == 2));
+
+        let missing_name = parser("test globals: 0").parse_test().map(|_| ());
+        assert_eq!(missing_name, Err("Expected test name, found Identifier(\"globals\")".to_string()));
+        let missing_colon = parser("test \"globals\" 0").parse_test().map(|_| ());
+        assert_eq!(missing_colon, Err("Expected ':', found Number(0.0)".to_string()));
+    }
+
+    #[test]
+    fn array_literals_and_indexing() {
+        assert_eq!(expr("[]"), ExprAST::Array(Vec::new()));
+        assert_eq!(
+            expr("[1, x, [2]]"),
+            ExprAST::Array(vec![ExprAST::Number(1.0), var("x"), ExprAST::Array(vec![ExprAST::Number(2.0)])])
+        );
+
+        let ExprAST::Index(array, index, loc) = expr("xs[i + 1][0]") else {
+            panic!("Expected an index");
+        };
+        assert_eq!(*index, ExprAST::Number(0.0));
+        assert_eq!(loc, SourceLoc { line: 1, col: 10 });
+        let ExprAST::Index(xs, i, _) = *array else {
+            panic!("Expected an index");
+        };
+        assert_eq!(*xs, var("xs"));
+        assert!(matches!(*i, ExprAST::BinaryOp(ref op, ..) if op == "+"));
+        assert!(matches!(expr("xs[0] = 1"), ExprAST::Assign(ref target, _) if matches!(**target, ExprAST::Index(..))));
+
+        assert!(parser("[1, 2").parse_expression().is_err());
+        assert!(parser("[1 2]").parse_expression().is_err());
+        assert!(parser("xs[0").parse_expression().is_err());
+    }
+
+    #[test]
+    fn structs_and_new() {
+        let def = parser("struct Node: value: f64, name: str, next: &Node, pos: Point").parse_struct().unwrap();
+        assert_eq!(def.name, "Node");
+        assert_eq!(
+            def.fields,
+            [
+                ("value".to_string(), TypeAST::F64),
+                ("name".to_string(), TypeAST::Str),
+                ("next".to_string(), TypeAST::Ref("Node".to_string())),
+                ("pos".to_string(), TypeAST::Struct("Point".to_string())),
+            ]
+        );
+        assert!(parser("struct Node:").parse_struct().is_err());
+        assert!(parser("struct Node: value f64").parse_struct().is_err());
+
+        assert_eq!(expr("new Point(1, y)"), ExprAST::New("Point".to_string(), vec![ExprAST::Number(1.0), var("y")]));
+        assert_eq!(expr("new Empty()"), ExprAST::New("Empty".to_string(), Vec::new()));
+        assert!(parser("new Point").parse_expression().is_err());
+    }
+
+    /// Render the operators of `source` fully parenthesized.
+    fn grouped(source: &str) -> String {
+        fn show(expr: &ExprAST) -> String {
+            match expr {
+                ExprAST::BinaryOp(op, lhs, rhs, _) => format!("({} {} {})", show(lhs), op, show(rhs)),
+                ExprAST::UnaryOp(op, operand) if op == "not" => format!("(not {})", show(operand)),
+                ExprAST::UnaryOp(op, operand) => format!("({}{})", op, show(operand)),
+                ExprAST::Number(value) => value.to_string(),
+                ExprAST::Bool(value) => value.to_string(),
+                ExprAST::Variable(name) => name.clone(),
+                expr => panic!("Unexpected expression {:?}", expr),
+            }
+        }
+        show(&expr(source))
+    }
+
+    #[test]
+    fn boolean_operators() {
+        assert_eq!(grouped("a or b and c"), "(a or (b and c))");
+        assert_eq!(grouped("a and b or c and d"), "((a and b) or (c and d))");
+        assert_eq!(grouped("not a == b and c"), "((not (a == b)) and c)");
+        assert_eq!(grouped("not not a or b"), "((not (not a)) or b)");
+        assert_eq!(grouped("x < 1 or x >= 2 and true"), "((x < 1) or ((x >= 2) and true))");
+        assert!(parser("a and").parse_expression().is_err());
+    }
+
+    #[test]
+    fn while_loops() {
+        let ExprAST::While { condition, body } = expr("while i < 10: if i == 5 then break else continue") else {
+            panic!("Expected a while loop");
+        };
+        assert!(matches!(*condition, ExprAST::BinaryOp(ref op, ..) if op == "<"));
+        let ExprAST::If { then, else_, .. } = *body else {
+            panic!("Expected an if");
+        };
+        assert_eq!((*then, *else_), (ExprAST::Break, ExprAST::Continue));
+
+        let ExprAST::While { body, .. } = expr("while true: { x = x + 1 }") else {
+            panic!("Expected a while loop");
+        };
+        assert!(matches!(*body, ExprAST::Block(ref statements) if statements.len() == 1));
+        assert!(parser("while true x").parse_expression().is_err());
+    }
+
+    #[test]
+    fn for_loops() {
+        let ExprAST::For { variable_name, start, end, step, body } = expr("for i = 0, i < n, 2 in print(i)") else {
+            panic!("Expected a for loop");
+        };
+        assert_eq!(variable_name, "i");
+        assert_eq!(*start, ExprAST::Number(0.0));
+        assert!(matches!(*end, ExprAST::BinaryOp(ref op, ..) if op == "<"));
+        assert_eq!(step, Some(Box::new(ExprAST::Number(2.0))));
+        assert!(matches!(*body, ExprAST::Call(ref name, _, _) if name == "print"));
+        assert!(matches!(expr("for i = 0, 10 in i"), ExprAST::For { step: None, .. }));
+
+        let ExprAST::ForIn { variable_name, iterable, body } = expr("for x in range(1, 10, -1): x") else {
+            panic!("Expected a for-in loop");
+        };
+        assert_eq!(variable_name, "x");
+        assert!(matches!(*iterable, ExprAST::Call(ref name, ref args, _) if name == "range" && args.len() == 3));
+        assert_eq!(*body, var("x"));
+
+        assert!(parser("for i = 0 in i").parse_expression().is_err());
+        assert!(parser("for x in xs x").parse_expression().is_err());
+    }
+
+    #[test]
+    fn blocks_let_and_return() {
+        let ExprAST::Block(statements) = expr("{ let x = 1; let y = x * 2; return x + y; }") else {
+            panic!("Expected a block");
+        };
+        assert_eq!(statements.len(), 3);
+        assert_eq!(statements[0], ExprAST::Let("x".to_string(), Box::new(ExprAST::Number(1.0))));
+        assert!(matches!(statements[1], ExprAST::Let(ref name, _) if name == "y"));
+        assert!(matches!(statements[2], ExprAST::Return(ref value) if matches!(**value, ExprAST::BinaryOp(..))));
+
+        assert_eq!(expr("{}"), ExprAST::Block(Vec::new()));
+        assert_eq!(expr("{ { 1 } }"), ExprAST::Block(vec![ExprAST::Block(vec![ExprAST::Number(1.0)])]));
+        assert!(parser("{ 1 2 }").parse_expression().is_err());
+        assert!(parser("{ let 1 = x }").parse_expression().is_err());
+        assert!(parser("{ let x 1 }").parse_expression().is_err());
+        assert!(parser("let x = 1").parse_expression().is_err());
+    }
+
+    #[test]
+    fn arithmetic_and_bitwise_precedence() {
+        assert_eq!(grouped("a ** b ** c"), "(a ** (b ** c))");
+        assert_eq!(grouped("-a ** 2"), "(-(a ** 2))");
+        assert_eq!(grouped("a * b // c % d"), "(((a * b) // c) % d)");
+        assert_eq!(grouped("a + b * c - d"), "((a + (b * c)) - d)");
+        assert_eq!(grouped("1 << a + b"), "(1 << (a + b))");
+        assert_eq!(grouped("a | b ^ c & d"), "(a | (b ^ (c & d)))");
+        assert_eq!(grouped("a & b == c | d"), "((a & b) == (c | d))");
+        assert_eq!(grouped("~a & b"), "((~a) & b)");
+    }
+
+    #[test]
+    fn bodies_starting_with_unary_minus() {
+        let negate = |x: &str| ExprAST::UnaryOp("-".to_string(), Box::new(var(x)));
+        assert_eq!(definition("def neg(x) -x").1, negate("x"));
+
+        let FunctionAST(proto, body) = definition("def neg(x) -> f64 -x");
+        assert_eq!(proto.ret_type, TypeAST::F64);
+        assert_eq!(body, negate("x"));
+
+        let FunctionAST(proto, body) = definition("def apply(f: fn(f64) -> f64, x) -> f64 -x");
+        assert_eq!(proto.arg_types[0], fn_type(vec![TypeAST::F64], TypeAST::F64));
+        assert_eq!(body, negate("x"));
+        assert!(parser("def f(x) -> -x").parse_definition().is_err());
+    }
+
+    #[test]
+    fn consts_and_globals() {
+        let ConstAST { name, value } = parser("const TAU = 2 * PI").parse_const().unwrap();
+        assert_eq!(name, "TAU");
+        assert!(matches!(value, ExprAST::BinaryOp(ref op, _, ref rhs, _) if op == "*" && **rhs == var("PI")));
+
+        let GlobalAST { name, value } = parser("global greeting = \"hi\"").parse_global().unwrap();
+        assert_eq!(name, "greeting");
+        assert_eq!(value, ExprAST::Str("hi".to_string()));
+
+        let missing_value = parser("global count").parse_global().map(|_| ());
+        assert_eq!(missing_value, Err("Expected '=', found Eof".to_string()));
+        let missing_name = parser("const 1 = 2").parse_const().map(|_| ());
+        assert_eq!(missing_name, Err("Expected constant name, found Number(1.0)".to_string()));
+    }
+
+    #[test]
+    fn try_except_finally() {
+        let ExprAST::Try { body, handler, finally } = expr("try: f(x) except e: g(e) finally: h()") else {
+            panic!("Expected a try");
+        };
+        assert!(matches!(*body, ExprAST::Call(ref name, _, _) if name == "f"));
+        let (name, handler) = handler.unwrap();
+        assert_eq!(name.as_deref(), Some("e"));
+        assert!(matches!(*handler, ExprAST::Call(ref name, ref args, _) if name == "g" && args[..] == [var("e")]));
+        assert!(matches!(finally.as_deref(), Some(ExprAST::Call(ref name, _, _)) if name == "h"));
+
+        assert!(matches!(expr("try: 1 except: 0"), ExprAST::Try { handler: Some((None, _)), finally: None, .. }));
+        assert!(matches!(expr("try: 1 finally: 0"), ExprAST::Try { handler: None, finally: Some(_), .. }));
+        let missing = parser("try: 1").parse_expression().map(|_| ());
+        assert_eq!(missing, Err("Expected 'except' or 'finally', found Eof".to_string()));
+
+        let ExprAST::Raise(message, loc) = expr("raise \"oops\"") else {
+            panic!("Expected a raise");
+        };
+        assert_eq!(*message, ExprAST::Str("oops".to_string()));
+        assert_eq!(loc, SourceLoc { line: 1, col: 1 });
+    }
+}
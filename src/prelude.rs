@@ -0,0 +1,162 @@
+//! The Cobra prelude.
+//!
+//! Prelude functions are available in every script without an `extern` declaration. Math
+//! functions are lowered to LLVM intrinsics, everything else calls into the `cobra_runtime`
+//! crate. Runtime functions are registered with the JIT (see [`jit_symbols`]) and resolved by
+//! the linker for ahead-of-time compiled executables (see [`crate::aot`]).
+
+use std::collections::HashMap;
+
+use cobra_runtime as rt;
+
+use crate::parser::{PrototypeAST, TypeAST};
+
+/// A function of the Cobra prelude.
+pub struct Builtin {
+    /// Name used in Cobra code.
+    pub name: &'static str,
+    /// Names of the arguments (all `f64`).
+    pub args: &'static [&'static str],
+    /// Name of the symbol implementing the function (a runtime function or an LLVM intrinsic).
+    pub symbol: &'static str,
+    /// Address of the runtime function, `None` for LLVM intrinsics.
+    pub addr: Option<*const libc::c_void>,
+}
+
+/// Get all functions of the Cobra prelude.
+pub fn prelude() -> Vec<Builtin> {
+    fn runtime(
+        name: &'static str,
+        args: &'static [&'static str],
+        symbol: &'static str,
+        addr: *const libc::c_void,
+    ) -> Builtin {
+        Builtin { name, args, symbol, addr: Some(addr) }
+    }
+
+    fn intrinsic(name: &'static str, args: &'static [&'static str], symbol: &'static str) -> Builtin {
+        Builtin { name, args, symbol, addr: None }
+    }
+
+    vec![
+        runtime("print", &["x"], "cobra_print", rt::cobra_print as *const libc::c_void),
+        runtime("println", &["x"], "cobra_println", rt::cobra_println as *const libc::c_void),
+        runtime("printd", &["x"], "cobra_printd", rt::cobra_printd as *const libc::c_void),
+        runtime("putchard", &["c"], "putchard", rt::putchard as *const libc::c_void),
+        runtime("clock", &[], "cobra_clock", rt::cobra_clock as *const libc::c_void),
+        runtime("random", &[], "cobra_random", rt::cobra_random as *const libc::c_void),
+        runtime("read_number", &[], "cobra_read_number", rt::cobra_read_number as *const libc::c_void),
+        intrinsic("sqrt", &["x"], "llvm.sqrt.f64"),
+        intrinsic("sin", &["x"], "llvm.sin.f64"),
+        intrinsic("pow", &["x", "y"], "llvm.pow.f64"),
+        intrinsic("floor", &["x"], "llvm.floor.f64"),
+    ]
+}
+
+/// Add prototypes for all prelude functions to `fn_protos`.
+///
+/// The prototypes are keyed by the Cobra name but named after the implementing symbol, so calls
+/// are emitted directly against the runtime function or intrinsic.
+pub fn register_prelude(fn_protos: &mut HashMap<String, PrototypeAST>) {
+    for builtin in prelude() {
+        let proto = PrototypeAST {
+            name: builtin.symbol.to_string(),
+            type_params: Vec::new(),
+            args: builtin.args.iter().map(|arg| arg.to_string()).collect(),
+            arg_types: vec![TypeAST::F64; builtin.args.len()],
+            ret_type: TypeAST::F64,
+        };
+        fn_protos.insert(builtin.name.to_string(), proto);
+    }
+}
+
+/// Runtime functions only called from generated code, they cannot be referenced by name in
+/// Cobra code.
+fn internal_symbols() -> Vec<(&'static str, *const libc::c_void)> {
+    vec![
+        ("cobra_print_str", rt::cobra_print_str as *const libc::c_void),
+        ("cobra_println_str", rt::cobra_println_str as *const libc::c_void),
+        ("cobra_str_concat", rt::cobra_str_concat as *const libc::c_void),
+        ("cobra_str_copy", rt::cobra_str_copy as *const libc::c_void),
+        ("cobra_str_index", rt::cobra_str_index as *const libc::c_void),
+        ("cobra_alloc", rt::cobra_alloc as *const libc::c_void),
+        ("cobra_index_out_of_bounds", rt::cobra_index_out_of_bounds as *const libc::c_void),
+        ("cobra_interrupted", rt::limits::cobra_interrupted as *const libc::c_void),
+        ("cobra_recursion_limit", rt::limits::cobra_recursion_limit as *const libc::c_void),
+        ("cobra_panic", rt::error::cobra_panic as *const libc::c_void),
+        ("cobra_personality", rt::exception::cobra_personality as *const libc::c_void),
+        ("cobra_begin_catch", rt::exception::cobra_begin_catch as *const libc::c_void),
+        ("cobra_uncaught", rt::exception::cobra_uncaught as *const libc::c_void),
+        // Variables checked by the generated code, see `cobra_runtime::limits`.
+        ("cobra_interrupt", &rt::limits::INTERRUPT as *const _ as *const libc::c_void),
+        ("cobra_call_depth", &rt::limits::CALL_DEPTH as *const _ as *const libc::c_void),
+        ("cobra_max_call_depth", &rt::limits::MAX_CALL_DEPTH as *const _ as *const libc::c_void),
+        // Stack frames linked by the generated code, see `cobra_runtime::error`.
+        ("cobra_frame", &rt::error::FRAME as *const _ as *const libc::c_void),
+    ]
+}
+
+/// Get the runtime function taking a `str` argument that replaces the prelude function
+/// implemented by `symbol` when it is called with a string (eg `println("hello")`).
+pub fn str_overload(symbol: &str) -> Option<&'static str> {
+    match symbol {
+        "cobra_print" => Some("cobra_print_str"),
+        "cobra_println" => Some("cobra_println_str"),
+        _ => None,
+    }
+}
+
+/// Get the symbols of all runtime functions, to be defined in the JIT.
+pub fn jit_symbols() -> Vec<(&'static str, *const libc::c_void)> {
+    prelude()
+        .into_iter()
+        .filter_map(|builtin| builtin.addr.map(|addr| (builtin.symbol, addr)))
+        .chain(internal_symbols())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prelude_names_are_unique() {
+        let builtins = prelude();
+        for (i, builtin) in builtins.iter().enumerate() {
+            assert!(builtins[i + 1..].iter().all(|other| other.name != builtin.name), "{}", builtin.name);
+        }
+    }
+
+    #[test]
+    fn prototypes_are_named_after_symbols() {
+        let mut fn_protos = HashMap::new();
+        register_prelude(&mut fn_protos);
+        assert_eq!(fn_protos.len(), prelude().len());
+
+        let pow = &fn_protos["pow"];
+        assert_eq!(pow.name, "llvm.pow.f64");
+        assert_eq!(pow.args, vec!["x", "y"]);
+        assert_eq!(pow.arg_types, vec![TypeAST::F64, TypeAST::F64]);
+        assert_eq!(pow.ret_type, TypeAST::F64);
+
+        let random = &fn_protos["random"];
+        assert_eq!(random.name, "cobra_random");
+        assert!(random.args.is_empty());
+    }
+
+    #[test]
+    fn jit_symbols_skip_intrinsics() {
+        let symbols = jit_symbols();
+        assert!(symbols.iter().all(|(name, _)| !name.starts_with("llvm.")));
+        for name in ["cobra_clock", "cobra_random", "cobra_str_concat", "cobra_frame"] {
+            assert!(symbols.iter().any(|(symbol, _)| *symbol == name), "{}", name);
+        }
+    }
+
+    #[test]
+    fn str_overloads() {
+        assert_eq!(str_overload("cobra_println"), Some("cobra_println_str"));
+        assert_eq!(str_overload("cobra_printd"), None);
+        assert!(jit_symbols().iter().any(|(symbol, _)| *symbol == "cobra_println_str"));
+    }
+}
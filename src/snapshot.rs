@@ -0,0 +1,236 @@
+//! Snapshots of interactive sessions (`:save` and `:restore`).
+//!
+//! A [`History`] records the source text of every definition accepted by the REPL. Saving a
+//! session writes the definitions which are still live (later definitions of a name replace
+//! earlier ones) in an order in which they can be compiled again: imports, externs and types
+//! first, then constants and globals, then functions after the functions they call.
+
+use std::collections::HashSet;
+
+use crate::consteval::ConstValue;
+use crate::ir_gen::collect_variables;
+use crate::parser::FunctionAST;
+
+/// Kind of a recorded definition, in the order the kinds are saved.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+pub enum DefinitionKind {
+    Import,
+    Extern,
+    Type,
+    Const,
+    /// A global variable. Its value is read from the session when it is saved.
+    Global,
+    Function,
+}
+
+/// A definition accepted by the REPL.
+#[derive(Debug, PartialEq)]
+pub struct Definition {
+    pub kind: DefinitionKind,
+    pub name: String,
+    /// Names of the functions (and variables) referenced by the definition.
+    pub uses: Vec<String>,
+    /// Source text of the definition, empty for globals.
+    pub source: String,
+}
+
+impl Definition {
+    /// An `import` or `from ... import` statement, identified by its source text.
+    pub fn import(source: &str) -> Definition {
+        Definition::new(DefinitionKind::Import, source, source)
+    }
+
+    /// An `extern` declaration.
+    pub fn external(name: &str, source: &str) -> Definition {
+        Definition::new(DefinitionKind::Extern, name, source)
+    }
+
+    /// A `struct` or `enum` definition.
+    pub fn type_def(name: &str, source: &str) -> Definition {
+        Definition::new(DefinitionKind::Type, name, source)
+    }
+
+    /// A constant, saved with its evaluated value so it doesn't depend on any function.
+    pub fn constant(name: &str, value: &ConstValue) -> Definition {
+        Definition::new(DefinitionKind::Const, name, &format!("const {} = {}", name, value))
+    }
+
+    /// A global variable, defined by `global` or by a top-level `name = value`.
+    pub fn global(name: &str) -> Definition {
+        Definition::new(DefinitionKind::Global, name, "")
+    }
+
+    /// A function definition.
+    pub fn function(FunctionAST(proto, body): &FunctionAST, source: &str) -> Definition {
+        let mut uses = Vec::new();
+        collect_variables(body, &mut uses);
+        Definition {
+            uses,
+            ..Definition::new(DefinitionKind::Function, &proto.name, source)
+        }
+    }
+
+    fn new(kind: DefinitionKind, name: &str, source: &str) -> Definition {
+        Definition {
+            kind,
+            name: name.to_string(),
+            uses: Vec::new(),
+            source: source.trim_end().to_string(),
+        }
+    }
+
+    /// Render the definition as source text, reading the value of a global with `global_value`.
+    pub fn render<F>(&self, global_value: F) -> String
+    where
+        F: FnOnce(&str) -> ConstValue,
+    {
+        match self.kind {
+            DefinitionKind::Global => format!("global {} = {}", self.name, global_value(&self.name)),
+            _ => self.source.clone(),
+        }
+    }
+}
+
+/// The definitions accepted in a session, in the order they were entered.
+#[derive(Debug, Default)]
+pub struct History {
+    definitions: Vec<Definition>,
+}
+
+impl History {
+    /// Record a definition, replacing an earlier definition of the same kind and name.
+    pub fn record(&mut self, definition: Definition) {
+        self.definitions
+            .retain(|def| def.kind != definition.kind || def.name != definition.name);
+        self.definitions.push(definition);
+    }
+
+    /// Get the recorded definitions in dependency order.
+    ///
+    /// Definitions are grouped by kind and otherwise keep the order they were entered in, except
+    /// that a function comes after all functions it calls. Only functions have dependencies
+    /// (constants and globals are saved as values), so this never breaks up the groups.
+    pub fn sorted(&self) -> Vec<&Definition> {
+        let mut order: Vec<usize> = (0..self.definitions.len()).collect();
+        order.sort_by_key(|&i| self.definitions[i].kind);
+
+        let mut visited = HashSet::new();
+        let mut sorted = Vec::with_capacity(order.len());
+        for i in order {
+            self.visit(i, &mut visited, &mut sorted);
+        }
+        sorted
+    }
+
+    fn visit<'a>(&'a self, i: usize, visited: &mut HashSet<usize>, sorted: &mut Vec<&'a Definition>) {
+        // Recursive functions are already visited when they reach themselves again.
+        if !visited.insert(i) {
+            return;
+        }
+
+        let definition = &self.definitions[i];
+        for name in &definition.uses {
+            let callee = self.definitions.iter().position(|def| {
+                def.kind == DefinitionKind::Function && &def.name == name
+            });
+            if let Some(callee) = callee {
+                self.visit(callee, visited, sorted);
+            }
+        }
+        sorted.push(definition);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+
+    fn function(source: &str) -> Definition {
+        let mut parser = Parser::new(Lexer::new(source.chars()));
+        parser.get_next_token();
+        Definition::function(&parser.parse_definition().unwrap(), source)
+    }
+
+    fn names(history: &History) -> Vec<&str> {
+        history.sorted().iter().map(|def| def.name.as_str()).collect()
+    }
+
+    #[test]
+    fn globals_are_rendered_with_their_value() {
+        let global = Definition::global("x");
+        assert_eq!(global.render(|_| ConstValue::Number(2.5)), "global x = 2.5");
+        assert_eq!(global.render(|_| ConstValue::Bool(true)), "global x = true");
+        assert_eq!(
+            global.render(|_| ConstValue::Str("say \"hi\"\n\\".to_string())),
+            r#"global x = "say \"hi\"\n\\""#
+        );
+        assert_eq!(global.render(|_| ConstValue::Number(f64::NEG_INFINITY)), "global x = (-1 / 0)");
+    }
+
+    #[test]
+    fn later_definitions_replace_earlier_ones() {
+        let mut history = History::default();
+        history.record(function("def f(x) x"));
+        history.record(function("def g(x) x"));
+        history.record(function("def f(x) x + 1"));
+        // A global of the same name is a different definition.
+        history.record(Definition::global("f"));
+
+        let sorted = history.sorted();
+        let sources: Vec<_> = sorted.iter().map(|def| def.source.as_str()).collect();
+        assert_eq!(sources, ["", "def g(x) x", "def f(x) x + 1"]);
+    }
+
+    #[test]
+    fn definitions_are_grouped_by_kind() {
+        let mut history = History::default();
+        history.record(function("def f(x) x"));
+        history.record(Definition::global("g"));
+        history.record(Definition::constant("C", &ConstValue::Number(1.0)));
+        history.record(Definition::type_def("Point", "struct Point: x: f64"));
+        history.record(Definition::external("sin", "extern sin(x)"));
+        history.record(Definition::import("import util"));
+        history.record(Definition::global("h"));
+
+        let kinds: Vec<_> = history.sorted().iter().map(|def| def.kind).collect();
+        assert_eq!(
+            kinds,
+            [
+                DefinitionKind::Import,
+                DefinitionKind::Extern,
+                DefinitionKind::Type,
+                DefinitionKind::Const,
+                DefinitionKind::Global,
+                DefinitionKind::Global,
+                DefinitionKind::Function,
+            ]
+        );
+        // Definitions of the same kind keep their order.
+        assert_eq!(names(&history)[4..], ["g", "h", "f"]);
+    }
+
+    #[test]
+    fn functions_follow_their_callees() {
+        let mut history = History::default();
+        history.record(function("def a(x) b(x) + 1"));
+        history.record(function("def b(x) c(x) * 2"));
+        history.record(function("def unrelated(x) x"));
+        history.record(function("def c(x) x"));
+        assert_eq!(names(&history), ["c", "b", "a", "unrelated"]);
+
+        // Redefining a callee moves it, its callers still follow it.
+        history.record(function("def b(x) c(x) * 3"));
+        assert_eq!(names(&history), ["c", "b", "a", "unrelated"]);
+    }
+
+    #[test]
+    fn mutually_recursive_functions() {
+        let mut history = History::default();
+        history.record(function("def even(n) if n == 0 then 1 else odd(n - 1)"));
+        history.record(function("def odd(n) if n == 0 then 0 else even(n - 1)"));
+        history.record(function("def fact(n) if n < 2 then 1 else n * fact(n - 1)"));
+        assert_eq!(names(&history), ["odd", "even", "fact"]);
+    }
+}
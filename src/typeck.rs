@@ -0,0 +1,185 @@
+//! Static checks on the AST which don't depend on generated code.
+
+use crate::ir_gen::TypeDefs;
+use crate::parser::{EnumAST, MatchArm, PatternAST};
+
+/// Check the patterns of a `match` expression and that they cover every possible value.
+///
+/// Returns the enum matched on, or `None` if the arms match numbers.
+///
+/// An arm only counts towards exhaustiveness if it has no guard and all its sub-patterns match
+/// anything (eg `Rect(w, _)` covers `Rect`, `Rect(0, h)` does not).
+pub fn check_match<'a>(arms: &[MatchArm], type_defs: &'a TypeDefs) -> Result<Option<&'a EnumAST>, String> {
+    let mut matched: Option<&EnumAST> = None;
+    let mut covered = Vec::new();
+    let mut catch_all = false;
+
+    for arm in arms {
+        let (variant, patterns) = match arm.pattern {
+            PatternAST::Wildcard => {
+                catch_all |= arm.guard.is_none();
+                continue;
+            }
+            PatternAST::Binding(ref name) if type_defs.variants.contains_key(name) => (name, &[][..]),
+            PatternAST::Binding(_) => {
+                catch_all |= arm.guard.is_none();
+                continue;
+            }
+            PatternAST::Literal(_) => continue,
+            PatternAST::Variant(ref name, ref patterns) => (name, &patterns[..]),
+        };
+
+        let (def, tag) = type_defs
+            .variant(variant)
+            .ok_or_else(|| format!("Unknown variant {} in pattern", variant))?;
+        match matched {
+            Some(other) if other.name != def.name => {
+                return Err(format!("Variant {} of {} cannot match a value of enum {}", variant, def.name, other.name));
+            }
+            _ => matched = Some(def),
+        }
+
+        let fields = &def.variants[tag].fields;
+        if patterns.len() != fields.len() {
+            return Err(format!("Pattern {} must have {} fields, found {}", variant, fields.len(), patterns.len()));
+        }
+
+        let mut irrefutable = arm.guard.is_none();
+        for pattern in patterns {
+            match pattern {
+                PatternAST::Binding(name) if type_defs.variants.contains_key(name) => {
+                    return Err(format!("Nested variant pattern {} is not supported", name));
+                }
+                PatternAST::Variant(name, _) => {
+                    return Err(format!("Nested variant pattern {} is not supported", name));
+                }
+                PatternAST::Literal(_) => irrefutable = false,
+                PatternAST::Wildcard | PatternAST::Binding(_) => {}
+            }
+        }
+        if irrefutable {
+            covered.push(tag);
+        }
+    }
+
+    if let Some(def) = matched {
+        if arms.iter().any(|arm| matches!(arm.pattern, PatternAST::Literal(_))) {
+            return Err(format!("Literal pattern cannot match a value of enum {}", def.name));
+        }
+    }
+    if catch_all {
+        return Ok(matched);
+    }
+
+    match matched {
+        Some(def) => {
+            let missing: Vec<_> = def.variants
+                .iter()
+                .enumerate()
+                .filter(|(tag, _)| !covered.contains(tag))
+                .map(|(_, variant)| variant.name.as_str())
+                .collect();
+            if missing.is_empty() {
+                Ok(matched)
+            } else {
+                Err(format!("Non-exhaustive match on {}: missing {}", def.name, missing.join(", ")))
+            }
+        }
+        None => Err("Non-exhaustive match: add a `case _` arm".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::Lexer;
+    use crate::parser::{ExprAST, FunctionAST, Parser};
+
+    fn type_defs() -> TypeDefs {
+        let source = "enum Shape = Circle(r) | Rect(w, h) | Empty";
+        let mut parser = Parser::new(Lexer::new(source.chars()));
+        parser.get_next_token();
+        let mut type_defs = TypeDefs::default();
+        type_defs.define_enum(parser.parse_enum().unwrap()).unwrap();
+        type_defs
+    }
+
+    /// Parse the arms of `match s: <arms>`.
+    fn arms(arms: &str) -> Vec<MatchArm> {
+        let source = format!("match s: {}", arms);
+        let mut parser = Parser::new(Lexer::new(source.chars()));
+        parser.get_next_token();
+        match parser.parse_top_level_expr().unwrap() {
+            FunctionAST(_, ExprAST::Match { arms, .. }) => arms,
+            expr => panic!("Expected a match expression, found {:?}", expr),
+        }
+    }
+
+    fn check(source: &str) -> Result<Option<String>, String> {
+        let type_defs = type_defs();
+        check_match(&arms(source), &type_defs).map(|def| def.map(|def| def.name.clone()))
+    }
+
+    #[test]
+    fn exhaustive_enum_match() {
+        let all = "case Circle(r): r case Rect(w, h): w * h case Empty: 0";
+        assert_eq!(check(all), Ok(Some("Shape".to_string())));
+        assert_eq!(check("case Circle(_): 1 case _: 0"), Ok(Some("Shape".to_string())));
+        assert_eq!(check("case Rect(w, h): w case other: 0"), Ok(Some("Shape".to_string())));
+    }
+
+    #[test]
+    fn missing_variants() {
+        assert_eq!(
+            check("case Circle(r): r"),
+            Err("Non-exhaustive match on Shape: missing Rect, Empty".to_string())
+        );
+        assert_eq!(check("case Empty: 0 case Circle(r): r"), Err("Non-exhaustive match on Shape: missing Rect".to_string()));
+    }
+
+    #[test]
+    fn guarded_arms_are_not_exhaustive() {
+        let guarded = "case Circle(r) if r > 0: r case Rect(w, h): w case Empty: 0";
+        assert_eq!(check(guarded), Err("Non-exhaustive match on Shape: missing Circle".to_string()));
+        // A guarded wildcard doesn't cover anything either.
+        assert!(check("case Circle(r): r case _ if true: 0").is_err());
+        let covered = "case Circle(r) if r > 0: r case Circle(r): 0 case Rect(w, h): w case Empty: 0";
+        assert_eq!(check(covered), Ok(Some("Shape".to_string())));
+    }
+
+    #[test]
+    fn nested_literal_patterns() {
+        // `Rect(0, h)` only matches some rectangles.
+        let partial = "case Rect(0, h): h case Circle(r): r case Empty: 0";
+        assert_eq!(check(partial), Err("Non-exhaustive match on Shape: missing Rect".to_string()));
+        let covered = "case Rect(0, h): h case Rect(w, h): w * h case Circle(r): r case Empty: 0";
+        assert_eq!(check(covered), Ok(Some("Shape".to_string())));
+    }
+
+    #[test]
+    fn nested_variant_patterns_are_rejected() {
+        assert!(check("case Rect(Empty, h): h case _: 0").unwrap_err().contains("Nested variant pattern Empty"));
+        assert!(check("case Rect(Circle(r), h): h case _: 0").unwrap_err().contains("Nested variant pattern Circle"));
+    }
+
+    #[test]
+    fn literal_arms_on_enums() {
+        assert_eq!(
+            check("case Circle(r): r case 1: 0 case _: 2"),
+            Err("Literal pattern cannot match a value of enum Shape".to_string())
+        );
+    }
+
+    #[test]
+    fn number_matches() {
+        assert_eq!(check("case 1: 10 case 2: 20 case _: 0"), Ok(None));
+        assert_eq!(check("case 1: 10 case n: n"), Ok(None));
+        assert_eq!(check("case 1: 10"), Err("Non-exhaustive match: add a `case _` arm".to_string()));
+    }
+
+    #[test]
+    fn invalid_variant_patterns() {
+        assert_eq!(check("case Square(s): s case _: 0"), Err("Unknown variant Square in pattern".to_string()));
+        assert_eq!(check("case Rect(w): w case _: 0"), Err("Pattern Rect must have 2 fields, found 1".to_string()));
+    }
+}
@@ -0,0 +1,98 @@
+//! Runs programs through the `cobra` binary, for behavior which depends on the JIT.
+
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+/// Run `source` read from stdin with the command line options `args`. Return stdout, failing
+/// the test if any error was reported on stderr.
+fn run(args: &[&str], source: &str) -> String {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_cobra-lang"))
+        .arg("--no-cache")
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("Failed to start cobra");
+    child.stdin.take().unwrap().write_all(source.as_bytes()).unwrap();
+
+    let output = child.wait_with_output().unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout).into_owned();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    // The IR of compiled functions is dumped to stderr as well.
+    let errors: Vec<_> = stderr
+        .lines()
+        .filter(|line| line.starts_with("Error: ") || line.starts_with("Runtime error: "))
+        .collect();
+    assert!(errors.is_empty(), "{}\n{}", errors.join("\n"), stdout);
+    stdout
+}
+
+/// Get the values printed for the top-level expressions in `stdout`.
+fn values(stdout: &str) -> Vec<&str> {
+    stdout.lines().filter_map(|line| line.strip_prefix("Evaluated to ")).collect()
+}
+
+#[test]
+fn strings_assigned_to_globals_outlive_the_function() {
+    let source = "
+        global greeting = \"hi\"
+        def set() { greeting = \"hello\"; 0 }
+        set()
+        def set() { greeting = \"bye\"; 1 }
+        greeting
+    ";
+    assert_eq!(values(&run(&[], source)), ["0", "\"hello\""]);
+}
+
+#[test]
+fn top_level_string_bindings_are_read_later() {
+    let path = std::env::temp_dir().join(format!("cobra-repl-test-{}.ks", std::process::id()));
+    let source = format!("x = \"hi\"\nx + \"!\"\n:vars\n:save \"{}\"\n", path.display());
+    let stdout = run(&[], &source);
+    assert_eq!(values(&stdout), ["\"hi\"", "\"hi!\""]);
+    assert!(stdout.lines().any(|line| line == "x = \"hi\""), "{}", stdout);
+
+    let saved = std::fs::read_to_string(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert!(saved.lines().any(|line| line == "global x = \"hi\""), "{}", saved);
+}
+
+#[test]
+fn forward_calls_compile_with_any_number_of_jobs() {
+    let source = "
+        def even(n) if n == 0 then 1 else odd(n - 1)
+        def odd(n) if n == 0 then 0 else even(n - 1)
+        even(10) + odd(7)
+    ";
+    assert_eq!(values(&run(&["-j", "1"], source)), ["2"]);
+    assert_eq!(values(&run(&["-j", "4"], source)), ["2"]);
+
+    // Functions of imported modules may call the ones defined after them too.
+    let dir = std::env::temp_dir().join(format!("cobra-jobs-test-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("parity.ks"), "def is_even(n) helper(n)\ndef helper(n) if n % 2 == 0 then 1 else 0").unwrap();
+    let main = dir.join("main.ks");
+    std::fs::write(&main, "from parity import is_even\nis_even(4) + is_even(3)").unwrap();
+    for jobs in ["1", "4"] {
+        assert_eq!(values(&run(&["-j", jobs, main.to_str().unwrap()], "")), ["1"], "-j {}", jobs);
+    }
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn recursion_limit_errors_are_caught() {
+    let source = "
+        :max_depth 50
+        def forever(n) forever(n + 1)
+        def down(n) if n > 0 then down(n - 1) else n
+        try: forever(0) except err: err
+        down(40)
+    ";
+    let stdout = run(&[], source);
+    let values = values(&stdout);
+    assert_eq!(values.len(), 2, "{}", stdout);
+    assert!(values[0].starts_with("\"Maximum recursion depth 50 exceeded in "), "{}", stdout);
+    // The call depth of the caught calls is restored.
+    assert_eq!(values[1], "0");
+}